@@ -1,11 +1,23 @@
 import {
+  ASSOCIATED_TOKEN_PROGRAM_ID,
+  createAssociatedTokenAccountInstruction,
   createInitializeMintInstruction,
   createMintToInstruction,
   createInitializeAccountInstruction,
+  createInitializeNonTransferableMintInstruction,
+  createInitializeTransferFeeConfigInstruction,
+  ExtensionType,
+  getAccountLen,
+  getAssociatedTokenAddressSync,
+  getMintLen,
   MINT_SIZE,
+  MintLayout,
+  NATIVE_MINT,
+  TOKEN_2022_PROGRAM_ID,
   TOKEN_PROGRAM_ID,
 } from "@solana/spl-token";
 import {
+  ComputeBudgetProgram,
   Keypair,
   LAMPORTS_PER_SOL,
   PublicKey,
@@ -15,16 +27,36 @@ import {
   TransactionInstruction,
 } from "@solana/web3.js";
 import { expect, test, beforeAll } from "bun:test";
-import { LiteSVM } from "litesvm";
+import { Clock, LiteSVM } from "litesvm";
 import {
+  ConsumeEventsResultSchema,
+  DELEGATE_CANCEL_ORDERS,
+  DELEGATE_PLACE_ORDERS,
   InstructionSchema,
+  LegacyMarketEventsSchema,
+  LegacyMarketStateSchema,
+  LegacyOrderbookSchema,
+  LegacyUserBalanceSchema,
   MARKET_EVENT_LEN,
+  MARKET_STATE_LEN,
+  MARKET_STATE_VERSION,
+  MAX_OPEN_ORDERS,
+  MAX_ORDERS,
+  MarketEventsSchema,
   MarketStateSchema,
   ORDERBOOK_LEN,
+  OraclePriceSchema,
   OrderbookSchema,
+  decodeOrderbookEvents,
+  PlaceOrderResultSchema,
+  TRIGGER_BOOK_LEN,
+  TriggerBookSchema,
+  USER_BALANCE_LEN,
+  USER_BALANCE_VERSION,
   UserBalanceSchema,
 } from "./states";
 import BN from "bn.js";
+import { rustEnum, struct, u8, u64 } from "@coral-xyz/borsh";
 
 let testEnv: {
   svm: LiteSVM;
@@ -526,7 +558,8 @@ test("Initialize Market", async () => {
   expect(marketState.quote_mint.equals(quoteAsset)).toBeTrue();
   expect(marketState.min_order_size.eq(minOrderSize)).toBeTrue();
   expect(marketState.tick_size.eq(tickSize)).toBeTrue();
-  expect(marketState.is_initialized).toBeTrue();
+  expect(Buffer.from(marketState.discriminator).toString()).toBe("market\0\0");
+  expect(marketState.version).toBe(MARKET_STATE_VERSION);
 
   console.log(" Market initialized successfully!");
 });
@@ -571,6 +604,7 @@ test("Create User Quote Balance Account and Deposit Funds", async () => {
         isSigner: false,
         isWritable: false,
       },
+      { pubkey: testEnv.quoteAsset, isSigner: false, isWritable: false },
     ],
   });
 
@@ -660,6 +694,7 @@ test("Create User Base Balance Account and Deposit Funds", async () => {
         isSigner: false,
         isWritable: false,
       },
+      { pubkey: testEnv.baseAsset, isSigner: false, isWritable: false },
     ],
   });
 
@@ -862,6 +897,7 @@ test("Place buy order,Place sell order, Check market events", async () => {
         isSigner: false,
         isWritable: false,
       },
+      { pubkey: testEnv.baseAsset, isSigner: false, isWritable: false },
     ],
   });
 
@@ -991,6 +1027,13 @@ test("Place buy order,Place sell order, Check market events", async () => {
   expect(bidsDataAfter.active_orders_count.eq(new BN(0))).toBeTrue();
   console.log("Buy order was also filled/removed from bids orderbook");
 
+  // the fill should have moved last_price, which feeds the TWAP accumulator
+  const marketAfterFill = MarketStateSchema.decode(
+    Buffer.from(svm.getAccount(marketAccountPda)!.data)
+  );
+  expect(marketAfterFill.last_price.eq(price)).toBeTrue();
+  console.log("Market last price updated on fill");
+
   // balance check before consume_events
   const userBalanceBeforeConsume = svm.getAccount(userBalancePda);
   const userBalanceDataBefore = UserBalanceSchema.decode(
@@ -1029,10 +1072,10 @@ test("Place buy order,Place sell order, Check market events", async () => {
     )} USDC`
   );
 
-  const consumeEventsDataBuffer = Buffer.alloc(1);
+  const consumeEventsDataBuffer = Buffer.alloc(3);
   InstructionSchema.encode(
     {
-      ConsumeEvents: {},
+      ConsumeEvents: { limit: 0 },
     },
     consumeEventsDataBuffer
   );
@@ -1048,31 +1091,8 @@ test("Place buy order,Place sell order, Check market events", async () => {
       },
       { pubkey: marketAccountPda, isSigner: false, isWritable: true },
       { pubkey: marketEventsAcc, isSigner: false, isWritable: true },
-      { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
       { pubkey: userBalancePda, isSigner: false, isWritable: true }, // Maker
       { pubkey: takerBalancePda, isSigner: false, isWritable: true }, // Taker
-      {
-        pubkey: userBaseTokenAccount,
-        isSigner: false,
-        isWritable: true,
-      },
-      {
-        pubkey: userQuoteTokenAccount,
-        isSigner: false,
-        isWritable: true,
-      },
-      {
-        pubkey: takerBaseTokenAccount,
-        isSigner: false,
-        isWritable: true,
-      },
-      {
-        pubkey: takerQuoteTokenAccount,
-        isSigner: false,
-        isWritable: true,
-      },
-      { pubkey: baseVaultPda, isSigner: false, isWritable: true },
-      { pubkey: quoteVaultPda, isSigner: false, isWritable: true },
     ],
   });
 
@@ -1295,6 +1315,7 @@ test("Complex Multi-User Order Matching and Consume Events", async () => {
             isSigner: false,
             isWritable: false,
           },
+          { pubkey: testEnv.baseAsset, isSigner: false, isWritable: false },
         ],
       })
     );
@@ -1326,6 +1347,7 @@ test("Complex Multi-User Order Matching and Consume Events", async () => {
             isSigner: false,
             isWritable: false,
           },
+          { pubkey: testEnv.quoteAsset, isSigner: false, isWritable: false },
         ],
       })
     );
@@ -1504,8 +1526,11 @@ test("Complex Multi-User Order Matching and Consume Events", async () => {
   // scenario 4: complex consume events with multiple users
   console.log("\nConsuming events for all participants:");
 
-  const consumeEventsBuffer = Buffer.alloc(1);
-  InstructionSchema.encode({ ConsumeEvents: {} }, consumeEventsBuffer);
+  const consumeEventsBuffer = Buffer.alloc(3);
+  InstructionSchema.encode(
+    { ConsumeEvents: { limit: 0 } },
+    consumeEventsBuffer
+  );
 
   const consumeEventsIx = new TransactionInstruction({
     programId: programId.publicKey,
@@ -1518,26 +1543,10 @@ test("Complex Multi-User Order Matching and Consume Events", async () => {
       },
       { pubkey: marketAccountPda, isSigner: false, isWritable: true },
       { pubkey: marketEventsAcc, isSigner: false, isWritable: true },
-      { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
       { pubkey: userBalancePda, isSigner: false, isWritable: true },
+      { pubkey: takerBalancePda, isSigner: false, isWritable: true },
       { pubkey: user2Data.balancePda, isSigner: false, isWritable: true },
       { pubkey: user3Data.balancePda, isSigner: false, isWritable: true },
-      { pubkey: userBaseTokenAccount, isSigner: false, isWritable: true },
-      { pubkey: userQuoteTokenAccount, isSigner: false, isWritable: true },
-      { pubkey: user2Data.baseTokenAccount, isSigner: false, isWritable: true },
-      {
-        pubkey: user2Data.quoteTokenAccount,
-        isSigner: false,
-        isWritable: true,
-      },
-      { pubkey: user3Data.baseTokenAccount, isSigner: false, isWritable: true },
-      {
-        pubkey: user3Data.quoteTokenAccount,
-        isSigner: false,
-        isWritable: true,
-      },
-      { pubkey: baseVaultPda, isSigner: false, isWritable: true },
-      { pubkey: quoteVaultPda, isSigner: false, isWritable: true },
     ],
   });
 
@@ -1813,6 +1822,14 @@ test("Settle Balance Operations for Complex Order Consumed above", async () => {
         { pubkey: baseVaultPda, isSigner: false, isWritable: true },
         { pubkey: quoteVaultPda, isSigner: false, isWritable: true },
         { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+        { pubkey: testEnv.baseAsset, isSigner: false, isWritable: false },
+        { pubkey: testEnv.quoteAsset, isSigner: false, isWritable: false },
+        { pubkey: SystemProgram.programId, isSigner: false, isWritable: false },
+        {
+          pubkey: ASSOCIATED_TOKEN_PROGRAM_ID,
+          isSigner: false,
+          isWritable: false,
+        },
       ],
     });
 
@@ -1992,6 +2009,7 @@ test("Extreme Order Matching Scenarios", async () => {
         isSigner: false,
         isWritable: false,
       },
+      { pubkey: testEnv.baseAsset, isSigner: false, isWritable: false },
     ],
   });
 
@@ -2021,6 +2039,7 @@ test("Extreme Order Matching Scenarios", async () => {
         isSigner: false,
         isWritable: false,
       },
+      { pubkey: testEnv.quoteAsset, isSigner: false, isWritable: false },
     ],
   });
 
@@ -2351,8 +2370,11 @@ test("Comprehensive Stress Test with Event Processing", async () => {
 
   while (shouldContinue && iterationCount < 10) {
     // Limit iterations
-    const consumeEventsBuffer = Buffer.alloc(1);
-    InstructionSchema.encode({ ConsumeEvents: {} }, consumeEventsBuffer);
+    const consumeEventsBuffer = Buffer.alloc(3);
+    InstructionSchema.encode(
+      { ConsumeEvents: { limit: 7 } },
+      consumeEventsBuffer
+    );
 
     const consumeEventsIx = new TransactionInstruction({
       programId: programId.publicKey,
@@ -2365,12 +2387,7 @@ test("Comprehensive Stress Test with Event Processing", async () => {
         },
         { pubkey: marketAccountPda, isSigner: false, isWritable: true },
         { pubkey: marketEventsAcc, isSigner: false, isWritable: true },
-        { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
         { pubkey: userBalancePda, isSigner: false, isWritable: true },
-        { pubkey: userBaseTokenAccount, isSigner: false, isWritable: true },
-        { pubkey: userQuoteTokenAccount, isSigner: false, isWritable: true },
-        { pubkey: baseVaultPda, isSigner: false, isWritable: true },
-        { pubkey: quoteVaultPda, isSigner: false, isWritable: true },
       ],
     });
 
@@ -2390,7 +2407,7 @@ test("Comprehensive Stress Test with Event Processing", async () => {
       break;
     }
 
-    totalEventsProcessed += 7; // MAX_EVENTS_TO_CONSUME
+    totalEventsProcessed += 7; // limit
     iterationCount++;
 
     // For simulation purposes, we'll break after a few iterations
@@ -2465,6 +2482,5749 @@ test("Comprehensive Stress Test with Event Processing", async () => {
   console.log("   - Final state consistency confirmed");
 });
 
+test("Fills accumulate the TWAP and move the mid-price EMA", async () => {
+  const { svm, authority } = testEnv;
+
+  const baseMint = await newBaseMint();
+  const listing = await createMarket(baseMint, TOKEN_PROGRAM_ID);
+  expect(isFailed(listing.result)).toBeFalse();
+
+  // TWAP reference over a 60s window with a 50% band
+  const configureIx = marketIx(
+    {
+      ConfigurePriceBand: {
+        price_band_bps: 5_000,
+        price_reference: 0,
+        oracle: PublicKey.default,
+        max_oracle_staleness: new BN(0),
+        twap_window: new BN(60),
+        circuit_breaker_bps: 0,
+        circuit_breaker_window: new BN(0),
+      },
+    },
+    62,
+    [meta(authority.publicKey, false, true), meta(listing.market, true)]
+  );
+  expect(isFailed(await sendIxs([configureIx], authority))).toBeFalse();
+
+  const seller = await newMarketTrader(
+    listing,
+    new BN(10 * LAMPORTS_PER_SOL),
+    new BN(0)
+  );
+  const buyer = await newMarketTrader(
+    listing,
+    new BN(0),
+    new BN(1_000 * 1_000_000)
+  );
+  const usdc = (amount: number) => new BN(amount * 1_000_000);
+  const place = (trader: MarketTrader, side: number, price: number) =>
+    sendIxs(
+      [
+        marketOrderIx(
+          listing,
+          trader,
+          side,
+          usdc(price),
+          new BN(LAMPORTS_PER_SOL)
+        ),
+      ],
+      trader.keypair
+    );
+  const marketState = () =>
+    MarketStateSchema.decode(Buffer.from(svm.getAccount(listing.market)!.data));
+  const t0 = BigInt(svm.getClock().unixTimestamp.toString());
+  const warpTo = (timestamp: bigint) => {
+    const clock = svm.getClock();
+    svm.setClock(
+      new Clock(
+        clock.slot,
+        clock.epochStartTimestamp,
+        clock.epoch,
+        clock.leaderScheduleEpoch,
+        timestamp
+      )
+    );
+  };
+
+  // A 90/130 spread seeds the EMA at the 110 mid
+  expect(isFailed(await place(buyer, 1, 90))).toBeFalse();
+  expect(isFailed(await place(seller, 2, 130))).toBeFalse();
+  expect(marketState().mid_price_ema.eq(usdc(110))).toBeTrue();
+
+  // First fill at 100: nothing has accrued yet and the EMA sees no time pass
+  expect(isFailed(await place(seller, 2, 100))).toBeFalse();
+  expect(isFailed(await place(buyer, 1, 100))).toBeFalse();
+  let state = marketState();
+  expect(state.last_price.eq(usdc(100))).toBeTrue();
+  expect(state.cumulative_price.isZero()).toBeTrue();
+  expect(state.last_price_timestamp.toString()).toBe(t0.toString());
+  expect(state.mid_price_ema.eq(usdc(110))).toBeTrue();
+  expect(state.twap_checkpoint_timestamp.isZero()).toBeTrue();
+
+  // 30s later the first band check takes the TWAP checkpoint. The 90/110
+  // spread pulls the EMA a tenth of the way from 110 towards 100.
+  warpTo(t0 + 30n);
+  expect(isFailed(await place(seller, 2, 110))).toBeFalse();
+  state = marketState();
+  expect(
+    state.twap_checkpoint_cumulative_price.eq(new BN(3_000_000_000))
+  ).toBeTrue();
+  expect(state.twap_checkpoint_timestamp.toString()).toBe(
+    (t0 + 30n).toString()
+  );
+  expect(state.mid_price_ema.eq(usdc(109))).toBeTrue();
+
+  // 100 stood for 30s before the fill at 110
+  expect(isFailed(await place(buyer, 1, 110))).toBeFalse();
+  state = marketState();
+  expect(state.last_price.eq(usdc(110))).toBeTrue();
+  expect(state.cumulative_price.eq(new BN(3_000_000_000))).toBeTrue();
+  expect(state.mid_price_ema.eq(usdc(109))).toBeTrue();
+
+  // 70s on the window has elapsed, so the checkpoint rolls forward and the
+  // TWAP between the two checkpoints is the 110 that stood all along
+  warpTo(t0 + 100n);
+  expect(isFailed(await place(seller, 2, 120))).toBeFalse();
+  const rolled = marketState();
+  expect(
+    rolled.twap_checkpoint_cumulative_price.eq(new BN(10_700_000_000))
+  ).toBeTrue();
+  expect(rolled.twap_checkpoint_timestamp.toString()).toBe(
+    (t0 + 100n).toString()
+  );
+  const twap = rolled.twap_checkpoint_cumulative_price
+    .sub(state.twap_checkpoint_cumulative_price)
+    .div(rolled.twap_checkpoint_timestamp.sub(state.twap_checkpoint_timestamp));
+  expect(twap.eq(usdc(110))).toBeTrue();
+  // 70/300 of the way from 109 towards the 90/120 mid of 105
+  expect(rolled.mid_price_ema.eq(new BN(108_066_667))).toBeTrue();
+
+  expect(isFailed(await place(buyer, 1, 120))).toBeFalse();
+  state = marketState();
+  expect(state.last_price.eq(usdc(120))).toBeTrue();
+  expect(state.cumulative_price.eq(new BN(10_700_000_000))).toBeTrue();
+  expect(state.mid_price_ema.eq(new BN(108_066_667))).toBeTrue();
+
+  // 10s later the TWAP since the checkpoint is 120, so the band is 60..180
+  warpTo(t0 + 110n);
+  expect(isFailed(await place(seller, 2, 190))).toBeTrue();
+  expect(isFailed(await place(seller, 2, 175))).toBeFalse();
+  console.log("TWAP, cumulative price and mid-price EMA track the fills");
+});
+
+test("Price band and cancel-only status reject out-of-range orders", async () => {
+  const {
+    svm,
+    programId,
+    authority,
+    user,
+    marketAccountPda,
+    userBalancePda,
+    userBaseTokenAccount,
+    userQuoteTokenAccount,
+  } = testEnv;
+
+  // Mock oracle: 50 USDC per SOL, published now
+  const oracle = Keypair.generate();
+  const oracleData = Buffer.alloc(16);
+  OraclePriceSchema.encode(
+    {
+      price: new BN(50_000_000),
+      publish_time: new BN(svm.getClock().unixTimestamp.toString()),
+    },
+    oracleData
+  );
+  svm.setAccount(oracle.publicKey, {
+    lamports: LAMPORTS_PER_SOL,
+    data: oracleData,
+    owner: SystemProgram.programId,
+    executable: false,
+  });
+
+  // 10% band around the oracle price
+  const configureResult = await sendIxs(
+    [configurePriceBandIx(1_000, 1, oracle.publicKey, 60)],
+    authority
+  );
+  expect(isFailed(configureResult)).toBeFalse();
+
+  const oracleKey = {
+    pubkey: oracle.publicKey,
+    isSigner: false,
+    isWritable: false,
+  };
+
+  const outsideBand = await sendIxs(
+    [
+      placeOrderIx(
+        user,
+        userBalancePda,
+        userBaseTokenAccount,
+        userQuoteTokenAccount,
+        1,
+        new BN(100_000_000),
+        new BN(LAMPORTS_PER_SOL),
+        [oracleKey]
+      ),
+    ],
+    user
+  );
+  expect(isFailed(outsideBand)).toBeTrue();
+  console.log("Order at 100 USDC rejected by 10% band around 50 USDC");
+
+  const missingOracle = await sendIxs(
+    [
+      placeOrderIx(
+        user,
+        userBalancePda,
+        userBaseTokenAccount,
+        userQuoteTokenAccount,
+        1,
+        new BN(50_000_000),
+        new BN(LAMPORTS_PER_SOL)
+      ),
+    ],
+    user
+  );
+  expect(isFailed(missingOracle)).toBeTrue();
+  console.log("Order without the oracle account rejected");
+
+  // Cancel-only markets reject placement even inside the band
+  const cancelOnlyResult = await sendIxs([setMarketStatusIx(1)], authority);
+  expect(isFailed(cancelOnlyResult)).toBeFalse();
+
+  const marketState = MarketStateSchema.decode(
+    Buffer.from(svm.getAccount(marketAccountPda)!.data)
+  );
+  expect(marketState.status).toBe(1);
+
+  const whileCancelOnly = await sendIxs(
+    [
+      placeOrderIx(
+        user,
+        userBalancePda,
+        userBaseTokenAccount,
+        userQuoteTokenAccount,
+        1,
+        new BN(50_000_000),
+        new BN(LAMPORTS_PER_SOL),
+        [oracleKey]
+      ),
+    ],
+    user
+  );
+  expect(isFailed(whileCancelOnly)).toBeTrue();
+  console.log("Order rejected while market is cancel-only");
+
+  // Restore continuous trading without a band
+  expect(isFailed(await sendIxs([setMarketStatusIx(0)], authority))).toBeFalse();
+  expect(
+    isFailed(
+      await sendIxs(
+        [configurePriceBandIx(0, 0, PublicKey.default, 0)],
+        authority
+      )
+    )
+  ).toBeFalse();
+});
+
+test("A circuit breaker trip cancels a remainder that still crosses the book", async () => {
+  const { svm, authority } = testEnv;
+
+  const baseMint = await newBaseMint();
+  const listing = await createMarket(baseMint, TOKEN_PROGRAM_ID);
+  expect(isFailed(listing.result)).toBeFalse();
+
+  // 5% breaker over a minute, no band
+  const configureIx = marketIx(
+    {
+      ConfigurePriceBand: {
+        price_band_bps: 0,
+        price_reference: 0,
+        oracle: PublicKey.default,
+        max_oracle_staleness: new BN(0),
+        twap_window: new BN(0),
+        circuit_breaker_bps: 500,
+        circuit_breaker_window: new BN(60),
+      },
+    },
+    62,
+    [meta(authority.publicKey, false, true), meta(listing.market, true)]
+  );
+  expect(isFailed(await sendIxs([configureIx], authority))).toBeFalse();
+
+  const usdc = (amount: number) => new BN(amount * 1_000_000);
+  const seller = await newMarketTrader(
+    listing,
+    new BN(2 * LAMPORTS_PER_SOL),
+    new BN(0)
+  );
+  const buyer = await newMarketTrader(listing, new BN(0), usdc(200));
+  for (const price of [50, 60]) {
+    expect(
+      isFailed(
+        await sendIxs(
+          [
+            marketOrderIx(
+              listing,
+              seller,
+              2,
+              usdc(price),
+              new BN(LAMPORTS_PER_SOL)
+            ),
+          ],
+          seller.keypair
+        )
+      )
+    ).toBeFalse();
+  }
+
+  // The fill at 50 arms the breaker and the ask at 60 trips it, leaving a
+  // bid at 60 that would cross the ask still resting there
+  expect(
+    isFailed(
+      await sendIxs(
+        [
+          marketOrderIx(
+            listing,
+            buyer,
+            1,
+            usdc(60),
+            new BN(2 * LAMPORTS_PER_SOL)
+          ),
+        ],
+        buyer.keypair
+      )
+    )
+  ).toBeFalse();
+
+  const marketState = MarketStateSchema.decode(
+    Buffer.from(svm.getAccount(listing.market)!.data)
+  );
+  expect(marketState.status).toBe(1);
+  const bids = OrderbookSchema.decode(
+    Buffer.from(svm.getAccount(listing.bids)!.data)
+  );
+  expect(bids.active_orders_count.isZero()).toBeTrue();
+  const asks = OrderbookSchema.decode(
+    Buffer.from(svm.getAccount(listing.asks)!.data)
+  );
+  expect(asks.active_orders_count.toNumber()).toBe(1);
+
+  // Only the filled token's 60 stays locked until the fill is consumed
+  const balance = UserBalanceSchema.decode(
+    Buffer.from(svm.getAccount(buyer.balancePda)!.data)
+  );
+  expect(balance.locked_quote_balance.eq(usdc(60))).toBeTrue();
+  expect(balance.available_quote_balance.eq(usdc(140))).toBeTrue();
+  expect(balance.open_orders_count).toBe(0);
+  console.log("Breaker trip cancelled the crossing remainder");
+});
+
+test("Pegged orders float with the oracle price", async () => {
+  const {
+    svm,
+    programId,
+    authority,
+    user,
+    taker,
+    asksAcc,
+    userBalancePda,
+    takerBalancePda,
+    userBaseTokenAccount,
+    userQuoteTokenAccount,
+    takerBaseTokenAccount,
+    takerQuoteTokenAccount,
+  } = testEnv;
+
+  const oracle = Keypair.generate();
+  const setOraclePrice = (price: number) => {
+    const data = Buffer.alloc(16);
+    OraclePriceSchema.encode(
+      {
+        price: new BN(price),
+        publish_time: new BN(svm.getClock().unixTimestamp.toString()),
+      },
+      data
+    );
+    svm.setAccount(oracle.publicKey, {
+      lamports: LAMPORTS_PER_SOL,
+      data,
+      owner: SystemProgram.programId,
+      executable: false,
+    });
+  };
+  setOraclePrice(50_000_000);
+
+  // Oracle configured for pegging only, no band
+  expect(
+    isFailed(
+      await sendIxs([configurePriceBandIx(0, 0, oracle.publicKey, 60)], authority)
+    )
+  ).toBeFalse();
+  const oracleKey = {
+    pubkey: oracle.publicKey,
+    isSigner: false,
+    isWritable: false,
+  };
+
+  // Sell at oracle + 1 USDC, never below 40 USDC
+  const pegData = Buffer.alloc(26);
+  InstructionSchema.encode(
+    {
+      PlacePeggedOrder: {
+        side: 2,
+        peg_offset: new BN(1_000_000),
+        limit_price: new BN(40_000_000),
+        quantity: new BN(LAMPORTS_PER_SOL),
+      },
+    },
+    pegData
+  );
+  const pegIx = placeOrderIx(
+    user,
+    userBalancePda,
+    userBaseTokenAccount,
+    userQuoteTokenAccount,
+    2,
+    new BN(0),
+    new BN(0),
+    [oracleKey]
+  );
+  pegIx.data = pegData;
+  expect(isFailed(await sendIxs([pegIx], user))).toBeFalse();
+
+  const asksBefore = OrderbookSchema.decode(
+    Buffer.from(svm.getAccount(asksAcc)!.data)
+  );
+  const pegged = asksBefore.orders[asksBefore.active_orders_count.toNumber() - 1];
+  expect(pegged.order_type).toBe(1);
+  expect(pegged.peg_offset.eq(new BN(1_000_000))).toBeTrue();
+  expect(pegged.price.eq(new BN(40_000_000))).toBeTrue();
+
+  // A bid at 52 doesn't cross while the oracle is 55 (pegged ask at 56)
+  setOraclePrice(55_000_000);
+  const buyAt52 = placeOrderIx(
+    taker,
+    takerBalancePda,
+    takerBaseTokenAccount,
+    takerQuoteTokenAccount,
+    1,
+    new BN(52_000_000),
+    new BN(LAMPORTS_PER_SOL),
+    [oracleKey]
+  );
+  await sendIxs([buyAt52], taker);
+  const asksAfterHighOracle = OrderbookSchema.decode(
+    Buffer.from(svm.getAccount(asksAcc)!.data)
+  );
+  expect(
+    asksAfterHighOracle.active_orders_count.eq(asksBefore.active_orders_count)
+  ).toBeTrue();
+
+  // Once the oracle drops to 45 the pegged ask (46) sits below the 52 bid
+  setOraclePrice(45_000_000);
+  const buyAgain = placeOrderIx(
+    taker,
+    takerBalancePda,
+    takerBaseTokenAccount,
+    takerQuoteTokenAccount,
+    1,
+    new BN(52_000_000),
+    new BN(LAMPORTS_PER_SOL),
+    [oracleKey]
+  );
+  expect(isFailed(await sendIxs([buyAgain], taker))).toBeFalse();
+  const asksAfter = OrderbookSchema.decode(
+    Buffer.from(svm.getAccount(asksAcc)!.data)
+  );
+  expect(
+    asksAfter.active_orders_count.lt(asksBefore.active_orders_count)
+  ).toBeTrue();
+  console.log("Pegged ask filled after the oracle moved below the bid");
+
+  expect(
+    isFailed(
+      await sendIxs(
+        [configurePriceBandIx(0, 0, PublicKey.default, 0)],
+        authority
+      )
+    )
+  ).toBeFalse();
+});
+
+test("Pegged bids release the quote locked above their fill price", async () => {
+  const { svm, authority, consumerEventsAuthority } = testEnv;
+
+  const baseMint = await newBaseMint();
+  const listing = await createMarket(baseMint, TOKEN_PROGRAM_ID);
+  expect(isFailed(listing.result)).toBeFalse();
+
+  // Oracle at 50 USDC, configured for pegging only
+  const oracle = Keypair.generate();
+  const oracleData = Buffer.alloc(16);
+  OraclePriceSchema.encode(
+    {
+      price: new BN(50 * 1_000_000),
+      publish_time: new BN(svm.getClock().unixTimestamp.toString()),
+    },
+    oracleData
+  );
+  svm.setAccount(oracle.publicKey, {
+    lamports: LAMPORTS_PER_SOL,
+    data: oracleData,
+    owner: SystemProgram.programId,
+    executable: false,
+  });
+  const configureIx = marketIx(
+    {
+      ConfigurePriceBand: {
+        price_band_bps: 0,
+        price_reference: 0,
+        oracle: oracle.publicKey,
+        max_oracle_staleness: new BN(60),
+        twap_window: new BN(0),
+        circuit_breaker_bps: 0,
+        circuit_breaker_window: new BN(0),
+      },
+    },
+    62,
+    [meta(authority.publicKey, false, true), meta(listing.market, true)]
+  );
+  expect(isFailed(await sendIxs([configureIx], authority))).toBeFalse();
+
+  const seller = await newMarketTrader(
+    listing,
+    new BN(2 * LAMPORTS_PER_SOL),
+    new BN(0)
+  );
+  const buyer = await newMarketTrader(
+    listing,
+    new BN(0),
+    new BN(200 * 1_000_000)
+  );
+  const usdc = (amount: number) => new BN(amount * 1_000_000);
+  const withOracle = (ix: TransactionInstruction) => {
+    ix.keys.push(meta(oracle.publicKey));
+    return ix;
+  };
+  const limit = (trader: MarketTrader, side: number, price: number) =>
+    sendIxs(
+      [
+        withOracle(
+          marketOrderIx(
+            listing,
+            trader,
+            side,
+            usdc(price),
+            new BN(LAMPORTS_PER_SOL)
+          )
+        ),
+      ],
+      trader.keypair
+    );
+  // A bid at oracle + offset, never above 60
+  const peggedBid = (offset: number) =>
+    sendIxs(
+      [
+        withOracle(
+          marketIx(
+            {
+              PlacePeggedOrder: {
+                side: 1,
+                peg_offset: usdc(offset),
+                limit_price: usdc(60),
+                quantity: new BN(LAMPORTS_PER_SOL),
+              },
+            },
+            26,
+            marketOrderIx(listing, buyer, 1, new BN(0), new BN(0)).keys
+          )
+        ),
+      ],
+      buyer.keypair
+    );
+
+  // As the maker: rests at 49 with 60 locked, then a sell at 45 fills it at 49
+  expect(isFailed(await peggedBid(-1))).toBeFalse();
+  expect(isFailed(await limit(seller, 2, 45))).toBeFalse();
+  // As the taker: bids 52 into an ask at 51, still with 60 locked
+  expect(isFailed(await limit(seller, 2, 51))).toBeFalse();
+  expect(isFailed(await peggedBid(2))).toBeFalse();
+
+  const queue = MarketEventsSchema.decode(
+    Buffer.from(svm.getAccount(listing.events)!.data)
+  );
+  expect(queue.count.toNumber()).toBe(2);
+  const [makerFill, takerFill] = queue.events;
+  expect(makerFill.maker.equals(buyer.keypair.publicKey)).toBeTrue();
+  expect(makerFill.price.eq(usdc(49))).toBeTrue();
+  expect(makerFill.locked_price.eq(usdc(60))).toBeTrue();
+  expect(takerFill.taker.equals(buyer.keypair.publicKey)).toBeTrue();
+  expect(takerFill.price.eq(usdc(51))).toBeTrue();
+  expect(takerFill.locked_price.eq(usdc(60))).toBeTrue();
+  expect(
+    UserBalanceSchema.decode(
+      Buffer.from(svm.getAccount(buyer.balancePda)!.data)
+    ).locked_quote_balance.eq(usdc(120))
+  ).toBeTrue();
+
+  const consumeIx = marketIx({ ConsumeEvents: { limit: 0 } }, 3, [
+    meta(consumerEventsAuthority.publicKey, false, true),
+    meta(listing.market, true),
+    meta(listing.events, true),
+    meta(seller.balancePda, true),
+    meta(buyer.balancePda, true),
+  ]);
+  expect(
+    isFailed(await sendIxs([consumeIx], authority, consumerEventsAuthority))
+  ).toBeFalse();
+
+  // 100 paid for two tokens, the 11 + 9 locked above the fills is back
+  const settled = UserBalanceSchema.decode(
+    Buffer.from(svm.getAccount(buyer.balancePda)!.data)
+  );
+  expect(settled.locked_quote_balance.isZero()).toBeTrue();
+  expect(settled.available_quote_balance.eq(usdc(100))).toBeTrue();
+  expect(
+    settled.pending_base_balance.eq(new BN(2 * LAMPORTS_PER_SOL))
+  ).toBeTrue();
+  expect(settled.open_orders_count).toBe(0);
+  console.log("Pegged maker and taker bids released their excess lock");
+});
+
+test("Stop-loss trigger order locks funds and is released by the crank", async () => {
+  const {
+    svm,
+    programId,
+    authority,
+    user,
+    marketAccountPda,
+    bidsAcc,
+    asksAcc,
+    marketEventsAcc,
+    userBalancePda,
+  } = testEnv;
+
+  const clockSysvar = new PublicKey(
+    "SysvarC1ock11111111111111111111111111111111"
+  );
+  const rentSysvar = new PublicKey(
+    "SysvarRent111111111111111111111111111111111"
+  );
+
+  const triggerBook = Keypair.generate();
+  const createTriggerBookIx = SystemProgram.createAccount({
+    fromPubkey: authority.publicKey,
+    newAccountPubkey: triggerBook.publicKey,
+    lamports: Number(
+      svm.minimumBalanceForRentExemption(BigInt(TRIGGER_BOOK_LEN))
+    ),
+    space: TRIGGER_BOOK_LEN,
+    programId: programId.publicKey,
+  });
+
+  const initData = Buffer.alloc(1);
+  InstructionSchema.encode({ InitializeTriggerBook: {} }, initData);
+  const initTriggerBookIx = new TransactionInstruction({
+    programId: programId.publicKey,
+    data: initData,
+    keys: [
+      { pubkey: authority.publicKey, isSigner: true, isWritable: false },
+      { pubkey: marketAccountPda, isSigner: false, isWritable: true },
+      { pubkey: triggerBook.publicKey, isSigner: false, isWritable: true },
+      { pubkey: rentSysvar, isSigner: false, isWritable: false },
+    ],
+  });
+
+  expect(
+    isFailed(
+      await sendIxs(
+        [createTriggerBookIx, initTriggerBookIx],
+        authority,
+        triggerBook
+      )
+    )
+  ).toBeFalse();
+
+  const marketBefore = MarketStateSchema.decode(
+    Buffer.from(svm.getAccount(marketAccountPda)!.data)
+  );
+  expect(marketBefore.trigger_book.equals(triggerBook.publicKey)).toBeTrue();
+  const lastPrice: BN = marketBefore.last_price;
+
+  const balanceBefore = UserBalanceSchema.decode(
+    Buffer.from(svm.getAccount(userBalancePda)!.data)
+  );
+
+  // Sell stop at the current last price, so it is immediately triggerable
+  const quantity = new BN(LAMPORTS_PER_SOL / 10);
+  const placeData = Buffer.alloc(27);
+  InstructionSchema.encode(
+    {
+      PlaceTriggerOrder: {
+        side: 2,
+        trigger_type: 0,
+        trigger_price: lastPrice,
+        price: lastPrice,
+        quantity,
+      },
+    },
+    placeData
+  );
+  const placeTriggerIx = new TransactionInstruction({
+    programId: programId.publicKey,
+    data: placeData,
+    keys: [
+      { pubkey: user.publicKey, isSigner: true, isWritable: false },
+      { pubkey: userBalancePda, isSigner: false, isWritable: true },
+      { pubkey: marketAccountPda, isSigner: false, isWritable: true },
+      { pubkey: triggerBook.publicKey, isSigner: false, isWritable: true },
+      { pubkey: clockSysvar, isSigner: false, isWritable: false },
+    ],
+  });
+  expect(isFailed(await sendIxs([placeTriggerIx], user))).toBeFalse();
+
+  const balanceAfter = UserBalanceSchema.decode(
+    Buffer.from(svm.getAccount(userBalancePda)!.data)
+  );
+  expect(
+    balanceAfter.locked_base_balance
+      .sub(balanceBefore.locked_base_balance)
+      .eq(quantity)
+  ).toBeTrue();
+
+  let book = TriggerBookSchema.decode(
+    Buffer.from(svm.getAccount(triggerBook.publicKey)!.data)
+  );
+  expect(book.active_orders_count.eq(new BN(1))).toBeTrue();
+  expect(book.orders[0].trigger_price.eq(lastPrice)).toBeTrue();
+
+  // Anyone can crank; the payer here is not involved in the market at all
+  const crankData = Buffer.alloc(1);
+  InstructionSchema.encode({ TriggerOrders: {} }, crankData);
+  const crankIx = new TransactionInstruction({
+    programId: programId.publicKey,
+    data: crankData,
+    keys: [
+      { pubkey: marketAccountPda, isSigner: false, isWritable: true },
+      { pubkey: bidsAcc, isSigner: false, isWritable: true },
+      { pubkey: asksAcc, isSigner: false, isWritable: true },
+      { pubkey: marketEventsAcc, isSigner: false, isWritable: true },
+      { pubkey: triggerBook.publicKey, isSigner: false, isWritable: true },
+      { pubkey: clockSysvar, isSigner: false, isWritable: false },
+    ],
+  });
+  expect(isFailed(await sendIxs([crankIx], authority))).toBeFalse();
+
+  book = TriggerBookSchema.decode(
+    Buffer.from(svm.getAccount(triggerBook.publicKey)!.data)
+  );
+  expect(book.active_orders_count.eq(new BN(0))).toBeTrue();
+  console.log("Triggered stop-loss moved into the live book");
+});
+
+test("Iceberg order shows one slice and releases the hidden rest on cancel", async () => {
+  const {
+    svm,
+    programId,
+    user,
+    marketAccountPda,
+    bidsAcc,
+    asksAcc,
+    marketEventsAcc,
+    userBalancePda,
+    userBaseTokenAccount,
+    userQuoteTokenAccount,
+  } = testEnv;
+
+  const balanceBefore = UserBalanceSchema.decode(
+    Buffer.from(svm.getAccount(userBalancePda)!.data)
+  );
+
+  // 3 SOL at 1000 USDC, showing 1 SOL at a time; far above any bid so it rests
+  const total = new BN(3 * LAMPORTS_PER_SOL);
+  const display = new BN(LAMPORTS_PER_SOL);
+  const icebergIx = placeOrderIx(
+    user,
+    userBalancePda,
+    userBaseTokenAccount,
+    userQuoteTokenAccount,
+    2,
+    new BN(0),
+    new BN(0)
+  );
+  icebergIx.data = Buffer.alloc(26);
+  InstructionSchema.encode(
+    {
+      PlaceIcebergOrder: {
+        side: 2,
+        price: new BN(1_000_000_000),
+        quantity: total,
+        display_quantity: display,
+      },
+    },
+    icebergIx.data
+  );
+  expect(isFailed(await sendIxs([icebergIx], user))).toBeFalse();
+
+  const asks = OrderbookSchema.decode(
+    Buffer.from(svm.getAccount(asksAcc)!.data)
+  );
+  const iceberg = asks.orders[asks.active_orders_count.toNumber() - 1];
+  expect(iceberg.order_type).toBe(2);
+  expect(iceberg.quantity.eq(display)).toBeTrue();
+  expect(iceberg.hidden_quantity.eq(total.sub(display))).toBeTrue();
+
+  const balancePlaced = UserBalanceSchema.decode(
+    Buffer.from(svm.getAccount(userBalancePda)!.data)
+  );
+  expect(
+    balancePlaced.locked_base_balance
+      .sub(balanceBefore.locked_base_balance)
+      .eq(total)
+  ).toBeTrue();
+
+  const cancelData = Buffer.alloc(9);
+  InstructionSchema.encode(
+    { CancelOrder: { order_id: iceberg.order_id } },
+    cancelData
+  );
+  const cancelIx = new TransactionInstruction({
+    programId: programId.publicKey,
+    data: cancelData,
+    keys: [
+      { pubkey: user.publicKey, isSigner: true, isWritable: false },
+      { pubkey: userBalancePda, isSigner: false, isWritable: true },
+      { pubkey: marketAccountPda, isSigner: false, isWritable: false },
+      { pubkey: bidsAcc, isSigner: false, isWritable: true },
+      { pubkey: asksAcc, isSigner: false, isWritable: true },
+      { pubkey: marketEventsAcc, isSigner: false, isWritable: true },
+      {
+        pubkey: new PublicKey("SysvarC1ock11111111111111111111111111111111"),
+        isSigner: false,
+        isWritable: false,
+      },
+    ],
+  });
+  expect(isFailed(await sendIxs([cancelIx], user))).toBeFalse();
+
+  // The whole 3 SOL is released, not just the visible slice
+  const balanceCancelled = UserBalanceSchema.decode(
+    Buffer.from(svm.getAccount(userBalancePda)!.data)
+  );
+  expect(
+    balanceCancelled.locked_base_balance.eq(balanceBefore.locked_base_balance)
+  ).toBeTrue();
+  console.log("Iceberg cancel released visible and hidden quantity");
+});
+
+test("Call auction collects crossing orders and uncrosses them at one price", async () => {
+  const {
+    svm,
+    programId,
+    authority,
+    user,
+    taker,
+    marketAccountPda,
+    bidsAcc,
+    asksAcc,
+    marketEventsAcc,
+    userBalancePda,
+    takerBalancePda,
+    userBaseTokenAccount,
+    userQuoteTokenAccount,
+    takerBaseTokenAccount,
+    takerQuoteTokenAccount,
+  } = testEnv;
+
+  const bestPrices = () => {
+    const bids = OrderbookSchema.decode(Buffer.from(svm.getAccount(bidsAcc)!.data));
+    const asks = OrderbookSchema.decode(Buffer.from(svm.getAccount(asksAcc)!.data));
+    const prices = (book: any) =>
+      book.orders
+        .slice(0, book.active_orders_count.toNumber())
+        .map((order: any) => order.price.toNumber());
+    return {
+      bestBid: Math.max(0, ...prices(bids)),
+      bestAsk: Math.min(Number.MAX_SAFE_INTEGER, ...prices(asks)),
+    };
+  };
+
+  expect(isFailed(await sendIxs([setMarketStatusIx(2)], authority))).toBeFalse();
+
+  // Buy at 60 and sell at 40 would trade immediately in continuous mode
+  expect(
+    isFailed(
+      await sendIxs(
+        [
+          placeOrderIx(
+            user,
+            userBalancePda,
+            userBaseTokenAccount,
+            userQuoteTokenAccount,
+            1,
+            new BN(60_000_000),
+            new BN(LAMPORTS_PER_SOL)
+          ),
+        ],
+        user
+      )
+    )
+  ).toBeFalse();
+  expect(
+    isFailed(
+      await sendIxs(
+        [
+          placeOrderIx(
+            taker,
+            takerBalancePda,
+            takerBaseTokenAccount,
+            takerQuoteTokenAccount,
+            2,
+            new BN(40_000_000),
+            new BN(LAMPORTS_PER_SOL)
+          ),
+        ],
+        taker
+      )
+    )
+  ).toBeFalse();
+
+  const collected = bestPrices();
+  expect(collected.bestBid).toBeGreaterThanOrEqual(collected.bestAsk);
+  console.log(
+    `Auction book crossed: bid ${collected.bestBid} >= ask ${collected.bestAsk}`
+  );
+
+  const runAuctionData = Buffer.alloc(1);
+  InstructionSchema.encode({ RunAuction: {} }, runAuctionData);
+  const runAuctionIx = new TransactionInstruction({
+    programId: programId.publicKey,
+    data: runAuctionData,
+    keys: [
+      { pubkey: authority.publicKey, isSigner: true, isWritable: false },
+      { pubkey: marketAccountPda, isSigner: false, isWritable: true },
+      { pubkey: bidsAcc, isSigner: false, isWritable: true },
+      { pubkey: asksAcc, isSigner: false, isWritable: true },
+      { pubkey: marketEventsAcc, isSigner: false, isWritable: true },
+      {
+        pubkey: new PublicKey("SysvarC1ock11111111111111111111111111111111"),
+        isSigner: false,
+        isWritable: false,
+      },
+    ],
+  });
+
+  // Only the market authority can close the auction
+  const notAuthority = await sendIxs(
+    [
+      new TransactionInstruction({
+        ...runAuctionIx,
+        keys: [
+          { pubkey: user.publicKey, isSigner: true, isWritable: false },
+          ...runAuctionIx.keys.slice(1),
+        ],
+      }),
+    ],
+    user
+  );
+  expect(isFailed(notAuthority)).toBeTrue();
+
+  expect(isFailed(await sendIxs([runAuctionIx], authority))).toBeFalse();
+
+  const marketState = MarketStateSchema.decode(
+    Buffer.from(svm.getAccount(marketAccountPda)!.data)
+  );
+  expect(marketState.status).toBe(0);
+
+  const uncrossed = bestPrices();
+  expect(uncrossed.bestBid).toBeLessThan(uncrossed.bestAsk);
+  expect(marketState.last_price.toNumber()).toBeGreaterThanOrEqual(
+    collected.bestAsk
+  );
+  expect(marketState.last_price.toNumber()).toBeLessThanOrEqual(
+    collected.bestBid
+  );
+  console.log(
+    `Auction cleared at ${marketState.last_price.toString()}, market back to continuous trading`
+  );
+});
+
+test("Auction bids get back the quote locked above the clearing price", async () => {
+  const { svm, authority, consumerEventsAuthority } = testEnv;
+
+  const baseMint = await newBaseMint();
+  const listing = await createMarket(baseMint, TOKEN_PROGRAM_ID);
+  expect(isFailed(listing.result)).toBeFalse();
+  const seller = await newMarketTrader(
+    listing,
+    new BN(LAMPORTS_PER_SOL),
+    new BN(0)
+  );
+  const buyer = await newMarketTrader(
+    listing,
+    new BN(0),
+    new BN(100 * 1_000_000)
+  );
+  const authorityKeys = [
+    meta(authority.publicKey, false, true),
+    meta(listing.market, true),
+  ];
+  expect(
+    isFailed(
+      await sendIxs(
+        [marketIx({ SetMarketStatus: { status: 2 } }, 2, authorityKeys)],
+        authority
+      )
+    )
+  ).toBeFalse();
+
+  // A bid at 60 and an ask at 40 are collected without trading
+  const order = (trader: MarketTrader, side: number, price: number) =>
+    sendIxs(
+      [
+        marketOrderIx(
+          listing,
+          trader,
+          side,
+          new BN(price * 1_000_000),
+          new BN(LAMPORTS_PER_SOL)
+        ),
+      ],
+      trader.keypair
+    );
+  expect(isFailed(await order(buyer, 1, 60))).toBeFalse();
+  expect(isFailed(await order(seller, 2, 40))).toBeFalse();
+
+  const runAuctionIx = marketIx({ RunAuction: {} }, 1, [
+    ...authorityKeys,
+    meta(listing.bids, true),
+    meta(listing.asks, true),
+    meta(listing.events, true),
+    meta(new PublicKey("SysvarC1ock11111111111111111111111111111111")),
+  ]);
+  expect(isFailed(await sendIxs([runAuctionIx], authority))).toBeFalse();
+  const clearing = MarketStateSchema.decode(
+    Buffer.from(svm.getAccount(listing.market)!.data)
+  ).last_price;
+  expect(clearing.lt(new BN(60 * 1_000_000))).toBeTrue();
+
+  const fill = MarketEventsSchema.decode(
+    Buffer.from(svm.getAccount(listing.events)!.data)
+  ).events[0];
+  expect(fill.price.eq(clearing)).toBeTrue();
+  expect(fill.locked_price.eq(new BN(60 * 1_000_000))).toBeTrue();
+
+  const consumeIx = marketIx({ ConsumeEvents: { limit: 0 } }, 3, [
+    meta(consumerEventsAuthority.publicKey, false, true),
+    meta(listing.market, true),
+    meta(listing.events, true),
+    meta(seller.balancePda, true),
+    meta(buyer.balancePda, true),
+  ]);
+  expect(
+    isFailed(await sendIxs([consumeIx], authority, consumerEventsAuthority))
+  ).toBeFalse();
+
+  // The buyer paid the clearing price and nothing stays locked
+  const settled = UserBalanceSchema.decode(
+    Buffer.from(svm.getAccount(buyer.balancePda)!.data)
+  );
+  expect(settled.locked_quote_balance.isZero()).toBeTrue();
+  expect(
+    settled.available_quote_balance.eq(new BN(100 * 1_000_000).sub(clearing))
+  ).toBeTrue();
+  expect(settled.pending_base_balance.eq(new BN(LAMPORTS_PER_SOL))).toBeTrue();
+  const sold = UserBalanceSchema.decode(
+    Buffer.from(svm.getAccount(seller.balancePda)!.data)
+  );
+  expect(sold.pending_quote_balance.eq(clearing)).toBeTrue();
+  console.log(`Auction at ${clearing.toString()} released the bid's excess lock`);
+});
+
+test("PlaceOrder returns its result through return data", async () => {
+  const {
+    svm,
+    user,
+    taker,
+    marketAccountPda,
+    userBalancePda,
+    takerBalancePda,
+    userBaseTokenAccount,
+    userQuoteTokenAccount,
+    takerBaseTokenAccount,
+    takerQuoteTokenAccount,
+  } = testEnv;
+
+  const readResult = (result: unknown) =>
+    PlaceOrderResultSchema.decode(
+      Buffer.from((result as any).returnData().data())
+    );
+
+  const marketBefore = MarketStateSchema.decode(
+    Buffer.from(svm.getAccount(marketAccountPda)!.data)
+  );
+
+  // 1 USDC bid sits below the whole book and rests untouched
+  const restingResult = await sendIxs(
+    [
+      placeOrderIx(
+        user,
+        userBalancePda,
+        userBaseTokenAccount,
+        userQuoteTokenAccount,
+        1,
+        new BN(1_000_000),
+        new BN(LAMPORTS_PER_SOL)
+      ),
+    ],
+    user
+  );
+  expect(isFailed(restingResult)).toBeFalse();
+
+  const resting = readResult(restingResult);
+  expect(resting.order_id.eq(marketBefore.next_order_id)).toBeTrue();
+  expect(resting.filled_quantity.isZero()).toBeTrue();
+  expect(resting.quote_quantity.isZero()).toBeTrue();
+  expect(resting.resting_quantity.eq(new BN(LAMPORTS_PER_SOL))).toBeTrue();
+  expect(resting.makers_hit).toBe(0);
+
+  // A sell at the same price takes the best bids first
+  const quantity = new BN(LAMPORTS_PER_SOL / 2);
+  const takingResult = await sendIxs(
+    [
+      placeOrderIx(
+        taker,
+        takerBalancePda,
+        takerBaseTokenAccount,
+        takerQuoteTokenAccount,
+        2,
+        new BN(1_000_000),
+        quantity
+      ),
+    ],
+    taker
+  );
+  expect(isFailed(takingResult)).toBeFalse();
+
+  const taking = readResult(takingResult);
+  expect(taking.order_id.gt(resting.order_id)).toBeTrue();
+  expect(taking.filled_quantity.eq(quantity)).toBeTrue();
+  expect(taking.resting_quantity.isZero()).toBeTrue();
+  expect(taking.makers_hit).toBeGreaterThan(0);
+  expect(taking.quote_quantity.gtn(0)).toBeTrue();
+  console.log(
+    `Sell filled ${taking.filled_quantity.toString()} against ${taking.makers_hit} makers for ${taking.quote_quantity.toString()} quote`
+  );
+});
+
+test("State changes are logged as versioned events", async () => {
+  const {
+    user,
+    marketAccountPda,
+    userBalancePda,
+    userBaseTokenAccount,
+    userQuoteTokenAccount,
+  } = testEnv;
+
+  const result = await sendIxs(
+    [
+      placeOrderIx(
+        user,
+        userBalancePda,
+        userBaseTokenAccount,
+        userQuoteTokenAccount,
+        1,
+        new BN(1_000_000),
+        new BN(LAMPORTS_PER_SOL)
+      ),
+    ],
+    user
+  );
+  expect(isFailed(result)).toBeFalse();
+
+  const events = decodeOrderbookEvents((result as any).logs());
+  const placed = events.find((event: any) => event.OrderPlaced)?.OrderPlaced;
+  expect(placed).toBeDefined();
+  expect(placed.market.equals(marketAccountPda)).toBeTrue();
+  expect(placed.owner.equals(user.publicKey)).toBeTrue();
+  expect(placed.side).toBe(1);
+  expect(placed.price.eq(new BN(1_000_000))).toBeTrue();
+  expect(placed.quantity.eq(new BN(LAMPORTS_PER_SOL))).toBeTrue();
+
+  // Progress chatter is compiled out without the debug-logs feature
+  expect(
+    (result as any).logs().some((log: string) => log.includes("Instruction: Place Order"))
+  ).toBeFalse();
+  console.log(`Decoded ${events.length} events from placement logs`);
+});
+
+test("A second program places and cancels orders through CPI with a PDA signer", async () => {
+  const {
+    svm,
+    programId,
+    authority,
+    baseAsset,
+    quoteAsset,
+    marketAccountPda,
+    bidsAcc,
+    asksAcc,
+    marketEventsAcc,
+    baseVaultPda,
+    quoteVaultPda,
+  } = testEnv;
+
+  const callerId = Keypair.generate();
+  svm.addProgramFromFile(
+    callerId.publicKey,
+    process.env.cpi_caller_path ||
+      "../examples/cpi-caller/target/deploy/cpi_caller.so"
+  );
+
+  // The caller's PDA holds the funds and owns its own UserBalance
+  const [traderPda] = PublicKey.findProgramAddressSync(
+    [Buffer.from("trader"), marketAccountPda.toBuffer()],
+    callerId.publicKey
+  );
+  const [traderBalancePda] = PublicKey.findProgramAddressSync(
+    [
+      Buffer.from("user_balance"),
+      traderPda.toBuffer(),
+      marketAccountPda.toBuffer(),
+    ],
+    programId.publicKey
+  );
+  svm.airdrop(traderPda, BigInt(LAMPORTS_PER_SOL));
+
+  const traderBase = Keypair.generate();
+  const traderQuote = Keypair.generate();
+  const tokenAccountRent = Number(
+    svm.minimumBalanceForRentExemption(BigInt(165))
+  );
+  const setupIxs = [traderBase, traderQuote].map((account) =>
+    SystemProgram.createAccount({
+      fromPubkey: authority.publicKey,
+      newAccountPubkey: account.publicKey,
+      lamports: tokenAccountRent,
+      space: 165,
+      programId: TOKEN_PROGRAM_ID,
+    })
+  );
+  setupIxs.push(
+    createInitializeAccountInstruction(traderBase.publicKey, baseAsset, traderPda),
+    createInitializeAccountInstruction(traderQuote.publicKey, quoteAsset, traderPda),
+    createMintToInstruction(
+      baseAsset,
+      traderBase.publicKey,
+      authority.publicKey,
+      10 * LAMPORTS_PER_SOL
+    ),
+    createMintToInstruction(
+      quoteAsset,
+      traderQuote.publicKey,
+      authority.publicKey,
+      1000 * 1_000_000
+    )
+  );
+  expect(
+    isFailed(await sendIxs(setupIxs, authority, traderBase, traderQuote))
+  ).toBeFalse();
+
+  const sysvarKeys = [
+    SystemProgram.programId,
+    new PublicKey("SysvarRent111111111111111111111111111111111"),
+  ];
+  const clockSysvar = new PublicKey(
+    "SysvarC1ock11111111111111111111111111111111"
+  );
+  const readonlyKeys = [TOKEN_PROGRAM_ID, clockSysvar, quoteAsset, ...sysvarKeys];
+
+  const CallerInstructionSchema = rustEnum([
+    struct([u64("quantity")], "DepositBase"),
+    struct([u64("quantity")], "DepositQuote"),
+    struct([u8("side"), u64("price"), u64("quantity")], "PlaceOrder"),
+    struct([u64("order_id")], "CancelOrder"),
+  ]);
+  const callerIx = (instruction: object, keys: PublicKey[]) => {
+    const data = Buffer.alloc(32);
+    const len = CallerInstructionSchema.encode(instruction, data);
+    return new TransactionInstruction({
+      programId: callerId.publicKey,
+      data: data.subarray(0, len),
+      keys: [
+        { pubkey: programId.publicKey, isSigner: false, isWritable: false },
+        // Not a transaction signer, the caller signs for it with invoke_signed
+        { pubkey: traderPda, isSigner: false, isWritable: true },
+        { pubkey: traderBalancePda, isSigner: false, isWritable: true },
+        { pubkey: marketAccountPda, isSigner: false, isWritable: true },
+        ...keys.map((pubkey) => ({
+          pubkey,
+          isSigner: false,
+          isWritable: !readonlyKeys.some((key) => key.equals(pubkey)),
+        })),
+      ],
+    });
+  };
+
+  const depositResult = await sendIxs(
+    [
+      callerIx({ DepositQuote: { quantity: new BN(500 * 1_000_000) } }, [
+        traderQuote.publicKey,
+        quoteVaultPda,
+        TOKEN_PROGRAM_ID,
+        ...sysvarKeys,
+        quoteAsset,
+      ]),
+    ],
+    authority
+  );
+  expect(isFailed(depositResult)).toBeFalse();
+
+  const deposited = UserBalanceSchema.decode(
+    Buffer.from(svm.getAccount(traderBalancePda)!.data)
+  );
+  expect(deposited.owner.equals(traderPda)).toBeTrue();
+  expect(deposited.available_quote_balance.eq(new BN(500 * 1_000_000))).toBeTrue();
+
+  // 1 SOL bid at 1 USDC rests below the book
+  const placeResult = await sendIxs(
+    [
+      callerIx(
+        {
+          PlaceOrder: {
+            side: 1,
+            price: new BN(1_000_000),
+            quantity: new BN(LAMPORTS_PER_SOL),
+          },
+        },
+        [
+          bidsAcc,
+          asksAcc,
+          marketEventsAcc,
+          traderBase.publicKey,
+          traderQuote.publicKey,
+          baseVaultPda,
+          quoteVaultPda,
+          TOKEN_PROGRAM_ID,
+          clockSysvar,
+        ]
+      ),
+    ],
+    authority
+  );
+  expect(isFailed(placeResult)).toBeFalse();
+
+  const bids = OrderbookSchema.decode(Buffer.from(svm.getAccount(bidsAcc)!.data));
+  const traderOrder = bids.orders
+    .slice(0, bids.active_orders_count.toNumber())
+    .find((order: any) => order.owner.equals(traderPda));
+  expect(traderOrder).toBeDefined();
+
+  const placed = UserBalanceSchema.decode(
+    Buffer.from(svm.getAccount(traderBalancePda)!.data)
+  );
+  expect(placed.locked_quote_balance.eq(new BN(1_000_000))).toBeTrue();
+
+  const cancelResult = await sendIxs(
+    [
+      callerIx({ CancelOrder: { order_id: traderOrder.order_id } }, [
+        bidsAcc,
+        asksAcc,
+        marketEventsAcc,
+        clockSysvar,
+      ]),
+    ],
+    authority
+  );
+  expect(isFailed(cancelResult)).toBeFalse();
+
+  const cancelled = UserBalanceSchema.decode(
+    Buffer.from(svm.getAccount(traderBalancePda)!.data)
+  );
+  expect(cancelled.locked_quote_balance.isZero()).toBeTrue();
+  console.log(
+    `CPI trader ${traderPda.toBase58()} placed and cancelled order ${traderOrder.order_id.toString()}`
+  );
+});
+
+test("A delegate key places and cancels orders but cannot withdraw", async () => {
+  const {
+    svm,
+    programId,
+    user,
+    marketAccountPda,
+    bidsAcc,
+    asksAcc,
+    marketEventsAcc,
+    userBalancePda,
+    userBaseTokenAccount,
+    userQuoteTokenAccount,
+    baseVaultPda,
+    quoteVaultPda,
+  } = testEnv;
+
+  const delegate = Keypair.generate();
+  const now = BigInt(svm.getClock().unixTimestamp.toString());
+
+  const setDelegateIx = (expiry: bigint, permissions: number) => {
+    const data = Buffer.alloc(42);
+    InstructionSchema.encode(
+      {
+        SetDelegate: {
+          delegate: delegate.publicKey,
+          expiry: new BN(expiry.toString()),
+          permissions,
+        },
+      },
+      data
+    );
+    return new TransactionInstruction({
+      programId: programId.publicKey,
+      data,
+      keys: [
+        { pubkey: user.publicKey, isSigner: true, isWritable: false },
+        { pubkey: userBalancePda, isSigner: false, isWritable: true },
+        { pubkey: marketAccountPda, isSigner: false, isWritable: false },
+      ],
+    });
+  };
+
+  // Only the owner can hand out a session key
+  const hijackIx = setDelegateIx(now + 3600n, DELEGATE_PLACE_ORDERS);
+  hijackIx.keys[0].pubkey = delegate.publicKey;
+  expect(isFailed(await sendIxs([hijackIx], user, delegate))).toBeTrue();
+
+  expect(
+    isFailed(
+      await sendIxs(
+        [setDelegateIx(now + 3600n, DELEGATE_PLACE_ORDERS | DELEGATE_CANCEL_ORDERS)],
+        user
+      )
+    )
+  ).toBeFalse();
+
+  const balance = UserBalanceSchema.decode(
+    Buffer.from(svm.getAccount(userBalancePda)!.data)
+  );
+  expect(balance.delegate.equals(delegate.publicKey)).toBeTrue();
+  expect(balance.delegate_permissions).toBe(3);
+
+  // The delegate signs, the order and its funds belong to the owner
+  const delegatePlaceIx = () =>
+    placeOrderIx(
+      delegate,
+      userBalancePda,
+      userBaseTokenAccount,
+      userQuoteTokenAccount,
+      2,
+      new BN(1_000_000_000),
+      new BN(LAMPORTS_PER_SOL)
+    );
+  expect(isFailed(await sendIxs([delegatePlaceIx()], user, delegate))).toBeFalse();
+
+  const asks = OrderbookSchema.decode(
+    Buffer.from(svm.getAccount(asksAcc)!.data)
+  );
+  const placed = asks.orders[asks.active_orders_count.toNumber() - 1];
+  expect(placed.owner.equals(user.publicKey)).toBeTrue();
+
+  const cancelData = Buffer.alloc(9);
+  InstructionSchema.encode(
+    { CancelOrder: { order_id: placed.order_id } },
+    cancelData
+  );
+  const cancelIx = new TransactionInstruction({
+    programId: programId.publicKey,
+    data: cancelData,
+    keys: [
+      { pubkey: delegate.publicKey, isSigner: true, isWritable: false },
+      { pubkey: userBalancePda, isSigner: false, isWritable: true },
+      { pubkey: marketAccountPda, isSigner: false, isWritable: false },
+      { pubkey: bidsAcc, isSigner: false, isWritable: true },
+      { pubkey: asksAcc, isSigner: false, isWritable: true },
+      { pubkey: marketEventsAcc, isSigner: false, isWritable: true },
+      {
+        pubkey: new PublicKey("SysvarC1ock11111111111111111111111111111111"),
+        isSigner: false,
+        isWritable: false,
+      },
+    ],
+  });
+  expect(isFailed(await sendIxs([cancelIx], user, delegate))).toBeFalse();
+
+  // Withdrawals stay with the owner
+  const settleData = Buffer.alloc(1);
+  InstructionSchema.encode({ SettleBalance: {} }, settleData);
+  const delegateSettleIx = new TransactionInstruction({
+    programId: programId.publicKey,
+    data: settleData,
+    keys: [
+      { pubkey: delegate.publicKey, isSigner: true, isWritable: true },
+      { pubkey: userBalancePda, isSigner: false, isWritable: true },
+      { pubkey: marketAccountPda, isSigner: false, isWritable: false },
+      { pubkey: marketAccountPda, isSigner: false, isWritable: false },
+      { pubkey: userBaseTokenAccount, isSigner: false, isWritable: true },
+      { pubkey: userQuoteTokenAccount, isSigner: false, isWritable: true },
+      { pubkey: baseVaultPda, isSigner: false, isWritable: true },
+      { pubkey: quoteVaultPda, isSigner: false, isWritable: true },
+      { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+      { pubkey: testEnv.baseAsset, isSigner: false, isWritable: false },
+      { pubkey: testEnv.quoteAsset, isSigner: false, isWritable: false },
+      { pubkey: SystemProgram.programId, isSigner: false, isWritable: false },
+      {
+        pubkey: ASSOCIATED_TOKEN_PROGRAM_ID,
+        isSigner: false,
+        isWritable: false,
+      },
+    ],
+  });
+  expect(isFailed(await sendIxs([delegateSettleIx], user, delegate))).toBeTrue();
+
+  // An expired session key is rejected
+  expect(
+    isFailed(await sendIxs([setDelegateIx(now - 1n, DELEGATE_PLACE_ORDERS)], user))
+  ).toBeFalse();
+  expect(isFailed(await sendIxs([delegatePlaceIx()], user, delegate))).toBeTrue();
+
+  expect(isFailed(await sendIxs([setDelegateIx(0n, 0)], user))).toBeFalse();
+  console.log("Delegate traded for the owner until its session expired");
+});
+
+test("Open orders are listed in the user's balance until cancelled", async () => {
+  const {
+    svm,
+    programId,
+    user,
+    marketAccountPda,
+    bidsAcc,
+    asksAcc,
+    marketEventsAcc,
+    userBalancePda,
+    userBaseTokenAccount,
+    userQuoteTokenAccount,
+  } = testEnv;
+
+  const readBalance = () =>
+    UserBalanceSchema.decode(Buffer.from(svm.getAccount(userBalancePda)!.data));
+  const countBefore = readBalance().open_orders_count;
+
+  // A 1 USDC bid far below the book rests in full
+  const clientOrderId = new BN(4242);
+  const placeIx = placeOrderIx(
+    user,
+    userBalancePda,
+    userBaseTokenAccount,
+    userQuoteTokenAccount,
+    1,
+    new BN(0),
+    new BN(0)
+  );
+  placeIx.data = Buffer.alloc(26);
+  InstructionSchema.encode(
+    {
+      PlaceOrderWithClientId: {
+        side: 1,
+        price: new BN(1_000_000),
+        quantity: new BN(LAMPORTS_PER_SOL),
+        client_order_id: clientOrderId,
+      },
+    },
+    placeIx.data
+  );
+  const placeResult = await sendIxs([placeIx], user);
+  expect(isFailed(placeResult)).toBeFalse();
+  const { order_id } = PlaceOrderResultSchema.decode(
+    Buffer.from((placeResult as any).returnData().data())
+  );
+
+  const placed = readBalance();
+  expect(placed.open_orders_count).toBe(countBefore + 1);
+  const listed = placed.open_orders[placed.open_orders_count - 1];
+  expect(listed.order_id.eq(order_id)).toBeTrue();
+  expect(listed.client_order_id.eq(clientOrderId)).toBeTrue();
+  expect(listed.price.eq(new BN(1_000_000))).toBeTrue();
+  expect(listed.quantity.eq(new BN(LAMPORTS_PER_SOL))).toBeTrue();
+  expect(listed.side).toBe(1);
+
+  const cancelData = Buffer.alloc(9);
+  InstructionSchema.encode({ CancelOrder: { order_id } }, cancelData);
+  const cancelIx = new TransactionInstruction({
+    programId: programId.publicKey,
+    data: cancelData,
+    keys: [
+      { pubkey: user.publicKey, isSigner: true, isWritable: false },
+      { pubkey: userBalancePda, isSigner: false, isWritable: true },
+      { pubkey: marketAccountPda, isSigner: false, isWritable: false },
+      { pubkey: bidsAcc, isSigner: false, isWritable: true },
+      { pubkey: asksAcc, isSigner: false, isWritable: true },
+      { pubkey: marketEventsAcc, isSigner: false, isWritable: true },
+      {
+        pubkey: new PublicKey("SysvarC1ock11111111111111111111111111111111"),
+        isSigner: false,
+        isWritable: false,
+      },
+    ],
+  });
+  expect(isFailed(await sendIxs([cancelIx], user))).toBeFalse();
+
+  const cancelled = readBalance();
+  expect(cancelled.open_orders_count).toBe(countBefore);
+  expect(
+    cancelled.open_orders
+      .slice(0, cancelled.open_orders_count)
+      .some((openOrder: any) => openOrder.order_id.eq(order_id))
+  ).toBeFalse();
+  console.log(`Open orders index tracked order ${order_id.toString()}`);
+});
+
+test("Market authority caps resting orders per user", async () => {
+  const {
+    svm,
+    user,
+    authority,
+    marketAccountPda,
+    userBalancePda,
+    userBaseTokenAccount,
+    userQuoteTokenAccount,
+  } = testEnv;
+
+  const orderLimitIx = (maxOrdersPerUser: number, signer: Keypair = authority) => {
+    const data = Buffer.alloc(3);
+    InstructionSchema.encode(
+      { ConfigureOrderLimit: { max_orders_per_user: maxOrdersPerUser } },
+      data
+    );
+    return new TransactionInstruction({
+      programId: testEnv.programId.publicKey,
+      data,
+      keys: [
+        { pubkey: signer.publicKey, isSigner: true, isWritable: false },
+        { pubkey: marketAccountPda, isSigner: false, isWritable: true },
+      ],
+    });
+  };
+
+  const market = MarketStateSchema.decode(
+    Buffer.from(svm.getAccount(marketAccountPda)!.data)
+  );
+  expect(market.max_orders_per_user).toBe(MAX_OPEN_ORDERS);
+
+  // Only the authority configures it, and only within the index capacity
+  expect(isFailed(await sendIxs([orderLimitIx(1, user)], user))).toBeTrue();
+  expect(isFailed(await sendIxs([orderLimitIx(0)], authority))).toBeTrue();
+  expect(
+    isFailed(await sendIxs([orderLimitIx(MAX_OPEN_ORDERS + 1)], authority))
+  ).toBeTrue();
+
+  const listed = UserBalanceSchema.decode(
+    Buffer.from(svm.getAccount(userBalancePda)!.data)
+  ).open_orders_count;
+  expect(
+    isFailed(await sendIxs([orderLimitIx(listed + 1)], authority))
+  ).toBeFalse();
+
+  // A 1 USDC bid rests below the book, the first one takes the last slot
+  const restingBidIx = () =>
+    placeOrderIx(
+      user,
+      userBalancePda,
+      userBaseTokenAccount,
+      userQuoteTokenAccount,
+      1,
+      new BN(1_000_000),
+      new BN(LAMPORTS_PER_SOL / 10)
+    );
+  expect(isFailed(await sendIxs([restingBidIx()], user))).toBeFalse();
+  expect(isFailed(await sendIxs([restingBidIx()], user))).toBeTrue();
+
+  expect(
+    isFailed(await sendIxs([orderLimitIx(MAX_OPEN_ORDERS)], authority))
+  ).toBeFalse();
+  expect(isFailed(await sendIxs([restingBidIx()], user))).toBeFalse();
+  console.log(`Order limit of ${listed + 1} rejected the next resting order`);
+});
+
+test("A full book evicts its worst order for a better one and rejects the rest", async () => {
+  const { svm, programId, authority, consumerEventsAuthority } = testEnv;
+
+  const baseMint = await newBaseMint();
+  const listing = await createMarket(baseMint, TOKEN_PROGRAM_ID);
+  expect(isFailed(listing.result)).toBeFalse();
+  const readBalance = (trader: MarketTrader) =>
+    UserBalanceSchema.decode(
+      Buffer.from(svm.getAccount(trader.balancePda)!.data)
+    );
+  const readBids = () =>
+    OrderbookSchema.decode(Buffer.from(svm.getAccount(listing.bids)!.data));
+  const bidIx = (trader: MarketTrader, price: number) =>
+    marketOrderIx(listing, trader, 1, new BN(price), new BN(1_000_000));
+
+  // One minimum-size bid per tick from 10 USDC up, the per-user limit of
+  // resting orders spreads them over sixteen traders
+  const bidPrice = (index: number) => 10_000_000 + index * 1_000;
+  const traders: MarketTrader[] = [];
+  for (let first = 0; first < MAX_ORDERS; first += MAX_OPEN_ORDERS) {
+    const trader = await newMarketTrader(
+      listing,
+      new BN(0),
+      new BN(10 * 1_000_000)
+    );
+    traders.push(trader);
+    for (let index = first; index < first + MAX_OPEN_ORDERS; index += 8) {
+      const ixs = [
+        ComputeBudgetProgram.setComputeUnitLimit({ units: 1_400_000 }),
+      ];
+      for (let i = index; i < index + 8; i++) {
+        ixs.push(bidIx(trader, bidPrice(i)));
+      }
+      expect(isFailed(await sendIxs(ixs, trader.keypair))).toBeFalse();
+    }
+  }
+  expect(readBids().active_orders_count.toNumber()).toBe(MAX_ORDERS);
+
+  // A bid that does not beat the worst resting price is turned away
+  const newcomer = await newMarketTrader(
+    listing,
+    new BN(0),
+    new BN(10 * 1_000_000)
+  );
+  const rejected = await sendIxs(
+    [bidIx(newcomer, bidPrice(0))],
+    newcomer.keypair
+  );
+  expect(failedWithCode(rejected, 2)).toBeTrue();
+
+  // A better one takes the slot of the lowest bid, which is queued as Out
+  const worst = traders[0];
+  const lockedBefore = readBalance(worst).locked_quote_balance;
+  expect(
+    isFailed(await sendIxs([bidIx(newcomer, 10_500_000)], newcomer.keypair))
+  ).toBeFalse();
+  const bids = readBids();
+  expect(bids.active_orders_count.toNumber()).toBe(MAX_ORDERS);
+  expect(
+    bids.orders.some(
+      (order: any) =>
+        order.quantity.gt(new BN(0)) &&
+        order.price.eq(new BN(bidPrice(0)))
+    )
+  ).toBeFalse();
+  const events = MarketEventsSchema.decode(
+    Buffer.from(svm.getAccount(listing.events)!.data)
+  );
+  expect(events.count.toNumber()).toBe(1);
+  const out = events.events[0];
+  expect(out.event_type).toBe(1);
+  expect(out.maker.equals(worst.keypair.publicKey)).toBeTrue();
+  expect(out.price.eq(new BN(bidPrice(0)))).toBeTrue();
+  expect(out.quantity.eq(new BN(1_000_000))).toBeTrue();
+
+  // The crank hands the evicted bid's quote back to its owner
+  const consumeIx = marketIx({ ConsumeEvents: { limit: 0 } }, 3, [
+    meta(consumerEventsAuthority.publicKey, false, true),
+    meta(listing.market, true),
+    meta(listing.events, true),
+    meta(worst.balancePda, true),
+  ]);
+  expect(
+    isFailed(await sendIxs([consumeIx], authority, consumerEventsAuthority))
+  ).toBeFalse();
+  const released = readBalance(worst);
+  const evictedQuote = new BN(bidPrice(0) / 1_000);
+  expect(
+    released.locked_quote_balance.eq(lockedBefore.sub(evictedQuote))
+  ).toBeTrue();
+  expect(
+    released.available_quote_balance.eq(
+      new BN(10 * 1_000_000).sub(released.locked_quote_balance)
+    )
+  ).toBeTrue();
+  expect(released.open_orders_count).toBe(MAX_OPEN_ORDERS - 1);
+  console.log(`Full book of ${MAX_ORDERS} bids evicted its worst for a better one`);
+
+  // The trigger crank cannot reject an order without reverting every other
+  // trigger, so a triggered bid with no room is cancelled through an Out of
+  // its own while the next one still evicts its way in
+  const triggerBook = Keypair.generate();
+  expect(
+    isFailed(
+      await sendIxs(
+        [
+          SystemProgram.createAccount({
+            fromPubkey: authority.publicKey,
+            newAccountPubkey: triggerBook.publicKey,
+            lamports: Number(
+              svm.minimumBalanceForRentExemption(BigInt(TRIGGER_BOOK_LEN))
+            ),
+            space: TRIGGER_BOOK_LEN,
+            programId: programId.publicKey,
+          }),
+          marketIx({ InitializeTriggerBook: {} }, 1, [
+            meta(authority.publicKey, false, true),
+            meta(listing.market, true),
+            meta(triggerBook.publicKey, true),
+            meta(new PublicKey("SysvarRent111111111111111111111111111111111")),
+          ]),
+        ],
+        authority,
+        triggerBook
+      )
+    )
+  ).toBeFalse();
+
+  // A sale into the newcomer's bid sets the last price, the newcomer bids
+  // again to fill the book back up
+  const seller = await newMarketTrader(listing, new BN(1_000_000), new BN(0));
+  expect(
+    isFailed(
+      await sendIxs(
+        [
+          marketOrderIx(
+            listing,
+            seller,
+            2,
+            new BN(10_500_000),
+            new BN(1_000_000)
+          ),
+        ],
+        seller.keypair
+      )
+    )
+  ).toBeFalse();
+  expect(
+    isFailed(await sendIxs([bidIx(newcomer, 10_500_000)], newcomer.keypair))
+  ).toBeFalse();
+  expect(readBids().active_orders_count.toNumber()).toBe(MAX_ORDERS);
+
+  const clock = new PublicKey("SysvarC1ock11111111111111111111111111111111");
+  const stopper = await newMarketTrader(
+    listing,
+    new BN(0),
+    new BN(10 * 1_000_000)
+  );
+  const buyStopIx = (price: number) =>
+    marketIx(
+      {
+        PlaceTriggerOrder: {
+          side: 1,
+          trigger_type: 0,
+          trigger_price: new BN(10_500_000),
+          price: new BN(price),
+          quantity: new BN(1_000_000),
+        },
+      },
+      27,
+      [
+        meta(stopper.keypair.publicKey, false, true),
+        meta(stopper.balancePda, true),
+        meta(listing.market, true),
+        meta(triggerBook.publicKey, true),
+        meta(clock),
+      ]
+    );
+  expect(
+    isFailed(
+      await sendIxs(
+        [buyStopIx(bidPrice(0)), buyStopIx(11_000_000)],
+        stopper.keypair
+      )
+    )
+  ).toBeFalse();
+
+  const crankIx = marketIx({ TriggerOrders: {} }, 1, [
+    meta(listing.market, true),
+    meta(listing.bids, true),
+    meta(listing.asks, true),
+    meta(listing.events, true),
+    meta(triggerBook.publicKey, true),
+    meta(clock),
+  ]);
+  expect(
+    isFailed(
+      await sendIxs(
+        [
+          ComputeBudgetProgram.setComputeUnitLimit({ units: 1_400_000 }),
+          crankIx,
+        ],
+        authority
+      )
+    )
+  ).toBeFalse();
+  expect(
+    TriggerBookSchema.decode(
+      Buffer.from(svm.getAccount(triggerBook.publicKey)!.data)
+    ).active_orders_count.isZero()
+  ).toBeTrue();
+  const triggeredBids = readBids();
+  expect(triggeredBids.active_orders_count.toNumber()).toBe(MAX_ORDERS);
+  expect(
+    triggeredBids.orders.some(
+      (order: any) =>
+        order.quantity.gt(new BN(0)) &&
+        order.owner.equals(stopper.keypair.publicKey)
+    )
+  ).toBeTrue();
+  const outs = MarketEventsSchema.decode(
+    Buffer.from(svm.getAccount(listing.events)!.data)
+  ).events.filter((event: any) => event.event_type === 1);
+  expect(outs.length).toBe(2);
+  const [cancelledOut, evictedOut] = outs;
+  expect(cancelledOut.maker.equals(stopper.keypair.publicKey)).toBeTrue();
+  expect(cancelledOut.price.eq(new BN(bidPrice(0)))).toBeTrue();
+  expect(evictedOut.maker.equals(worst.keypair.publicKey)).toBeTrue();
+  expect(evictedOut.price.eq(new BN(bidPrice(1)))).toBeTrue();
+
+  // Consuming hands the cancelled bid's quote back, only the resting one
+  // stays locked
+  const consumeAllIx = marketIx({ ConsumeEvents: { limit: 0 } }, 3, [
+    meta(consumerEventsAuthority.publicKey, false, true),
+    meta(listing.market, true),
+    meta(listing.events, true),
+    meta(newcomer.balancePda, true),
+    meta(seller.balancePda, true),
+    meta(stopper.balancePda, true),
+    meta(worst.balancePda, true),
+  ]);
+  expect(
+    isFailed(
+      await sendIxs([consumeAllIx], authority, consumerEventsAuthority)
+    )
+  ).toBeFalse();
+  const stopperBalance = readBalance(stopper);
+  expect(stopperBalance.locked_quote_balance.eq(new BN(11_000))).toBeTrue();
+  expect(
+    stopperBalance.available_quote_balance.eq(
+      new BN(10 * 1_000_000 - 11_000)
+    )
+  ).toBeTrue();
+  expect(stopperBalance.open_orders_count).toBe(1);
+  console.log("Trigger crank cancelled a bid the full book had no room for");
+});
+
+test("Balance accounts are created, funded on both sides and closed explicitly", async () => {
+  const {
+    svm,
+    programId,
+    marketAccountPda,
+    marketEventsAcc,
+    baseVaultPda,
+    quoteVaultPda,
+  } = testEnv;
+
+  const trader = await newTrader();
+  const balanceIx = (variant: string, keys: PublicKey[] = []) => {
+    const data = Buffer.alloc(1);
+    InstructionSchema.encode({ [variant]: {} }, data);
+    return new TransactionInstruction({
+      programId: programId.publicKey,
+      data,
+      keys: [
+        { pubkey: trader.keypair.publicKey, isSigner: true, isWritable: true },
+        { pubkey: trader.balancePda, isSigner: false, isWritable: true },
+        { pubkey: marketAccountPda, isSigner: false, isWritable: false },
+        ...keys.map((pubkey) => ({ pubkey, isSigner: false, isWritable: false })),
+      ],
+    });
+  };
+  const depositIx = (baseAmount: BN, quoteAmount: BN) => {
+    const data = Buffer.alloc(17);
+    InstructionSchema.encode(
+      { Deposit: { base_amount: baseAmount, quote_amount: quoteAmount } },
+      data
+    );
+    return new TransactionInstruction({
+      programId: programId.publicKey,
+      data,
+      keys: [
+        { pubkey: trader.keypair.publicKey, isSigner: true, isWritable: false },
+        { pubkey: trader.balancePda, isSigner: false, isWritable: true },
+        { pubkey: marketAccountPda, isSigner: false, isWritable: false },
+        { pubkey: trader.baseTokenAccount, isSigner: false, isWritable: true },
+        { pubkey: trader.quoteTokenAccount, isSigner: false, isWritable: true },
+        { pubkey: baseVaultPda, isSigner: false, isWritable: true },
+        { pubkey: quoteVaultPda, isSigner: false, isWritable: true },
+        { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+        { pubkey: testEnv.baseAsset, isSigner: false, isWritable: false },
+        { pubkey: testEnv.quoteAsset, isSigner: false, isWritable: false },
+      ],
+    });
+  };
+  const createIx = () =>
+    balanceIx("CreateUserBalance", [SystemProgram.programId]);
+
+  // Depositing needs the account to exist first
+  const amount = new BN(LAMPORTS_PER_SOL);
+  expect(
+    isFailed(await sendIxs([depositIx(amount, amount)], trader.keypair))
+  ).toBeTrue();
+
+  expect(isFailed(await sendIxs([createIx()], trader.keypair))).toBeFalse();
+  expect(isFailed(await sendIxs([createIx()], trader.keypair))).toBeTrue();
+  const rent = svm.getAccount(trader.balancePda)!.lamports;
+
+  // An empty account closes and refunds its rent
+  const lamportsBefore = svm.getAccount(trader.keypair.publicKey)!.lamports;
+  expect(
+    isFailed(await sendIxs([balanceIx("CloseUserBalance", [marketEventsAcc])], trader.keypair))
+  ).toBeFalse();
+  expect(svm.getAccount(trader.balancePda)?.lamports ?? 0).toBe(0);
+  expect(svm.getAccount(trader.keypair.publicKey)!.lamports).toBe(
+    lamportsBefore + rent - 5000
+  );
+
+  // One call funds both sides
+  expect(isFailed(await sendIxs([createIx()], trader.keypair))).toBeFalse();
+  const quoteAmount = new BN(250 * 1_000_000);
+  expect(
+    isFailed(await sendIxs([depositIx(amount, quoteAmount)], trader.keypair))
+  ).toBeFalse();
+
+  const balance = UserBalanceSchema.decode(
+    Buffer.from(svm.getAccount(trader.balancePda)!.data)
+  );
+  expect(balance.available_base_balance.eq(amount)).toBeTrue();
+  expect(balance.available_quote_balance.eq(quoteAmount)).toBeTrue();
+
+  // Funds still held keep the account open
+  expect(
+    isFailed(await sendIxs([balanceIx("CloseUserBalance", [marketEventsAcc])], trader.keypair))
+  ).toBeTrue();
+  console.log("Balance account created, funded and closed explicitly");
+});
+
+test("Cancels release funds at once and balances named by queued events stay open", async () => {
+  const { svm, authority, consumerEventsAuthority, quoteAsset } = testEnv;
+
+  const baseMint = await newBaseMint();
+  const listing = await createMarket(baseMint, TOKEN_PROGRAM_ID);
+  expect(isFailed(listing.result)).toBeFalse();
+  const seller = await newMarketTrader(
+    listing,
+    new BN(LAMPORTS_PER_SOL),
+    new BN(0)
+  );
+  const buyer = await newMarketTrader(
+    listing,
+    new BN(0),
+    new BN(100 * 1_000_000)
+  );
+  const readBalance = (trader: MarketTrader) =>
+    UserBalanceSchema.decode(
+      Buffer.from(svm.getAccount(trader.balancePda)!.data)
+    );
+  const queued = () =>
+    MarketEventsSchema.decode(
+      Buffer.from(svm.getAccount(listing.events)!.data)
+    ).count.toNumber();
+  const closeIx = (trader: MarketTrader) =>
+    marketIx({ CloseUserBalance: {} }, 1, [
+      meta(trader.keypair.publicKey, true, true),
+      meta(trader.balancePda, true),
+      meta(listing.market),
+      meta(listing.events),
+    ]);
+
+  // A cancelled bid is back in the available balance without a crank
+  const bidIx = marketOrderIx(
+    listing,
+    buyer,
+    1,
+    new BN(90 * 1_000_000),
+    new BN(LAMPORTS_PER_SOL)
+  );
+  expect(isFailed(await sendIxs([bidIx], buyer.keypair))).toBeFalse();
+  // The first order of a market gets id 1
+  const cancelIx = marketIx({ CancelOrder: { order_id: new BN(1) } }, 9, [
+    meta(buyer.keypair.publicKey, false, true),
+    meta(buyer.balancePda, true),
+    meta(listing.market),
+    meta(listing.bids, true),
+    meta(listing.asks, true),
+    meta(listing.events, true),
+    meta(new PublicKey("SysvarC1ock11111111111111111111111111111111")),
+  ]);
+  expect(isFailed(await sendIxs([cancelIx], buyer.keypair))).toBeFalse();
+  const cancelled = readBalance(buyer);
+  expect(
+    cancelled.available_quote_balance.eq(new BN(100 * 1_000_000))
+  ).toBeTrue();
+  expect(cancelled.locked_quote_balance.isZero()).toBeTrue();
+  expect(cancelled.open_orders_count).toBe(0);
+  expect(queued()).toBe(0);
+
+  // The buyer takes the seller's ask, is settled at placement and withdraws
+  // everything, but the maker's fill still names them
+  const askIx = marketOrderIx(
+    listing,
+    seller,
+    2,
+    new BN(100 * 1_000_000),
+    new BN(LAMPORTS_PER_SOL)
+  );
+  expect(isFailed(await sendIxs([askIx], seller.keypair))).toBeFalse();
+  const takeIx = marketIx(
+    {
+      PlaceOrderSettleTaker: {
+        side: 1,
+        price: new BN(100 * 1_000_000),
+        quantity: new BN(LAMPORTS_PER_SOL),
+        client_order_id: new BN(0),
+      },
+    },
+    26,
+    marketOrderIx(listing, buyer, 1, new BN(0), new BN(0)).keys
+  );
+  const settleIx = marketIx({ SettleBalance: {} }, 1, [
+    meta(buyer.keypair.publicKey, true, true),
+    meta(buyer.balancePda, true),
+    meta(listing.market),
+    meta(listing.market),
+    meta(buyer.baseTokenAccount, true),
+    meta(buyer.quoteTokenAccount, true),
+    meta(listing.baseVault, true),
+    meta(listing.quoteVault, true),
+    meta(TOKEN_PROGRAM_ID),
+    meta(baseMint),
+    meta(quoteAsset),
+    meta(SystemProgram.programId),
+    meta(ASSOCIATED_TOKEN_PROGRAM_ID),
+  ]);
+  expect(
+    isFailed(await sendIxs([takeIx, settleIx], buyer.keypair))
+  ).toBeFalse();
+  const emptied = readBalance(buyer);
+  expect(emptied.available_quote_balance.isZero()).toBeTrue();
+  expect(emptied.pending_base_balance.isZero()).toBeTrue();
+  expect(emptied.open_orders_count).toBe(0);
+  expect(queued()).toBe(1);
+  expect(isFailed(await sendIxs([closeIx(buyer)], buyer.keypair))).toBeTrue();
+
+  // Once the crank has applied the fill the account closes
+  const consumeIx = marketIx({ ConsumeEvents: { limit: 0 } }, 3, [
+    meta(consumerEventsAuthority.publicKey, false, true),
+    meta(listing.market, true),
+    meta(listing.events, true),
+    meta(seller.balancePda, true),
+  ]);
+  expect(
+    isFailed(await sendIxs([consumeIx], authority, consumerEventsAuthority))
+  ).toBeFalse();
+  expect(queued()).toBe(0);
+  expect(isFailed(await sendIxs([closeIx(buyer)], buyer.keypair))).toBeFalse();
+  expect(svm.getAccount(buyer.balancePda)?.lamports ?? 0).toBe(0);
+  console.log("Cancel released funds at once, close waited for the crank");
+});
+
+test("Traders withdraw what a partial trade leaves them and close their balances", async () => {
+  const { svm, authority, consumerEventsAuthority, quoteAsset } = testEnv;
+
+  const baseMint = await newBaseMint();
+  const listing = await createMarket(baseMint, TOKEN_PROGRAM_ID);
+  expect(isFailed(listing.result)).toBeFalse();
+  const seller = await newMarketTrader(
+    listing,
+    new BN(LAMPORTS_PER_SOL),
+    new BN(0)
+  );
+  const buyer = await newMarketTrader(
+    listing,
+    new BN(0),
+    new BN(100 * 1_000_000)
+  );
+  const readBalance = (trader: MarketTrader) =>
+    UserBalanceSchema.decode(
+      Buffer.from(svm.getAccount(trader.balancePda)!.data)
+    );
+  const tokenAmount = (account: PublicKey) =>
+    new BN(Buffer.from(svm.getAccount(account)!.data).subarray(64, 72), "le");
+  const withdrawIx = (trader: MarketTrader, base: BN, quote: BN) =>
+    marketIx({ Withdraw: { base_amount: base, quote_amount: quote } }, 17, [
+      meta(trader.keypair.publicKey, false, true),
+      meta(trader.balancePda, true),
+      meta(listing.market),
+      meta(listing.events),
+    ]);
+  const settleIx = (trader: MarketTrader) =>
+    marketIx({ SettleBalance: {} }, 1, [
+      meta(trader.keypair.publicKey, true, true),
+      meta(trader.balancePda, true),
+      meta(listing.market),
+      meta(listing.market),
+      meta(trader.baseTokenAccount, true),
+      meta(trader.quoteTokenAccount, true),
+      meta(listing.baseVault, true),
+      meta(listing.quoteVault, true),
+      meta(TOKEN_PROGRAM_ID),
+      meta(baseMint),
+      meta(quoteAsset),
+      meta(SystemProgram.programId),
+      meta(ASSOCIATED_TOKEN_PROGRAM_ID),
+    ]);
+  const closeIx = (trader: MarketTrader) =>
+    marketIx({ CloseUserBalance: {} }, 1, [
+      meta(trader.keypair.publicKey, true, true),
+      meta(trader.balancePda, true),
+      meta(listing.market),
+      meta(listing.events),
+    ]);
+
+  // A third of a bid at 33.333 fills and the rest is cancelled; both locks
+  // are released rounded down, leaving one unit of quote locked
+  const price = new BN(33_333_000);
+  expect(
+    isFailed(
+      await sendIxs(
+        [marketOrderIx(listing, buyer, 1, price, new BN(LAMPORTS_PER_SOL))],
+        buyer.keypair
+      )
+    )
+  ).toBeFalse();
+  expect(
+    isFailed(
+      await sendIxs(
+        [marketOrderIx(listing, seller, 2, price, new BN(333_333_333))],
+        seller.keypair
+      )
+    )
+  ).toBeFalse();
+  const consumeIx = marketIx({ ConsumeEvents: { limit: 0 } }, 3, [
+    meta(consumerEventsAuthority.publicKey, false, true),
+    meta(listing.market, true),
+    meta(listing.events, true),
+    meta(buyer.balancePda, true),
+    meta(seller.balancePda, true),
+  ]);
+  expect(
+    isFailed(await sendIxs([consumeIx], authority, consumerEventsAuthority))
+  ).toBeFalse();
+  // The first order of a market gets id 1
+  const cancelIx = marketIx({ CancelOrder: { order_id: new BN(1) } }, 9, [
+    meta(buyer.keypair.publicKey, false, true),
+    meta(buyer.balancePda, true),
+    meta(listing.market),
+    meta(listing.bids, true),
+    meta(listing.asks, true),
+    meta(listing.events, true),
+    meta(new PublicKey("SysvarC1ock11111111111111111111111111111111")),
+  ]);
+  expect(isFailed(await sendIxs([cancelIx], buyer.keypair))).toBeFalse();
+
+  const paid = new BN(11_110_999);
+  const traded = readBalance(buyer);
+  expect(traded.open_orders_count).toBe(0);
+  expect(traded.locked_quote_balance.eq(new BN(1))).toBeTrue();
+  expect(
+    traded.available_quote_balance.eq(
+      new BN(100 * 1_000_000).sub(paid).subn(1)
+    )
+  ).toBeTrue();
+  expect(traded.pending_base_balance.eq(new BN(333_333_333))).toBeTrue();
+  expect(isFailed(await sendIxs([closeIx(buyer)], buyer.keypair))).toBeTrue();
+
+  // Withdrawing more than is available fails
+  expect(
+    isFailed(
+      await sendIxs(
+        [withdrawIx(seller, new BN(LAMPORTS_PER_SOL), new BN(0))],
+        seller.keypair
+      )
+    )
+  ).toBeTrue();
+
+  // With nothing open or queued the leftover unit is withdrawn as well, and
+  // each trader settles and closes in one transaction
+  const sellerQuoteBefore = tokenAmount(seller.quoteTokenAccount);
+  expect(
+    isFailed(
+      await sendIxs(
+        [
+          withdrawIx(buyer, new BN(0), new BN(100 * 1_000_000).sub(paid)),
+          settleIx(buyer),
+          closeIx(buyer),
+        ],
+        buyer.keypair
+      )
+    )
+  ).toBeFalse();
+  expect(
+    isFailed(
+      await sendIxs(
+        [
+          withdrawIx(seller, new BN(666_666_667), new BN(0)),
+          settleIx(seller),
+          closeIx(seller),
+        ],
+        seller.keypair
+      )
+    )
+  ).toBeFalse();
+
+  expect(svm.getAccount(buyer.balancePda)?.lamports ?? 0).toBe(0);
+  expect(svm.getAccount(seller.balancePda)?.lamports ?? 0).toBe(0);
+  expect(
+    tokenAmount(buyer.baseTokenAccount).eq(new BN(333_333_333))
+  ).toBeTrue();
+  expect(
+    tokenAmount(seller.quoteTokenAccount).sub(sellerQuoteBefore).eq(paid)
+  ).toBeTrue();
+  expect(
+    tokenAmount(seller.baseTokenAccount).eq(new BN(666_666_667))
+  ).toBeTrue();
+  expect(tokenAmount(listing.baseVault).isZero()).toBeTrue();
+  expect(tokenAmount(listing.quoteVault).isZero()).toBeTrue();
+  console.log("Partial trade withdrawn, settled and both balances closed");
+});
+
+test("A trader crossing their own order gets both locks back", async () => {
+  const { svm, authority, consumerEventsAuthority } = testEnv;
+
+  const baseMint = await newBaseMint();
+  const listing = await createMarket(baseMint, TOKEN_PROGRAM_ID);
+  expect(isFailed(listing.result)).toBeFalse();
+  const trader = await newMarketTrader(
+    listing,
+    new BN(LAMPORTS_PER_SOL),
+    new BN(100 * 1_000_000)
+  );
+  const usdc = (amount: number) => new BN(amount * 1_000_000);
+  const order = (side: number, price: number) =>
+    sendIxs(
+      [
+        marketOrderIx(
+          listing,
+          trader,
+          side,
+          usdc(price),
+          new BN(LAMPORTS_PER_SOL)
+        ),
+      ],
+      trader.keypair
+    );
+  const consumeIx = marketIx({ ConsumeEvents: { limit: 0 } }, 3, [
+    meta(consumerEventsAuthority.publicKey, false, true),
+    meta(listing.market, true),
+    meta(listing.events, true),
+    meta(trader.balancePda, true),
+  ]);
+  const expectUntouched = () => {
+    const balance = UserBalanceSchema.decode(
+      Buffer.from(svm.getAccount(trader.balancePda)!.data)
+    );
+    expect(
+      balance.available_base_balance.eq(new BN(LAMPORTS_PER_SOL))
+    ).toBeTrue();
+    expect(balance.available_quote_balance.eq(usdc(100))).toBeTrue();
+    expect(balance.locked_base_balance.isZero()).toBeTrue();
+    expect(balance.locked_quote_balance.isZero()).toBeTrue();
+    expect(balance.pending_base_balance.isZero()).toBeTrue();
+    expect(balance.pending_quote_balance.isZero()).toBeTrue();
+    expect(balance.open_orders_count).toBe(0);
+  };
+
+  // A bid at 60 takes the trader's own ask at 50, then a sell at 30 takes
+  // their own bid at 40
+  for (const [makerSide, makerPrice, takerPrice] of [
+    [2, 50, 60],
+    [1, 40, 30],
+  ]) {
+    expect(isFailed(await order(makerSide, makerPrice))).toBeFalse();
+    expect(isFailed(await order(3 - makerSide, takerPrice))).toBeFalse();
+    expect(
+      isFailed(await sendIxs([consumeIx], authority, consumerEventsAuthority))
+    ).toBeFalse();
+    expectUntouched();
+  }
+  console.log("Self-cross released the resting and the taking side");
+});
+
+test("Token-2022 base mints are listed and transfer fees are credited net", async () => {
+  const { svm, programId, authority, quoteAsset } = testEnv;
+
+  const newMint = async (
+    extensions: ExtensionType[],
+    extensionIxs: (mint: PublicKey) => TransactionInstruction[]
+  ) => {
+    const mint = Keypair.generate();
+    const space = getMintLen(extensions);
+    const result = await sendIxs(
+      [
+        SystemProgram.createAccount({
+          fromPubkey: authority.publicKey,
+          newAccountPubkey: mint.publicKey,
+          lamports: Number(svm.minimumBalanceForRentExemption(BigInt(space))),
+          space,
+          programId: TOKEN_2022_PROGRAM_ID,
+        }),
+        ...extensionIxs(mint.publicKey),
+        createInitializeMintInstruction(
+          mint.publicKey,
+          9,
+          authority.publicKey,
+          null,
+          TOKEN_2022_PROGRAM_ID
+        ),
+      ],
+      authority,
+      mint
+    );
+    expect(isFailed(result)).toBeFalse();
+    return mint.publicKey;
+  };
+
+  // 1% transfer fee, capped well above the deposit
+  const feeMint = await newMint([ExtensionType.TransferFeeConfig], (mint) => [
+    createInitializeTransferFeeConfigInstruction(
+      mint,
+      authority.publicKey,
+      authority.publicKey,
+      100,
+      BigInt(1_000 * LAMPORTS_PER_SOL),
+      TOKEN_2022_PROGRAM_ID
+    ),
+  ]);
+  const feeMarket = await createMarket(feeMint, TOKEN_2022_PROGRAM_ID);
+  expect(isFailed(feeMarket.result)).toBeFalse();
+
+  const marketState = MarketStateSchema.decode(
+    Buffer.from(svm.getAccount(feeMarket.market)!.data)
+  );
+  expect(marketState.base_token_program.equals(TOKEN_2022_PROGRAM_ID)).toBeTrue();
+  expect(marketState.quote_token_program.equals(TOKEN_PROGRAM_ID)).toBeTrue();
+  expect(marketState.base_decimals).toBe(9);
+  expect(marketState.quote_decimals).toBe(6);
+  // Token account bumps are stored for create_program_address checks
+  for (const [seed, bump] of [
+    ["base_vault", marketState.base_vault_bump],
+    ["quote_vault", marketState.quote_vault_bump],
+    ["fee_account", marketState.fee_account_bump],
+  ] as const) {
+    expect(
+      PublicKey.findProgramAddressSync(
+        [Buffer.from(seed), feeMarket.market.toBuffer()],
+        programId.publicKey
+      )[1]
+    ).toBe(bump);
+  }
+
+  const trader = Keypair.generate();
+  svm.airdrop(trader.publicKey, BigInt(10 * LAMPORTS_PER_SOL));
+  const traderBase = Keypair.generate();
+  const accountLen = getAccountLen([ExtensionType.TransferFeeAmount]);
+  const [balancePda] = PublicKey.findProgramAddressSync(
+    [
+      Buffer.from("user_balance"),
+      trader.publicKey.toBuffer(),
+      feeMarket.market.toBuffer(),
+    ],
+    programId.publicKey
+  );
+  const createBalanceData = Buffer.alloc(1);
+  InstructionSchema.encode({ CreateUserBalance: {} }, createBalanceData);
+  expect(
+    isFailed(
+      await sendIxs(
+        [
+          SystemProgram.createAccount({
+            fromPubkey: trader.publicKey,
+            newAccountPubkey: traderBase.publicKey,
+            lamports: Number(
+              svm.minimumBalanceForRentExemption(BigInt(accountLen))
+            ),
+            space: accountLen,
+            programId: TOKEN_2022_PROGRAM_ID,
+          }),
+          createInitializeAccountInstruction(
+            traderBase.publicKey,
+            feeMint,
+            trader.publicKey,
+            TOKEN_2022_PROGRAM_ID
+          ),
+          new TransactionInstruction({
+            programId: programId.publicKey,
+            data: createBalanceData,
+            keys: [
+              { pubkey: trader.publicKey, isSigner: true, isWritable: true },
+              { pubkey: balancePda, isSigner: false, isWritable: true },
+              { pubkey: feeMarket.market, isSigner: false, isWritable: false },
+              {
+                pubkey: SystemProgram.programId,
+                isSigner: false,
+                isWritable: false,
+              },
+            ],
+          }),
+        ],
+        trader,
+        traderBase
+      )
+    )
+  ).toBeFalse();
+  expect(
+    isFailed(
+      await sendIxs(
+        [
+          createMintToInstruction(
+            feeMint,
+            traderBase.publicKey,
+            authority.publicKey,
+            100 * LAMPORTS_PER_SOL,
+            [],
+            TOKEN_2022_PROGRAM_ID
+          ),
+        ],
+        authority
+      )
+    )
+  ).toBeFalse();
+
+  const depositData = Buffer.alloc(17);
+  InstructionSchema.encode(
+    {
+      Deposit: {
+        base_amount: new BN(10 * LAMPORTS_PER_SOL),
+        quote_amount: new BN(0),
+      },
+    },
+    depositData
+  );
+  const depositResult = await sendIxs(
+    [
+      new TransactionInstruction({
+        programId: programId.publicKey,
+        data: depositData,
+        keys: [
+          { pubkey: trader.publicKey, isSigner: true, isWritable: false },
+          { pubkey: balancePda, isSigner: false, isWritable: true },
+          { pubkey: feeMarket.market, isSigner: false, isWritable: false },
+          { pubkey: traderBase.publicKey, isSigner: false, isWritable: true },
+          // Unused without a quote amount
+          { pubkey: traderBase.publicKey, isSigner: false, isWritable: true },
+          { pubkey: feeMarket.baseVault, isSigner: false, isWritable: true },
+          { pubkey: feeMarket.quoteVault, isSigner: false, isWritable: true },
+          { pubkey: TOKEN_2022_PROGRAM_ID, isSigner: false, isWritable: false },
+          { pubkey: feeMint, isSigner: false, isWritable: false },
+          { pubkey: quoteAsset, isSigner: false, isWritable: false },
+          { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+        ],
+      }),
+    ],
+    trader
+  );
+  expect(isFailed(depositResult)).toBeFalse();
+
+  // The vault only receives 99% and the balance is credited what arrived
+  const received = new BN((99 * LAMPORTS_PER_SOL) / 10);
+  const balance = UserBalanceSchema.decode(
+    Buffer.from(svm.getAccount(balancePda)!.data)
+  );
+  expect(balance.available_base_balance.eq(received)).toBeTrue();
+  const vault = Buffer.from(svm.getAccount(feeMarket.baseVault)!.data);
+  expect(new BN(vault.subarray(64, 72), "le").eq(received)).toBeTrue();
+
+  // Soulbound tokens could never leave the vault again
+  const nonTransferableMint = await newMint(
+    [ExtensionType.NonTransferable],
+    (mint) => [
+      createInitializeNonTransferableMintInstruction(
+        mint,
+        TOKEN_2022_PROGRAM_ID
+      ),
+    ]
+  );
+  expect(
+    isFailed(
+      (await createMarket(nonTransferableMint, TOKEN_2022_PROGRAM_ID)).result
+    )
+  ).toBeTrue();
+  console.log(
+    `Token-2022 deposit of 10 credited ${received.toString()} after fees`
+  );
+});
+
+test("Native SOL markets take lamports on deposit and pay lamports on settlement", async () => {
+  const { svm, programId, authority, consumerEventsAuthority, quoteAsset } =
+    testEnv;
+
+  ensureNativeMint();
+  const solMarket = await createMarket(NATIVE_MINT, TOKEN_PROGRAM_ID);
+  expect(isFailed(solMarket.result)).toBeFalse();
+  const [unwrapPda] = PublicKey.findProgramAddressSync(
+    [Buffer.from("native_unwrap"), solMarket.market.toBuffer()],
+    programId.publicKey
+  );
+
+  const openBalance = async (trader: Keypair) => {
+    const [balancePda] = PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("user_balance"),
+        trader.publicKey.toBuffer(),
+        solMarket.market.toBuffer(),
+      ],
+      programId.publicKey
+    );
+    const createIx = marketIx({ CreateUserBalance: {} }, 1, [
+      meta(trader.publicKey, true, true),
+      meta(balancePda, true),
+      meta(solMarket.market),
+      meta(SystemProgram.programId),
+    ]);
+    expect(isFailed(await sendIxs([createIx], trader))).toBeFalse();
+    return balancePda;
+  };
+  const orderIx = (
+    trader: Keypair,
+    balancePda: PublicKey,
+    quoteTokenAccount: PublicKey,
+    side: number
+  ) =>
+    marketIx(
+      {
+        PlaceOrder: {
+          side,
+          price: new BN(100 * 1_000_000),
+          quantity: new BN(LAMPORTS_PER_SOL),
+        },
+      },
+      18,
+      [
+        meta(trader.publicKey, false, true),
+        meta(balancePda, true),
+        meta(solMarket.market, true),
+        meta(solMarket.bids, true),
+        meta(solMarket.asks, true),
+        meta(solMarket.events, true),
+        // No wrapped SOL account is needed for the native side
+        meta(trader.publicKey),
+        meta(quoteTokenAccount, true),
+        meta(solMarket.baseVault, true),
+        meta(solMarket.quoteVault, true),
+        meta(TOKEN_PROGRAM_ID),
+        meta(new PublicKey("SysvarC1ock11111111111111111111111111111111")),
+      ]
+    );
+  const vaultAmount = () =>
+    new BN(
+      Buffer.from(svm.getAccount(solMarket.baseVault)!.data).subarray(64, 72),
+      "le"
+    );
+
+  // The seller funds the base side straight from their wallet
+  const seller = Keypair.generate();
+  svm.airdrop(seller.publicKey, BigInt(20 * LAMPORTS_PER_SOL));
+  const sellerBalance = await openBalance(seller);
+  const depositNativeIx = marketIx(
+    { DepositNative: { amount: new BN(5 * LAMPORTS_PER_SOL) } },
+    9,
+    [
+      meta(seller.publicKey, true, true),
+      meta(sellerBalance, true),
+      meta(solMarket.market),
+      meta(solMarket.baseVault, true),
+      meta(TOKEN_PROGRAM_ID),
+      meta(SystemProgram.programId),
+    ]
+  );
+  expect(isFailed(await sendIxs([depositNativeIx], seller))).toBeFalse();
+  expect(vaultAmount().eq(new BN(5 * LAMPORTS_PER_SOL))).toBeTrue();
+  const funded = UserBalanceSchema.decode(
+    Buffer.from(svm.getAccount(sellerBalance)!.data)
+  );
+  expect(
+    funded.available_base_balance.eq(new BN(5 * LAMPORTS_PER_SOL))
+  ).toBeTrue();
+
+  // A USDC holder buys 1 SOL at 100 USDC
+  const buyer = await newTrader();
+  const buyerBalance = await openBalance(buyer.keypair);
+  const depositQuoteIx = marketIx(
+    { Deposit: { base_amount: new BN(0), quote_amount: new BN(200 * 1_000_000) } },
+    17,
+    [
+      meta(buyer.keypair.publicKey, false, true),
+      meta(buyerBalance, true),
+      meta(solMarket.market),
+      meta(buyer.quoteTokenAccount, true),
+      meta(buyer.quoteTokenAccount, true),
+      meta(solMarket.baseVault, true),
+      meta(solMarket.quoteVault, true),
+      meta(TOKEN_PROGRAM_ID),
+      meta(NATIVE_MINT),
+      meta(quoteAsset),
+    ]
+  );
+  expect(isFailed(await sendIxs([depositQuoteIx], buyer.keypair))).toBeFalse();
+
+  const sellerQuote = await newTokenAccount(quoteAsset, seller.publicKey);
+  expect(
+    isFailed(
+      await sendIxs(
+        [orderIx(seller, sellerBalance, sellerQuote, 2)],
+        seller
+      )
+    )
+  ).toBeFalse();
+  expect(
+    isFailed(
+      await sendIxs(
+        [orderIx(buyer.keypair, buyerBalance, buyer.quoteTokenAccount, 1)],
+        buyer.keypair
+      )
+    )
+  ).toBeFalse();
+
+  const consumeIx = marketIx({ ConsumeEvents: { limit: 0 } }, 3, [
+    meta(consumerEventsAuthority.publicKey, false, true),
+    meta(solMarket.market, true),
+    meta(solMarket.events, true),
+    meta(sellerBalance, true),
+    meta(buyerBalance, true),
+  ]);
+  expect(
+    isFailed(await sendIxs([consumeIx], authority, consumerEventsAuthority))
+  ).toBeFalse();
+
+  // The bought SOL arrives as lamports and the unwrap account is closed again
+  const lamportsBefore = svm.getBalance(buyer.keypair.publicKey)!;
+  const settleIx = (
+    trader: Keypair,
+    balancePda: PublicKey,
+    quoteTokenAccount: PublicKey
+  ) =>
+    marketIx({ SettleBalanceNative: {} }, 1, [
+      meta(trader.publicKey, true, true),
+      meta(balancePda, true),
+      meta(solMarket.market),
+      meta(quoteTokenAccount, true),
+      meta(solMarket.baseVault, true),
+      meta(solMarket.quoteVault, true),
+      meta(TOKEN_PROGRAM_ID),
+      meta(NATIVE_MINT),
+      meta(quoteAsset),
+      meta(unwrapPda, true),
+      meta(SystemProgram.programId),
+      meta(ASSOCIATED_TOKEN_PROGRAM_ID),
+    ]);
+  expect(
+    isFailed(
+      await sendIxs(
+        [settleIx(buyer.keypair, buyerBalance, buyer.quoteTokenAccount)],
+        buyer.keypair
+      )
+    )
+  ).toBeFalse();
+
+  const received = svm.getBalance(buyer.keypair.publicKey)! - lamportsBefore;
+  expect(received > BigInt(LAMPORTS_PER_SOL - 10_000)).toBeTrue();
+  expect(svm.getAccount(unwrapPda)?.lamports ?? 0).toBe(0);
+  expect(vaultAmount().eq(new BN(4 * LAMPORTS_PER_SOL))).toBeTrue();
+  const settled = UserBalanceSchema.decode(
+    Buffer.from(svm.getAccount(buyerBalance)!.data)
+  );
+  expect(settled.pending_base_balance.isZero()).toBeTrue();
+  console.log(`Settled ${received} lamports to the buyer's wallet`);
+
+  // The seller has no associated USDC account: someone else's account is
+  // rejected, and their associated token account is created on payout
+  expect(
+    isFailed(
+      await sendIxs(
+        [settleIx(seller, sellerBalance, buyer.quoteTokenAccount)],
+        seller
+      )
+    )
+  ).toBeTrue();
+  const sellerQuoteAta = getAssociatedTokenAddressSync(
+    quoteAsset,
+    seller.publicKey
+  );
+  expect(svm.getAccount(sellerQuoteAta)).toBeNull();
+  expect(
+    isFailed(
+      await sendIxs([settleIx(seller, sellerBalance, sellerQuoteAta)], seller)
+    )
+  ).toBeFalse();
+  const sellerQuote = Buffer.from(svm.getAccount(sellerQuoteAta)!.data);
+  expect(
+    new BN(sellerQuote.subarray(64, 72), "le").eq(new BN(100 * 1_000_000))
+  ).toBeTrue();
+});
+
+test("Anyone settles many users to their associated token accounts in one call", async () => {
+  const { svm, programId, authority, consumerEventsAuthority, quoteAsset } =
+    testEnv;
+
+  const baseMint = await newBaseMint();
+  const settleMarket = await createMarket(baseMint, TOKEN_PROGRAM_ID);
+  expect(isFailed(settleMarket.result)).toBeFalse();
+
+  const balanceAddress = (owner: PublicKey) =>
+    PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("user_balance"),
+        owner.toBuffer(),
+        settleMarket.market.toBuffer(),
+      ],
+      programId.publicKey
+    )[0];
+  const fundTrader = async (
+    trader: Keypair,
+    baseAccount: PublicKey,
+    quoteAccount: PublicKey,
+    baseAmount: BN,
+    quoteAmount: BN
+  ) => {
+    const balancePda = balanceAddress(trader.publicKey);
+    const createIx = marketIx({ CreateUserBalance: {} }, 1, [
+      meta(trader.publicKey, true, true),
+      meta(balancePda, true),
+      meta(settleMarket.market),
+      meta(SystemProgram.programId),
+    ]);
+    const depositIx = marketIx(
+      { Deposit: { base_amount: baseAmount, quote_amount: quoteAmount } },
+      17,
+      [
+        meta(trader.publicKey, false, true),
+        meta(balancePda, true),
+        meta(settleMarket.market),
+        meta(baseAccount, true),
+        meta(quoteAccount, true),
+        meta(settleMarket.baseVault, true),
+        meta(settleMarket.quoteVault, true),
+        meta(TOKEN_PROGRAM_ID),
+        meta(baseMint),
+        meta(quoteAsset),
+      ]
+    );
+    expect(isFailed(await sendIxs([createIx, depositIx], trader))).toBeFalse();
+    return balancePda;
+  };
+  const orderIx = (
+    trader: Keypair,
+    baseAccount: PublicKey,
+    quoteAccount: PublicKey,
+    side: number
+  ) =>
+    marketIx(
+      {
+        PlaceOrder: {
+          side,
+          price: new BN(100 * 1_000_000),
+          quantity: new BN(LAMPORTS_PER_SOL),
+        },
+      },
+      18,
+      [
+        meta(trader.publicKey, false, true),
+        meta(balanceAddress(trader.publicKey), true),
+        meta(settleMarket.market, true),
+        meta(settleMarket.bids, true),
+        meta(settleMarket.asks, true),
+        meta(settleMarket.events, true),
+        meta(baseAccount, true),
+        meta(quoteAccount, true),
+        meta(settleMarket.baseVault, true),
+        meta(settleMarket.quoteVault, true),
+        meta(TOKEN_PROGRAM_ID),
+        meta(new PublicKey("SysvarC1ock11111111111111111111111111111111")),
+      ]
+    );
+
+  // The seller funds from a plain token account and holds no associated ones
+  const seller = Keypair.generate();
+  svm.airdrop(seller.publicKey, BigInt(10 * LAMPORTS_PER_SOL));
+  const sellerBase = Keypair.generate();
+  expect(
+    isFailed(
+      await sendIxs(
+        [
+          SystemProgram.createAccount({
+            fromPubkey: seller.publicKey,
+            newAccountPubkey: sellerBase.publicKey,
+            lamports: Number(svm.minimumBalanceForRentExemption(BigInt(165))),
+            space: 165,
+            programId: TOKEN_PROGRAM_ID,
+          }),
+          createInitializeAccountInstruction(
+            sellerBase.publicKey,
+            baseMint,
+            seller.publicKey
+          ),
+        ],
+        seller,
+        sellerBase
+      )
+    )
+  ).toBeFalse();
+  expect(
+    isFailed(
+      await sendIxs(
+        [
+          createMintToInstruction(
+            baseMint,
+            sellerBase.publicKey,
+            authority.publicKey,
+            LAMPORTS_PER_SOL
+          ),
+        ],
+        authority
+      )
+    )
+  ).toBeFalse();
+  const buyer = await newTrader();
+
+  const sellerBalance = await fundTrader(
+    seller,
+    sellerBase.publicKey,
+    sellerBase.publicKey,
+    new BN(LAMPORTS_PER_SOL),
+    new BN(0)
+  );
+  const buyerBalance = await fundTrader(
+    buyer.keypair,
+    buyer.quoteTokenAccount,
+    buyer.quoteTokenAccount,
+    new BN(0),
+    new BN(100 * 1_000_000)
+  );
+
+  const sellerQuote = await newTokenAccount(quoteAsset, seller.publicKey);
+  const buyerBase = await newTokenAccount(baseMint, buyer.keypair.publicKey);
+  expect(
+    isFailed(
+      await sendIxs(
+        [orderIx(seller, sellerBase.publicKey, sellerQuote, 2)],
+        seller
+      )
+    )
+  ).toBeFalse();
+  expect(
+    isFailed(
+      await sendIxs(
+        [orderIx(buyer.keypair, buyerBase, buyer.quoteTokenAccount, 1)],
+        buyer.keypair
+      )
+    )
+  ).toBeFalse();
+  const consumeIx = marketIx({ ConsumeEvents: { limit: 0 } }, 3, [
+    meta(consumerEventsAuthority.publicKey, false, true),
+    meta(settleMarket.market, true),
+    meta(settleMarket.events, true),
+    meta(sellerBalance, true),
+    meta(buyerBalance, true),
+  ]);
+  expect(
+    isFailed(await sendIxs([consumeIx], authority, consumerEventsAuthority))
+  ).toBeFalse();
+
+  const ata = (owner: PublicKey, mint: PublicKey) =>
+    getAssociatedTokenAddressSync(mint, owner);
+  const settleForIx = (groups: PublicKey[][]) =>
+    marketIx({ SettleFor: {} }, 1, [
+      meta(authority.publicKey, true, true),
+      meta(settleMarket.market),
+      meta(settleMarket.baseVault, true),
+      meta(settleMarket.quoteVault, true),
+      meta(TOKEN_PROGRAM_ID),
+      meta(baseMint),
+      meta(quoteAsset),
+      meta(SystemProgram.programId),
+      meta(ASSOCIATED_TOKEN_PROGRAM_ID),
+      meta(TOKEN_PROGRAM_ID),
+      ...groups.flatMap(([balance, owner, base, quote]) => [
+        meta(balance, true),
+        meta(owner),
+        meta(base, true),
+        meta(quote, true),
+      ]),
+    ]);
+  const sellerGroup = [
+    sellerBalance,
+    seller.publicKey,
+    ata(seller.publicKey, baseMint),
+    ata(seller.publicKey, quoteAsset),
+  ];
+  const buyerGroup = [
+    buyerBalance,
+    buyer.keypair.publicKey,
+    ata(buyer.keypair.publicKey, baseMint),
+    ata(buyer.keypair.publicKey, quoteAsset),
+  ];
+
+  // Only the canonical associated token accounts may receive a payout
+  expect(
+    isFailed(
+      await sendIxs(
+        [
+          settleForIx([
+            [...sellerGroup.slice(0, 3), buyer.quoteTokenAccount],
+          ]),
+        ],
+        authority
+      )
+    )
+  ).toBeTrue();
+  expect(
+    isFailed(
+      await sendIxs(
+        [
+          settleForIx([
+            [sellerBalance, buyer.keypair.publicKey, ...buyerGroup.slice(2)],
+          ]),
+        ],
+        authority
+      )
+    )
+  ).toBeTrue();
+
+  // A third party settles both traders at once, paying for the new accounts
+  expect(
+    isFailed(await sendIxs([settleForIx([sellerGroup, buyerGroup])], authority))
+  ).toBeFalse();
+  const tokenAmount = (account: PublicKey) =>
+    new BN(Buffer.from(svm.getAccount(account)!.data).subarray(64, 72), "le");
+  expect(tokenAmount(sellerGroup[3]).eq(new BN(100 * 1_000_000))).toBeTrue();
+  expect(tokenAmount(buyerGroup[2]).eq(new BN(LAMPORTS_PER_SOL))).toBeTrue();
+  for (const balance of [sellerBalance, buyerBalance]) {
+    const settled = UserBalanceSchema.decode(
+      Buffer.from(svm.getAccount(balance)!.data)
+    );
+    expect(settled.pending_base_balance.isZero()).toBeTrue();
+    expect(settled.pending_quote_balance.isZero()).toBeTrue();
+  }
+
+  // Settling again has nothing left to pay
+  expect(
+    isFailed(await sendIxs([settleForIx([sellerGroup, buyerGroup])], authority))
+  ).toBeFalse();
+  console.log("Settled two traders to their associated token accounts");
+});
+
+test("Takers settle their fills while placing and withdraw in the same transaction", async () => {
+  const { svm, programId, authority, consumerEventsAuthority, quoteAsset } =
+    testEnv;
+
+  const baseMint = await newBaseMint();
+  const takerMarket = await createMarket(baseMint, TOKEN_PROGRAM_ID);
+  expect(isFailed(takerMarket.result)).toBeFalse();
+
+  const balanceAddress = (owner: PublicKey) =>
+    PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("user_balance"),
+        owner.toBuffer(),
+        takerMarket.market.toBuffer(),
+      ],
+      programId.publicKey
+    )[0];
+  const fund = async (
+    trader: Keypair,
+    tokenAccount: PublicKey,
+    baseAmount: BN,
+    quoteAmount: BN
+  ) => {
+    const balancePda = balanceAddress(trader.publicKey);
+    const ixs = [
+      marketIx({ CreateUserBalance: {} }, 1, [
+        meta(trader.publicKey, true, true),
+        meta(balancePda, true),
+        meta(takerMarket.market),
+        meta(SystemProgram.programId),
+      ]),
+      marketIx(
+        { Deposit: { base_amount: baseAmount, quote_amount: quoteAmount } },
+        17,
+        [
+          meta(trader.publicKey, false, true),
+          meta(balancePda, true),
+          meta(takerMarket.market),
+          meta(tokenAccount, true),
+          meta(tokenAccount, true),
+          meta(takerMarket.baseVault, true),
+          meta(takerMarket.quoteVault, true),
+          meta(TOKEN_PROGRAM_ID),
+          meta(baseMint),
+          meta(quoteAsset),
+        ]
+      ),
+    ];
+    expect(isFailed(await sendIxs(ixs, trader))).toBeFalse();
+    return balancePda;
+  };
+  const orderKeys = (
+    trader: Keypair,
+    baseAccount: PublicKey,
+    quoteAccount: PublicKey
+  ) => [
+    meta(trader.publicKey, false, true),
+    meta(balanceAddress(trader.publicKey), true),
+    meta(takerMarket.market, true),
+    meta(takerMarket.bids, true),
+    meta(takerMarket.asks, true),
+    meta(takerMarket.events, true),
+    meta(baseAccount, true),
+    meta(quoteAccount, true),
+    meta(takerMarket.baseVault, true),
+    meta(takerMarket.quoteVault, true),
+    meta(TOKEN_PROGRAM_ID),
+    meta(new PublicKey("SysvarC1ock11111111111111111111111111111111")),
+  ];
+
+  // A maker rests 1 token at 100 USDC
+  const maker = Keypair.generate();
+  svm.airdrop(maker.publicKey, BigInt(10 * LAMPORTS_PER_SOL));
+  const makerBase = getAssociatedTokenAddressSync(
+    baseMint,
+    maker.publicKey
+  );
+  expect(
+    isFailed(
+      await sendIxs(
+        [
+          createAssociatedTokenAccountInstruction(
+            authority.publicKey,
+            makerBase,
+            maker.publicKey,
+            baseMint
+          ),
+          createMintToInstruction(
+            baseMint,
+            makerBase,
+            authority.publicKey,
+            LAMPORTS_PER_SOL
+          ),
+        ],
+        authority
+      )
+    )
+  ).toBeFalse();
+  const makerBalance = await fund(
+    maker,
+    makerBase,
+    new BN(LAMPORTS_PER_SOL),
+    new BN(0)
+  );
+  const buyer = await newTrader();
+  expect(
+    isFailed(
+      await sendIxs(
+        [
+          marketIx(
+            {
+              PlaceOrder: {
+                side: 2,
+                price: new BN(100 * 1_000_000),
+                quantity: new BN(LAMPORTS_PER_SOL),
+              },
+            },
+            18,
+            orderKeys(
+              maker,
+              makerBase,
+              await newTokenAccount(quoteAsset, maker.publicKey)
+            )
+          ),
+        ],
+        maker
+      )
+    )
+  ).toBeFalse();
+
+  // The taker bids up to 120 USDC, is filled at 100 and withdraws right away
+  const buyerBalance = await fund(
+    buyer.keypair,
+    buyer.quoteTokenAccount,
+    new BN(0),
+    new BN(150 * 1_000_000)
+  );
+  const buyerBaseAta = getAssociatedTokenAddressSync(
+    baseMint,
+    buyer.keypair.publicKey
+  );
+  // The buyer's associated base account is only created by the settle
+  const buyerBase = await newTokenAccount(baseMint, buyer.keypair.publicKey);
+  const takeIx = marketIx(
+    {
+      PlaceOrderSettleTaker: {
+        side: 1,
+        price: new BN(120 * 1_000_000),
+        quantity: new BN(LAMPORTS_PER_SOL),
+        client_order_id: new BN(7),
+      },
+    },
+    26,
+    orderKeys(buyer.keypair, buyerBase, buyer.quoteTokenAccount)
+  );
+  const settleIx = marketIx({ SettleBalance: {} }, 1, [
+    meta(buyer.keypair.publicKey, true, true),
+    meta(buyerBalance, true),
+    meta(takerMarket.market),
+    meta(takerMarket.market),
+    meta(buyerBaseAta, true),
+    meta(buyer.quoteTokenAccount, true),
+    meta(takerMarket.baseVault, true),
+    meta(takerMarket.quoteVault, true),
+    meta(TOKEN_PROGRAM_ID),
+    meta(baseMint),
+    meta(quoteAsset),
+    meta(SystemProgram.programId),
+    meta(ASSOCIATED_TOKEN_PROGRAM_ID),
+  ]);
+  expect(isFailed(await sendIxs([takeIx, settleIx], buyer.keypair))).toBeFalse();
+
+  const tokenAmount = (account: PublicKey) =>
+    new BN(Buffer.from(svm.getAccount(account)!.data).subarray(64, 72), "le");
+  expect(tokenAmount(buyerBaseAta).eq(new BN(LAMPORTS_PER_SOL))).toBeTrue();
+  const taker = UserBalanceSchema.decode(
+    Buffer.from(svm.getAccount(buyerBalance)!.data)
+  );
+  // The 20 USDC bid above the fill price is released rather than left locked
+  expect(taker.available_quote_balance.eq(new BN(50 * 1_000_000))).toBeTrue();
+  expect(taker.locked_quote_balance.isZero()).toBeTrue();
+  expect(taker.pending_base_balance.isZero()).toBeTrue();
+  expect(taker.open_orders_count).toBe(0);
+
+  // Only the maker's balance is needed to consume the fill
+  const consumeIx = marketIx({ ConsumeEvents: { limit: 0 } }, 3, [
+    meta(consumerEventsAuthority.publicKey, false, true),
+    meta(takerMarket.market, true),
+    meta(takerMarket.events, true),
+    meta(makerBalance, true),
+  ]);
+  expect(
+    isFailed(await sendIxs([consumeIx], authority, consumerEventsAuthority))
+  ).toBeFalse();
+  const makerState = UserBalanceSchema.decode(
+    Buffer.from(svm.getAccount(makerBalance)!.data)
+  );
+  expect(makerState.locked_base_balance.isZero()).toBeTrue();
+  expect(
+    makerState.pending_quote_balance.eq(new BN(100 * 1_000_000))
+  ).toBeTrue();
+  const afterConsume = UserBalanceSchema.decode(
+    Buffer.from(svm.getAccount(buyerBalance)!.data)
+  );
+  expect(afterConsume.pending_base_balance.isZero()).toBeTrue();
+  console.log("Taker filled and withdrew in a single transaction");
+});
+
+test("ConsumeEvents stops at the first event missing a balance and never drops it", async () => {
+  const { svm, programId, authority, consumerEventsAuthority, quoteAsset } =
+    testEnv;
+
+  const baseMint = await newBaseMint();
+  const queueMarket = await createMarket(baseMint, TOKEN_PROGRAM_ID);
+  expect(isFailed(queueMarket.result)).toBeFalse();
+
+  const balanceAddress = (owner: PublicKey) =>
+    PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("user_balance"),
+        owner.toBuffer(),
+        queueMarket.market.toBuffer(),
+      ],
+      programId.publicKey
+    )[0];
+  const trade = async (
+    trader: Keypair,
+    baseAccount: PublicKey,
+    quoteAccount: PublicKey,
+    side: number,
+    deposit: { base_amount: BN; quote_amount: BN }
+  ) => {
+    const balancePda = balanceAddress(trader.publicKey);
+    const ixs = [
+      marketIx({ CreateUserBalance: {} }, 1, [
+        meta(trader.publicKey, true, true),
+        meta(balancePda, true),
+        meta(queueMarket.market),
+        meta(SystemProgram.programId),
+      ]),
+      marketIx({ Deposit: deposit }, 17, [
+        meta(trader.publicKey, false, true),
+        meta(balancePda, true),
+        meta(queueMarket.market),
+        meta(baseAccount, true),
+        meta(quoteAccount, true),
+        meta(queueMarket.baseVault, true),
+        meta(queueMarket.quoteVault, true),
+        meta(TOKEN_PROGRAM_ID),
+        meta(baseMint),
+        meta(quoteAsset),
+      ]),
+      marketIx(
+        {
+          PlaceOrder: {
+            side,
+            price: new BN(100 * 1_000_000),
+            quantity: new BN(LAMPORTS_PER_SOL),
+          },
+        },
+        18,
+        [
+          meta(trader.publicKey, false, true),
+          meta(balancePda, true),
+          meta(queueMarket.market, true),
+          meta(queueMarket.bids, true),
+          meta(queueMarket.asks, true),
+          meta(queueMarket.events, true),
+          meta(baseAccount, true),
+          meta(quoteAccount, true),
+          meta(queueMarket.baseVault, true),
+          meta(queueMarket.quoteVault, true),
+          meta(TOKEN_PROGRAM_ID),
+          meta(new PublicKey("SysvarC1ock11111111111111111111111111111111")),
+        ]
+      ),
+    ];
+    expect(isFailed(await sendIxs(ixs, trader))).toBeFalse();
+    return balancePda;
+  };
+
+  const maker = Keypair.generate();
+  svm.airdrop(maker.publicKey, BigInt(10 * LAMPORTS_PER_SOL));
+  const makerBase = getAssociatedTokenAddressSync(baseMint, maker.publicKey);
+  expect(
+    isFailed(
+      await sendIxs(
+        [
+          createAssociatedTokenAccountInstruction(
+            authority.publicKey,
+            makerBase,
+            maker.publicKey,
+            baseMint
+          ),
+          createMintToInstruction(
+            baseMint,
+            makerBase,
+            authority.publicKey,
+            LAMPORTS_PER_SOL
+          ),
+        ],
+        authority
+      )
+    )
+  ).toBeFalse();
+  const taker = await newTrader();
+  const makerBalance = await trade(
+    maker,
+    makerBase,
+    await newTokenAccount(quoteAsset, maker.publicKey),
+    2,
+    { base_amount: new BN(LAMPORTS_PER_SOL), quote_amount: new BN(0) }
+  );
+  const takerBalance = await trade(
+    taker.keypair,
+    await newTokenAccount(baseMint, taker.keypair.publicKey),
+    taker.quoteTokenAccount,
+    1,
+    { base_amount: new BN(0), quote_amount: new BN(100 * 1_000_000) }
+  );
+
+  const consume = async (balances: PublicKey[]) => {
+    const result = await sendIxs(
+      [
+        marketIx({ ConsumeEvents: { limit: 0 } }, 3, [
+          meta(consumerEventsAuthority.publicKey, false, true),
+          meta(queueMarket.market, true),
+          meta(queueMarket.events, true),
+          ...balances.map((balance) => meta(balance, true)),
+        ]),
+      ],
+      authority,
+      consumerEventsAuthority
+    );
+    if (isFailed(result)) {
+      return null;
+    }
+    return ConsumeEventsResultSchema.decode(
+      Buffer.from((result as any).returnData().data())
+    );
+  };
+  const readBalance = (balance: PublicKey) =>
+    UserBalanceSchema.decode(Buffer.from(svm.getAccount(balance)!.data));
+
+  // Without the taker's balance the fill stays queued and nothing is applied
+  const stalled = (await consume([makerBalance]))!;
+  expect(stalled.consumed.toNumber()).toBe(0);
+  expect(stalled.remaining.toNumber()).toBe(1);
+  expect(
+    readBalance(makerBalance).locked_base_balance.eq(new BN(LAMPORTS_PER_SOL))
+  ).toBeTrue();
+
+  // Anything but a distinct balance account of this market is rejected
+  expect(await consume([makerBalance, takerBalance, makerBase])).toBeNull();
+  expect(await consume([makerBalance, makerBalance, takerBalance])).toBeNull();
+  expect(await consume([makerBalance, testEnv.takerBalancePda])).toBeNull();
+
+  const applied = (await consume([makerBalance, takerBalance]))!;
+  expect(applied.consumed.toNumber()).toBe(1);
+  expect(applied.remaining.toNumber()).toBe(0);
+  expect(
+    readBalance(makerBalance).pending_quote_balance.eq(new BN(100 * 1_000_000))
+  ).toBeTrue();
+  expect(
+    readBalance(takerBalance).pending_base_balance.eq(new BN(LAMPORTS_PER_SOL))
+  ).toBeTrue();
+
+  // Consumed events leave the queue and are not applied twice
+  const drained = (await consume([makerBalance, takerBalance]))!;
+  expect(drained.consumed.toNumber()).toBe(0);
+  expect(
+    readBalance(takerBalance).pending_base_balance.eq(new BN(LAMPORTS_PER_SOL))
+  ).toBeTrue();
+  console.log("Events consumed only once all their balances were passed");
+});
+
+test("ConsumeEvents applies up to the caller's limit and otherwise to the compute budget", async () => {
+  const { svm, programId, authority, consumerEventsAuthority, quoteAsset } =
+    testEnv;
+
+  const baseMint = await newBaseMint();
+  const limitMarket = await createMarket(baseMint, TOKEN_PROGRAM_ID);
+  expect(isFailed(limitMarket.result)).toBeFalse();
+
+
+  const trader = await newTrader();
+  const traderBase = getAssociatedTokenAddressSync(
+    baseMint,
+    trader.keypair.publicKey
+  );
+  const [balancePda] = PublicKey.findProgramAddressSync(
+    [
+      Buffer.from("user_balance"),
+      trader.keypair.publicKey.toBuffer(),
+      limitMarket.market.toBuffer(),
+    ],
+    programId.publicKey
+  );
+  expect(
+    isFailed(
+      await sendIxs(
+        [
+          createAssociatedTokenAccountInstruction(
+            authority.publicKey,
+            traderBase,
+            trader.keypair.publicKey,
+            baseMint
+          ),
+          createMintToInstruction(
+            baseMint,
+            traderBase,
+            authority.publicKey,
+            LAMPORTS_PER_SOL
+          ),
+        ],
+        authority
+      )
+    )
+  ).toBeFalse();
+  expect(
+    isFailed(
+      await sendIxs(
+        [
+          marketIx({ CreateUserBalance: {} }, 1, [
+            meta(trader.keypair.publicKey, true, true),
+            meta(balancePda, true),
+            meta(limitMarket.market),
+            meta(SystemProgram.programId),
+          ]),
+          marketIx(
+            {
+              Deposit: {
+                base_amount: new BN(LAMPORTS_PER_SOL),
+                quote_amount: new BN(100 * 1_000_000),
+              },
+            },
+            17,
+            [
+              meta(trader.keypair.publicKey, false, true),
+              meta(balancePda, true),
+              meta(limitMarket.market),
+              meta(traderBase, true),
+              meta(trader.quoteTokenAccount, true),
+              meta(limitMarket.baseVault, true),
+              meta(limitMarket.quoteVault, true),
+              meta(TOKEN_PROGRAM_ID),
+              meta(baseMint),
+              meta(quoteAsset),
+            ]
+          ),
+        ],
+        trader.keypair
+      )
+    )
+  ).toBeFalse();
+
+  const placeOrder = (side: number, quantity: number) =>
+    marketIx(
+      {
+        PlaceOrder: {
+          side,
+          price: new BN(100 * 1_000_000),
+          quantity: new BN(quantity),
+        },
+      },
+      18,
+      [
+        meta(trader.keypair.publicKey, false, true),
+        meta(balancePda, true),
+        meta(limitMarket.market, true),
+        meta(limitMarket.bids, true),
+        meta(limitMarket.asks, true),
+        meta(limitMarket.events, true),
+        meta(traderBase, true),
+        meta(trader.quoteTokenAccount, true),
+        meta(limitMarket.baseVault, true),
+        meta(limitMarket.quoteVault, true),
+        meta(TOKEN_PROGRAM_ID),
+        meta(new PublicKey("SysvarC1ock11111111111111111111111111111111")),
+      ]
+    );
+
+  // Ten resting asks swept by one bid queue ten fills
+  for (let i = 0; i < 10; i++) {
+    expect(
+      isFailed(
+        await sendIxs([placeOrder(2, LAMPORTS_PER_SOL / 10)], trader.keypair)
+      )
+    ).toBeFalse();
+  }
+  expect(
+    isFailed(await sendIxs([placeOrder(1, LAMPORTS_PER_SOL)], trader.keypair))
+  ).toBeFalse();
+
+  const consume = async (limit: number) => {
+    const result = await sendIxs(
+      [
+        ComputeBudgetProgram.setComputeUnitLimit({ units: 1_400_000 }),
+        marketIx({ ConsumeEvents: { limit } }, 3, [
+          meta(consumerEventsAuthority.publicKey, false, true),
+          meta(limitMarket.market, true),
+          meta(limitMarket.events, true),
+          meta(balancePda, true),
+        ]),
+      ],
+      authority,
+      consumerEventsAuthority
+    );
+    expect(isFailed(result)).toBeFalse();
+    return ConsumeEventsResultSchema.decode(
+      Buffer.from((result as any).returnData().data())
+    );
+  };
+
+  const limited = await consume(3);
+  expect(limited.consumed.toNumber()).toBe(3);
+  expect(limited.remaining.toNumber()).toBe(7);
+
+  // With no limit the raised compute budget clears the rest in one call
+  const unlimited = await consume(0);
+  expect(unlimited.consumed.toNumber()).toBe(7);
+  expect(unlimited.remaining.toNumber()).toBe(0);
+
+  const balance = UserBalanceSchema.decode(
+    Buffer.from(svm.getAccount(balancePda)!.data)
+  );
+  expect(balance.locked_base_balance.toNumber()).toBe(0);
+  expect(balance.available_base_balance.eq(new BN(LAMPORTS_PER_SOL))).toBeTrue();
+  console.log("ConsumeEvents honoured the limit and the compute budget");
+});
+
+test("Migrate converts first release market, book, event and balance accounts", async () => {
+  const { svm, programId, authority, consumerEventsAuthority, quoteAsset } =
+    testEnv;
+
+  const baseMint = await newBaseMint();
+  const legacyMarket = await createMarket(baseMint, TOKEN_PROGRAM_ID);
+  expect(isFailed(legacyMarket.result)).toBeFalse();
+
+  // A trader with 150 USDC deposited and a base account for the new mint
+  const trader = await newTrader();
+  const [balancePda] = PublicKey.findProgramAddressSync(
+    [
+      Buffer.from("user_balance"),
+      trader.keypair.publicKey.toBuffer(),
+      legacyMarket.market.toBuffer(),
+    ],
+    programId.publicKey
+  );
+  const traderBase = getAssociatedTokenAddressSync(
+    baseMint,
+    trader.keypair.publicKey
+  );
+  expect(
+    isFailed(
+      await sendIxs(
+        [
+          createAssociatedTokenAccountInstruction(
+            authority.publicKey,
+            traderBase,
+            trader.keypair.publicKey,
+            baseMint
+          ),
+        ],
+        authority
+      )
+    )
+  ).toBeFalse();
+  expect(
+    isFailed(
+      await sendIxs(
+        [
+          marketIx({ CreateUserBalance: {} }, 1, [
+            meta(trader.keypair.publicKey, true, true),
+            meta(balancePda, true),
+            meta(legacyMarket.market),
+            meta(SystemProgram.programId),
+          ]),
+          marketIx(
+            {
+              Deposit: {
+                base_amount: new BN(0),
+                quote_amount: new BN(150 * 1_000_000),
+              },
+            },
+            17,
+            [
+              meta(trader.keypair.publicKey, false, true),
+              meta(balancePda, true),
+              meta(legacyMarket.market),
+              meta(traderBase, true),
+              meta(trader.quoteTokenAccount, true),
+              meta(legacyMarket.baseVault, true),
+              meta(legacyMarket.quoteVault, true),
+              meta(TOKEN_PROGRAM_ID),
+              meta(baseMint),
+              meta(quoteAsset),
+            ]
+          ),
+        ],
+        trader.keypair
+      )
+    )
+  ).toBeFalse();
+
+  const seller = Keypair.generate();
+  const [sellerBalancePda] = PublicKey.findProgramAddressSync(
+    [
+      Buffer.from("user_balance"),
+      seller.publicKey.toBuffer(),
+      legacyMarket.market.toBuffer(),
+    ],
+    programId.publicKey
+  );
+
+  // Rewrite the market's accounts as the first release laid them out
+  const writeLegacy = (
+    address: PublicKey,
+    value: object,
+    schema: typeof LegacyMarketStateSchema
+  ) => {
+    const data = Buffer.alloc(schema.span);
+    schema.encode(value, data);
+    svm.setAccount(address, {
+      lamports: Number(svm.minimumBalanceForRentExemption(BigInt(data.length))),
+      data,
+      owner: programId.publicKey,
+      executable: false,
+    });
+  };
+  const market = MarketStateSchema.decode(
+    Buffer.from(svm.getAccount(legacyMarket.market)!.data)
+  );
+  writeLegacy(
+    legacyMarket.market,
+    {
+      ...market,
+      is_initialized: true,
+      next_order_id: new BN(42),
+      last_price: new BN(95 * 1_000_000),
+    },
+    LegacyMarketStateSchema
+  );
+
+  const legacyBalance = (owner: PublicKey, balances: object) => ({
+    owner,
+    market: legacyMarket.market,
+    available_base_balance: new BN(0),
+    available_quote_balance: new BN(0),
+    locked_base_balance: new BN(0),
+    locked_quote_balance: new BN(0),
+    pending_base_balance: new BN(0),
+    pending_quote_balance: new BN(0),
+    ...balances,
+  });
+  // 10 USDC locked for order 41, a bid for 1 SOL at 10 USDC
+  writeLegacy(
+    balancePda,
+    legacyBalance(trader.keypair.publicKey, {
+      available_quote_balance: new BN(140 * 1_000_000),
+      locked_quote_balance: new BN(10 * 1_000_000),
+    }),
+    LegacyUserBalanceSchema
+  );
+  // The seller that hit half of it, the fill still queued
+  writeLegacy(
+    sellerBalancePda,
+    legacyBalance(seller.publicKey, {
+      locked_base_balance: new BN(LAMPORTS_PER_SOL / 2),
+    }),
+    LegacyUserBalanceSchema
+  );
+
+  const emptyOrder = {
+    owner: PublicKey.default,
+    market: PublicKey.default,
+    timestamp: new BN(0),
+    order_id: new BN(0),
+    price: new BN(0),
+    quantity: new BN(0),
+    filled_quantity: new BN(0),
+    side: 0,
+  };
+  const legacyBook = (side: number, orders: object[]) => ({
+    orders: Array.from(
+      { length: MAX_ORDERS },
+      (_, i) => orders[i] ?? emptyOrder
+    ),
+    market: legacyMarket.market,
+    active_orders_count: new BN(orders.length),
+    side,
+  });
+  writeLegacy(
+    legacyMarket.bids,
+    legacyBook(1, [
+      {
+        owner: trader.keypair.publicKey,
+        market: legacyMarket.market,
+        timestamp: new BN(0),
+        order_id: new BN(41),
+        price: new BN(10 * 1_000_000),
+        quantity: new BN(LAMPORTS_PER_SOL),
+        filled_quantity: new BN(LAMPORTS_PER_SOL / 2),
+        side: 1,
+      },
+    ]),
+    LegacyOrderbookSchema
+  );
+  writeLegacy(legacyMarket.asks, legacyBook(2, []), LegacyOrderbookSchema);
+
+  const legacyEvent = (event_type: number, fields: object) => ({
+    maker: trader.keypair.publicKey,
+    taker: seller.publicKey,
+    timestamp: new BN(0),
+    maker_order_id: new BN(41),
+    quantity: new BN(LAMPORTS_PER_SOL / 2),
+    price: new BN(10 * 1_000_000),
+    event_type,
+    side: 2,
+    ...fields,
+  });
+  const queued = [
+    // Already applied, the first release never removed events
+    legacyEvent(0, {
+      maker: Keypair.generate().publicKey,
+      maker_order_id: new BN(7),
+    }),
+    // A cancel, its funds were released when it was queued
+    legacyEvent(1, { maker_order_id: new BN(40), side: 1 }),
+    // Still pending
+    legacyEvent(0, {}),
+  ];
+  writeLegacy(
+    legacyMarket.events,
+    {
+      events: Array.from(
+        { length: 512 },
+        (_, i) =>
+          queued[i] ??
+          legacyEvent(0, {
+            maker: PublicKey.default,
+            taker: PublicKey.default,
+            maker_order_id: new BN(0),
+            quantity: new BN(0),
+            price: new BN(0),
+            side: 0,
+          })
+      ),
+      market: legacyMarket.market,
+      count: new BN(queued.length),
+      seq_num: new BN(queued.length),
+      events_to_process: new BN(2),
+    },
+    LegacyMarketEventsSchema
+  );
+
+  const orderKeys = [
+    meta(trader.keypair.publicKey, false, true),
+    meta(balancePda, true),
+    meta(legacyMarket.market, true),
+    meta(legacyMarket.bids, true),
+    meta(legacyMarket.asks, true),
+    meta(legacyMarket.events, true),
+    meta(traderBase, true),
+    meta(trader.quoteTokenAccount, true),
+    meta(legacyMarket.baseVault, true),
+    meta(legacyMarket.quoteVault, true),
+    meta(TOKEN_PROGRAM_ID),
+    meta(new PublicKey("SysvarC1ock11111111111111111111111111111111")),
+  ];
+  const bidIx = () =>
+    marketIx(
+      {
+        PlaceOrder: {
+          side: 1,
+          price: new BN(100 * 1_000_000),
+          quantity: new BN(LAMPORTS_PER_SOL),
+        },
+      },
+      18,
+      orderKeys
+    );
+
+  // First release accounts are refused until they are migrated
+  expect(isFailed(await sendIxs([bidIx()], trader.keypair))).toBeTrue();
+
+  const migrate = (...accounts: PublicKey[]) =>
+    sendIxs(
+      [
+        ComputeBudgetProgram.setComputeUnitLimit({ units: 1_400_000 }),
+        marketIx({ Migrate: {} }, 1, [
+          meta(authority.publicKey, true, true),
+          meta(legacyMarket.market, true),
+          meta(SystemProgram.programId),
+          ...accounts.map((account) => meta(account, true)),
+        ]),
+      ],
+      authority
+    );
+  const marketAccounts = [
+    baseMint,
+    quoteAsset,
+    legacyMarket.bids,
+    legacyMarket.asks,
+    legacyMarket.events,
+  ];
+
+  // The market can't be migrated without its mints, read for their decimals
+  expect(isFailed(await migrate(...marketAccounts.slice(2)))).toBeTrue();
+
+  // The first call migrates the market and the event queue, the books only
+  // grow as far as one instruction may
+  const first = await migrate(...marketAccounts);
+  expect(isFailed(first)).toBeFalse();
+  expect(
+    decodeOrderbookEvents((first as any).logs()).filter(
+      (event: any) => event.AccountMigrated
+    ).length
+  ).toBe(2);
+  expect(svm.getAccount(legacyMarket.bids)!.data.length).toBe(
+    LegacyOrderbookSchema.span + 10_240
+  );
+
+  const marketAccount = svm.getAccount(legacyMarket.market)!;
+  expect(marketAccount.data.length).toBe(MARKET_STATE_LEN);
+  const migratedMarket = MarketStateSchema.decode(
+    Buffer.from(marketAccount.data)
+  );
+  expect(Buffer.from(migratedMarket.discriminator).toString()).toBe(
+    "market\0\0"
+  );
+  expect(migratedMarket.version).toBe(MARKET_STATE_VERSION);
+  expect(migratedMarket.authority.equals(market.authority)).toBeTrue();
+  expect(migratedMarket.base_mint.equals(baseMint)).toBeTrue();
+  expect(migratedMarket.base_decimals).toBe(9);
+  expect(migratedMarket.quote_decimals).toBe(6);
+  expect(migratedMarket.base_token_program.equals(TOKEN_PROGRAM_ID)).toBeTrue();
+  expect(migratedMarket.max_orders_per_user).toBe(MAX_OPEN_ORDERS);
+  expect(migratedMarket.next_order_id.toNumber()).toBe(42);
+  expect(migratedMarket.last_price.eq(new BN(95 * 1_000_000))).toBeTrue();
+  expect(migratedMarket.base_vault_bump).toBe(market.base_vault_bump);
+  expect(migratedMarket.quote_vault_bump).toBe(market.quote_vault_bump);
+  expect(migratedMarket.fee_account_bump).toBe(market.fee_account_bump);
+  expect(migratedMarket.native_unwrap_bump).toBe(market.native_unwrap_bump);
+
+  // Only the pending fill is left, released at the price it filled at
+  const eventsAccount = svm.getAccount(legacyMarket.events)!;
+  expect(eventsAccount.data.length).toBe(MARKET_EVENT_LEN);
+  const migratedEvents = MarketEventsSchema.decode(
+    Buffer.from(eventsAccount.data)
+  );
+  expect(migratedEvents.count.toNumber()).toBe(1);
+  expect(migratedEvents.events_to_process.toNumber()).toBe(1);
+  expect(migratedEvents.seq_num.toNumber()).toBe(3);
+  expect(migratedEvents.events[0].event_type).toBe(0);
+  expect(migratedEvents.events[0].maker_order_id.toNumber()).toBe(41);
+  expect(migratedEvents.events[0].taker_order_id.toNumber()).toBe(0);
+  expect(
+    migratedEvents.events[0].locked_price.eq(new BN(10 * 1_000_000))
+  ).toBeTrue();
+
+  // Balances list their orders from the books, which aren't current yet
+  expect(isFailed(await migrate(...marketAccounts, balancePda))).toBeTrue();
+
+  // Two more calls grow the books, the fourth converts them
+  for (let call = 0; call < 3; call++) {
+    expect(isFailed(await migrate(...marketAccounts))).toBeFalse();
+  }
+  const bidsAccount = svm.getAccount(legacyMarket.bids)!;
+  expect(bidsAccount.data.length).toBe(ORDERBOOK_LEN);
+  const migratedBids = OrderbookSchema.decode(Buffer.from(bidsAccount.data));
+  expect(Buffer.from(migratedBids.discriminator).toString()).toBe(
+    "orderbk\0"
+  );
+  expect(migratedBids.active_orders_count.toNumber()).toBe(1);
+  expect(migratedBids.side).toBe(1);
+  expect(migratedBids.orders[0].order_id.toNumber()).toBe(41);
+  expect(migratedBids.orders[0].sequence.toNumber()).toBe(41);
+  expect(migratedBids.orders[0].order_type).toBe(0);
+  expect(
+    migratedBids.orders[0].filled_quantity.eq(new BN(LAMPORTS_PER_SOL / 2))
+  ).toBeTrue();
+  const migratedAsks = OrderbookSchema.decode(
+    Buffer.from(svm.getAccount(legacyMarket.asks)!.data)
+  );
+  expect(migratedAsks.active_orders_count.toNumber()).toBe(0);
+  expect(migratedAsks.side).toBe(2);
+
+  expect(
+    isFailed(await migrate(...marketAccounts, balancePda, sellerBalancePda))
+  ).toBeFalse();
+  const balanceAccount = svm.getAccount(balancePda)!;
+  expect(balanceAccount.data.length).toBe(USER_BALANCE_LEN);
+  const migratedBalance = UserBalanceSchema.decode(
+    Buffer.from(balanceAccount.data)
+  );
+  expect(migratedBalance.version).toBe(USER_BALANCE_VERSION);
+  expect(migratedBalance.owner.equals(trader.keypair.publicKey)).toBeTrue();
+  expect(
+    migratedBalance.available_quote_balance.eq(new BN(140 * 1_000_000))
+  ).toBeTrue();
+  // The queued fill is still to be settled from the open order
+  expect(migratedBalance.open_orders_count).toBe(1);
+  expect(migratedBalance.open_orders[0].order_id.toNumber()).toBe(41);
+  expect(migratedBalance.open_orders[0].side).toBe(1);
+  expect(
+    migratedBalance.open_orders[0].quantity.eq(new BN(LAMPORTS_PER_SOL))
+  ).toBeTrue();
+
+  // The pending fill settles like any other
+  expect(
+    isFailed(
+      await sendIxs(
+        [
+          marketIx({ ConsumeEvents: { limit: 0 } }, 3, [
+            meta(consumerEventsAuthority.publicKey, false, true),
+            meta(legacyMarket.market, true),
+            meta(legacyMarket.events, true),
+            meta(balancePda, true),
+            meta(sellerBalancePda, true),
+          ]),
+        ],
+        authority,
+        consumerEventsAuthority
+      )
+    )
+  ).toBeFalse();
+  const consumed = UserBalanceSchema.decode(
+    Buffer.from(svm.getAccount(balancePda)!.data)
+  );
+  expect(consumed.locked_quote_balance.eq(new BN(5 * 1_000_000))).toBeTrue();
+  expect(
+    consumed.pending_base_balance.eq(new BN(LAMPORTS_PER_SOL / 2))
+  ).toBeTrue();
+  expect(
+    consumed.open_orders[0].quantity.eq(new BN(LAMPORTS_PER_SOL / 2))
+  ).toBeTrue();
+  const sellerBalance = UserBalanceSchema.decode(
+    Buffer.from(svm.getAccount(sellerBalancePda)!.data)
+  );
+  expect(sellerBalance.locked_base_balance.toNumber()).toBe(0);
+  expect(
+    sellerBalance.pending_quote_balance.eq(new BN(5 * 1_000_000))
+  ).toBeTrue();
+
+  // Migrating again leaves current accounts untouched
+  expect(
+    isFailed(await migrate(...marketAccounts, balancePda, sellerBalancePda))
+  ).toBeFalse();
+
+  // Trading resumes on the migrated accounts with the carried-over order ids
+  expect(isFailed(await sendIxs([bidIx()], trader.keypair))).toBeFalse();
+  const placed = UserBalanceSchema.decode(
+    Buffer.from(svm.getAccount(balancePda)!.data)
+  );
+  expect(placed.locked_quote_balance.eq(new BN(105 * 1_000_000))).toBeTrue();
+  expect(placed.open_orders_count).toBe(2);
+  expect(placed.open_orders[1].order_id.toNumber()).toBe(42);
+  console.log("First release market migrated and trading resumed");
+});
+
+test("Accounts of the wrong type, side or market are rejected", async () => {
+  const { svm, programId, consumerEventsAuthority, authority, quoteAsset } =
+    testEnv;
+
+  const baseMint = await newBaseMint();
+  const spoofMarket = await createMarket(baseMint, TOKEN_PROGRAM_ID);
+  expect(isFailed(spoofMarket.result)).toBeFalse();
+  const otherMarket = await createMarket(await newBaseMint(), TOKEN_PROGRAM_ID);
+  expect(isFailed(otherMarket.result)).toBeFalse();
+
+
+  // A trader with 150 USDC on the market under attack
+  const trader = await newTrader();
+  const traderBase = getAssociatedTokenAddressSync(
+    baseMint,
+    trader.keypair.publicKey
+  );
+  const [balancePda] = PublicKey.findProgramAddressSync(
+    [
+      Buffer.from("user_balance"),
+      trader.keypair.publicKey.toBuffer(),
+      spoofMarket.market.toBuffer(),
+    ],
+    programId.publicKey
+  );
+  expect(
+    isFailed(
+      await sendIxs(
+        [
+          createAssociatedTokenAccountInstruction(
+            trader.keypair.publicKey,
+            traderBase,
+            trader.keypair.publicKey,
+            baseMint
+          ),
+          marketIx({ CreateUserBalance: {} }, 1, [
+            meta(trader.keypair.publicKey, true, true),
+            meta(balancePda, true),
+            meta(spoofMarket.market),
+            meta(SystemProgram.programId),
+          ]),
+          marketIx(
+            {
+              Deposit: {
+                base_amount: new BN(0),
+                quote_amount: new BN(150 * 1_000_000),
+              },
+            },
+            17,
+            [
+              meta(trader.keypair.publicKey, false, true),
+              meta(balancePda, true),
+              meta(spoofMarket.market),
+              meta(traderBase, true),
+              meta(trader.quoteTokenAccount, true),
+              meta(spoofMarket.baseVault, true),
+              meta(spoofMarket.quoteVault, true),
+              meta(TOKEN_PROGRAM_ID),
+              meta(baseMint),
+              meta(quoteAsset),
+            ]
+          ),
+        ],
+        trader.keypair
+      )
+    )
+  ).toBeFalse();
+
+  const orderKeys = () => [
+    meta(trader.keypair.publicKey, false, true),
+    meta(balancePda, true),
+    meta(spoofMarket.market, true),
+    meta(spoofMarket.bids, true),
+    meta(spoofMarket.asks, true),
+    meta(spoofMarket.events, true),
+    meta(traderBase, true),
+    meta(trader.quoteTokenAccount, true),
+    meta(spoofMarket.baseVault, true),
+    meta(spoofMarket.quoteVault, true),
+    meta(TOKEN_PROGRAM_ID),
+    meta(new PublicKey("SysvarC1ock11111111111111111111111111111111")),
+  ];
+  const bid = (keys: AccountMeta[]) =>
+    sendIxs(
+      [
+        marketIx(
+          {
+            PlaceOrder: {
+              side: 1,
+              price: new BN(100 * 1_000_000),
+              quantity: new BN(LAMPORTS_PER_SOL),
+            },
+          },
+          18,
+          keys
+        ),
+      ],
+      trader.keypair
+    );
+  const spoofed = (index: number, pubkey: PublicKey) => {
+    const keys = orderKeys();
+    keys[index] = meta(pubkey, true);
+    return keys;
+  };
+
+  // Books swapped, or taken from another market
+  const swapped = orderKeys();
+  [swapped[3], swapped[4]] = [swapped[4], swapped[3]];
+  expect(isFailed(await bid(swapped))).toBeTrue();
+  expect(isFailed(await bid(spoofed(3, otherMarket.bids)))).toBeTrue();
+  expect(isFailed(await bid(spoofed(4, otherMarket.asks)))).toBeTrue();
+  expect(isFailed(await bid(spoofed(5, otherMarket.events)))).toBeTrue();
+
+  // One account type passed where another is expected
+  expect(isFailed(await bid(spoofed(2, balancePda)))).toBeTrue();
+  expect(isFailed(await bid(spoofed(1, spoofMarket.market)))).toBeTrue();
+  expect(isFailed(await bid(spoofed(3, spoofMarket.events)))).toBeTrue();
+  expect(isFailed(await bid(spoofed(5, spoofMarket.bids)))).toBeTrue();
+
+  // The stored address holding another book is caught by its market and side
+  const withData = async (
+    address: PublicKey,
+    source: PublicKey,
+    run: () => Promise<unknown>
+  ) => {
+    const original = svm.getAccount(address)!;
+    svm.setAccount(address, {
+      ...original,
+      data: Buffer.from(svm.getAccount(source)!.data),
+    });
+    const result = await run();
+    svm.setAccount(address, original);
+    return result;
+  };
+  expect(
+    isFailed(
+      await withData(spoofMarket.bids, otherMarket.bids, () => bid(orderKeys()))
+    )
+  ).toBeTrue();
+  expect(
+    isFailed(
+      await withData(spoofMarket.bids, spoofMarket.asks, () => bid(orderKeys()))
+    )
+  ).toBeTrue();
+  expect(
+    isFailed(
+      await withData(spoofMarket.events, otherMarket.events, () =>
+        bid(orderKeys())
+      )
+    )
+  ).toBeTrue();
+
+  // Token accounts must be the balance owner's, not just of the right mint
+  const strangerQuote = await newTokenAccount(
+    testEnv.quoteAsset,
+    Keypair.generate().publicKey
+  );
+  expect(isFailed(await bid(spoofed(7, strangerQuote)))).toBeTrue();
+  expect(isFailed(await bid(spoofed(6, trader.quoteTokenAccount)))).toBeTrue();
+
+  // Cancels and the crank are held to the same accounts
+  const cancelKeys = (keys: AccountMeta[]) =>
+    marketIx({ CancelOrder: { order_id: new BN(0) } }, 9, [
+      ...keys.slice(0, 6),
+      keys[11],
+    ]);
+  expect(
+    isFailed(await sendIxs([cancelKeys(swapped)], trader.keypair))
+  ).toBeTrue();
+  const consume = (events: PublicKey) =>
+    sendIxs(
+      [
+        marketIx({ ConsumeEvents: { limit: 0 } }, 3, [
+          meta(consumerEventsAuthority.publicKey, false, true),
+          meta(spoofMarket.market, true),
+          meta(events, true),
+          meta(balancePda, true),
+        ]),
+      ],
+      authority,
+      consumerEventsAuthority
+    );
+  expect(isFailed(await consume(otherMarket.events))).toBeTrue();
+  expect(isFailed(await consume(spoofMarket.bids))).toBeTrue();
+
+  const untouched = UserBalanceSchema.decode(
+    Buffer.from(svm.getAccount(balancePda)!.data)
+  );
+  expect(untouched.locked_quote_balance.isZero()).toBeTrue();
+  expect(untouched.open_orders_count).toBe(0);
+
+  // The genuine accounts still trade
+  expect(isFailed(await bid(orderKeys()))).toBeFalse();
+  const bids = OrderbookSchema.decode(
+    Buffer.from(svm.getAccount(spoofMarket.bids)!.data)
+  );
+  expect(Buffer.from(bids.discriminator).toString()).toBe("orderbk\0");
+  expect(bids.market.equals(spoofMarket.market)).toBeTrue();
+  expect(bids.active_orders_count.toNumber()).toBe(1);
+  console.log("Spoofed books, queues and account types were all rejected");
+});
+
+test("Vaults of another market are rejected", async () => {
+  const { svm, programId, authority, quoteAsset } = testEnv;
+
+  // Both markets quote in the same mint, so their quote vaults are
+  // interchangeable token accounts unless the stored address is enforced
+  const baseMint = await newBaseMint();
+  const vaultMarket = await createMarket(baseMint, TOKEN_PROGRAM_ID);
+  expect(isFailed(vaultMarket.result)).toBeFalse();
+  const otherMarket = await createMarket(await newBaseMint(), TOKEN_PROGRAM_ID);
+  expect(isFailed(otherMarket.result)).toBeFalse();
+
+  const withVaults = (
+    keys: AccountMeta[],
+    baseIndex: number,
+    baseVault: PublicKey,
+    quoteVault: PublicKey
+  ) => {
+    const swapped = [...keys];
+    swapped[baseIndex] = meta(baseVault, true);
+    swapped[baseIndex + 1] = meta(quoteVault, true);
+    return swapped;
+  };
+
+  const trader = await newTrader();
+  const traderBase = getAssociatedTokenAddressSync(
+    baseMint,
+    trader.keypair.publicKey
+  );
+  const [balancePda] = PublicKey.findProgramAddressSync(
+    [
+      Buffer.from("user_balance"),
+      trader.keypair.publicKey.toBuffer(),
+      vaultMarket.market.toBuffer(),
+    ],
+    programId.publicKey
+  );
+  expect(
+    isFailed(
+      await sendIxs(
+        [
+          createAssociatedTokenAccountInstruction(
+            trader.keypair.publicKey,
+            traderBase,
+            trader.keypair.publicKey,
+            baseMint
+          ),
+          marketIx({ CreateUserBalance: {} }, 1, [
+            meta(trader.keypair.publicKey, true, true),
+            meta(balancePda, true),
+            meta(vaultMarket.market),
+            meta(SystemProgram.programId),
+          ]),
+        ],
+        trader.keypair
+      )
+    )
+  ).toBeFalse();
+
+  const depositKeys = [
+    meta(trader.keypair.publicKey, false, true),
+    meta(balancePda, true),
+    meta(vaultMarket.market),
+    meta(traderBase, true),
+    meta(trader.quoteTokenAccount, true),
+    meta(vaultMarket.baseVault, true),
+    meta(vaultMarket.quoteVault, true),
+    meta(TOKEN_PROGRAM_ID),
+    meta(baseMint),
+    meta(quoteAsset),
+  ];
+  const deposit = (keys: AccountMeta[]) =>
+    sendIxs(
+      [
+        marketIx(
+          {
+            Deposit: {
+              base_amount: new BN(0),
+              quote_amount: new BN(150 * 1_000_000),
+            },
+          },
+          17,
+          keys
+        ),
+      ],
+      trader.keypair
+    );
+
+  const depositTo = (baseVault: PublicKey, quoteVault: PublicKey) =>
+    deposit(withVaults(depositKeys, 5, baseVault, quoteVault));
+
+  // Funds sent to another market's vault would be credited here
+  expect(
+    isFailed(await depositTo(vaultMarket.baseVault, otherMarket.quoteVault))
+  ).toBeTrue();
+  // Both vaults are checked, even for the side that is not deposited
+  expect(
+    isFailed(await depositTo(otherMarket.baseVault, vaultMarket.quoteVault))
+  ).toBeTrue();
+  // A vault of this market on the wrong side is just as wrong
+  expect(
+    isFailed(await depositTo(vaultMarket.quoteVault, vaultMarket.baseVault))
+  ).toBeTrue();
+  expect(
+    isFailed(
+      await sendIxs(
+        [
+          marketIx({ DepositQuoteTokens: { quantity: new BN(1) } }, 9, [
+            meta(trader.keypair.publicKey, true, true),
+            meta(balancePda, true),
+            meta(vaultMarket.market),
+            meta(trader.quoteTokenAccount, true),
+            meta(otherMarket.quoteVault, true),
+            meta(TOKEN_PROGRAM_ID),
+            meta(SystemProgram.programId),
+            meta(new PublicKey("SysvarRent111111111111111111111111111111111")),
+            meta(quoteAsset),
+          ]),
+        ],
+        trader.keypair
+      )
+    )
+  ).toBeTrue();
+  expect(
+    UserBalanceSchema.decode(Buffer.from(svm.getAccount(balancePda)!.data))
+      .available_quote_balance.isZero()
+  ).toBeTrue();
+  expect(isFailed(await deposit(depositKeys))).toBeFalse();
+
+  const orderKeys = [
+    meta(trader.keypair.publicKey, false, true),
+    meta(balancePda, true),
+    meta(vaultMarket.market, true),
+    meta(vaultMarket.bids, true),
+    meta(vaultMarket.asks, true),
+    meta(vaultMarket.events, true),
+    meta(traderBase, true),
+    meta(trader.quoteTokenAccount, true),
+    meta(vaultMarket.baseVault, true),
+    meta(vaultMarket.quoteVault, true),
+    meta(TOKEN_PROGRAM_ID),
+    meta(new PublicKey("SysvarC1ock11111111111111111111111111111111")),
+  ];
+  const bid = (keys: AccountMeta[]) =>
+    sendIxs(
+      [
+        marketIx(
+          {
+            PlaceOrder: {
+              side: 1,
+              price: new BN(100 * 1_000_000),
+              quantity: new BN(LAMPORTS_PER_SOL),
+            },
+          },
+          18,
+          keys
+        ),
+      ],
+      trader.keypair
+    );
+  expect(
+    isFailed(
+      await bid(
+        withVaults(orderKeys, 8, otherMarket.baseVault, vaultMarket.quoteVault)
+      )
+    )
+  ).toBeTrue();
+  expect(
+    isFailed(
+      await bid(
+        withVaults(orderKeys, 8, vaultMarket.baseVault, otherMarket.quoteVault)
+      )
+    )
+  ).toBeTrue();
+
+  // Payouts are refused before anything is checked to be pending
+  const settleKeys = [
+    meta(trader.keypair.publicKey, true, true),
+    meta(balancePda, true),
+    meta(vaultMarket.market),
+    meta(vaultMarket.market),
+    meta(traderBase, true),
+    meta(trader.quoteTokenAccount, true),
+    meta(vaultMarket.baseVault, true),
+    meta(vaultMarket.quoteVault, true),
+    meta(TOKEN_PROGRAM_ID),
+    meta(baseMint),
+    meta(quoteAsset),
+    meta(SystemProgram.programId),
+    meta(ASSOCIATED_TOKEN_PROGRAM_ID),
+  ];
+  const settle = (keys: AccountMeta[]) =>
+    sendIxs([marketIx({ SettleBalance: {} }, 1, keys)], trader.keypair);
+  expect(
+    isFailed(
+      await settle(
+        withVaults(settleKeys, 6, vaultMarket.baseVault, otherMarket.quoteVault)
+      )
+    )
+  ).toBeTrue();
+  expect(
+    isFailed(
+      await settle(
+        withVaults(settleKeys, 6, otherMarket.baseVault, vaultMarket.quoteVault)
+      )
+    )
+  ).toBeTrue();
+
+  const settleForKeys = [
+    meta(authority.publicKey, true, true),
+    meta(vaultMarket.market),
+    meta(vaultMarket.baseVault, true),
+    meta(vaultMarket.quoteVault, true),
+    meta(TOKEN_PROGRAM_ID),
+    meta(baseMint),
+    meta(quoteAsset),
+    meta(SystemProgram.programId),
+    meta(ASSOCIATED_TOKEN_PROGRAM_ID),
+    meta(TOKEN_PROGRAM_ID),
+    meta(balancePda, true),
+    meta(trader.keypair.publicKey),
+    meta(traderBase, true),
+    meta(trader.quoteTokenAccount, true),
+  ];
+  expect(
+    isFailed(
+      await sendIxs(
+        [
+          marketIx(
+            { SettleFor: {} },
+            1,
+            withVaults(
+              settleForKeys,
+              2,
+              vaultMarket.baseVault,
+              otherMarket.quoteVault
+            )
+          ),
+        ],
+        authority
+      )
+    )
+  ).toBeTrue();
+
+  // The stored vaults still take orders and pay out
+  expect(isFailed(await bid(orderKeys))).toBeFalse();
+  expect(isFailed(await settle(settleKeys))).toBeFalse();
+  const balance = UserBalanceSchema.decode(
+    Buffer.from(svm.getAccount(balancePda)!.data)
+  );
+  expect(balance.locked_quote_balance.toNumber()).toBe(100 * 1_000_000);
+  expect(balance.available_quote_balance.toNumber()).toBe(50 * 1_000_000);
+  console.log("Another market's vaults were rejected by every handler");
+});
+
+test("Each instruction stays within its compute unit budget", async () => {
+  const { svm, programId, authority, consumerEventsAuthority, quoteAsset } =
+    testEnv;
+
+  // Ceilings with headroom over measured usage on a book holding 128 orders
+  // a side, a regression past one of them usually means a PDA is being
+  // searched for again or the book is walked more often than it has to be
+  const budgets: Record<string, number> = {
+    InitializeMarket: 120_000,
+    CreateUserBalance: 25_000,
+    Deposit: 45_000,
+    DepositBaseTokens: 45_000,
+    DepositQuoteTokens: 45_000,
+    PlaceOrder: 100_000,
+    "PlaceOrder (crossing)": 140_000,
+    PlaceOrderWithClientId: 100_000,
+    PlaceIcebergOrder: 100_000,
+    PlacePeggedOrder: 120_000,
+    CancelOrder: 60_000,
+    PlaceOrderSettleTaker: 140_000,
+    ConsumeEvents: 60_000,
+    SettleBalance: 70_000,
+    SettleFor: 90_000,
+    SetDelegate: 15_000,
+    ConfigureOrderLimit: 10_000,
+    ConfigurePriceBand: 15_000,
+    SetMarketStatus: 10_000,
+    InitializeTriggerBook: 20_000,
+    PlaceTriggerOrder: 30_000,
+    CancelTriggerOrder: 30_000,
+    TriggerOrders: 140_000,
+    RunAuction: 400_000,
+    CloseUserBalance: 40_000,
+    Withdraw: 40_000,
+    Migrate: 30_000,
+    DepositNative: 45_000,
+    SettleBalanceNative: 80_000,
+  };
+  const measured = new Set<string>();
+  const expectWithinBudget = (name: string, result: unknown) => {
+    expect(isFailed(result)).toBeFalse();
+    const units = Number((result as any).computeUnitsConsumed());
+    console.log(`   ${name}: ${units} CU (budget ${budgets[name]})`);
+    expect(units).toBeLessThanOrEqual(budgets[name]);
+    measured.add(name);
+    return result;
+  };
+  // Runs with the largest limit, so an overrun fails the budget, not the tx
+  const measure = async (
+    name: string,
+    ixs: TransactionInstruction[],
+    payer: Keypair,
+    ...signers: Keypair[]
+  ) =>
+    expectWithinBudget(
+      name,
+      await sendIxs(
+        [
+          ComputeBudgetProgram.setComputeUnitLimit({ units: 1_400_000 }),
+          ...ixs,
+        ],
+        payer,
+        ...signers
+      )
+    );
+
+  const baseMint = await newBaseMint();
+  const cuMarket = await createMarket(baseMint, TOKEN_PROGRAM_ID);
+  expectWithinBudget("InitializeMarket", cuMarket.result);
+
+  // Minimum-size bids from 10 USDC and asks from 1000 USDC, one per tick,
+  // never touched by the orders measured below
+  for (const side of [1, 2]) {
+    for (let trader = 0; trader < 2; trader++) {
+      const owner = await newMarketTrader(
+        cuMarket,
+        new BN(LAMPORTS_PER_SOL),
+        new BN(10 * 1_000_000)
+      );
+      for (let first = 0; first < MAX_OPEN_ORDERS; first += 8) {
+        const ixs = [
+          ComputeBudgetProgram.setComputeUnitLimit({ units: 1_400_000 }),
+        ];
+        for (let i = first; i < first + 8; i++) {
+          const tick = trader * MAX_OPEN_ORDERS + i;
+          const price = side === 1 ? 10_000_000 : 1_000_000_000;
+          ixs.push(
+            marketOrderIx(
+              cuMarket,
+              owner,
+              side,
+              new BN(price + tick * 1_000),
+              new BN(1_000_000)
+            )
+          );
+        }
+        expect(isFailed(await sendIxs(ixs, owner.keypair))).toBeFalse();
+      }
+    }
+  }
+
+  const balanceAddress = (owner: PublicKey) =>
+    PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("user_balance"),
+        owner.toBuffer(),
+        cuMarket.market.toBuffer(),
+      ],
+      programId.publicKey
+    )[0];
+  const createBalanceIx = (owner: PublicKey) =>
+    marketIx({ CreateUserBalance: {} }, 1, [
+      meta(owner, true, true),
+      meta(balanceAddress(owner), true),
+      meta(cuMarket.market),
+      meta(SystemProgram.programId),
+    ]);
+  const fund = async (
+    trader: Keypair,
+    tokenAccount: PublicKey,
+    deposit: { base_amount: BN; quote_amount: BN }
+  ) => {
+    const balancePda = balanceAddress(trader.publicKey);
+    await measure(
+      "CreateUserBalance",
+      [createBalanceIx(trader.publicKey)],
+      trader
+    );
+    await measure(
+      "Deposit",
+      [
+        marketIx({ Deposit: deposit }, 17, [
+          meta(trader.publicKey, false, true),
+          meta(balancePda, true),
+          meta(cuMarket.market),
+          meta(tokenAccount, true),
+          meta(tokenAccount, true),
+          meta(cuMarket.baseVault, true),
+          meta(cuMarket.quoteVault, true),
+          meta(TOKEN_PROGRAM_ID),
+          meta(baseMint),
+          meta(quoteAsset),
+        ]),
+      ],
+      trader
+    );
+    return balancePda;
+  };
+  // Single-sided deposit into an existing balance
+  const legacyDepositIx = (
+    instruction: "DepositBaseTokens" | "DepositQuoteTokens",
+    trader: Keypair,
+    tokenAccount: PublicKey,
+    quantity: BN
+  ) =>
+    marketIx({ [instruction]: { quantity } }, 9, [
+      meta(trader.publicKey, true, true),
+      meta(balanceAddress(trader.publicKey), true),
+      meta(cuMarket.market),
+      meta(tokenAccount, true),
+      meta(
+        instruction === "DepositBaseTokens"
+          ? cuMarket.baseVault
+          : cuMarket.quoteVault,
+        true
+      ),
+      meta(TOKEN_PROGRAM_ID),
+      meta(SystemProgram.programId),
+      meta(new PublicKey("SysvarRent111111111111111111111111111111111")),
+      meta(instruction === "DepositBaseTokens" ? baseMint : quoteAsset),
+    ]);
+  const orderKeys = (
+    trader: Keypair,
+    baseAccount: PublicKey,
+    quoteAccount: PublicKey
+  ) => [
+    meta(trader.publicKey, false, true),
+    meta(balanceAddress(trader.publicKey), true),
+    meta(cuMarket.market, true),
+    meta(cuMarket.bids, true),
+    meta(cuMarket.asks, true),
+    meta(cuMarket.events, true),
+    meta(baseAccount, true),
+    meta(quoteAccount, true),
+    meta(cuMarket.baseVault, true),
+    meta(cuMarket.quoteVault, true),
+    meta(TOKEN_PROGRAM_ID),
+    meta(new PublicKey("SysvarC1ock11111111111111111111111111111111")),
+  ];
+  const order = (side: number, price = 100) => ({
+    side,
+    price: new BN(price * 1_000_000),
+    quantity: new BN(LAMPORTS_PER_SOL / 2),
+  });
+
+  const maker = Keypair.generate();
+  svm.airdrop(maker.publicKey, BigInt(10 * LAMPORTS_PER_SOL));
+  const makerBase = getAssociatedTokenAddressSync(baseMint, maker.publicKey);
+  expect(
+    isFailed(
+      await sendIxs(
+        [
+          createAssociatedTokenAccountInstruction(
+            authority.publicKey,
+            makerBase,
+            maker.publicKey,
+            baseMint
+          ),
+          createMintToInstruction(
+            baseMint,
+            makerBase,
+            authority.publicKey,
+            3 * LAMPORTS_PER_SOL
+          ),
+        ],
+        authority
+      )
+    )
+  ).toBeFalse();
+  const makerBalance = await fund(maker, makerBase, {
+    base_amount: new BN(2 * LAMPORTS_PER_SOL),
+    quote_amount: new BN(0),
+  });
+  const taker = await newTrader();
+  const takerBalance = await fund(taker.keypair, taker.quoteTokenAccount, {
+    base_amount: new BN(0),
+    quote_amount: new BN(200 * 1_000_000),
+  });
+  await measure(
+    "DepositBaseTokens",
+    [
+      legacyDepositIx(
+        "DepositBaseTokens",
+        maker,
+        makerBase,
+        new BN(LAMPORTS_PER_SOL)
+      ),
+    ],
+    maker
+  );
+  await measure(
+    "DepositQuoteTokens",
+    [
+      legacyDepositIx(
+        "DepositQuoteTokens",
+        taker.keypair,
+        taker.quoteTokenAccount,
+        new BN(10 * 1_000_000)
+      ),
+    ],
+    taker.keypair
+  );
+  const makerKeys = orderKeys(
+    maker,
+    makerBase,
+    await newTokenAccount(quoteAsset, maker.publicKey)
+  );
+  const takerKeys = orderKeys(
+    taker.keypair,
+    await newTokenAccount(baseMint, taker.keypair.publicKey),
+    taker.quoteTokenAccount
+  );
+
+  // Rest an ask and cancel it again
+  const resting = await measure(
+    "PlaceOrder",
+    [marketIx({ PlaceOrder: order(2) }, 18, makerKeys)],
+    maker
+  );
+  const { order_id } = PlaceOrderResultSchema.decode(
+    Buffer.from((resting as any).returnData().data())
+  );
+  await measure(
+    "CancelOrder",
+    [
+      marketIx({ CancelOrder: { order_id } }, 9, [
+        ...makerKeys.slice(0, 6),
+        makerKeys[11],
+      ]),
+    ],
+    maker
+  );
+
+  // Asks above the traded price that stay on the book
+  await measure(
+    "PlaceOrderWithClientId",
+    [
+      marketIx(
+        {
+          PlaceOrderWithClientId: {
+            ...order(2, 200),
+            client_order_id: new BN(7),
+          },
+        },
+        26,
+        makerKeys
+      ),
+    ],
+    maker
+  );
+  await measure(
+    "PlaceIcebergOrder",
+    [
+      marketIx(
+        {
+          PlaceIcebergOrder: {
+            ...order(2, 150),
+            display_quantity: new BN(LAMPORTS_PER_SOL / 10),
+          },
+        },
+        26,
+        makerKeys
+      ),
+    ],
+    maker
+  );
+
+  // Two asks, one taken by a plain order and one settled at placement
+  for (let i = 0; i < 2; i++) {
+    expect(
+      isFailed(
+        await sendIxs(
+          [marketIx({ PlaceOrder: order(2) }, 18, makerKeys)],
+          maker
+        )
+      )
+    ).toBeFalse();
+  }
+  await measure(
+    "PlaceOrder (crossing)",
+    [marketIx({ PlaceOrder: order(1) }, 18, takerKeys)],
+    taker.keypair
+  );
+  await measure(
+    "PlaceOrderSettleTaker",
+    [
+      marketIx(
+        {
+          PlaceOrderSettleTaker: {
+            ...order(1),
+            client_order_id: new BN(1),
+          },
+        },
+        26,
+        takerKeys
+      ),
+    ],
+    taker.keypair
+  );
+
+  const consumeIx = (...balances: PublicKey[]) =>
+    marketIx({ ConsumeEvents: { limit: 0 } }, 3, [
+      meta(consumerEventsAuthority.publicKey, false, true),
+      meta(cuMarket.market, true),
+      meta(cuMarket.events, true),
+      ...balances.map((balance) => meta(balance, true)),
+    ]);
+  await measure(
+    "ConsumeEvents",
+    [consumeIx(makerBalance, takerBalance)],
+    authority,
+    consumerEventsAuthority
+  );
+
+  // Checks the queue for the taker before moving a unit to pending
+  await measure(
+    "Withdraw",
+    [
+      marketIx(
+        { Withdraw: { base_amount: new BN(0), quote_amount: new BN(1) } },
+        17,
+        [
+          meta(taker.keypair.publicKey, false, true),
+          meta(takerBalance, true),
+          meta(cuMarket.market),
+          meta(cuMarket.events),
+        ]
+      ),
+    ],
+    taker.keypair
+  );
+  const takerBaseAta = getAssociatedTokenAddressSync(
+    baseMint,
+    taker.keypair.publicKey
+  );
+  await measure(
+    "SettleBalance",
+    [
+      marketIx({ SettleBalance: {} }, 1, [
+        meta(taker.keypair.publicKey, true, true),
+        meta(takerBalance, true),
+        meta(cuMarket.market),
+        meta(cuMarket.market),
+        meta(takerBaseAta, true),
+        meta(taker.quoteTokenAccount, true),
+        meta(cuMarket.baseVault, true),
+        meta(cuMarket.quoteVault, true),
+        meta(TOKEN_PROGRAM_ID),
+        meta(baseMint),
+        meta(quoteAsset),
+        meta(SystemProgram.programId),
+        meta(ASSOCIATED_TOKEN_PROGRAM_ID),
+      ]),
+    ],
+    taker.keypair
+  );
+  await measure(
+    "SettleFor",
+    [
+      marketIx({ SettleFor: {} }, 1, [
+        meta(authority.publicKey, true, true),
+        meta(cuMarket.market),
+        meta(cuMarket.baseVault, true),
+        meta(cuMarket.quoteVault, true),
+        meta(TOKEN_PROGRAM_ID),
+        meta(baseMint),
+        meta(quoteAsset),
+        meta(SystemProgram.programId),
+        meta(ASSOCIATED_TOKEN_PROGRAM_ID),
+        meta(TOKEN_PROGRAM_ID),
+        meta(makerBalance, true),
+        meta(maker.publicKey),
+        meta(makerBase, true),
+        meta(
+          getAssociatedTokenAddressSync(quoteAsset, maker.publicKey),
+          true
+        ),
+      ]),
+    ],
+    authority
+  );
+
+  // Account and market configuration
+  const clock = new PublicKey("SysvarC1ock11111111111111111111111111111111");
+  await measure(
+    "SetDelegate",
+    [
+      marketIx(
+        {
+          SetDelegate: {
+            delegate: Keypair.generate().publicKey,
+            expiry: new BN(svm.getClock().unixTimestamp.toString()).addn(3600),
+            permissions: DELEGATE_PLACE_ORDERS,
+          },
+        },
+        42,
+        [
+          meta(maker.publicKey, false, true),
+          meta(makerBalance, true),
+          meta(cuMarket.market),
+        ]
+      ),
+    ],
+    maker
+  );
+  const authorityKeys = [
+    meta(authority.publicKey, false, true),
+    meta(cuMarket.market, true),
+  ];
+  await measure(
+    "ConfigureOrderLimit",
+    [
+      marketIx(
+        { ConfigureOrderLimit: { max_orders_per_user: MAX_OPEN_ORDERS } },
+        3,
+        authorityKeys
+      ),
+    ],
+    authority
+  );
+
+  // A sell stop at the last price fires on the next crank, a second one is
+  // cancelled while it waits
+  const triggerBook = Keypair.generate();
+  await measure(
+    "InitializeTriggerBook",
+    [
+      SystemProgram.createAccount({
+        fromPubkey: authority.publicKey,
+        newAccountPubkey: triggerBook.publicKey,
+        lamports: Number(
+          svm.minimumBalanceForRentExemption(BigInt(TRIGGER_BOOK_LEN))
+        ),
+        space: TRIGGER_BOOK_LEN,
+        programId: programId.publicKey,
+      }),
+      marketIx({ InitializeTriggerBook: {} }, 1, [
+        ...authorityKeys,
+        meta(triggerBook.publicKey, true),
+        meta(new PublicKey("SysvarRent111111111111111111111111111111111")),
+      ]),
+    ],
+    authority,
+    triggerBook
+  );
+  const lastPrice: BN = MarketStateSchema.decode(
+    Buffer.from(svm.getAccount(cuMarket.market)!.data)
+  ).last_price;
+  const placeTriggerIx = () =>
+    marketIx(
+      {
+        PlaceTriggerOrder: {
+          side: 2,
+          trigger_type: 0,
+          trigger_price: lastPrice,
+          price: lastPrice,
+          quantity: new BN(LAMPORTS_PER_SOL / 10),
+        },
+      },
+      27,
+      [
+        meta(maker.publicKey, false, true),
+        meta(makerBalance, true),
+        meta(cuMarket.market, true),
+        meta(triggerBook.publicKey, true),
+        meta(clock),
+      ]
+    );
+  await measure("PlaceTriggerOrder", [placeTriggerIx()], maker);
+  const waiting = TriggerBookSchema.decode(
+    Buffer.from(svm.getAccount(triggerBook.publicKey)!.data)
+  ).orders[0];
+  await measure(
+    "CancelTriggerOrder",
+    [
+      marketIx({ CancelTriggerOrder: { order_id: waiting.order_id } }, 9, [
+        meta(maker.publicKey, false, true),
+        meta(makerBalance, true),
+        meta(cuMarket.market, true),
+        meta(triggerBook.publicKey, true),
+      ]),
+    ],
+    maker
+  );
+  expect(isFailed(await sendIxs([placeTriggerIx()], maker))).toBeFalse();
+  await measure(
+    "TriggerOrders",
+    [
+      marketIx({ TriggerOrders: {} }, 1, [
+        meta(cuMarket.market, true),
+        meta(cuMarket.bids, true),
+        meta(cuMarket.asks, true),
+        meta(cuMarket.events, true),
+        meta(triggerBook.publicKey, true),
+        meta(clock),
+      ]),
+    ],
+    authority
+  );
+
+  // An oracle at 100 USDC for a pegged bid 50 below it
+  const oracle = Keypair.generate();
+  const oracleData = Buffer.alloc(16);
+  OraclePriceSchema.encode(
+    {
+      price: new BN(100 * 1_000_000),
+      publish_time: new BN(svm.getClock().unixTimestamp.toString()),
+    },
+    oracleData
+  );
+  svm.setAccount(oracle.publicKey, {
+    lamports: LAMPORTS_PER_SOL,
+    data: oracleData,
+    owner: SystemProgram.programId,
+    executable: false,
+  });
+  await measure(
+    "ConfigurePriceBand",
+    [
+      marketIx(
+        {
+          ConfigurePriceBand: {
+            price_band_bps: 0,
+            price_reference: 0,
+            oracle: oracle.publicKey,
+            max_oracle_staleness: new BN(60),
+            twap_window: new BN(0),
+            circuit_breaker_bps: 0,
+            circuit_breaker_window: new BN(0),
+          },
+        },
+        62,
+        authorityKeys
+      ),
+    ],
+    authority
+  );
+  const withOracle = [...takerKeys, meta(oracle.publicKey)];
+  await measure(
+    "PlacePeggedOrder",
+    [
+      marketIx(
+        {
+          PlacePeggedOrder: {
+            side: 1,
+            peg_offset: new BN(-50 * 1_000_000),
+            limit_price: new BN(60 * 1_000_000),
+            quantity: new BN(LAMPORTS_PER_SOL / 10),
+          },
+        },
+        26,
+        withOracle
+      ),
+    ],
+    taker.keypair
+  );
+
+  // An auction uncrossing a bid at 120 against the triggered ask
+  await measure(
+    "SetMarketStatus",
+    [marketIx({ SetMarketStatus: { status: 2 } }, 2, authorityKeys)],
+    authority
+  );
+  expect(
+    isFailed(
+      await sendIxs(
+        [
+          marketIx(
+            {
+              PlaceOrder: {
+                side: 1,
+                price: new BN(120 * 1_000_000),
+                quantity: new BN(LAMPORTS_PER_SOL / 10),
+              },
+            },
+            18,
+            withOracle
+          ),
+        ],
+        taker.keypair
+      )
+    )
+  ).toBeFalse();
+  await measure(
+    "RunAuction",
+    [
+      marketIx({ RunAuction: {} }, 1, [
+        ...authorityKeys,
+        meta(cuMarket.bids, true),
+        meta(cuMarket.asks, true),
+        meta(cuMarket.events, true),
+        meta(clock),
+        meta(oracle.publicKey),
+      ]),
+    ],
+    authority
+  );
+
+  // A balance nobody traded with closes right away
+  const visitor = Keypair.generate();
+  svm.airdrop(visitor.publicKey, BigInt(LAMPORTS_PER_SOL));
+  expect(
+    isFailed(await sendIxs([createBalanceIx(visitor.publicKey)], visitor))
+  ).toBeFalse();
+  await measure(
+    "CloseUserBalance",
+    [
+      marketIx({ CloseUserBalance: {} }, 1, [
+        meta(visitor.publicKey, true, true),
+        meta(balanceAddress(visitor.publicKey), true),
+        meta(cuMarket.market),
+        meta(cuMarket.events),
+      ]),
+    ],
+    visitor
+  );
+
+  // Current accounts are checked and skipped
+  await measure(
+    "Migrate",
+    [
+      marketIx({ Migrate: {} }, 1, [
+        meta(authority.publicKey, true, true),
+        meta(cuMarket.market, true),
+        meta(SystemProgram.programId),
+        meta(cuMarket.bids, true),
+        meta(makerBalance, true),
+      ]),
+    ],
+    authority
+  );
+
+  // The same base mint against native SOL: a lamport deposit buys from a
+  // token seller, who is paid out in lamports
+  ensureNativeMint();
+  const solMarket = await createMarket(baseMint, TOKEN_PROGRAM_ID, NATIVE_MINT);
+  expect(isFailed(solMarket.result)).toBeFalse();
+  const solTrader = async (baseAmount: number) => {
+    const keypair = Keypair.generate();
+    svm.airdrop(keypair.publicKey, BigInt(10 * LAMPORTS_PER_SOL));
+    const baseTokenAccount = await newTokenAccount(baseMint, keypair.publicKey);
+    const [balancePda] = PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("user_balance"),
+        keypair.publicKey.toBuffer(),
+        solMarket.market.toBuffer(),
+      ],
+      programId.publicKey
+    );
+    const ixs = [
+      marketIx({ CreateUserBalance: {} }, 1, [
+        meta(keypair.publicKey, true, true),
+        meta(balancePda, true),
+        meta(solMarket.market),
+        meta(SystemProgram.programId),
+      ]),
+    ];
+    if (baseAmount > 0) {
+      expect(
+        isFailed(
+          await sendIxs(
+            [
+              createMintToInstruction(
+                baseMint,
+                baseTokenAccount,
+                authority.publicKey,
+                baseAmount
+              ),
+            ],
+            authority
+          )
+        )
+      ).toBeFalse();
+      ixs.push(
+        marketIx(
+          {
+            Deposit: { base_amount: new BN(baseAmount), quote_amount: new BN(0) },
+          },
+          17,
+          [
+            meta(keypair.publicKey, false, true),
+            meta(balancePda, true),
+            meta(solMarket.market),
+            meta(baseTokenAccount, true),
+            meta(baseTokenAccount, true),
+            meta(solMarket.baseVault, true),
+            meta(solMarket.quoteVault, true),
+            meta(TOKEN_PROGRAM_ID),
+            meta(baseMint),
+            meta(NATIVE_MINT),
+          ]
+        )
+      );
+    }
+    expect(isFailed(await sendIxs(ixs, keypair))).toBeFalse();
+    // Native SOL sides hold no token account, the wallet stands in
+    return {
+      keypair,
+      baseTokenAccount,
+      quoteTokenAccount: keypair.publicKey,
+      balancePda,
+    };
+  };
+  const seller = await solTrader(LAMPORTS_PER_SOL);
+  const buyer = await solTrader(0);
+  await measure(
+    "DepositNative",
+    [
+      marketIx({ DepositNative: { amount: new BN(LAMPORTS_PER_SOL) } }, 9, [
+        meta(buyer.keypair.publicKey, true, true),
+        meta(buyer.balancePda, true),
+        meta(solMarket.market),
+        meta(solMarket.quoteVault, true),
+        meta(TOKEN_PROGRAM_ID),
+        meta(SystemProgram.programId),
+      ]),
+    ],
+    buyer.keypair
+  );
+  // 1 base unit at 0.1 SOL
+  for (const [trader, side] of [
+    [seller, 2],
+    [buyer, 1],
+  ] as [MarketTrader, number][]) {
+    const ix = marketOrderIx(
+      solMarket,
+      trader,
+      side,
+      new BN(LAMPORTS_PER_SOL / 10),
+      new BN(LAMPORTS_PER_SOL)
+    );
+    expect(isFailed(await sendIxs([ix], trader.keypair))).toBeFalse();
+  }
+  expect(
+    isFailed(
+      await sendIxs(
+        [
+          marketIx({ ConsumeEvents: { limit: 0 } }, 3, [
+            meta(consumerEventsAuthority.publicKey, false, true),
+            meta(solMarket.market, true),
+            meta(solMarket.events, true),
+            meta(seller.balancePda, true),
+            meta(buyer.balancePda, true),
+          ]),
+        ],
+        authority,
+        consumerEventsAuthority
+      )
+    )
+  ).toBeFalse();
+  const [unwrapPda] = PublicKey.findProgramAddressSync(
+    [Buffer.from("native_unwrap"), solMarket.market.toBuffer()],
+    programId.publicKey
+  );
+  await measure(
+    "SettleBalanceNative",
+    [
+      marketIx({ SettleBalanceNative: {} }, 1, [
+        meta(seller.keypair.publicKey, true, true),
+        meta(seller.balancePda, true),
+        meta(solMarket.market),
+        meta(seller.baseTokenAccount, true),
+        meta(solMarket.baseVault, true),
+        meta(solMarket.quoteVault, true),
+        meta(TOKEN_PROGRAM_ID),
+        meta(baseMint),
+        meta(NATIVE_MINT),
+        meta(unwrapPda, true),
+        meta(SystemProgram.programId),
+        meta(ASSOCIATED_TOKEN_PROGRAM_ID),
+      ]),
+    ],
+    seller.keypair
+  );
+
+  expect([...measured].sort()).toEqual(Object.keys(budgets).sort());
+});
+
+const isFailed = (result: unknown) =>
+  !!result && typeof result === "object" && "err" in result;
+
+/** Whether a transaction failed with the program's `Custom(code)` error. */
+const failedWithCode = (result: unknown, code: number) =>
+  isFailed(result) &&
+  (result as any)
+    .meta()
+    .logs()
+    .some((log: string) =>
+      log.endsWith(`custom program error: 0x${code.toString(16)}`)
+    );
+
+const sendIxs = async (
+  ixs: TransactionInstruction[],
+  payer: Keypair,
+  ...signers: Keypair[]
+) => {
+  const tx = new Transaction().add(...ixs);
+  tx.feePayer = payer.publicKey;
+  tx.recentBlockhash = await testEnv.svm.latestBlockhash();
+  tx.sign(payer, ...signers);
+  // a fresh blockhash per tx keeps identical retries from being deduplicated
+  testEnv.svm.expireBlockhash();
+  return testEnv.svm.sendTransaction(tx);
+};
+
+type AccountMeta = { pubkey: PublicKey; isSigner: boolean; isWritable: boolean };
+
+const meta = (
+  pubkey: PublicKey,
+  isWritable = false,
+  isSigner = false
+): AccountMeta => ({
+  pubkey,
+  isSigner,
+  isWritable,
+});
+
+/** An instruction to the program under test, `dataLen` bytes of encoded data. */
+const marketIx = (
+  instruction: object,
+  dataLen: number,
+  keys: AccountMeta[]
+) => {
+  const data = Buffer.alloc(dataLen);
+  InstructionSchema.encode(instruction, data);
+  return new TransactionInstruction({
+    programId: testEnv.programId.publicKey,
+    data,
+    keys,
+  });
+};
+
+/** A new 9-decimal SPL Token mint with the test authority as mint authority. */
+const newBaseMint = async () => {
+  const { svm, authority } = testEnv;
+  const mint = Keypair.generate();
+  const result = await sendIxs(
+    [
+      SystemProgram.createAccount({
+        fromPubkey: authority.publicKey,
+        newAccountPubkey: mint.publicKey,
+        lamports: Number(svm.minimumBalanceForRentExemption(BigInt(MINT_SIZE))),
+        space: MINT_SIZE,
+        programId: TOKEN_PROGRAM_ID,
+      }),
+      createInitializeMintInstruction(
+        mint.publicKey,
+        9,
+        authority.publicKey,
+        null
+      ),
+    ],
+    authority,
+    mint
+  );
+  expect(isFailed(result)).toBeFalse();
+  return mint.publicKey;
+};
+
+/** An empty SPL Token account for `mint` owned by `owner`, outside the associated token program. */
+/** Puts the wrapped SOL mint in place, unless an earlier test already did. */
+const ensureNativeMint = () => {
+  const { svm } = testEnv;
+  if (svm.getAccount(NATIVE_MINT)) {
+    return;
+  }
+  const mintData = Buffer.alloc(MINT_SIZE);
+  MintLayout.encode(
+    {
+      mintAuthorityOption: 0,
+      mintAuthority: PublicKey.default,
+      supply: BigInt(0),
+      decimals: 9,
+      isInitialized: true,
+      freezeAuthorityOption: 0,
+      freezeAuthority: PublicKey.default,
+    },
+    mintData
+  );
+  svm.setAccount(NATIVE_MINT, {
+    lamports: Number(svm.minimumBalanceForRentExemption(BigInt(MINT_SIZE))),
+    data: mintData,
+    owner: TOKEN_PROGRAM_ID,
+    executable: false,
+  });
+};
+
+const newTokenAccount = async (mint: PublicKey, owner: PublicKey) => {
+  const { svm, authority } = testEnv;
+  const account = Keypair.generate();
+  const result = await sendIxs(
+    [
+      SystemProgram.createAccount({
+        fromPubkey: authority.publicKey,
+        newAccountPubkey: account.publicKey,
+        lamports: Number(svm.minimumBalanceForRentExemption(BigInt(165))),
+        space: 165,
+        programId: TOKEN_PROGRAM_ID,
+      }),
+      createInitializeAccountInstruction(account.publicKey, mint, owner),
+    ],
+    authority,
+    account
+  );
+  expect(isFailed(result)).toBeFalse();
+  return account.publicKey;
+};
+
+/** A new wallet holding base and quote token accounts, without a balance account. */
+const newTrader = async () => {
+  const { svm, authority, baseAsset, quoteAsset, programId, marketAccountPda } =
+    testEnv;
+  const keypair = Keypair.generate();
+  svm.airdrop(keypair.publicKey, BigInt(10 * LAMPORTS_PER_SOL));
+
+  const tokenAccount = (mint: PublicKey) => {
+    const account = Keypair.generate();
+    return {
+      account,
+      ixs: [
+        SystemProgram.createAccount({
+          fromPubkey: keypair.publicKey,
+          newAccountPubkey: account.publicKey,
+          lamports: Number(svm.minimumBalanceForRentExemption(BigInt(165))),
+          space: 165,
+          programId: TOKEN_PROGRAM_ID,
+        }),
+        createInitializeAccountInstruction(
+          account.publicKey,
+          mint,
+          keypair.publicKey
+        ),
+      ],
+    };
+  };
+  const base = tokenAccount(baseAsset);
+  const quote = tokenAccount(quoteAsset);
+
+  await sendIxs(
+    [...base.ixs, ...quote.ixs],
+    keypair,
+    base.account,
+    quote.account
+  );
+  await sendIxs(
+    [
+      createMintToInstruction(
+        baseAsset,
+        base.account.publicKey,
+        authority.publicKey,
+        100 * LAMPORTS_PER_SOL
+      ),
+      createMintToInstruction(
+        quoteAsset,
+        quote.account.publicKey,
+        authority.publicKey,
+        100_000 * 1_000_000
+      ),
+    ],
+    authority
+  );
+
+  const [balancePda] = PublicKey.findProgramAddressSync(
+    [
+      Buffer.from("user_balance"),
+      keypair.publicKey.toBuffer(),
+      marketAccountPda.toBuffer(),
+    ],
+    programId.publicKey
+  );
+
+  return {
+    keypair,
+    baseTokenAccount: base.account.publicKey,
+    quoteTokenAccount: quote.account.publicKey,
+    balancePda,
+  };
+};
+
+/// Lists `baseMint` against the shared quote mint, or `quoteMint` (an SPL
+/// Token mint), on fresh book accounts. The quote mint's token program is
+/// passed last when it differs from the base's.
+const createMarket = async (
+  baseMint: PublicKey,
+  baseTokenProgram: PublicKey,
+  quoteMint: PublicKey = testEnv.quoteAsset
+) => {
+  const { svm, programId, authority, consumerEventsAuthority } = testEnv;
+  const [market] = PublicKey.findProgramAddressSync(
+    [Buffer.from("market"), baseMint.toBuffer(), quoteMint.toBuffer()],
+    programId.publicKey
+  );
+  const pda = (seed: string) =>
+    PublicKey.findProgramAddressSync(
+      [Buffer.from(seed), market.toBuffer()],
+      programId.publicKey
+    )[0];
+
+  const bids = Keypair.generate();
+  const asks = Keypair.generate();
+  const events = Keypair.generate();
+  const createIxs = (
+    [
+      [bids, ORDERBOOK_LEN],
+      [asks, ORDERBOOK_LEN],
+      [events, MARKET_EVENT_LEN],
+    ] as [Keypair, number][]
+  ).map(([account, space]) =>
+    SystemProgram.createAccount({
+      fromPubkey: authority.publicKey,
+      newAccountPubkey: account.publicKey,
+      lamports: Number(svm.minimumBalanceForRentExemption(BigInt(space))),
+      space,
+      programId: programId.publicKey,
+    })
+  );
+  expect(
+    isFailed(await sendIxs(createIxs, authority, bids, asks, events))
+  ).toBeFalse();
+
+  const data = Buffer.alloc(17);
+  InstructionSchema.encode(
+    {
+      InitializeMarket: {
+        min_order_size: new BN(1_000_000),
+        tick_size: new BN(1_000),
+      },
+    },
+    data
+  );
+  const keys = [
+    authority.publicKey,
+    consumerEventsAuthority.publicKey,
+    market,
+    baseMint,
+    quoteMint,
+    bids.publicKey,
+    asks.publicKey,
+    pda("base_vault"),
+    pda("quote_vault"),
+    events.publicKey,
+    pda("fee_account"),
+    SystemProgram.programId,
+    baseTokenProgram,
+    new PublicKey("SysvarRent111111111111111111111111111111111"),
+  ];
+  if (!baseTokenProgram.equals(TOKEN_PROGRAM_ID)) {
+    keys.push(TOKEN_PROGRAM_ID);
+  }
+  const ix = new TransactionInstruction({
+    programId: programId.publicKey,
+    data,
+    keys: keys.map((pubkey, index) => ({
+      pubkey,
+      isSigner: index === 0,
+      isWritable: [0, 2, 5, 6, 7, 8, 9, 10].includes(index),
+    })),
+  });
+  return {
+    result: await sendIxs([ix], authority),
+    market,
+    baseMint,
+    bids: bids.publicKey,
+    asks: asks.publicKey,
+    events: events.publicKey,
+    baseVault: pda("base_vault"),
+    quoteVault: pda("quote_vault"),
+  };
+};
+
+type Listing = Awaited<ReturnType<typeof createMarket>>;
+type MarketTrader = Awaited<ReturnType<typeof newMarketTrader>>;
+
+/** A new trader on `listing` with the given amounts deposited into its balance. */
+const newMarketTrader = async (
+  listing: Listing,
+  baseAmount: BN,
+  quoteAmount: BN
+) => {
+  const { authority, programId, quoteAsset } = testEnv;
+  const trader = await newTrader();
+  const baseTokenAccount = getAssociatedTokenAddressSync(
+    listing.baseMint,
+    trader.keypair.publicKey
+  );
+  const fundIxs = [
+    createAssociatedTokenAccountInstruction(
+      authority.publicKey,
+      baseTokenAccount,
+      trader.keypair.publicKey,
+      listing.baseMint
+    ),
+  ];
+  if (!baseAmount.isZero()) {
+    fundIxs.push(
+      createMintToInstruction(
+        listing.baseMint,
+        baseTokenAccount,
+        authority.publicKey,
+        BigInt(baseAmount.toString())
+      )
+    );
+  }
+  expect(isFailed(await sendIxs(fundIxs, authority))).toBeFalse();
+
+  const [balancePda] = PublicKey.findProgramAddressSync(
+    [
+      Buffer.from("user_balance"),
+      trader.keypair.publicKey.toBuffer(),
+      listing.market.toBuffer(),
+    ],
+    programId.publicKey
+  );
+  const depositIxs = [
+    marketIx({ CreateUserBalance: {} }, 1, [
+      meta(trader.keypair.publicKey, true, true),
+      meta(balancePda, true),
+      meta(listing.market),
+      meta(SystemProgram.programId),
+    ]),
+    marketIx(
+      { Deposit: { base_amount: baseAmount, quote_amount: quoteAmount } },
+      17,
+      [
+        meta(trader.keypair.publicKey, false, true),
+        meta(balancePda, true),
+        meta(listing.market),
+        meta(baseTokenAccount, true),
+        meta(trader.quoteTokenAccount, true),
+        meta(listing.baseVault, true),
+        meta(listing.quoteVault, true),
+        meta(TOKEN_PROGRAM_ID),
+        meta(listing.baseMint),
+        meta(quoteAsset),
+      ]
+    ),
+  ];
+  expect(isFailed(await sendIxs(depositIxs, trader.keypair))).toBeFalse();
+
+  return {
+    keypair: trader.keypair,
+    baseTokenAccount,
+    quoteTokenAccount: trader.quoteTokenAccount,
+    balancePda,
+  };
+};
+
+/** A limit order on `listing` from one of its traders. */
+const marketOrderIx = (
+  listing: Listing,
+  trader: MarketTrader,
+  side: number,
+  price: BN,
+  quantity: BN
+) =>
+  marketIx({ PlaceOrder: { side, price, quantity } }, 18, [
+    meta(trader.keypair.publicKey, false, true),
+    meta(trader.balancePda, true),
+    meta(listing.market, true),
+    meta(listing.bids, true),
+    meta(listing.asks, true),
+    meta(listing.events, true),
+    meta(trader.baseTokenAccount, true),
+    meta(trader.quoteTokenAccount, true),
+    meta(listing.baseVault, true),
+    meta(listing.quoteVault, true),
+    meta(TOKEN_PROGRAM_ID),
+    meta(new PublicKey("SysvarC1ock11111111111111111111111111111111")),
+  ]);
+
+const placeOrderIx = (
+  owner: Keypair,
+  balancePda: PublicKey,
+  baseTokenAccount: PublicKey,
+  quoteTokenAccount: PublicKey,
+  side: number,
+  price: BN,
+  quantity: BN,
+  extraKeys: { pubkey: PublicKey; isSigner: boolean; isWritable: boolean }[] = []
+) => {
+  const data = Buffer.alloc(18);
+  InstructionSchema.encode({ PlaceOrder: { side, price, quantity } }, data);
+
+  return new TransactionInstruction({
+    programId: testEnv.programId.publicKey,
+    data,
+    keys: [
+      { pubkey: owner.publicKey, isSigner: true, isWritable: false },
+      { pubkey: balancePda, isSigner: false, isWritable: true },
+      { pubkey: testEnv.marketAccountPda, isSigner: false, isWritable: true },
+      { pubkey: testEnv.bidsAcc, isSigner: false, isWritable: true },
+      { pubkey: testEnv.asksAcc, isSigner: false, isWritable: true },
+      { pubkey: testEnv.marketEventsAcc, isSigner: false, isWritable: true },
+      { pubkey: baseTokenAccount, isSigner: false, isWritable: true },
+      { pubkey: quoteTokenAccount, isSigner: false, isWritable: true },
+      { pubkey: testEnv.baseVaultPda, isSigner: false, isWritable: true },
+      { pubkey: testEnv.quoteVaultPda, isSigner: false, isWritable: true },
+      { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+      {
+        pubkey: new PublicKey("SysvarC1ock11111111111111111111111111111111"),
+        isSigner: false,
+        isWritable: false,
+      },
+      ...extraKeys,
+    ],
+  });
+};
+
+const configurePriceBandIx = (
+  priceBandBps: number,
+  priceReference: number,
+  oracle: PublicKey,
+  maxOracleStaleness: number,
+  circuitBreakerBps: number = 0,
+  circuitBreakerWindow: number = 0
+) => {
+  const data = Buffer.alloc(64);
+  const len = InstructionSchema.encode(
+    {
+      ConfigurePriceBand: {
+        price_band_bps: priceBandBps,
+        price_reference: priceReference,
+        oracle,
+        max_oracle_staleness: new BN(maxOracleStaleness),
+        twap_window: new BN(0),
+        circuit_breaker_bps: circuitBreakerBps,
+        circuit_breaker_window: new BN(circuitBreakerWindow),
+      },
+    },
+    data
+  );
+
+  return new TransactionInstruction({
+    programId: testEnv.programId.publicKey,
+    data: data.subarray(0, len),
+    keys: [
+      { pubkey: testEnv.authority.publicKey, isSigner: true, isWritable: false },
+      { pubkey: testEnv.marketAccountPda, isSigner: false, isWritable: true },
+    ],
+  });
+};
+
+const setMarketStatusIx = (status: number) => {
+  const data = Buffer.alloc(2);
+  InstructionSchema.encode({ SetMarketStatus: { status } }, data);
+
+  return new TransactionInstruction({
+    programId: testEnv.programId.publicKey,
+    data,
+    keys: [
+      { pubkey: testEnv.authority.publicKey, isSigner: true, isWritable: false },
+      { pubkey: testEnv.marketAccountPda, isSigner: false, isWritable: true },
+    ],
+  });
+};
+
 // Helper function for the stress test
 const placeOrderSafe = async (
   userKeypair: Keypair,
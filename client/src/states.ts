@@ -2,7 +2,9 @@ import {
   struct,
   u8,
   u16,
+  u32,
   u64,
+  u128,
   publicKey,
   bool,
   rustEnum,
@@ -11,7 +13,61 @@ import {
   array,
 } from "@coral-xyz/borsh";
 
+export const MARKET_STATE_VERSION = 1;
+export const USER_BALANCE_VERSION = 1;
+
 export const MarketStateSchema = struct([
+  array(u8(), 8, "discriminator"),
+  u8("version"),
+  u8("bump"),
+  u8("status"),
+  u8("price_reference"),
+  u8("base_decimals"),
+  u8("quote_decimals"),
+  u16("fee_rate_bps"),
+  u16("price_band_bps"),
+  u16("circuit_breaker_bps"),
+  u16("max_orders_per_user"),
+  u8("base_vault_bump"),
+  u8("quote_vault_bump"),
+  u8("fee_account_bump"),
+  u8("native_unwrap_bump"),
+  array(u8(), 6, "padding"),
+  publicKey("authority"),
+  publicKey("consume_events_authority"),
+  publicKey("base_mint"),
+  publicKey("quote_mint"),
+  publicKey("fee_account"),
+  publicKey("base_vault"),
+  publicKey("quote_vault"),
+  publicKey("market_events"),
+  publicKey("bids"),
+  publicKey("asks"),
+  publicKey("oracle"),
+  publicKey("trigger_book"),
+  publicKey("base_token_program"),
+  publicKey("quote_token_program"),
+  u64("min_order_size"),
+  u64("tick_size"),
+  u64("next_order_id"),
+  u64("last_price"),
+  u64("volume_24h"),
+  i64("last_price_timestamp"),
+  u64("mid_price_ema"),
+  i64("mid_price_ema_timestamp"),
+  i64("max_oracle_staleness"),
+  i64("twap_window"),
+  i64("circuit_breaker_window"),
+  u64("circuit_breaker_price"),
+  i64("circuit_breaker_timestamp"),
+  u128("cumulative_price"),
+  u128("twap_checkpoint_cumulative_price"),
+  i64("twap_checkpoint_timestamp"),
+  array(u8(), 256, "reserved"),
+]);
+
+// Borsh layout of markets created by the first release
+export const LegacyMarketStateSchema = struct([
   publicKey("authority"),
   publicKey("consume_events_authority"),
   publicKey("base_mint"),
@@ -42,12 +98,101 @@ export const InstructionSchema = rustEnum([
   struct([u64("quantity")], "DepositQuoteTokens"),
   struct([u64("quantity")], "DepositBaseTokens"),
   struct([u8("side"), u64("price"), u64("quantity")], "PlaceOrder"),
-  struct([], "ConsumeEvents"),
+  struct([u16("limit")], "ConsumeEvents"),
   struct([], "SettleBalance"),
   struct([u64("order_id")], "CancelOrder"),
+  struct(
+    [
+      u16("price_band_bps"),
+      u8("price_reference"),
+      publicKey("oracle"),
+      i64("max_oracle_staleness"),
+      i64("twap_window"),
+      u16("circuit_breaker_bps"),
+      i64("circuit_breaker_window"),
+    ],
+    "ConfigurePriceBand"
+  ),
+  struct([u8("status")], "SetMarketStatus"),
+  struct(
+    [u8("side"), i64("peg_offset"), u64("limit_price"), u64("quantity")],
+    "PlacePeggedOrder"
+  ),
+  struct([], "InitializeTriggerBook"),
+  struct(
+    [
+      u8("side"),
+      u8("trigger_type"),
+      u64("trigger_price"),
+      u64("price"),
+      u64("quantity"),
+    ],
+    "PlaceTriggerOrder"
+  ),
+  struct([u64("order_id")], "CancelTriggerOrder"),
+  struct([], "TriggerOrders"),
+  struct(
+    [u8("side"), u64("price"), u64("quantity"), u64("display_quantity")],
+    "PlaceIcebergOrder"
+  ),
+  struct([], "RunAuction"),
+  struct(
+    [publicKey("delegate"), i64("expiry"), u8("permissions")],
+    "SetDelegate"
+  ),
+  struct(
+    [u8("side"), u64("price"), u64("quantity"), u64("client_order_id")],
+    "PlaceOrderWithClientId"
+  ),
+  struct([u16("max_orders_per_user")], "ConfigureOrderLimit"),
+  struct([], "CreateUserBalance"),
+  struct([u64("base_amount"), u64("quote_amount")], "Deposit"),
+  struct([], "CloseUserBalance"),
+  struct([u64("amount")], "DepositNative"),
+  struct([], "SettleBalanceNative"),
+  struct([], "SettleFor"),
+  struct(
+    [u8("side"), u64("price"), u64("quantity"), u64("client_order_id")],
+    "PlaceOrderSettleTaker"
+  ),
+  struct([], "Migrate"),
+  struct([u64("base_amount"), u64("quote_amount")], "Withdraw"),
+]);
+
+export const MAX_OPEN_ORDERS = 64;
+
+export const OpenOrderSchema = struct([
+  u64("order_id"),
+  u64("client_order_id"),
+  u64("price"),
+  u64("quantity"),
+  u8("side"),
+  array(u8(), 7, "padding"),
 ]);
 
 export const UserBalanceSchema = struct([
+  array(u8(), 8, "discriminator"),
+  u8("version"),
+  u8("bump"),
+  u8("delegate_permissions"),
+  u8("open_orders_count"),
+  array(u8(), 4, "padding"),
+  publicKey("owner"),
+  publicKey("market"),
+  publicKey("delegate"),
+  u64("available_base_balance"),
+  u64("available_quote_balance"),
+  u64("locked_base_balance"),
+  u64("locked_quote_balance"),
+  u64("pending_base_balance"),
+  u64("pending_quote_balance"),
+  i64("delegate_expiry"),
+  array(OpenOrderSchema, MAX_OPEN_ORDERS, "open_orders"),
+  array(u8(), 128, "reserved"),
+]);
+
+// Borsh layout of balances created by the first release
+export const LegacyUserBalanceSchema = struct([
   publicKey("owner"),
   publicKey("market"),
   u64("available_base_balance"),
@@ -58,6 +203,9 @@ export const UserBalanceSchema = struct([
   u64("pending_quote_balance"),
 ]);
 
+export const DELEGATE_PLACE_ORDERS = 1;
+export const DELEGATE_CANCEL_ORDERS = 2;
+
 export const OrderSchema = struct([
   publicKey("owner"),
   publicKey("market"),
@@ -67,14 +215,292 @@ export const OrderSchema = struct([
   u64("quantity"),
   u64("filled_quantity"),
   u8("side"),
+  u8("order_type"),
+  i64("peg_offset"),
+  u64("display_quantity"),
+  u64("hidden_quantity"),
+  u64("sequence"),
 ]);
 
+export const MAX_ORDERS = 1024;
+
 export const OrderbookSchema = struct([
-  array(OrderSchema, 1024, "orders"),
+  array(u8(), 8, "discriminator"),
+  array(OrderSchema, MAX_ORDERS, "orders"),
+  publicKey("market"),
+  u64("active_orders_count"),
+  u8("side"),
+]);
+
+// First release books, without the discriminator and the order fields
+// added since
+export const LegacyOrderSchema = struct([
+  publicKey("owner"),
+  publicKey("market"),
+  i64("timestamp"),
+  u64("order_id"),
+  u64("price"),
+  u64("quantity"),
+  u64("filled_quantity"),
+  u8("side"),
+]);
+
+export const LegacyOrderbookSchema = struct([
+  array(LegacyOrderSchema, MAX_ORDERS, "orders"),
   publicKey("market"),
   u64("active_orders_count"),
   u8("side"),
 ]);
 
-export const MARKET_EVENT_LEN = 50232; // bytes
-export const ORDERBOOK_LEN = 107561; // bytes
+export const EventSchema = struct([
+  publicKey("maker"),
+  publicKey("taker"),
+  i64("timestamp"),
+  u64("maker_order_id"),
+  u64("taker_order_id"),
+  u64("quantity"),
+  u64("price"),
+  u64("locked_price"),
+  u8("event_type"),
+  u8("side"),
+]);
+
+export const MarketEventsSchema = struct([
+  array(u8(), 8, "discriminator"),
+  array(EventSchema, 512, "events"),
+  publicKey("market"),
+  u64("count"),
+  u64("seq_num"),
+  u64("events_to_process"),
+]);
+
+// First release event queues, without the discriminator and the event
+// fields added since
+export const LegacyEventSchema = struct([
+  publicKey("maker"),
+  publicKey("taker"),
+  i64("timestamp"),
+  u64("maker_order_id"),
+  u64("quantity"),
+  u64("price"),
+  u8("event_type"),
+  u8("side"),
+]);
+
+export const LegacyMarketEventsSchema = struct([
+  array(LegacyEventSchema, 512, "events"),
+  publicKey("market"),
+  u64("count"),
+  u64("seq_num"),
+  u64("events_to_process"),
+]);
+
+export const TriggerOrderSchema = struct([
+  publicKey("owner"),
+  i64("timestamp"),
+  u64("order_id"),
+  u64("trigger_price"),
+  u64("price"),
+  u64("quantity"),
+  u8("side"),
+  u8("trigger_type"),
+]);
+
+export const TriggerBookSchema = struct([
+  array(u8(), 8, "discriminator"),
+  array(TriggerOrderSchema, 256, "orders"),
+  publicKey("market"),
+  u64("active_orders_count"),
+]);
+
+export const OraclePriceSchema = struct([u64("price"), i64("publish_time")]);
+
+export const PlaceOrderResultSchema = struct([
+  u64("order_id"),
+  u64("filled_quantity"),
+  u64("quote_quantity"),
+  u64("resting_quantity"),
+  u32("makers_hit"),
+]);
+
+export const ConsumeEventsResultSchema = struct([
+  u64("consumed"),
+  u64("remaining"),
+]);
+
+// Records logged with sol_log_data: one version byte, then this enum
+export const EVENT_VERSION = 1;
+
+export const OrderbookEventSchema = rustEnum([
+  struct(
+    [
+      publicKey("market"),
+      publicKey("authority"),
+      publicKey("base_mint"),
+      publicKey("quote_mint"),
+      u64("min_order_size"),
+      u64("tick_size"),
+    ],
+    "MarketInitialized"
+  ),
+  struct(
+    [
+      publicKey("market"),
+      publicKey("owner"),
+      u64("order_id"),
+      u8("side"),
+      u8("order_type"),
+      u64("price"),
+      u64("quantity"),
+      i64("timestamp"),
+    ],
+    "OrderPlaced"
+  ),
+  struct(
+    [
+      publicKey("market"),
+      publicKey("maker"),
+      publicKey("taker"),
+      u64("maker_order_id"),
+      u64("taker_order_id"),
+      u8("side"),
+      u64("price"),
+      u64("quantity"),
+      i64("timestamp"),
+    ],
+    "OrderFilled"
+  ),
+  struct(
+    [
+      publicKey("market"),
+      publicKey("owner"),
+      u64("order_id"),
+      u8("side"),
+      u64("price"),
+      u64("quantity"),
+      i64("timestamp"),
+    ],
+    "OrderCancelled"
+  ),
+  struct(
+    [
+      publicKey("market"),
+      publicKey("owner"),
+      u64("base_amount"),
+      u64("quote_amount"),
+    ],
+    "Deposit"
+  ),
+  struct(
+    [
+      publicKey("market"),
+      u8("event_type"),
+      publicKey("maker"),
+      publicKey("taker"),
+      u64("maker_order_id"),
+      u8("side"),
+      u64("price"),
+      u64("quantity"),
+    ],
+    "Settled"
+  ),
+  struct(
+    [
+      publicKey("market"),
+      publicKey("owner"),
+      u64("base_amount"),
+      u64("quote_amount"),
+    ],
+    "Withdraw"
+  ),
+  struct(
+    [
+      publicKey("market"),
+      u16("price_band_bps"),
+      u8("price_reference"),
+      publicKey("oracle"),
+      i64("max_oracle_staleness"),
+      i64("twap_window"),
+      u16("circuit_breaker_bps"),
+      i64("circuit_breaker_window"),
+    ],
+    "PriceBandConfigured"
+  ),
+  struct([publicKey("market"), u8("status")], "MarketStatusChanged"),
+  struct(
+    [publicKey("market"), u64("price"), u64("quantity"), i64("timestamp")],
+    "AuctionCleared"
+  ),
+  struct(
+    [publicKey("market"), publicKey("trigger_book")],
+    "TriggerBookInitialized"
+  ),
+  struct(
+    [
+      publicKey("market"),
+      publicKey("owner"),
+      u64("order_id"),
+      u8("side"),
+      u8("trigger_type"),
+      u64("trigger_price"),
+      u64("price"),
+      u64("quantity"),
+    ],
+    "TriggerOrderPlaced"
+  ),
+  struct(
+    [publicKey("market"), publicKey("owner"), u64("order_id")],
+    "TriggerOrderCancelled"
+  ),
+  struct(
+    [
+      publicKey("market"),
+      publicKey("owner"),
+      publicKey("delegate"),
+      i64("expiry"),
+      u8("permissions"),
+    ],
+    "DelegateSet"
+  ),
+  struct(
+    [
+      publicKey("market"),
+      publicKey("owner"),
+      u64("order_id"),
+      u8("side"),
+      u64("price"),
+      u64("quantity"),
+      i64("timestamp"),
+    ],
+    "OrderEvicted"
+  ),
+  struct(
+    [publicKey("market"), u16("max_orders_per_user")],
+    "OrderLimitConfigured"
+  ),
+  struct([publicKey("market"), publicKey("owner")], "UserBalanceCreated"),
+  struct([publicKey("market"), publicKey("owner")], "UserBalanceClosed"),
+  struct([publicKey("account"), u8("version")], "AccountMigrated"),
+  struct(
+    [
+      publicKey("market"),
+      publicKey("owner"),
+      u64("base_amount"),
+      u64("quote_amount"),
+    ],
+    "AvailableWithdrawn"
+  ),
+]);
+
+export const decodeOrderbookEvents = (logs: string[]) =>
+  logs
+    .filter((log) => log.startsWith("Program data: "))
+    .map((log) => Buffer.from(log.slice("Program data: ".length), "base64"))
+    .filter((data) => data[0] === EVENT_VERSION)
+    .map((data) => OrderbookEventSchema.decode(data.subarray(1)));
+
+export const MARKET_STATE_LEN = 880; // bytes
+export const USER_BALANCE_LEN = 2856; // bytes
+export const MARKET_EVENT_LEN = 58432; // bytes
+export const ORDERBOOK_LEN = 141361; // bytes
+export const TRIGGER_BOOK_LEN = 18992; // bytes
@@ -0,0 +1,138 @@
+//! Example program trading on the orderbook through CPI.
+//!
+//! Funds live in token accounts owned by a `["trader", market]` PDA of this
+//! program, which signs every orderbook instruction with `invoke_signed` and
+//! owns its own `UserBalance` on the market.
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use program::{
+    cpi::{self, CancelOrderAccounts, DepositAccounts, PlaceOrderAccounts},
+    state::Side,
+};
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    entrypoint,
+    entrypoint::ProgramResult,
+    msg,
+    program_error::ProgramError,
+    pubkey::Pubkey,
+};
+
+#[derive(BorshSerialize, BorshDeserialize, Debug)]
+pub enum CallerInstruction {
+    DepositBase {
+        quantity: u64,
+    },
+    DepositQuote {
+        quantity: u64,
+    },
+    PlaceOrder {
+        side: Side,
+        price: u64,
+        quantity: u64,
+    },
+    CancelOrder {
+        order_id: u64,
+    },
+}
+
+entrypoint!(process_instruction);
+
+fn process_instruction(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    instruction_data: &[u8],
+) -> ProgramResult {
+    let instruction = CallerInstruction::try_from_slice(instruction_data)
+        .map_err(|_| ProgramError::InvalidInstructionData)?;
+
+    let account_info_iter = &mut accounts.iter();
+
+    let orderbook_program_info = next_account_info(account_info_iter)?;
+    let trader_info = next_account_info(account_info_iter)?;
+    let user_balance_info = next_account_info(account_info_iter)?;
+    let market_info = next_account_info(account_info_iter)?;
+
+    let (trader_pda, trader_bump) =
+        Pubkey::find_program_address(&[b"trader", market_info.key.as_ref()], program_id);
+    if *trader_info.key != trader_pda {
+        msg!("Invalid trader account. Expected PDA: {}", trader_pda);
+        return Err(ProgramError::InvalidAccountData);
+    }
+    let signer_seeds: &[&[u8]] = &[b"trader", market_info.key.as_ref(), &[trader_bump]];
+
+    match instruction {
+        CallerInstruction::DepositBase { quantity }
+        | CallerInstruction::DepositQuote { quantity } => {
+            let deposit_accounts = DepositAccounts {
+                orderbook_program: orderbook_program_info,
+                user: trader_info,
+                user_balance: user_balance_info,
+                market: market_info,
+                user_token_account: next_account_info(account_info_iter)?,
+                vault: next_account_info(account_info_iter)?,
+                token_program: next_account_info(account_info_iter)?,
+                system_program: next_account_info(account_info_iter)?,
+                rent: next_account_info(account_info_iter)?,
+                mint: next_account_info(account_info_iter)?,
+            };
+
+            if matches!(instruction, CallerInstruction::DepositBase { .. }) {
+                cpi::deposit_base_tokens(&deposit_accounts, quantity, &[signer_seeds])
+            } else {
+                cpi::deposit_quote_tokens(&deposit_accounts, quantity, &[signer_seeds])
+            }
+        }
+        CallerInstruction::PlaceOrder {
+            side,
+            price,
+            quantity,
+        } => {
+            let place_order_accounts = PlaceOrderAccounts {
+                orderbook_program: orderbook_program_info,
+                user: trader_info,
+                user_balance: user_balance_info,
+                market: market_info,
+                bids: next_account_info(account_info_iter)?,
+                asks: next_account_info(account_info_iter)?,
+                market_events: next_account_info(account_info_iter)?,
+                user_base_token_account: next_account_info(account_info_iter)?,
+                user_quote_token_account: next_account_info(account_info_iter)?,
+                base_vault: next_account_info(account_info_iter)?,
+                quote_vault: next_account_info(account_info_iter)?,
+                token_program: next_account_info(account_info_iter)?,
+                clock: next_account_info(account_info_iter)?,
+                oracle: next_account_info(account_info_iter).ok(),
+            };
+
+            let result = cpi::place_order(
+                &place_order_accounts,
+                side,
+                price,
+                quantity,
+                &[signer_seeds],
+            )?;
+            msg!(
+                "Order {} placed through CPI: {} filled, {} resting",
+                result.order_id,
+                result.filled_quantity,
+                result.resting_quantity
+            );
+            Ok(())
+        }
+        CallerInstruction::CancelOrder { order_id } => {
+            let cancel_order_accounts = CancelOrderAccounts {
+                orderbook_program: orderbook_program_info,
+                user: trader_info,
+                user_balance: user_balance_info,
+                market: market_info,
+                bids: next_account_info(account_info_iter)?,
+                asks: next_account_info(account_info_iter)?,
+                market_events: next_account_info(account_info_iter)?,
+                clock: next_account_info(account_info_iter)?,
+            };
+
+            cpi::cancel_order(&cancel_order_accounts, order_id, &[signer_seeds])
+        }
+    }
+}
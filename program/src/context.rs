@@ -0,0 +1,208 @@
+//! A market loaded together with the market-owned accounts an instruction is
+//! passed. Each account is compared against the pubkey stored for it in
+//! `MarketState`, or for vaults the PDA rebuilt from the stored bump, before
+//! a typed handle to it is handed out, so a handler never touches a book,
+//! queue or vault of another market.
+
+use solana_program::{account_info::AccountInfo, msg, program_error::ProgramError, pubkey::Pubkey};
+use std::{
+    cell::{Ref, RefMut},
+    ops::{Deref, DerefMut},
+};
+
+use crate::state::{MarketEvents, MarketState, MarketToken, OrderBook, Side, TriggerBook};
+
+/// A market account checked against its PDA, with its state borrowed as
+/// `Ref` or `RefMut`. Derefs to `MarketState`.
+pub(crate) struct MarketContext<'a, 'info, M> {
+    program_id: Pubkey,
+    pub info: &'a AccountInfo<'info>,
+    state: M,
+}
+
+/// One of the market's vaults, with the token it holds.
+pub(crate) struct MarketVault<'b, 'info> {
+    pub info: &'b AccountInfo<'info>,
+    pub token: MarketToken,
+}
+
+impl<'a, 'info> MarketContext<'a, 'info, Ref<'a, MarketState>> {
+    /// Borrows the market read-only, as handlers that sign token transfers
+    /// with it must.
+    pub fn load(
+        program_id: &Pubkey,
+        market_info: &'a AccountInfo<'info>,
+    ) -> Result<Self, ProgramError> {
+        let state = MarketState::load(program_id, market_info)?;
+        state.check_address(program_id, market_info)?;
+        Ok(Self {
+            program_id: *program_id,
+            info: market_info,
+            state,
+        })
+    }
+}
+
+impl<'a, 'info> MarketContext<'a, 'info, RefMut<'a, MarketState>> {
+    pub fn load_mut(
+        program_id: &Pubkey,
+        market_info: &'a AccountInfo<'info>,
+    ) -> Result<Self, ProgramError> {
+        let state = MarketState::load_mut(program_id, market_info)?;
+        state.check_address(program_id, market_info)?;
+        Ok(Self {
+            program_id: *program_id,
+            info: market_info,
+            state,
+        })
+    }
+}
+
+impl<M: Deref<Target = MarketState>> Deref for MarketContext<'_, '_, M> {
+    type Target = MarketState;
+
+    fn deref(&self) -> &MarketState {
+        &self.state
+    }
+}
+
+impl<M: DerefMut<Target = MarketState>> DerefMut for MarketContext<'_, '_, M> {
+    fn deref_mut(&mut self) -> &mut MarketState {
+        &mut self.state
+    }
+}
+
+impl<M: Deref<Target = MarketState>> MarketContext<'_, '_, M> {
+    pub fn load_bids<'b>(
+        &self,
+        bids_info: &'b AccountInfo,
+    ) -> Result<RefMut<'b, OrderBook>, ProgramError> {
+        self.load_book(bids_info, Side::Buy)
+    }
+
+    pub fn load_asks<'b>(
+        &self,
+        asks_info: &'b AccountInfo,
+    ) -> Result<RefMut<'b, OrderBook>, ProgramError> {
+        self.load_book(asks_info, Side::Sell)
+    }
+
+    fn load_book<'b>(
+        &self,
+        book_info: &'b AccountInfo,
+        side: Side,
+    ) -> Result<RefMut<'b, OrderBook>, ProgramError> {
+        let expected = if side == Side::Buy {
+            self.bids
+        } else {
+            self.asks
+        };
+        if *book_info.key != expected {
+            msg!(
+                "Invalid {:?} book account. Expected: {}, Got: {}",
+                side,
+                expected,
+                book_info.key
+            );
+            return Err(ProgramError::InvalidAccountData);
+        }
+        OrderBook::load_mut(&self.program_id, book_info, self.info.key, side)
+    }
+
+    pub fn load_events<'b>(
+        &self,
+        events_info: &'b AccountInfo,
+    ) -> Result<RefMut<'b, MarketEvents>, ProgramError> {
+        if *events_info.key != self.market_events {
+            msg!(
+                "Invalid market events account. Expected: {}, Got: {}",
+                self.market_events,
+                events_info.key
+            );
+            return Err(ProgramError::InvalidAccountData);
+        }
+        MarketEvents::load_mut(&self.program_id, events_info, self.info.key)
+    }
+
+    pub fn load_trigger_book<'b>(
+        &self,
+        trigger_book_info: &'b AccountInfo,
+    ) -> Result<RefMut<'b, TriggerBook>, ProgramError> {
+        if *trigger_book_info.key != self.trigger_book {
+            msg!(
+                "Trigger book mismatch. Expected: {}, Got: {}",
+                self.trigger_book,
+                trigger_book_info.key
+            );
+            return Err(ProgramError::InvalidAccountData);
+        }
+        TriggerBook::load_mut(&self.program_id, trigger_book_info, self.info.key)
+    }
+
+    pub fn load_base_vault<'b, 'i>(
+        &self,
+        vault_info: &'b AccountInfo<'i>,
+    ) -> Result<MarketVault<'b, 'i>, ProgramError> {
+        self.load_vault(
+            self.base_token(),
+            "base",
+            b"base_vault",
+            self.base_vault_bump,
+            vault_info,
+        )
+    }
+
+    pub fn load_quote_vault<'b, 'i>(
+        &self,
+        vault_info: &'b AccountInfo<'i>,
+    ) -> Result<MarketVault<'b, 'i>, ProgramError> {
+        self.load_vault(
+            self.quote_token(),
+            "quote",
+            b"quote_vault",
+            self.quote_vault_bump,
+            vault_info,
+        )
+    }
+
+    /// The vault holding whichever side of the market is native SOL.
+    pub fn load_native_vault<'b, 'i>(
+        &self,
+        vault_info: &'b AccountInfo<'i>,
+    ) -> Result<MarketVault<'b, 'i>, ProgramError> {
+        if self.base_mint == spl_token::native_mint::id() {
+            self.load_base_vault(vault_info)
+        } else if self.quote_mint == spl_token::native_mint::id() {
+            self.load_quote_vault(vault_info)
+        } else {
+            msg!("Market does not trade native SOL");
+            Err(ProgramError::InvalidAccountData)
+        }
+    }
+
+    /// Checks `vault_info` against the `[seed, market]` PDA rebuilt from the
+    /// stored bump.
+    fn load_vault<'b, 'i>(
+        &self,
+        token: MarketToken,
+        name: &str,
+        seed: &[u8],
+        bump: u8,
+        vault_info: &'b AccountInfo<'i>,
+    ) -> Result<MarketVault<'b, 'i>, ProgramError> {
+        let vault = MarketState::market_pda(&self.program_id, seed, self.info.key, bump)?;
+        if *vault_info.key != vault {
+            msg!(
+                "Market {} vault mismatch. Expected: {}, Got: {}",
+                name,
+                vault,
+                vault_info.key
+            );
+            return Err(ProgramError::InvalidAccountData);
+        }
+        Ok(MarketVault {
+            info: vault_info,
+            token,
+        })
+    }
+}
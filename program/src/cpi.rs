@@ -0,0 +1,581 @@
+//! Cross-program invocation helpers for programs trading on the book.
+//!
+//! Depend on this crate with the `no-entrypoint` feature and call these from
+//! your own processor. `user` can be a wallet that signed the outer
+//! transaction or a PDA of the calling program: pass the PDA's seeds as
+//! `signer_seeds` and it signs both this program's checks and the token
+//! transfers made on its behalf. A PDA user owns its `UserBalance`
+//! (`["user_balance", pda, market]`) exactly like a wallet does.
+//!
+//! The first deposit creates the `UserBalance` with `user` as rent payer, so a
+//! PDA user must be a system-owned account holding enough lamports for rent.
+
+use borsh::BorshDeserialize;
+use solana_program::{
+    account_info::AccountInfo,
+    entrypoint::ProgramResult,
+    instruction::{AccountMeta, Instruction},
+    msg,
+    program::{get_return_data, invoke_signed},
+    program_error::ProgramError,
+};
+
+use crate::{
+    state::{PlaceOrderResult, Side},
+    Instruction as OrderbookInstruction,
+};
+
+fn account_metas(user: &AccountInfo, accounts: &[&AccountInfo]) -> Vec<AccountMeta> {
+    let mut metas = vec![AccountMeta {
+        pubkey: *user.key,
+        is_signer: true,
+        is_writable: user.is_writable,
+    }];
+    metas.extend(accounts.iter().map(|account| AccountMeta {
+        pubkey: *account.key,
+        is_signer: false,
+        is_writable: account.is_writable,
+    }));
+    metas
+}
+
+fn invoke_orderbook<'info>(
+    orderbook_program: &AccountInfo<'info>,
+    instruction: &OrderbookInstruction,
+    user: &AccountInfo<'info>,
+    accounts: &[&AccountInfo<'info>],
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    let ix = Instruction::new_with_borsh(
+        *orderbook_program.key,
+        instruction,
+        account_metas(user, accounts),
+    );
+
+    let mut account_infos = vec![user.clone()];
+    account_infos.extend(accounts.iter().map(|account| (*account).clone()));
+    account_infos.push(orderbook_program.clone());
+
+    invoke_signed(&ix, &account_infos, signer_seeds)
+}
+
+pub struct DepositAccounts<'a, 'info> {
+    pub orderbook_program: &'a AccountInfo<'info>,
+    /// Signer, writable: pays rent when the balance account is created
+    pub user: &'a AccountInfo<'info>,
+    pub user_balance: &'a AccountInfo<'info>,
+    pub market: &'a AccountInfo<'info>,
+    /// Token account of `user` for the deposited mint
+    pub user_token_account: &'a AccountInfo<'info>,
+    /// Market vault for the deposited mint
+    pub vault: &'a AccountInfo<'info>,
+    /// Token program owning the deposited mint
+    pub token_program: &'a AccountInfo<'info>,
+    pub system_program: &'a AccountInfo<'info>,
+    pub rent: &'a AccountInfo<'info>,
+    pub mint: &'a AccountInfo<'info>,
+}
+
+impl<'info> DepositAccounts<'_, 'info> {
+    fn accounts(&self) -> [&AccountInfo<'info>; 8] {
+        [
+            self.user_balance,
+            self.market,
+            self.user_token_account,
+            self.vault,
+            self.token_program,
+            self.system_program,
+            self.rent,
+            self.mint,
+        ]
+    }
+}
+
+pub fn deposit_base_tokens(
+    accounts: &DepositAccounts,
+    quantity: u64,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    invoke_orderbook(
+        accounts.orderbook_program,
+        &OrderbookInstruction::DepositBaseTokens { quantity },
+        accounts.user,
+        &accounts.accounts(),
+        signer_seeds,
+    )
+}
+
+pub fn deposit_quote_tokens(
+    accounts: &DepositAccounts,
+    quantity: u64,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    invoke_orderbook(
+        accounts.orderbook_program,
+        &OrderbookInstruction::DepositQuoteTokens { quantity },
+        accounts.user,
+        &accounts.accounts(),
+        signer_seeds,
+    )
+}
+
+pub struct CreateUserBalanceAccounts<'a, 'info> {
+    pub orderbook_program: &'a AccountInfo<'info>,
+    /// Signer, writable: pays rent for the balance account
+    pub user: &'a AccountInfo<'info>,
+    pub user_balance: &'a AccountInfo<'info>,
+    pub market: &'a AccountInfo<'info>,
+    pub system_program: &'a AccountInfo<'info>,
+}
+
+pub fn create_user_balance(
+    accounts: &CreateUserBalanceAccounts,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    invoke_orderbook(
+        accounts.orderbook_program,
+        &OrderbookInstruction::CreateUserBalance,
+        accounts.user,
+        &[
+            accounts.user_balance,
+            accounts.market,
+            accounts.system_program,
+        ],
+        signer_seeds,
+    )
+}
+
+/// Accounts for `deposit`, which funds both sides of an existing balance
+pub struct DepositPairAccounts<'a, 'info> {
+    pub orderbook_program: &'a AccountInfo<'info>,
+    /// Signer
+    pub user: &'a AccountInfo<'info>,
+    pub user_balance: &'a AccountInfo<'info>,
+    pub market: &'a AccountInfo<'info>,
+    pub user_base_token_account: &'a AccountInfo<'info>,
+    pub user_quote_token_account: &'a AccountInfo<'info>,
+    pub base_vault: &'a AccountInfo<'info>,
+    pub quote_vault: &'a AccountInfo<'info>,
+    /// Token program owning the base mint
+    pub token_program: &'a AccountInfo<'info>,
+    pub base_mint: &'a AccountInfo<'info>,
+    pub quote_mint: &'a AccountInfo<'info>,
+    /// Required when the quote mint is owned by the other token program
+    pub quote_token_program: Option<&'a AccountInfo<'info>>,
+}
+
+pub fn deposit(
+    accounts: &DepositPairAccounts,
+    base_amount: u64,
+    quote_amount: u64,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    let mut deposit_accounts = vec![
+        accounts.user_balance,
+        accounts.market,
+        accounts.user_base_token_account,
+        accounts.user_quote_token_account,
+        accounts.base_vault,
+        accounts.quote_vault,
+        accounts.token_program,
+        accounts.base_mint,
+        accounts.quote_mint,
+    ];
+    deposit_accounts.extend(accounts.quote_token_program);
+
+    invoke_orderbook(
+        accounts.orderbook_program,
+        &OrderbookInstruction::Deposit {
+            base_amount,
+            quote_amount,
+        },
+        accounts.user,
+        &deposit_accounts,
+        signer_seeds,
+    )
+}
+
+/// Accounts for `deposit_native`; `user` pays the lamports and must be
+/// system-owned
+pub struct DepositNativeAccounts<'a, 'info> {
+    pub orderbook_program: &'a AccountInfo<'info>,
+    /// Signer, writable
+    pub user: &'a AccountInfo<'info>,
+    pub user_balance: &'a AccountInfo<'info>,
+    pub market: &'a AccountInfo<'info>,
+    /// Vault of the native SOL side
+    pub vault: &'a AccountInfo<'info>,
+    pub token_program: &'a AccountInfo<'info>,
+    pub system_program: &'a AccountInfo<'info>,
+}
+
+pub fn deposit_native(
+    accounts: &DepositNativeAccounts,
+    amount: u64,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    invoke_orderbook(
+        accounts.orderbook_program,
+        &OrderbookInstruction::DepositNative { amount },
+        accounts.user,
+        &[
+            accounts.user_balance,
+            accounts.market,
+            accounts.vault,
+            accounts.token_program,
+            accounts.system_program,
+        ],
+        signer_seeds,
+    )
+}
+
+pub struct CloseUserBalanceAccounts<'a, 'info> {
+    pub orderbook_program: &'a AccountInfo<'info>,
+    /// Signer, writable: receives the refunded rent
+    pub user: &'a AccountInfo<'info>,
+    pub user_balance: &'a AccountInfo<'info>,
+    pub market: &'a AccountInfo<'info>,
+    pub market_events: &'a AccountInfo<'info>,
+}
+
+pub fn close_user_balance(
+    accounts: &CloseUserBalanceAccounts,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    invoke_orderbook(
+        accounts.orderbook_program,
+        &OrderbookInstruction::CloseUserBalance,
+        accounts.user,
+        &[
+            accounts.user_balance,
+            accounts.market,
+            accounts.market_events,
+        ],
+        signer_seeds,
+    )
+}
+
+/// Accounts for `withdraw`, which only moves balances to pending; the tokens
+/// are paid out by `settle_balance`
+pub struct WithdrawAccounts<'a, 'info> {
+    pub orderbook_program: &'a AccountInfo<'info>,
+    /// Signer
+    pub user: &'a AccountInfo<'info>,
+    pub user_balance: &'a AccountInfo<'info>,
+    pub market: &'a AccountInfo<'info>,
+    pub market_events: &'a AccountInfo<'info>,
+}
+
+pub fn withdraw(
+    accounts: &WithdrawAccounts,
+    base_amount: u64,
+    quote_amount: u64,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    invoke_orderbook(
+        accounts.orderbook_program,
+        &OrderbookInstruction::Withdraw {
+            base_amount,
+            quote_amount,
+        },
+        accounts.user,
+        &[
+            accounts.user_balance,
+            accounts.market,
+            accounts.market_events,
+        ],
+        signer_seeds,
+    )
+}
+
+pub struct PlaceOrderAccounts<'a, 'info> {
+    pub orderbook_program: &'a AccountInfo<'info>,
+    /// Signer
+    pub user: &'a AccountInfo<'info>,
+    pub user_balance: &'a AccountInfo<'info>,
+    pub market: &'a AccountInfo<'info>,
+    pub bids: &'a AccountInfo<'info>,
+    pub asks: &'a AccountInfo<'info>,
+    pub market_events: &'a AccountInfo<'info>,
+    pub user_base_token_account: &'a AccountInfo<'info>,
+    pub user_quote_token_account: &'a AccountInfo<'info>,
+    pub base_vault: &'a AccountInfo<'info>,
+    pub quote_vault: &'a AccountInfo<'info>,
+    pub token_program: &'a AccountInfo<'info>,
+    pub clock: &'a AccountInfo<'info>,
+    /// Required when the market has an oracle configured
+    pub oracle: Option<&'a AccountInfo<'info>>,
+}
+
+/// Sends a placement and reads back the `PlaceOrderResult` it returned.
+fn invoke_place_order(
+    accounts: &PlaceOrderAccounts,
+    instruction: &OrderbookInstruction,
+    signer_seeds: &[&[&[u8]]],
+) -> Result<PlaceOrderResult, ProgramError> {
+    let mut order_accounts = vec![
+        accounts.user_balance,
+        accounts.market,
+        accounts.bids,
+        accounts.asks,
+        accounts.market_events,
+        accounts.user_base_token_account,
+        accounts.user_quote_token_account,
+        accounts.base_vault,
+        accounts.quote_vault,
+        accounts.token_program,
+        accounts.clock,
+    ];
+    order_accounts.extend(accounts.oracle);
+
+    invoke_orderbook(
+        accounts.orderbook_program,
+        instruction,
+        accounts.user,
+        &order_accounts,
+        signer_seeds,
+    )?;
+
+    match get_return_data() {
+        Some((program_id, data)) if program_id == *accounts.orderbook_program.key => {
+            Ok(PlaceOrderResult::try_from_slice(&data)?)
+        }
+        _ => {
+            msg!("Order placement returned no result");
+            Err(ProgramError::InvalidAccountData)
+        }
+    }
+}
+
+pub fn place_order(
+    accounts: &PlaceOrderAccounts,
+    side: Side,
+    price: u64,
+    quantity: u64,
+    signer_seeds: &[&[&[u8]]],
+) -> Result<PlaceOrderResult, ProgramError> {
+    invoke_place_order(
+        accounts,
+        &OrderbookInstruction::PlaceOrder {
+            side,
+            price,
+            quantity,
+        },
+        signer_seeds,
+    )
+}
+
+pub fn place_pegged_order(
+    accounts: &PlaceOrderAccounts,
+    side: Side,
+    peg_offset: i64,
+    limit_price: u64,
+    quantity: u64,
+    signer_seeds: &[&[&[u8]]],
+) -> Result<PlaceOrderResult, ProgramError> {
+    invoke_place_order(
+        accounts,
+        &OrderbookInstruction::PlacePeggedOrder {
+            side,
+            peg_offset,
+            limit_price,
+            quantity,
+        },
+        signer_seeds,
+    )
+}
+
+pub fn place_iceberg_order(
+    accounts: &PlaceOrderAccounts,
+    side: Side,
+    price: u64,
+    quantity: u64,
+    display_quantity: u64,
+    signer_seeds: &[&[&[u8]]],
+) -> Result<PlaceOrderResult, ProgramError> {
+    invoke_place_order(
+        accounts,
+        &OrderbookInstruction::PlaceIcebergOrder {
+            side,
+            price,
+            quantity,
+            display_quantity,
+        },
+        signer_seeds,
+    )
+}
+
+pub fn place_order_with_client_id(
+    accounts: &PlaceOrderAccounts,
+    side: Side,
+    price: u64,
+    quantity: u64,
+    client_order_id: u64,
+    signer_seeds: &[&[&[u8]]],
+) -> Result<PlaceOrderResult, ProgramError> {
+    invoke_place_order(
+        accounts,
+        &OrderbookInstruction::PlaceOrderWithClientId {
+            side,
+            price,
+            quantity,
+            client_order_id,
+        },
+        signer_seeds,
+    )
+}
+
+pub fn place_order_settle_taker(
+    accounts: &PlaceOrderAccounts,
+    side: Side,
+    price: u64,
+    quantity: u64,
+    client_order_id: u64,
+    signer_seeds: &[&[&[u8]]],
+) -> Result<PlaceOrderResult, ProgramError> {
+    invoke_place_order(
+        accounts,
+        &OrderbookInstruction::PlaceOrderSettleTaker {
+            side,
+            price,
+            quantity,
+            client_order_id,
+        },
+        signer_seeds,
+    )
+}
+
+pub struct CancelOrderAccounts<'a, 'info> {
+    pub orderbook_program: &'a AccountInfo<'info>,
+    /// Signer
+    pub user: &'a AccountInfo<'info>,
+    pub user_balance: &'a AccountInfo<'info>,
+    pub market: &'a AccountInfo<'info>,
+    pub bids: &'a AccountInfo<'info>,
+    pub asks: &'a AccountInfo<'info>,
+    pub market_events: &'a AccountInfo<'info>,
+    pub clock: &'a AccountInfo<'info>,
+}
+
+pub fn cancel_order(
+    accounts: &CancelOrderAccounts,
+    order_id: u64,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    invoke_orderbook(
+        accounts.orderbook_program,
+        &OrderbookInstruction::CancelOrder { order_id },
+        accounts.user,
+        &[
+            accounts.user_balance,
+            accounts.market,
+            accounts.bids,
+            accounts.asks,
+            accounts.market_events,
+            accounts.clock,
+        ],
+        signer_seeds,
+    )
+}
+
+pub struct SettleBalanceAccounts<'a, 'info> {
+    pub orderbook_program: &'a AccountInfo<'info>,
+    /// Signer
+    pub user: &'a AccountInfo<'info>,
+    pub user_balance: &'a AccountInfo<'info>,
+    /// Passed twice by the program, as the market and as the vault authority
+    pub market: &'a AccountInfo<'info>,
+    pub user_base_token_account: &'a AccountInfo<'info>,
+    pub user_quote_token_account: &'a AccountInfo<'info>,
+    pub base_vault: &'a AccountInfo<'info>,
+    pub quote_vault: &'a AccountInfo<'info>,
+    /// Token program owning the base mint
+    pub token_program: &'a AccountInfo<'info>,
+    pub base_mint: &'a AccountInfo<'info>,
+    pub quote_mint: &'a AccountInfo<'info>,
+    pub system_program: &'a AccountInfo<'info>,
+    /// Creates missing associated token accounts for the payout
+    pub associated_token_program: &'a AccountInfo<'info>,
+    /// Required when the quote mint is owned by the other token program
+    pub quote_token_program: Option<&'a AccountInfo<'info>>,
+}
+
+pub fn settle_balance(
+    accounts: &SettleBalanceAccounts,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    let mut settle_accounts = vec![
+        accounts.user_balance,
+        accounts.market,
+        accounts.market,
+        accounts.user_base_token_account,
+        accounts.user_quote_token_account,
+        accounts.base_vault,
+        accounts.quote_vault,
+        accounts.token_program,
+        accounts.base_mint,
+        accounts.quote_mint,
+        accounts.system_program,
+        accounts.associated_token_program,
+    ];
+    settle_accounts.extend(accounts.quote_token_program);
+
+    invoke_orderbook(
+        accounts.orderbook_program,
+        &OrderbookInstruction::SettleBalance,
+        accounts.user,
+        &settle_accounts,
+        signer_seeds,
+    )
+}
+
+pub struct SettleBalanceNativeAccounts<'a, 'info> {
+    pub orderbook_program: &'a AccountInfo<'info>,
+    /// Signer, writable: receives the native SOL side as lamports
+    pub user: &'a AccountInfo<'info>,
+    pub user_balance: &'a AccountInfo<'info>,
+    pub market: &'a AccountInfo<'info>,
+    /// Token account of `user` for the side that is not native SOL
+    pub user_token_account: &'a AccountInfo<'info>,
+    pub base_vault: &'a AccountInfo<'info>,
+    pub quote_vault: &'a AccountInfo<'info>,
+    /// Token program owning the base mint
+    pub token_program: &'a AccountInfo<'info>,
+    pub base_mint: &'a AccountInfo<'info>,
+    pub quote_mint: &'a AccountInfo<'info>,
+    /// `["native_unwrap", market]` PDA of the orderbook program
+    pub native_unwrap: &'a AccountInfo<'info>,
+    pub system_program: &'a AccountInfo<'info>,
+    /// Creates a missing associated token account for the token side
+    pub associated_token_program: &'a AccountInfo<'info>,
+    /// Required when the quote mint is owned by the other token program
+    pub quote_token_program: Option<&'a AccountInfo<'info>>,
+}
+
+pub fn settle_balance_native(
+    accounts: &SettleBalanceNativeAccounts,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    let mut settle_accounts = vec![
+        accounts.user_balance,
+        accounts.market,
+        accounts.user_token_account,
+        accounts.base_vault,
+        accounts.quote_vault,
+        accounts.token_program,
+        accounts.base_mint,
+        accounts.quote_mint,
+        accounts.native_unwrap,
+        accounts.system_program,
+        accounts.associated_token_program,
+    ];
+    settle_accounts.extend(accounts.quote_token_program);
+
+    invoke_orderbook(
+        accounts.orderbook_program,
+        &OrderbookInstruction::SettleBalanceNative,
+        accounts.user,
+        &settle_accounts,
+        signer_seeds,
+    )
+}
@@ -0,0 +1,175 @@
+//! Typed records of every state change, emitted with `sol_log_data`.
+//!
+//! Each record is one log data segment: an `EVENT_VERSION` byte followed by
+//! the Borsh-encoded `OrderbookEvent`. Unlike the `MarketEvents` queue these
+//! are never overwritten, so indexers can rebuild the full market history
+//! from transaction logs alone.
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use solana_program::{entrypoint::ProgramResult, log::sol_log_data, pubkey::Pubkey};
+
+use crate::state::{EventType, MarketStatus, OrderType, PriceReference, Side, TriggerType};
+
+/// Bumped whenever a variant's layout changes. New variants are appended.
+pub const EVENT_VERSION: u8 = 1;
+
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq)]
+pub enum OrderbookEvent {
+    MarketInitialized {
+        market: Pubkey,
+        authority: Pubkey,
+        base_mint: Pubkey,
+        quote_mint: Pubkey,
+        min_order_size: u64,
+        tick_size: u64,
+    },
+    OrderPlaced {
+        market: Pubkey,
+        owner: Pubkey,
+        order_id: u64,
+        side: Side,
+        order_type: OrderType,
+        price: u64,
+        quantity: u64,
+        timestamp: i64,
+    },
+    OrderFilled {
+        market: Pubkey,
+        maker: Pubkey,
+        taker: Pubkey,
+        maker_order_id: u64,
+        taker_order_id: u64,
+        /// Taker side
+        side: Side,
+        price: u64,
+        quantity: u64,
+        timestamp: i64,
+    },
+    OrderCancelled {
+        market: Pubkey,
+        owner: Pubkey,
+        order_id: u64,
+        side: Side,
+        price: u64,
+        /// Unfilled quantity released, including hidden iceberg quantity
+        quantity: u64,
+        timestamp: i64,
+    },
+    Deposit {
+        market: Pubkey,
+        owner: Pubkey,
+        base_amount: u64,
+        quote_amount: u64,
+    },
+    /// A `MarketEvents` entry applied to user balances by `ConsumeEvents`
+    Settled {
+        market: Pubkey,
+        event_type: EventType,
+        maker: Pubkey,
+        taker: Pubkey,
+        maker_order_id: u64,
+        side: Side,
+        price: u64,
+        quantity: u64,
+    },
+    /// Pending balances paid out to the owner's token accounts
+    Withdraw {
+        market: Pubkey,
+        owner: Pubkey,
+        base_amount: u64,
+        quote_amount: u64,
+    },
+    PriceBandConfigured {
+        market: Pubkey,
+        price_band_bps: u16,
+        price_reference: PriceReference,
+        oracle: Pubkey,
+        max_oracle_staleness: i64,
+        twap_window: i64,
+        circuit_breaker_bps: u16,
+        circuit_breaker_window: i64,
+    },
+    /// Status set by the authority, a tripped circuit breaker or a finished auction
+    MarketStatusChanged {
+        market: Pubkey,
+        status: MarketStatus,
+    },
+    AuctionCleared {
+        market: Pubkey,
+        price: u64,
+        quantity: u64,
+        timestamp: i64,
+    },
+    TriggerBookInitialized {
+        market: Pubkey,
+        trigger_book: Pubkey,
+    },
+    TriggerOrderPlaced {
+        market: Pubkey,
+        owner: Pubkey,
+        order_id: u64,
+        side: Side,
+        trigger_type: TriggerType,
+        trigger_price: u64,
+        price: u64,
+        quantity: u64,
+    },
+    TriggerOrderCancelled {
+        market: Pubkey,
+        owner: Pubkey,
+        order_id: u64,
+    },
+    DelegateSet {
+        market: Pubkey,
+        owner: Pubkey,
+        delegate: Pubkey,
+        expiry: i64,
+        permissions: u8,
+    },
+    /// Worst-priced order pushed out of a full book by a better one
+    OrderEvicted {
+        market: Pubkey,
+        owner: Pubkey,
+        order_id: u64,
+        side: Side,
+        price: u64,
+        /// Unfilled quantity released, including hidden iceberg quantity
+        quantity: u64,
+        timestamp: i64,
+    },
+    OrderLimitConfigured {
+        market: Pubkey,
+        max_orders_per_user: u16,
+    },
+    UserBalanceCreated {
+        market: Pubkey,
+        owner: Pubkey,
+    },
+    /// Empty balance account closed and its rent refunded
+    UserBalanceClosed {
+        market: Pubkey,
+        owner: Pubkey,
+    },
+    /// First release account rewritten in the current layout; `version` is 0
+    /// for the books and event queue, which carry no version byte
+    AccountMigrated {
+        account: Pubkey,
+        version: u8,
+    },
+    /// Available balances moved to pending by the `Withdraw` instruction,
+    /// to be paid out by settlement
+    AvailableWithdrawn {
+        market: Pubkey,
+        owner: Pubkey,
+        base_amount: u64,
+        quote_amount: u64,
+    },
+}
+
+impl OrderbookEvent {
+    pub fn emit(&self) -> ProgramResult {
+        let data = borsh::to_vec(&(EVENT_VERSION, self))?;
+        sol_log_data(&[&data]);
+        Ok(())
+    }
+}
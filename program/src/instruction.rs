@@ -0,0 +1,981 @@
+//! Builders for every `Instruction` variant, for Rust clients and other
+//! programs depending on this crate with the `no-entrypoint` feature.
+//!
+//! Program-derived accounts (market, vaults, fee account, user balances) are
+//! derived here; only the accounts the market authority created itself are
+//! passed in through `MarketAccounts`.
+
+use solana_program::{
+    instruction::{AccountMeta, Instruction},
+    pubkey::Pubkey,
+    sysvar,
+};
+use solana_system_interface::program as system_program;
+
+use crate::{
+    state::{MarketState, MarketStatus, PriceReference, Side, TriggerType},
+    token, Instruction as OrderbookInstruction,
+};
+
+pub fn find_market_address(
+    program_id: &Pubkey,
+    base_mint: &Pubkey,
+    quote_mint: &Pubkey,
+) -> (Pubkey, u8) {
+    Pubkey::find_program_address(
+        &[b"market", base_mint.as_ref(), quote_mint.as_ref()],
+        program_id,
+    )
+}
+
+pub fn find_base_vault_address(program_id: &Pubkey, market: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"base_vault", market.as_ref()], program_id)
+}
+
+pub fn find_quote_vault_address(program_id: &Pubkey, market: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"quote_vault", market.as_ref()], program_id)
+}
+
+pub fn find_fee_account_address(program_id: &Pubkey, market: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"fee_account", market.as_ref()], program_id)
+}
+
+pub fn find_user_balance_address(
+    program_id: &Pubkey,
+    user: &Pubkey,
+    market: &Pubkey,
+) -> (Pubkey, u8) {
+    Pubkey::find_program_address(
+        &[b"user_balance", user.as_ref(), market.as_ref()],
+        program_id,
+    )
+}
+
+/// Associated token account settlement pays to, and creates when missing
+pub fn find_associated_token_address(
+    wallet: &Pubkey,
+    mint: &Pubkey,
+    token_program: &Pubkey,
+) -> Pubkey {
+    token::associated_token_address(wallet, mint, token_program)
+}
+
+/// Temporary wrapped SOL account `SettleBalanceNative` unwraps through
+pub fn find_native_unwrap_address(program_id: &Pubkey, market: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"native_unwrap", market.as_ref()], program_id)
+}
+
+/// The accounts of a market that are not derived from its mints.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MarketAccounts {
+    pub base_mint: Pubkey,
+    pub quote_mint: Pubkey,
+    pub bids: Pubkey,
+    pub asks: Pubkey,
+    pub market_events: Pubkey,
+    /// Appended after the clock sysvar wherever the program reads the oracle
+    pub oracle: Option<Pubkey>,
+    /// SPL Token or Token-2022, whichever owns each mint
+    pub base_token_program: Pubkey,
+    pub quote_token_program: Pubkey,
+}
+
+impl MarketAccounts {
+    pub fn from_state(market_state: &MarketState) -> Self {
+        Self {
+            base_mint: market_state.base_mint,
+            quote_mint: market_state.quote_mint,
+            bids: market_state.bids,
+            asks: market_state.asks,
+            market_events: market_state.market_events,
+            oracle: (market_state.oracle != Pubkey::default()).then_some(market_state.oracle),
+            base_token_program: market_state.base_token_program,
+            quote_token_program: market_state.quote_token_program,
+        }
+    }
+
+    pub fn market(&self, program_id: &Pubkey) -> Pubkey {
+        find_market_address(program_id, &self.base_mint, &self.quote_mint).0
+    }
+
+    fn oracle_meta(&self) -> Option<AccountMeta> {
+        self.oracle
+            .map(|oracle| AccountMeta::new_readonly(oracle, false))
+    }
+
+    /// The quote mint's token program, passed last only when it differs from
+    /// the base mint's.
+    fn quote_token_program_meta(&self) -> Option<AccountMeta> {
+        (self.quote_token_program != self.base_token_program)
+            .then(|| AccountMeta::new_readonly(self.quote_token_program, false))
+    }
+
+    /// Both mints followed by the quote token program when it differs, as
+    /// appended to every instruction that moves tokens.
+    fn mint_metas(&self) -> Vec<AccountMeta> {
+        let mut accounts = vec![
+            AccountMeta::new_readonly(self.base_mint, false),
+            AccountMeta::new_readonly(self.quote_mint, false),
+        ];
+        accounts.extend(self.quote_token_program_meta());
+        accounts
+    }
+}
+
+pub fn initialize_market(
+    program_id: &Pubkey,
+    authority: &Pubkey,
+    consume_events_authority: &Pubkey,
+    market_accounts: &MarketAccounts,
+    min_order_size: u64,
+    tick_size: u64,
+) -> Instruction {
+    let market = market_accounts.market(program_id);
+
+    let mut accounts = vec![
+        AccountMeta::new(*authority, true),
+        AccountMeta::new_readonly(*consume_events_authority, false),
+        AccountMeta::new(market, false),
+        AccountMeta::new_readonly(market_accounts.base_mint, false),
+        AccountMeta::new_readonly(market_accounts.quote_mint, false),
+        AccountMeta::new(market_accounts.bids, false),
+        AccountMeta::new(market_accounts.asks, false),
+        AccountMeta::new(find_base_vault_address(program_id, &market).0, false),
+        AccountMeta::new(find_quote_vault_address(program_id, &market).0, false),
+        AccountMeta::new(market_accounts.market_events, false),
+        AccountMeta::new(find_fee_account_address(program_id, &market).0, false),
+        AccountMeta::new_readonly(system_program::id(), false),
+        AccountMeta::new_readonly(market_accounts.base_token_program, false),
+        AccountMeta::new_readonly(sysvar::rent::id(), false),
+    ];
+    accounts.extend(market_accounts.quote_token_program_meta());
+
+    Instruction::new_with_borsh(
+        *program_id,
+        &OrderbookInstruction::InitializeMarket {
+            min_order_size,
+            tick_size,
+        },
+        accounts,
+    )
+}
+
+fn deposit_accounts(
+    program_id: &Pubkey,
+    user: &Pubkey,
+    market: &Pubkey,
+    user_token_account: &Pubkey,
+    vault: Pubkey,
+    mint: &Pubkey,
+    token_program: &Pubkey,
+) -> Vec<AccountMeta> {
+    vec![
+        AccountMeta::new(*user, true),
+        AccountMeta::new(find_user_balance_address(program_id, user, market).0, false),
+        AccountMeta::new_readonly(*market, false),
+        AccountMeta::new(*user_token_account, false),
+        AccountMeta::new(vault, false),
+        AccountMeta::new_readonly(*token_program, false),
+        AccountMeta::new_readonly(system_program::id(), false),
+        AccountMeta::new_readonly(sysvar::rent::id(), false),
+        AccountMeta::new_readonly(*mint, false),
+    ]
+}
+
+/// Creates the user's balance account on first use.
+pub fn deposit_quote_tokens(
+    program_id: &Pubkey,
+    user: &Pubkey,
+    market_accounts: &MarketAccounts,
+    user_quote_token_account: &Pubkey,
+    quantity: u64,
+) -> Instruction {
+    let market = market_accounts.market(program_id);
+
+    Instruction::new_with_borsh(
+        *program_id,
+        &OrderbookInstruction::DepositQuoteTokens { quantity },
+        deposit_accounts(
+            program_id,
+            user,
+            &market,
+            user_quote_token_account,
+            find_quote_vault_address(program_id, &market).0,
+            &market_accounts.quote_mint,
+            &market_accounts.quote_token_program,
+        ),
+    )
+}
+
+/// Creates the user's balance account on first use.
+pub fn deposit_base_tokens(
+    program_id: &Pubkey,
+    user: &Pubkey,
+    market_accounts: &MarketAccounts,
+    user_base_token_account: &Pubkey,
+    quantity: u64,
+) -> Instruction {
+    let market = market_accounts.market(program_id);
+
+    Instruction::new_with_borsh(
+        *program_id,
+        &OrderbookInstruction::DepositBaseTokens { quantity },
+        deposit_accounts(
+            program_id,
+            user,
+            &market,
+            user_base_token_account,
+            find_base_vault_address(program_id, &market).0,
+            &market_accounts.base_mint,
+            &market_accounts.base_token_program,
+        ),
+    )
+}
+
+pub fn create_user_balance(
+    program_id: &Pubkey,
+    user: &Pubkey,
+    market_accounts: &MarketAccounts,
+) -> Instruction {
+    let market = market_accounts.market(program_id);
+
+    Instruction::new_with_borsh(
+        *program_id,
+        &OrderbookInstruction::CreateUserBalance,
+        vec![
+            AccountMeta::new(*user, true),
+            AccountMeta::new(
+                find_user_balance_address(program_id, user, &market).0,
+                false,
+            ),
+            AccountMeta::new_readonly(market, false),
+            AccountMeta::new_readonly(system_program::id(), false),
+        ],
+    )
+}
+
+/// Either amount may be zero, the token account for that side is then unused.
+pub fn deposit(
+    program_id: &Pubkey,
+    user: &Pubkey,
+    market_accounts: &MarketAccounts,
+    user_base_token_account: &Pubkey,
+    user_quote_token_account: &Pubkey,
+    base_amount: u64,
+    quote_amount: u64,
+) -> Instruction {
+    let market = market_accounts.market(program_id);
+
+    let mut accounts = vec![
+        AccountMeta::new_readonly(*user, true),
+        AccountMeta::new(
+            find_user_balance_address(program_id, user, &market).0,
+            false,
+        ),
+        AccountMeta::new_readonly(market, false),
+        AccountMeta::new(*user_base_token_account, false),
+        AccountMeta::new(*user_quote_token_account, false),
+        AccountMeta::new(find_base_vault_address(program_id, &market).0, false),
+        AccountMeta::new(find_quote_vault_address(program_id, &market).0, false),
+        AccountMeta::new_readonly(market_accounts.base_token_program, false),
+    ];
+    accounts.extend(market_accounts.mint_metas());
+
+    Instruction::new_with_borsh(
+        *program_id,
+        &OrderbookInstruction::Deposit {
+            base_amount,
+            quote_amount,
+        },
+        accounts,
+    )
+}
+
+/// Deposits lamports on whichever side of the market is the native mint.
+pub fn deposit_native(
+    program_id: &Pubkey,
+    user: &Pubkey,
+    market_accounts: &MarketAccounts,
+    amount: u64,
+) -> Instruction {
+    let market = market_accounts.market(program_id);
+    let (vault, token_program) = if market_accounts.base_mint == spl_token::native_mint::id() {
+        (
+            find_base_vault_address(program_id, &market).0,
+            market_accounts.base_token_program,
+        )
+    } else {
+        (
+            find_quote_vault_address(program_id, &market).0,
+            market_accounts.quote_token_program,
+        )
+    };
+
+    Instruction::new_with_borsh(
+        *program_id,
+        &OrderbookInstruction::DepositNative { amount },
+        vec![
+            AccountMeta::new(*user, true),
+            AccountMeta::new(
+                find_user_balance_address(program_id, user, &market).0,
+                false,
+            ),
+            AccountMeta::new_readonly(market, false),
+            AccountMeta::new(vault, false),
+            AccountMeta::new_readonly(token_program, false),
+            AccountMeta::new_readonly(system_program::id(), false),
+        ],
+    )
+}
+
+pub fn close_user_balance(
+    program_id: &Pubkey,
+    user: &Pubkey,
+    market_accounts: &MarketAccounts,
+) -> Instruction {
+    let market = market_accounts.market(program_id);
+
+    Instruction::new_with_borsh(
+        *program_id,
+        &OrderbookInstruction::CloseUserBalance,
+        vec![
+            AccountMeta::new(*user, true),
+            AccountMeta::new(
+                find_user_balance_address(program_id, user, &market).0,
+                false,
+            ),
+            AccountMeta::new_readonly(market, false),
+            AccountMeta::new_readonly(market_accounts.market_events, false),
+        ],
+    )
+}
+
+/// Moves available balances to pending, so a `settle_balance` in the same
+/// transaction pays them out.
+pub fn withdraw(
+    program_id: &Pubkey,
+    user: &Pubkey,
+    market_accounts: &MarketAccounts,
+    base_amount: u64,
+    quote_amount: u64,
+) -> Instruction {
+    let market = market_accounts.market(program_id);
+
+    Instruction::new_with_borsh(
+        *program_id,
+        &OrderbookInstruction::Withdraw {
+            base_amount,
+            quote_amount,
+        },
+        vec![
+            AccountMeta::new_readonly(*user, true),
+            AccountMeta::new(
+                find_user_balance_address(program_id, user, &market).0,
+                false,
+            ),
+            AccountMeta::new_readonly(market, false),
+            AccountMeta::new_readonly(market_accounts.market_events, false),
+        ],
+    )
+}
+
+fn place_order_accounts(
+    program_id: &Pubkey,
+    user: &Pubkey,
+    market_accounts: &MarketAccounts,
+    user_base_token_account: &Pubkey,
+    user_quote_token_account: &Pubkey,
+) -> Vec<AccountMeta> {
+    let market = market_accounts.market(program_id);
+
+    let mut accounts = vec![
+        AccountMeta::new_readonly(*user, true),
+        AccountMeta::new(
+            find_user_balance_address(program_id, user, &market).0,
+            false,
+        ),
+        AccountMeta::new(market, false),
+        AccountMeta::new(market_accounts.bids, false),
+        AccountMeta::new(market_accounts.asks, false),
+        AccountMeta::new(market_accounts.market_events, false),
+        AccountMeta::new(*user_base_token_account, false),
+        AccountMeta::new(*user_quote_token_account, false),
+        AccountMeta::new(find_base_vault_address(program_id, &market).0, false),
+        AccountMeta::new(find_quote_vault_address(program_id, &market).0, false),
+        AccountMeta::new_readonly(market_accounts.base_token_program, false),
+        AccountMeta::new_readonly(sysvar::clock::id(), false),
+    ];
+    accounts.extend(market_accounts.oracle_meta());
+    accounts
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn place_order(
+    program_id: &Pubkey,
+    user: &Pubkey,
+    market_accounts: &MarketAccounts,
+    user_base_token_account: &Pubkey,
+    user_quote_token_account: &Pubkey,
+    side: Side,
+    price: u64,
+    quantity: u64,
+) -> Instruction {
+    Instruction::new_with_borsh(
+        *program_id,
+        &OrderbookInstruction::PlaceOrder {
+            side,
+            price,
+            quantity,
+        },
+        place_order_accounts(
+            program_id,
+            user,
+            market_accounts,
+            user_base_token_account,
+            user_quote_token_account,
+        ),
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn place_pegged_order(
+    program_id: &Pubkey,
+    user: &Pubkey,
+    market_accounts: &MarketAccounts,
+    user_base_token_account: &Pubkey,
+    user_quote_token_account: &Pubkey,
+    side: Side,
+    peg_offset: i64,
+    limit_price: u64,
+    quantity: u64,
+) -> Instruction {
+    Instruction::new_with_borsh(
+        *program_id,
+        &OrderbookInstruction::PlacePeggedOrder {
+            side,
+            peg_offset,
+            limit_price,
+            quantity,
+        },
+        place_order_accounts(
+            program_id,
+            user,
+            market_accounts,
+            user_base_token_account,
+            user_quote_token_account,
+        ),
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn place_iceberg_order(
+    program_id: &Pubkey,
+    user: &Pubkey,
+    market_accounts: &MarketAccounts,
+    user_base_token_account: &Pubkey,
+    user_quote_token_account: &Pubkey,
+    side: Side,
+    price: u64,
+    quantity: u64,
+    display_quantity: u64,
+) -> Instruction {
+    Instruction::new_with_borsh(
+        *program_id,
+        &OrderbookInstruction::PlaceIcebergOrder {
+            side,
+            price,
+            quantity,
+            display_quantity,
+        },
+        place_order_accounts(
+            program_id,
+            user,
+            market_accounts,
+            user_base_token_account,
+            user_quote_token_account,
+        ),
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn place_order_with_client_id(
+    program_id: &Pubkey,
+    user: &Pubkey,
+    market_accounts: &MarketAccounts,
+    user_base_token_account: &Pubkey,
+    user_quote_token_account: &Pubkey,
+    side: Side,
+    price: u64,
+    quantity: u64,
+    client_order_id: u64,
+) -> Instruction {
+    Instruction::new_with_borsh(
+        *program_id,
+        &OrderbookInstruction::PlaceOrderWithClientId {
+            side,
+            price,
+            quantity,
+            client_order_id,
+        },
+        place_order_accounts(
+            program_id,
+            user,
+            market_accounts,
+            user_base_token_account,
+            user_quote_token_account,
+        ),
+    )
+}
+
+/// `place_order_with_client_id` that credits the taker's fills to its pending
+/// balances, so a `settle_balance` in the same transaction pays them out.
+#[allow(clippy::too_many_arguments)]
+pub fn place_order_settle_taker(
+    program_id: &Pubkey,
+    user: &Pubkey,
+    market_accounts: &MarketAccounts,
+    user_base_token_account: &Pubkey,
+    user_quote_token_account: &Pubkey,
+    side: Side,
+    price: u64,
+    quantity: u64,
+    client_order_id: u64,
+) -> Instruction {
+    Instruction::new_with_borsh(
+        *program_id,
+        &OrderbookInstruction::PlaceOrderSettleTaker {
+            side,
+            price,
+            quantity,
+            client_order_id,
+        },
+        place_order_accounts(
+            program_id,
+            user,
+            market_accounts,
+            user_base_token_account,
+            user_quote_token_account,
+        ),
+    )
+}
+
+/// `owners` are the makers and takers of the queued events, each listed
+/// once; their balance accounts are passed as remaining accounts. `limit`
+/// caps the events applied, 0 leaves it to the compute budget.
+pub fn consume_events(
+    program_id: &Pubkey,
+    consume_events_authority: &Pubkey,
+    market_accounts: &MarketAccounts,
+    owners: &[Pubkey],
+    limit: u16,
+) -> Instruction {
+    let market = market_accounts.market(program_id);
+
+    let mut accounts = vec![
+        AccountMeta::new_readonly(*consume_events_authority, true),
+        AccountMeta::new(market, false),
+        AccountMeta::new(market_accounts.market_events, false),
+    ];
+    accounts.extend(owners.iter().map(|owner| {
+        AccountMeta::new(
+            find_user_balance_address(program_id, owner, &market).0,
+            false,
+        )
+    }));
+
+    Instruction::new_with_borsh(
+        *program_id,
+        &OrderbookInstruction::ConsumeEvents { limit },
+        accounts,
+    )
+}
+
+pub fn settle_balance(
+    program_id: &Pubkey,
+    user: &Pubkey,
+    market_accounts: &MarketAccounts,
+    user_base_token_account: &Pubkey,
+    user_quote_token_account: &Pubkey,
+) -> Instruction {
+    let market = market_accounts.market(program_id);
+
+    let mut accounts = vec![
+        AccountMeta::new(*user, true),
+        AccountMeta::new(
+            find_user_balance_address(program_id, user, &market).0,
+            false,
+        ),
+        AccountMeta::new_readonly(market, false),
+        // The market PDA signs for both vaults
+        AccountMeta::new_readonly(market, false),
+        AccountMeta::new(*user_base_token_account, false),
+        AccountMeta::new(*user_quote_token_account, false),
+        AccountMeta::new(find_base_vault_address(program_id, &market).0, false),
+        AccountMeta::new(find_quote_vault_address(program_id, &market).0, false),
+        AccountMeta::new_readonly(market_accounts.base_token_program, false),
+        AccountMeta::new_readonly(market_accounts.base_mint, false),
+        AccountMeta::new_readonly(market_accounts.quote_mint, false),
+        AccountMeta::new_readonly(system_program::id(), false),
+        AccountMeta::new_readonly(token::ASSOCIATED_TOKEN_PROGRAM_ID, false),
+    ];
+    accounts.extend(market_accounts.quote_token_program_meta());
+
+    Instruction::new_with_borsh(*program_id, &OrderbookInstruction::SettleBalance, accounts)
+}
+
+/// `user_token_account` receives the side that is not native SOL.
+pub fn settle_balance_native(
+    program_id: &Pubkey,
+    user: &Pubkey,
+    market_accounts: &MarketAccounts,
+    user_token_account: &Pubkey,
+) -> Instruction {
+    let market = market_accounts.market(program_id);
+
+    let mut accounts = vec![
+        AccountMeta::new(*user, true),
+        AccountMeta::new(
+            find_user_balance_address(program_id, user, &market).0,
+            false,
+        ),
+        AccountMeta::new_readonly(market, false),
+        AccountMeta::new(*user_token_account, false),
+        AccountMeta::new(find_base_vault_address(program_id, &market).0, false),
+        AccountMeta::new(find_quote_vault_address(program_id, &market).0, false),
+        AccountMeta::new_readonly(market_accounts.base_token_program, false),
+        AccountMeta::new_readonly(market_accounts.base_mint, false),
+        AccountMeta::new_readonly(market_accounts.quote_mint, false),
+        AccountMeta::new(find_native_unwrap_address(program_id, &market).0, false),
+        AccountMeta::new_readonly(system_program::id(), false),
+        AccountMeta::new_readonly(token::ASSOCIATED_TOKEN_PROGRAM_ID, false),
+    ];
+    accounts.extend(market_accounts.quote_token_program_meta());
+
+    Instruction::new_with_borsh(
+        *program_id,
+        &OrderbookInstruction::SettleBalanceNative,
+        accounts,
+    )
+}
+
+/// Settles every owner in `owners` to their associated token accounts, with
+/// `payer` funding any token account that has to be created.
+pub fn settle_for(
+    program_id: &Pubkey,
+    payer: &Pubkey,
+    market_accounts: &MarketAccounts,
+    owners: &[Pubkey],
+) -> Instruction {
+    let market = market_accounts.market(program_id);
+
+    let mut accounts = vec![
+        AccountMeta::new(*payer, true),
+        AccountMeta::new_readonly(market, false),
+        AccountMeta::new(find_base_vault_address(program_id, &market).0, false),
+        AccountMeta::new(find_quote_vault_address(program_id, &market).0, false),
+        AccountMeta::new_readonly(market_accounts.base_token_program, false),
+        AccountMeta::new_readonly(market_accounts.base_mint, false),
+        AccountMeta::new_readonly(market_accounts.quote_mint, false),
+        AccountMeta::new_readonly(system_program::id(), false),
+        AccountMeta::new_readonly(token::ASSOCIATED_TOKEN_PROGRAM_ID, false),
+        AccountMeta::new_readonly(market_accounts.quote_token_program, false),
+    ];
+    for owner in owners {
+        accounts.extend([
+            AccountMeta::new(
+                find_user_balance_address(program_id, owner, &market).0,
+                false,
+            ),
+            AccountMeta::new_readonly(*owner, false),
+            AccountMeta::new(
+                find_associated_token_address(
+                    owner,
+                    &market_accounts.base_mint,
+                    &market_accounts.base_token_program,
+                ),
+                false,
+            ),
+            AccountMeta::new(
+                find_associated_token_address(
+                    owner,
+                    &market_accounts.quote_mint,
+                    &market_accounts.quote_token_program,
+                ),
+                false,
+            ),
+        ]);
+    }
+
+    Instruction::new_with_borsh(*program_id, &OrderbookInstruction::SettleFor, accounts)
+}
+
+pub fn cancel_order(
+    program_id: &Pubkey,
+    user: &Pubkey,
+    market_accounts: &MarketAccounts,
+    order_id: u64,
+) -> Instruction {
+    let market = market_accounts.market(program_id);
+
+    Instruction::new_with_borsh(
+        *program_id,
+        &OrderbookInstruction::CancelOrder { order_id },
+        vec![
+            AccountMeta::new_readonly(*user, true),
+            AccountMeta::new(
+                find_user_balance_address(program_id, user, &market).0,
+                false,
+            ),
+            AccountMeta::new_readonly(market, false),
+            AccountMeta::new(market_accounts.bids, false),
+            AccountMeta::new(market_accounts.asks, false),
+            AccountMeta::new(market_accounts.market_events, false),
+            AccountMeta::new_readonly(sysvar::clock::id(), false),
+        ],
+    )
+}
+
+fn authority_accounts(
+    program_id: &Pubkey,
+    authority: &Pubkey,
+    market_accounts: &MarketAccounts,
+) -> Vec<AccountMeta> {
+    vec![
+        AccountMeta::new_readonly(*authority, true),
+        AccountMeta::new(market_accounts.market(program_id), false),
+    ]
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn configure_price_band(
+    program_id: &Pubkey,
+    authority: &Pubkey,
+    market_accounts: &MarketAccounts,
+    price_band_bps: u16,
+    price_reference: PriceReference,
+    oracle: Pubkey,
+    max_oracle_staleness: i64,
+    twap_window: i64,
+    circuit_breaker_bps: u16,
+    circuit_breaker_window: i64,
+) -> Instruction {
+    Instruction::new_with_borsh(
+        *program_id,
+        &OrderbookInstruction::ConfigurePriceBand {
+            price_band_bps,
+            price_reference,
+            oracle,
+            max_oracle_staleness,
+            twap_window,
+            circuit_breaker_bps,
+            circuit_breaker_window,
+        },
+        authority_accounts(program_id, authority, market_accounts),
+    )
+}
+
+pub fn set_market_status(
+    program_id: &Pubkey,
+    authority: &Pubkey,
+    market_accounts: &MarketAccounts,
+    status: MarketStatus,
+) -> Instruction {
+    Instruction::new_with_borsh(
+        *program_id,
+        &OrderbookInstruction::SetMarketStatus { status },
+        authority_accounts(program_id, authority, market_accounts),
+    )
+}
+
+pub fn configure_order_limit(
+    program_id: &Pubkey,
+    authority: &Pubkey,
+    market_accounts: &MarketAccounts,
+    max_orders_per_user: u16,
+) -> Instruction {
+    Instruction::new_with_borsh(
+        *program_id,
+        &OrderbookInstruction::ConfigureOrderLimit {
+            max_orders_per_user,
+        },
+        authority_accounts(program_id, authority, market_accounts),
+    )
+}
+
+pub fn initialize_trigger_book(
+    program_id: &Pubkey,
+    authority: &Pubkey,
+    market_accounts: &MarketAccounts,
+    trigger_book: &Pubkey,
+) -> Instruction {
+    let mut accounts = authority_accounts(program_id, authority, market_accounts);
+    accounts.push(AccountMeta::new(*trigger_book, false));
+    accounts.push(AccountMeta::new_readonly(sysvar::rent::id(), false));
+
+    Instruction::new_with_borsh(
+        *program_id,
+        &OrderbookInstruction::InitializeTriggerBook,
+        accounts,
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn place_trigger_order(
+    program_id: &Pubkey,
+    user: &Pubkey,
+    market_accounts: &MarketAccounts,
+    trigger_book: &Pubkey,
+    side: Side,
+    trigger_type: TriggerType,
+    trigger_price: u64,
+    price: u64,
+    quantity: u64,
+) -> Instruction {
+    let market = market_accounts.market(program_id);
+
+    Instruction::new_with_borsh(
+        *program_id,
+        &OrderbookInstruction::PlaceTriggerOrder {
+            side,
+            trigger_type,
+            trigger_price,
+            price,
+            quantity,
+        },
+        vec![
+            AccountMeta::new_readonly(*user, true),
+            AccountMeta::new(
+                find_user_balance_address(program_id, user, &market).0,
+                false,
+            ),
+            AccountMeta::new(market, false),
+            AccountMeta::new(*trigger_book, false),
+            AccountMeta::new_readonly(sysvar::clock::id(), false),
+        ],
+    )
+}
+
+pub fn cancel_trigger_order(
+    program_id: &Pubkey,
+    user: &Pubkey,
+    market_accounts: &MarketAccounts,
+    trigger_book: &Pubkey,
+    order_id: u64,
+) -> Instruction {
+    let market = market_accounts.market(program_id);
+
+    Instruction::new_with_borsh(
+        *program_id,
+        &OrderbookInstruction::CancelTriggerOrder { order_id },
+        vec![
+            AccountMeta::new_readonly(*user, true),
+            AccountMeta::new(
+                find_user_balance_address(program_id, user, &market).0,
+                false,
+            ),
+            AccountMeta::new_readonly(market, false),
+            AccountMeta::new(*trigger_book, false),
+        ],
+    )
+}
+
+pub fn trigger_orders(
+    program_id: &Pubkey,
+    market_accounts: &MarketAccounts,
+    trigger_book: &Pubkey,
+) -> Instruction {
+    let mut accounts = vec![
+        AccountMeta::new(market_accounts.market(program_id), false),
+        AccountMeta::new(market_accounts.bids, false),
+        AccountMeta::new(market_accounts.asks, false),
+        AccountMeta::new(market_accounts.market_events, false),
+        AccountMeta::new(*trigger_book, false),
+        AccountMeta::new_readonly(sysvar::clock::id(), false),
+    ];
+    accounts.extend(market_accounts.oracle_meta());
+
+    Instruction::new_with_borsh(*program_id, &OrderbookInstruction::TriggerOrders, accounts)
+}
+
+pub fn run_auction(
+    program_id: &Pubkey,
+    authority: &Pubkey,
+    market_accounts: &MarketAccounts,
+) -> Instruction {
+    let mut accounts = authority_accounts(program_id, authority, market_accounts);
+    accounts.push(AccountMeta::new(market_accounts.bids, false));
+    accounts.push(AccountMeta::new(market_accounts.asks, false));
+    accounts.push(AccountMeta::new(market_accounts.market_events, false));
+    accounts.push(AccountMeta::new_readonly(sysvar::clock::id(), false));
+    accounts.extend(market_accounts.oracle_meta());
+
+    Instruction::new_with_borsh(*program_id, &OrderbookInstruction::RunAuction, accounts)
+}
+
+pub fn set_delegate(
+    program_id: &Pubkey,
+    owner: &Pubkey,
+    market_accounts: &MarketAccounts,
+    delegate: &Pubkey,
+    expiry: i64,
+    permissions: u8,
+) -> Instruction {
+    let market = market_accounts.market(program_id);
+
+    Instruction::new_with_borsh(
+        *program_id,
+        &OrderbookInstruction::SetDelegate {
+            delegate: *delegate,
+            expiry,
+            permissions,
+        },
+        vec![
+            AccountMeta::new_readonly(*owner, true),
+            AccountMeta::new(
+                find_user_balance_address(program_id, owner, &market).0,
+                false,
+            ),
+            AccountMeta::new_readonly(market, false),
+        ],
+    )
+}
+
+/// Rewrites the market, its books and event queue and the balances of
+/// `owners` that are still in the first release's layouts; `payer` covers the
+/// extra rent of the larger accounts. Books only grow by 10KB per call, so
+/// send it without `owners` until the books are converted on the fourth call,
+/// then once more with them.
+pub fn migrate(
+    program_id: &Pubkey,
+    payer: &Pubkey,
+    market_accounts: &MarketAccounts,
+    owners: &[Pubkey],
+) -> Instruction {
+    let market = market_accounts.market(program_id);
+
+    let mut accounts = vec![
+        AccountMeta::new(*payer, true),
+        AccountMeta::new(market, false),
+        AccountMeta::new_readonly(system_program::ID, false),
+        AccountMeta::new_readonly(market_accounts.base_mint, false),
+        AccountMeta::new_readonly(market_accounts.quote_mint, false),
+        AccountMeta::new(market_accounts.bids, false),
+        AccountMeta::new(market_accounts.asks, false),
+        AccountMeta::new(market_accounts.market_events, false),
+    ];
+    accounts.extend(owners.iter().map(|owner| {
+        AccountMeta::new(
+            find_user_balance_address(program_id, owner, &market).0,
+            false,
+        )
+    }));
+
+    Instruction::new_with_borsh(*program_id, &OrderbookInstruction::Migrate, accounts)
+}
+
+/// Re-signs an order placement or cancellation built for the balance owner
+/// so that `delegate` signs it instead.
+pub fn signed_by_delegate(mut instruction: Instruction, delegate: &Pubkey) -> Instruction {
+    instruction.accounts[0] = AccountMeta::new_readonly(*delegate, true);
+    instruction
+}
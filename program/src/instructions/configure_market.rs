@@ -0,0 +1,160 @@
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    entrypoint::ProgramResult,
+    msg,
+    program_error::ProgramError,
+    pubkey::Pubkey,
+};
+use std::cell::RefMut;
+
+use crate::{
+    context::MarketContext,
+    events::OrderbookEvent,
+    state::{MarketState, MarketStatus, PriceObservation, PriceReference, MAX_OPEN_ORDERS},
+};
+
+pub(crate) fn load_market_for_authority<'a, 'info>(
+    program_id: &Pubkey,
+    authority_info: &AccountInfo,
+    market_info: &'a AccountInfo<'info>,
+) -> Result<MarketContext<'a, 'info, RefMut<'a, MarketState>>, ProgramError> {
+    if !authority_info.is_signer {
+        msg!("Market authority must be a signer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    let market_state = MarketContext::load_mut(program_id, market_info)?;
+
+    if market_state.authority != *authority_info.key {
+        msg!("Invalid market authority");
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    Ok(market_state)
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn process_configure_price_band(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    price_band_bps: u16,
+    price_reference: PriceReference,
+    oracle: Pubkey,
+    max_oracle_staleness: i64,
+    twap_window: i64,
+    circuit_breaker_bps: u16,
+    circuit_breaker_window: i64,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+
+    let authority_info = next_account_info(account_info_iter)?;
+    let market_info = next_account_info(account_info_iter)?;
+
+    let mut market_state = load_market_for_authority(program_id, authority_info, market_info)?;
+
+    if price_band_bps > 10_000 || circuit_breaker_bps > 10_000 {
+        msg!("Deviation limits must be at most 10000 bps");
+        return Err(ProgramError::InvalidInstructionData);
+    }
+
+    if max_oracle_staleness < 0 || twap_window < 0 || circuit_breaker_window < 0 {
+        msg!("Time windows must not be negative");
+        return Err(ProgramError::InvalidInstructionData);
+    }
+
+    if price_reference == PriceReference::Oracle && oracle == Pubkey::default() {
+        msg!("Oracle reference requires an oracle account");
+        return Err(ProgramError::InvalidInstructionData);
+    }
+
+    market_state.price_band_bps = price_band_bps;
+    market_state.price_reference = price_reference;
+    market_state.oracle = oracle;
+    market_state.max_oracle_staleness = max_oracle_staleness;
+    market_state.twap_window = twap_window;
+    market_state.twap_checkpoint = PriceObservation::default();
+    market_state.circuit_breaker_bps = circuit_breaker_bps;
+    market_state.circuit_breaker_window = circuit_breaker_window;
+    market_state.circuit_breaker_price = 0;
+    market_state.circuit_breaker_timestamp = 0;
+
+    OrderbookEvent::PriceBandConfigured {
+        market: *market_info.key,
+        price_band_bps,
+        price_reference,
+        oracle,
+        max_oracle_staleness,
+        twap_window,
+        circuit_breaker_bps,
+        circuit_breaker_window,
+    }
+    .emit()?;
+
+    debug_msg!(
+        "Price band set to {} bps, circuit breaker {} bps over {}s",
+        price_band_bps,
+        circuit_breaker_bps,
+        circuit_breaker_window
+    );
+    Ok(())
+}
+
+pub fn process_set_market_status(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    status: MarketStatus,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+
+    let authority_info = next_account_info(account_info_iter)?;
+    let market_info = next_account_info(account_info_iter)?;
+
+    let mut market_state = load_market_for_authority(program_id, authority_info, market_info)?;
+
+    market_state.status = status;
+    // Start a fresh breaker window so the move that tripped it doesn't re-trip on resume
+    market_state.circuit_breaker_price = 0;
+    market_state.circuit_breaker_timestamp = 0;
+
+    OrderbookEvent::MarketStatusChanged {
+        market: *market_info.key,
+        status,
+    }
+    .emit()?;
+
+    debug_msg!("Market status set to {:?}", status);
+    Ok(())
+}
+
+pub fn process_configure_order_limit(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    max_orders_per_user: u16,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+
+    let authority_info = next_account_info(account_info_iter)?;
+    let market_info = next_account_info(account_info_iter)?;
+
+    let mut market_state = load_market_for_authority(program_id, authority_info, market_info)?;
+
+    if max_orders_per_user == 0 || max_orders_per_user as usize > MAX_OPEN_ORDERS {
+        msg!(
+            "Order limit must be between 1 and {} orders per user",
+            MAX_OPEN_ORDERS
+        );
+        return Err(ProgramError::InvalidInstructionData);
+    }
+
+    // Users already above a lowered limit keep their orders but cannot add more
+    market_state.max_orders_per_user = max_orders_per_user;
+
+    OrderbookEvent::OrderLimitConfigured {
+        market: *market_info.key,
+        max_orders_per_user,
+    }
+    .emit()?;
+
+    debug_msg!("Order limit set to {} per user", max_orders_per_user);
+    Ok(())
+}
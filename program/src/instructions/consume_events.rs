@@ -1,18 +1,39 @@
-use borsh::{BorshDeserialize, BorshSerialize};
 use solana_program::{
     account_info::{next_account_info, AccountInfo},
+    compute_units::sol_remaining_compute_units,
     entrypoint::ProgramResult,
     msg,
+    program::set_return_data,
     program_error::ProgramError,
     pubkey::Pubkey,
 };
-use std::collections::HashMap;
+use std::{cell::RefMut, collections::HashMap};
 
-use crate::state::{EventType, MarketEvents, MarketState, Side, UserBalance};
+use crate::{
+    context::MarketContext,
+    events::OrderbookEvent,
+    state::{ConsumeEventsResult, EventType, Side, UserBalance},
+};
 
-const MAX_EVENTS_TO_CONSUME: usize = 7;
+/// Compute units kept back so an event that is started can always finish
+const COMPUTE_UNITS_PER_EVENT: u64 = 4_000;
+/// Compute units kept back to compact the queue and return the result
+const COMPUTE_UNITS_TO_FINISH: u64 = 5_000;
 
-pub fn process_consume_events(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+/// Applies queued events to the `UserBalance` accounts passed after the
+/// market events account, oldest first.
+///
+/// Every remaining account must be a balance account of this market. The
+/// queue is consumed up to the first event whose maker or taker balance was
+/// not passed, so no update is ever skipped; that event and everything after
+/// it stay queued for the next call. At most `limit` events are applied (no
+/// cap for 0), and consumption also stops once the remaining compute units
+/// would not cover another event plus compacting the queue.
+pub fn process_consume_events(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    limit: u16,
+) -> ProgramResult {
     let account_info_iter = &mut accounts.iter();
 
     let consume_events_authority_info = next_account_info(account_info_iter)?;
@@ -24,43 +45,42 @@ pub fn process_consume_events(program_id: &Pubkey, accounts: &[AccountInfo]) ->
         return Err(ProgramError::MissingRequiredSignature);
     }
 
-    let market_state = MarketState::try_from_slice(&market_info.data.borrow())?;
-    let (market_pda, _) = Pubkey::find_program_address(
-        &[
-            b"market",
-            market_state.base_mint.as_ref(),
-            market_state.quote_mint.as_ref(),
-        ],
-        program_id,
-    );
-
-    if *market_info.key != market_pda {
-        msg!("Invalid market account");
-        return Err(ProgramError::InvalidAccountData);
-    }
-
-    if market_events_info.owner != program_id {
-        msg!("Market events account must be owned by this program");
-        return Err(ProgramError::InvalidAccountData);
-    }
+    let market_state = MarketContext::load(program_id, market_info)?;
 
     if market_state.consume_events_authority != *consume_events_authority_info.key {
         msg!("Invalid consume events authority");
         return Err(ProgramError::InvalidAccountData);
     }
 
-    let remaining_accounts: Vec<&AccountInfo> = account_info_iter.collect();
-    let mut events_data = market_events_info.data.borrow_mut();
-    let market_events: &mut MarketEvents = bytemuck::from_bytes_mut(&mut events_data);
+    let mut market_events = market_state.load_events(market_events_info)?;
 
-    let mut consumed_count: usize = 0;
+    // Balances are borrowed in place for the whole call
+    let balance_infos = account_info_iter.as_slice();
+    let mut balances: Vec<RefMut<UserBalance>> = Vec::with_capacity(balance_infos.len());
+    let mut balance_index: HashMap<Pubkey, usize> = HashMap::new();
+    for (i, balance_info) in balance_infos.iter().enumerate() {
+        if balance_infos[..i]
+            .iter()
+            .any(|other| other.key == balance_info.key)
+        {
+            msg!("Balance account {} passed twice", balance_info.key);
+            return Err(ProgramError::InvalidArgument);
+        }
 
-    let mut balance_accounts: HashMap<Pubkey, &AccountInfo> = HashMap::new();
-    for account_info in &remaining_accounts {
-        balance_accounts.insert(*account_info.key, account_info);
+        // Each balance is its owner's PDA for this market, so owners are distinct
+        let balance = UserBalance::load(program_id, balance_info, market_info)?;
+        balance_index.insert(balance.owner, balances.len());
+        balances.push(balance);
     }
 
-    msg!(
+    let mut consumed_count: usize = 0;
+    let limit = if limit == 0 {
+        usize::MAX
+    } else {
+        limit as usize
+    };
+
+    debug_msg!(
         "Starting event consumption. Events to process: {}",
         market_events.events_to_process
     );
@@ -69,27 +89,62 @@ pub fn process_consume_events(program_id: &Pubkey, accounts: &[AccountInfo]) ->
         if i >= market_events.events.len() {
             break;
         }
-        if consumed_count >= MAX_EVENTS_TO_CONSUME {
-            msg!("Maximum event limit reached: {}", MAX_EVENTS_TO_CONSUME);
+        if consumed_count >= limit {
+            debug_msg!("Event limit reached: {}", limit);
+            break;
+        }
+        let remaining_compute_units = sol_remaining_compute_units();
+        if remaining_compute_units < COMPUTE_UNITS_TO_FINISH + COMPUTE_UNITS_PER_EVENT {
+            debug_msg!(
+                "Stopping with {} compute units left",
+                remaining_compute_units
+            );
             break;
         }
 
         let event = &market_events.events[i];
-        
+
         let event_maker = event.maker;
         let event_taker = event.taker;
         let event_type = event.event_type;
         let event_side = event.side;
         let event_quantity = event.quantity;
         let event_price = event.price;
+        let locked_price = event.locked_price;
+        let maker_order_id = event.maker_order_id;
+        let taker_order_id = event.taker_order_id;
 
         // Skip empty/removed events
         if event_maker == Pubkey::default() && event_taker == Pubkey::default() {
-            msg!("Skipping empty event at index {}", i);
+            debug_msg!("Skipping empty event at index {}", i);
+            consumed_count += 1;
             continue;
         }
 
-        msg!(
+        let Some(&maker_index) = balance_index.get(&event_maker) else {
+            msg!(
+                "Balance account of maker {} not passed, stopping at event {}",
+                event_maker,
+                i
+            );
+            break;
+        };
+        // The taker of a `MakerFill` was already settled by `PlaceOrderSettleTaker`
+        let taker_index = if event_type == EventType::Fill && event_maker != event_taker {
+            let Some(&taker_index) = balance_index.get(&event_taker) else {
+                msg!(
+                    "Balance account of taker {} not passed, stopping at event {}",
+                    event_taker,
+                    i
+                );
+                break;
+            };
+            Some(taker_index)
+        } else {
+            None
+        };
+
+        debug_msg!(
             "Processing event {}: {} {} {} at {} price",
             i,
             event_type as u8,
@@ -99,154 +154,119 @@ pub fn process_consume_events(program_id: &Pubkey, accounts: &[AccountInfo]) ->
         );
 
         let quote_amount = (event_quantity * event_price) / 1_000_000_000;
+        // The buyer locked at its limit, a better fill price frees the rest
+        let locked_quote = (event_quantity * locked_price) / 1_000_000_000;
 
         match event_type {
-            EventType::Fill => {
-                // maker == taker ,self-trade
-                if event_maker == event_taker {
-                    msg!("Self-trade detected: maker == taker");
-                    
-                    let (user_balance_pda, _) = Pubkey::find_program_address(
-                        &[
-                            b"user_balance",
-                            event_maker.as_ref(),
-                            market_info.key.as_ref(),
-                        ],
-                        program_id,
-                    );
+            // maker == taker ,self-trade
+            EventType::Fill if event_maker == event_taker => {
+                debug_msg!("Self-trade detected: maker == taker");
 
-                    if let Some(user_balance_info) = balance_accounts.get(&user_balance_pda) {
-                        let mut user_balance = UserBalance::try_from_slice(&user_balance_info.data.borrow())?;
-                        
-                        if user_balance.owner == event_maker {
-                            if event_side == Side::Buy {
-                                user_balance.locked_quote_balance -= quote_amount;
-                                user_balance.available_quote_balance += quote_amount;
-                            } else {
-                                user_balance.locked_base_balance -= event_quantity;
-                                user_balance.available_base_balance += event_quantity;
-                            }
-                            
-                            user_balance.serialize(&mut *user_balance_info.data.borrow_mut())?;
-                            msg!("Self-trade balance updated - unlocked funds with no net change");
-                        }
-                    } else {
-                        msg!("User balance account not found for self-trade");
-                    }
-                } else {
-                    //normal trade
-                    let (maker_balance_pda, _) = Pubkey::find_program_address(
-                        &[
-                            b"user_balance",
-                            event_maker.as_ref(),
-                            market_info.key.as_ref(),
-                        ],
-                        program_id,
+                // Nothing changes hands, so the buyer's quote lock and the
+                // seller's base lock, one of them the resting order's, both
+                // go back to available
+                let user_balance = &mut balances[maker_index];
+                user_balance.locked_quote_balance -= locked_quote;
+                user_balance.available_quote_balance += locked_quote;
+                user_balance.locked_base_balance -= event_quantity;
+                user_balance.available_base_balance += event_quantity;
+                user_balance.reduce_open_order(maker_order_id, event_quantity);
+                user_balance.reduce_open_order(taker_order_id, event_quantity);
+                debug_msg!("Self-trade balance updated - unlocked funds with no net change");
+            }
+            EventType::Fill | EventType::MakerFill => {
+                let maker_balance = &mut balances[maker_index];
+                if event_side == Side::Buy {
+                    // Taker is buying, so maker is selling
+                    maker_balance.locked_base_balance -= event_quantity;
+                    maker_balance.pending_quote_balance += quote_amount;
+                    debug_msg!(
+                        "Maker sold: -{} base locked, +{} quote pending",
+                        event_quantity,
+                        quote_amount
                     );
-
-                    if let Some(maker_balance_info) = balance_accounts.get(&maker_balance_pda) {
-                        let mut maker_balance = UserBalance::try_from_slice(&maker_balance_info.data.borrow())?;
-
-                        if maker_balance.owner == event_maker {
-                            if event_side == Side::Buy {
-                                // Taker is buying, so maker is selling
-                                maker_balance.locked_base_balance -= event_quantity;
-                                maker_balance.pending_quote_balance += quote_amount;
-                                msg!("Maker sold: -{} base locked, +{} quote pending", event_quantity, quote_amount);
-                            } else {
-                                // Taker is selling, so maker is buying
-                                maker_balance.locked_quote_balance -= quote_amount;
-                                maker_balance.pending_base_balance += event_quantity;
-                                msg!("Maker bought: -{} quote locked, +{} base pending", quote_amount, event_quantity);
-                            }
-                            
-                            maker_balance.serialize(&mut *maker_balance_info.data.borrow_mut())?;
-                            msg!("Maker balance updated");
-                        }
-                    } else {
-                        msg!("Maker balance account not found, skipping maker settlement");
-                    }
-
-                    // Process taker's balance
-                    let (taker_balance_pda, _) = Pubkey::find_program_address(
-                        &[
-                            b"user_balance",
-                            event_taker.as_ref(),
-                            market_info.key.as_ref(),
-                        ],
-                        program_id,
+                } else {
+                    // Taker is selling, so maker is buying
+                    maker_balance.locked_quote_balance -= locked_quote;
+                    maker_balance.available_quote_balance += locked_quote - quote_amount;
+                    maker_balance.pending_base_balance += event_quantity;
+                    debug_msg!(
+                        "Maker bought: -{} quote locked, +{} base pending",
+                        quote_amount,
+                        event_quantity
                     );
+                }
+                maker_balance.reduce_open_order(maker_order_id, event_quantity);
 
-                    if let Some(taker_balance_info) = balance_accounts.get(&taker_balance_pda) {
-                        let mut taker_balance = UserBalance::try_from_slice(&taker_balance_info.data.borrow())?;
-
-                        if taker_balance.owner == event_taker {
-                            if event_side == Side::Buy {
-                                // Taker is buying
-                                taker_balance.locked_quote_balance -= quote_amount;
-                                taker_balance.pending_base_balance += event_quantity;
-                                msg!("Taker bought: -{} quote locked, +{} base pending", quote_amount, event_quantity);
-                            } else {
-                                // Taker is selling
-                                taker_balance.locked_base_balance -= event_quantity;
-                                taker_balance.pending_quote_balance += quote_amount;
-                                msg!("Taker sold: -{} base locked, +{} quote pending", event_quantity, quote_amount);
-                            }
-
-                            taker_balance.serialize(&mut *taker_balance_info.data.borrow_mut())?;
-                            msg!("Taker balance updated");
-                        }
+                if let Some(taker_index) = taker_index {
+                    let taker_balance = &mut balances[taker_index];
+                    if event_side == Side::Buy {
+                        // Taker is buying
+                        taker_balance.locked_quote_balance -= locked_quote;
+                        taker_balance.available_quote_balance += locked_quote - quote_amount;
+                        taker_balance.pending_base_balance += event_quantity;
+                        debug_msg!(
+                            "Taker bought: -{} quote locked, +{} base pending",
+                            quote_amount,
+                            event_quantity
+                        );
                     } else {
-                        msg!("Taker balance account not found, skipping taker settlement");
+                        // Taker is selling
+                        taker_balance.locked_base_balance -= event_quantity;
+                        taker_balance.pending_quote_balance += quote_amount;
+                        debug_msg!(
+                            "Taker sold: -{} base locked, +{} quote pending",
+                            event_quantity,
+                            quote_amount
+                        );
                     }
+                    taker_balance.reduce_open_order(taker_order_id, event_quantity);
                 }
             }
             EventType::Out => {
-                // only for makers
-                let (maker_balance_pda, _) = Pubkey::find_program_address(
-                    &[
-                        b"user_balance",
-                        event_maker.as_ref(),
-                        market_info.key.as_ref(),
-                    ],
-                    program_id,
-                );
-
-                if let Some(maker_balance_info) = balance_accounts.get(&maker_balance_pda) {
-                    let mut maker_balance = UserBalance::try_from_slice(&maker_balance_info.data.borrow())?;
-
-                    if maker_balance.owner == event_maker {
-                        if event_side == Side::Buy {
-                            // cancelled buy order,unlock quote tokens
-                            maker_balance.locked_quote_balance -= quote_amount;
-                            maker_balance.available_quote_balance += quote_amount;
-                            msg!("Buy order cancelled: unlocked {} quote", quote_amount);
-                        } else {
-                            // cancelled sell order,unlock base tokens
-                            maker_balance.locked_base_balance -= event_quantity;
-                            maker_balance.available_base_balance += event_quantity;
-                            msg!("Sell order cancelled: unlocked {} base", event_quantity);
-                        }
-                        
-                        maker_balance.serialize(&mut *maker_balance_info.data.borrow_mut())?;
-                        msg!("Cancelled order balance updated");
-                    }
+                // An evicted order or a triggered remainder, always listed as maker
+                let maker_balance = &mut balances[maker_index];
+                if event_side == Side::Buy {
+                    maker_balance.locked_quote_balance -= quote_amount;
+                    maker_balance.available_quote_balance += quote_amount;
+                    debug_msg!("Buy order evicted: unlocked {} quote", quote_amount);
                 } else {
-                    msg!("Maker balance account not found for cancelled order");
+                    maker_balance.locked_base_balance -= event_quantity;
+                    maker_balance.available_base_balance += event_quantity;
+                    debug_msg!("Sell order evicted: unlocked {} base", event_quantity);
                 }
+                maker_balance.remove_open_order(maker_order_id);
             }
         }
 
+        OrderbookEvent::Settled {
+            market: *market_info.key,
+            event_type,
+            maker: event_maker,
+            taker: event_taker,
+            maker_order_id,
+            side: event_side,
+            price: event_price,
+            quantity: event_quantity,
+        }
+        .emit()?;
+
         consumed_count += 1;
-        msg!("Event {} consumed successfully", i);
+        debug_msg!("Event {} consumed successfully", i);
     }
-    market_events.events_to_process = market_events.events_to_process.saturating_sub(consumed_count as u64);
 
-    msg!(
+    market_events.pop_front(consumed_count);
+
+    debug_msg!(
         "Successfully consumed {} events. Remaining events: {}",
         consumed_count,
         market_events.events_to_process
     );
 
+    set_return_data(&borsh::to_vec(&ConsumeEventsResult {
+        consumed: consumed_count as u64,
+        remaining: market_events.events_to_process,
+    })?);
+
     Ok(())
 }
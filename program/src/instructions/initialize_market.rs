@@ -1,18 +1,24 @@
-use borsh::{BorshDeserialize, BorshSerialize};
+use bytemuck::Zeroable;
 use solana_program::{
     account_info::{next_account_info, AccountInfo},
     entrypoint::ProgramResult,
     msg,
-    program::{invoke, invoke_signed},
+    program::invoke_signed,
     program_error::ProgramError,
     pubkey::Pubkey,
     rent::Rent,
-    system_instruction, system_program,
     sysvar::Sysvar,
 };
-use spl_token::instruction as token_instruction;
-
-use crate::state::{MarketEvents, MarketState, OrderBook, Side};
+use solana_system_interface::{instruction as system_instruction, program as system_program};
+
+use crate::{
+    events::OrderbookEvent,
+    state::{
+        MarketEvents, MarketState, MarketStatus, OrderBook, PriceObservation, PriceReference, Side,
+        MAX_OPEN_ORDERS,
+    },
+    token,
+};
 
 pub fn process_initialize_market(
     program_id: &Pubkey,
@@ -36,6 +42,9 @@ pub fn process_initialize_market(
     let system_program_info = next_account_info(account_info_iter)?;
     let token_program_info = next_account_info(account_info_iter)?;
     let rent_info = next_account_info(account_info_iter)?;
+    // Only needed when the quote mint lives in the other token program
+    let quote_token_program_info =
+        next_account_info(account_info_iter).unwrap_or(token_program_info);
 
     if !(authority_info.is_signer && authority_info.is_writable) {
         msg!("Authority must be a signer and writable");
@@ -64,11 +73,38 @@ pub fn process_initialize_market(
         return Err(ProgramError::IncorrectProgramId);
     }
 
-    if !spl_token::check_id(token_program_info.key) {
-        msg!("Invalid token program");
+    token::check_token_program(token_program_info)?;
+    token::check_token_program(quote_token_program_info)?;
+
+    if base_mint_info.lamports() == 0 {
+        msg!("Base mint account does not exist: {}", base_mint_info.key);
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    if quote_mint_info.lamports() == 0 {
+        msg!("Quote mint account does not exist: {}", quote_mint_info.key);
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    if base_mint_info.owner != token_program_info.key {
+        msg!(
+            "Base mint is owned by {}, not the given token program",
+            base_mint_info.owner
+        );
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    if quote_mint_info.owner != quote_token_program_info.key {
+        msg!(
+            "Quote mint is owned by {}, not the given token program",
+            quote_mint_info.owner
+        );
         return Err(ProgramError::IncorrectProgramId);
     }
 
+    let base_decimals = token::load_mint(base_mint_info)?;
+    let quote_decimals = token::load_mint(quote_mint_info)?;
+
     if min_order_size == 0 {
         msg!("Minimum order size must be greater than 0");
         return Err(ProgramError::InvalidInstructionData);
@@ -124,6 +160,10 @@ pub fn process_initialize_market(
         return Err(ProgramError::InvalidAccountData);
     }
 
+    // Created on demand by native SOL payouts, only its bump is stored here
+    let (_, native_unwrap_bump) =
+        Pubkey::find_program_address(&[b"native_unwrap", market_pda.as_ref()], program_id);
+
     if bids_info.owner != program_id {
         msg!("Bids account must be owned by this program");
         return Err(ProgramError::InvalidAccountData);
@@ -139,13 +179,10 @@ pub fn process_initialize_market(
         return Err(ProgramError::InvalidAccountData);
     }
 
+    // The market PDA is only ever funded by its creation below
     if market_info.lamports() > 0 {
-        let market_data = MarketState::try_from_slice(&market_info.data.borrow())
-            .map_err(|_| ProgramError::InvalidAccountData)?;
-        if market_data.is_initialized {
-            msg!("Market is already initialized");
-            return Err(ProgramError::AccountAlreadyInitialized);
-        }
+        msg!("Market is already initialized");
+        return Err(ProgramError::AccountAlreadyInitialized);
     }
 
     let rent = Rent::from_account_info(rent_info)?;
@@ -168,7 +205,7 @@ pub fn process_initialize_market(
         return Err(ProgramError::AccountNotRentExempt);
     }
 
-    msg!("Initializing Bids Account");
+    debug_msg!("Initializing Bids Account");
     {
         let mut raw_data = bids_info.data.borrow_mut();
         if raw_data.len() != OrderBook::LEN {
@@ -180,15 +217,21 @@ pub fn process_initialize_market(
             return Err(ProgramError::InvalidAccountData);
         }
 
+        if raw_data[..8] != [0; 8] {
+            msg!("Bids account is already in use");
+            return Err(ProgramError::AccountAlreadyInitialized);
+        }
+
         raw_data.fill(0);
 
         let bids_account_data: &mut OrderBook = bytemuck::from_bytes_mut(&mut raw_data);
+        bids_account_data.discriminator = OrderBook::DISCRIMINATOR;
         bids_account_data.market = market_pda;
         bids_account_data.active_orders_count = 0;
         bids_account_data.side = Side::Buy;
     }
 
-    msg!("Initializing Asks Account");
+    debug_msg!("Initializing Asks Account");
     {
         let mut raw_data = asks_info.data.borrow_mut();
         if raw_data.len() != OrderBook::LEN {
@@ -200,15 +243,21 @@ pub fn process_initialize_market(
             return Err(ProgramError::InvalidAccountData);
         }
 
+        if raw_data[..8] != [0; 8] {
+            msg!("Asks account is already in use");
+            return Err(ProgramError::AccountAlreadyInitialized);
+        }
+
         raw_data.fill(0);
 
         let asks_account_data: &mut OrderBook = bytemuck::from_bytes_mut(&mut raw_data);
+        asks_account_data.discriminator = OrderBook::DISCRIMINATOR;
         asks_account_data.market = market_pda;
         asks_account_data.active_orders_count = 0;
         asks_account_data.side = Side::Sell;
     }
 
-    msg!("Initializing Market Events Account");
+    debug_msg!("Initializing Market Events Account");
     {
         let mut raw_data = market_events_info.data.borrow_mut(); // Fixed: was using asks_info
         if raw_data.len() != MarketEvents::LEN {
@@ -220,50 +269,54 @@ pub fn process_initialize_market(
             return Err(ProgramError::InvalidAccountData);
         }
 
+        if raw_data[..8] != [0; 8] {
+            msg!("Market events account is already in use");
+            return Err(ProgramError::AccountAlreadyInitialized);
+        }
+
         raw_data.fill(0);
 
         let market_events_data: &mut MarketEvents = bytemuck::from_bytes_mut(&mut raw_data);
+        market_events_data.discriminator = MarketEvents::DISCRIMINATOR;
         market_events_data.market = market_pda;
         market_events_data.count = 0;
         market_events_data.seq_num = 0;
         market_events_data.events_to_process = 0;
     }
 
-    if market_info.lamports() == 0 {
-        msg!("Creating market account with {} bytes", MarketState::LEN);
-        let market_rent = rent.minimum_balance(MarketState::LEN);
-        let create_market_ix = system_instruction::create_account(
-            authority_info.key,
-            &market_pda,
-            market_rent,
-            MarketState::LEN as u64,
-            program_id,
-        );
+    debug_msg!("Creating market account with {} bytes", MarketState::LEN);
+    let market_rent = rent.minimum_balance(MarketState::LEN);
+    let create_market_ix = system_instruction::create_account(
+        authority_info.key,
+        &market_pda,
+        market_rent,
+        MarketState::LEN as u64,
+        program_id,
+    );
 
-        invoke_signed(
-            &create_market_ix,
-            &[
-                authority_info.clone(),
-                market_info.clone(),
-                system_program_info.clone(),
-            ],
-            &[&[
-                b"market",
-                base_mint_info.key.as_ref(),
-                quote_mint_info.key.as_ref(),
-                &[bump],
-            ]],
-        )?;
-    }
+    invoke_signed(
+        &create_market_ix,
+        &[
+            authority_info.clone(),
+            market_info.clone(),
+            system_program_info.clone(),
+        ],
+        &[&[
+            b"market",
+            base_mint_info.key.as_ref(),
+            quote_mint_info.key.as_ref(),
+            &[bump],
+        ]],
+    )?;
 
     if base_vault_info.lamports() == 0 {
-        let vault_rent = rent.minimum_balance(165);
+        let vault_len = token::account_data_size(token_program_info, base_mint_info)?;
         let create_base_vault_ix = system_instruction::create_account(
             authority_info.key,
             &base_vault_pda,
-            vault_rent,
-            165,
-            &spl_token::id(),
+            rent.minimum_balance(vault_len),
+            vault_len as u64,
+            token_program_info.key,
         );
 
         invoke_signed(
@@ -278,13 +331,13 @@ pub fn process_initialize_market(
     }
 
     if quote_vault_info.lamports() == 0 {
-        let vault_rent = rent.minimum_balance(165);
+        let vault_len = token::account_data_size(quote_token_program_info, quote_mint_info)?;
         let create_quote_vault_ix = system_instruction::create_account(
             authority_info.key,
             &quote_vault_pda,
-            vault_rent,
-            165,
-            &spl_token::id(),
+            rent.minimum_balance(vault_len),
+            vault_len as u64,
+            quote_token_program_info.key,
         );
 
         invoke_signed(
@@ -299,13 +352,13 @@ pub fn process_initialize_market(
     }
 
     if fee_account_info.lamports() == 0 {
-        let fee_rent = rent.minimum_balance(165);
+        let fee_account_len = token::account_data_size(quote_token_program_info, quote_mint_info)?;
         let create_fee_account_ix = system_instruction::create_account(
             authority_info.key,
             &fee_account_pda,
-            fee_rent,
-            165,
-            &spl_token::id(),
+            rent.minimum_balance(fee_account_len),
+            fee_account_len as u64,
+            quote_token_program_info.key,
         );
 
         invoke_signed(
@@ -319,71 +372,30 @@ pub fn process_initialize_market(
         )?;
     }
 
-    if base_mint_info.lamports() == 0 {
-        msg!("Base mint account does not exist: {}", base_mint_info.key);
-        return Err(ProgramError::InvalidAccountData);
-    }
-
-    if quote_mint_info.lamports() == 0 {
-        msg!("Quote mint account does not exist: {}", quote_mint_info.key);
-        return Err(ProgramError::InvalidAccountData);
-    }
-
-    let init_base_vault_ix = token_instruction::initialize_account(
-        token_program_info.key,
-        base_vault_info.key,
-        base_mint_info.key,
+    token::initialize_account(
+        token_program_info,
+        base_vault_info,
+        base_mint_info,
         &market_pda,
     )?;
-
-    invoke(
-        &init_base_vault_ix,
-        &[
-            base_vault_info.clone(),
-            base_mint_info.clone(),
-            market_info.clone(),
-            token_program_info.clone(),
-            rent_info.clone(),
-        ],
-    )?;
-
-    let init_quote_vault_ix = token_instruction::initialize_account(
-        token_program_info.key,
-        quote_vault_info.key,
-        quote_mint_info.key,
+    token::initialize_account(
+        quote_token_program_info,
+        quote_vault_info,
+        quote_mint_info,
         &market_pda,
     )?;
-
-    invoke(
-        &init_quote_vault_ix,
-        &[
-            quote_vault_info.clone(),
-            quote_mint_info.clone(),
-            market_info.clone(),
-            token_program_info.clone(),
-            rent_info.clone(),
-        ],
-    )?;
-
-    let init_fee_account_ix = token_instruction::initialize_account(
-        token_program_info.key,
-        fee_account_info.key,
-        quote_mint_info.key,
+    token::initialize_account(
+        quote_token_program_info,
+        fee_account_info,
+        quote_mint_info,
         &market_pda,
     )?;
 
-    invoke(
-        &init_fee_account_ix,
-        &[
-            fee_account_info.clone(),
-            quote_mint_info.clone(),
-            market_info.clone(),
-            token_program_info.clone(),
-            rent_info.clone(),
-        ],
-    )?;
-
-    let market_state = MarketState {
+    let mut market_data = market_info.data.borrow_mut();
+    let market_state: &mut MarketState = bytemuck::from_bytes_mut(&mut market_data);
+    *market_state = MarketState {
+        discriminator: MarketState::DISCRIMINATOR,
+        version: MarketState::VERSION,
         authority: *authority_info.key,
         consume_events_authority: *consume_events_authority.key,
         base_mint: *base_mint_info.key,
@@ -400,25 +412,60 @@ pub fn process_initialize_market(
         last_price: 0,
         volume_24h: 0,
         fee_rate_bps: 30,
-        bump: bump,
-        is_initialized: true,
+        bump,
+        cumulative_price: 0.into(),
+        last_price_timestamp: 0,
+        mid_price_ema: 0,
+        mid_price_ema_timestamp: 0,
+        status: MarketStatus::Active,
+        price_band_bps: 0,
+        price_reference: PriceReference::Twap,
+        oracle: Pubkey::default(),
+        max_oracle_staleness: 0,
+        twap_window: 0,
+        twap_checkpoint: PriceObservation::default(),
+        circuit_breaker_bps: 0,
+        circuit_breaker_window: 0,
+        circuit_breaker_price: 0,
+        circuit_breaker_timestamp: 0,
+        trigger_book: Pubkey::default(),
+        max_orders_per_user: MAX_OPEN_ORDERS as u16,
+        base_token_program: *token_program_info.key,
+        quote_token_program: *quote_token_program_info.key,
+        base_decimals,
+        quote_decimals,
+        base_vault_bump,
+        quote_vault_bump,
+        fee_account_bump,
+        native_unwrap_bump,
+        ..MarketState::zeroed()
     };
-
-    market_state.serialize(&mut *market_info.data.borrow_mut())?;
-    msg!("MarketState serialized successfully");
-
-    msg!("Market PDA: {}", market_pda);
-    msg!("Bids PDA: {}", bids_info.key);
-    msg!("Asks PDA: {}", asks_info.key);
-    msg!("Events PDA: {}", market_info.key);
-    msg!("Authority: {}", authority_info.key);
-    msg!("Base mint: {}", base_mint_info.key);
-    msg!("Quote mint: {}", quote_mint_info.key);
-    msg!("Min order size: {}", min_order_size);
-    msg!("Tick size: {}", tick_size);
-    msg!("Fee account: {}", fee_account_pda);
-    msg!("Base vault: {}", base_vault_pda);
-    msg!("Quote vault: {}", quote_vault_pda);
+    drop(market_data);
+
+    debug_msg!("MarketState written successfully");
+
+    debug_msg!("Market PDA: {}", market_pda);
+    debug_msg!("Bids PDA: {}", bids_info.key);
+    debug_msg!("Asks PDA: {}", asks_info.key);
+    debug_msg!("Events PDA: {}", market_info.key);
+    debug_msg!("Authority: {}", authority_info.key);
+    debug_msg!("Base mint: {}", base_mint_info.key);
+    debug_msg!("Quote mint: {}", quote_mint_info.key);
+    debug_msg!("Min order size: {}", min_order_size);
+    debug_msg!("Tick size: {}", tick_size);
+    debug_msg!("Fee account: {}", fee_account_pda);
+    debug_msg!("Base vault: {}", base_vault_pda);
+    debug_msg!("Quote vault: {}", quote_vault_pda);
+
+    OrderbookEvent::MarketInitialized {
+        market: market_pda,
+        authority: *authority_info.key,
+        base_mint: *base_mint_info.key,
+        quote_mint: *quote_mint_info.key,
+        min_order_size,
+        tick_size,
+    }
+    .emit()?;
 
     Ok(())
 }
@@ -0,0 +1,712 @@
+use borsh::BorshDeserialize;
+use bytemuck::{Pod, Zeroable};
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    entrypoint::{ProgramResult, MAX_PERMITTED_DATA_INCREASE},
+    msg,
+    program::invoke,
+    program_error::ProgramError,
+    pubkey::Pubkey,
+    rent::Rent,
+    sysvar::Sysvar,
+};
+use solana_system_interface::{instruction as system_instruction, program as system_program};
+use std::mem::size_of;
+
+use crate::{
+    events::OrderbookEvent,
+    state::{
+        Event, EventType, MarketEvents, MarketState, MarketStatus, OpenOrder, Order, OrderBook,
+        OrderType, PriceReference, Side, UserBalance, MAX_EVENTS, MAX_OPEN_ORDERS, MAX_ORDERS,
+    },
+    token,
+};
+
+/// `MarketState` as Borsh-encoded by the first release.
+#[derive(BorshDeserialize)]
+struct LegacyMarketState {
+    authority: Pubkey,
+    consume_events_authority: Pubkey,
+    base_mint: Pubkey,
+    quote_mint: Pubkey,
+    fee_account: Pubkey,
+    base_vault: Pubkey,
+    quote_vault: Pubkey,
+    market_events: Pubkey,
+    bids: Pubkey,
+    asks: Pubkey,
+    min_order_size: u64,
+    tick_size: u64,
+    next_order_id: u64,
+    last_price: u64,
+    volume_24h: u64,
+    fee_rate_bps: u16,
+    bump: u8,
+    is_initialized: bool,
+}
+
+/// `UserBalance` as Borsh-encoded by the first release, 112 bytes.
+#[derive(BorshDeserialize)]
+struct LegacyUserBalance {
+    owner: Pubkey,
+    market: Pubkey,
+    available_base_balance: u64,
+    available_quote_balance: u64,
+    locked_base_balance: u64,
+    locked_quote_balance: u64,
+    pending_base_balance: u64,
+    pending_quote_balance: u64,
+}
+
+/// `Order` of the first release, 105 bytes.
+#[repr(C, packed)]
+#[derive(Clone, Copy, Zeroable, Pod)]
+struct LegacyOrder {
+    owner: Pubkey,
+    market: Pubkey,
+    timestamp: i64,
+    order_id: u64,
+    price: u64,
+    quantity: u64,
+    filled_quantity: u64,
+    side: Side,
+}
+
+/// Fields after the orders of a first release `OrderBook`, which had no
+/// discriminator in front.
+#[repr(C, packed)]
+#[derive(Clone, Copy, Zeroable, Pod)]
+struct LegacyOrderBookTail {
+    market: Pubkey,
+    active_orders_count: u64,
+    side: Side,
+}
+
+/// `Event` of the first release, 98 bytes.
+#[repr(C, packed)]
+#[derive(Clone, Copy, Zeroable, Pod)]
+struct LegacyEvent {
+    maker: Pubkey,
+    taker: Pubkey,
+    timestamp: i64,
+    maker_order_id: u64,
+    quantity: u64,
+    price: u64,
+    event_type: EventType,
+    side: Side,
+}
+
+/// Fields after the events of a first release `MarketEvents`.
+#[repr(C, packed)]
+#[derive(Clone, Copy, Zeroable, Pod)]
+struct LegacyMarketEventsTail {
+    market: Pubkey,
+    count: u64,
+    seq_num: u64,
+    events_to_process: u64,
+}
+
+const LEGACY_ORDER_BOOK_LEN: usize =
+    size_of::<LegacyOrder>() * MAX_ORDERS + size_of::<LegacyOrderBookTail>(); // 107,561 bytes
+const LEGACY_MARKET_EVENTS_LEN: usize =
+    size_of::<LegacyEvent>() * MAX_EVENTS + size_of::<LegacyMarketEventsTail>(); // 50,232 bytes
+
+/// Rewrites the accounts of a market created by the first release in the
+/// current layouts. The market needs its base and quote mints passed along
+/// for their decimals. Books grow by at most 10KB per instruction, so they
+/// are only converted on the fourth call; until then the call just grows
+/// them. Balances list their resting orders, so both books and the event
+/// queue must be current, or passed before the balances in the same call.
+/// Accounts already current are left untouched, so the instruction can be
+/// retried safely.
+pub fn process_migrate(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+
+    let payer_info = next_account_info(account_info_iter)?;
+    let market_info = next_account_info(account_info_iter)?;
+    let system_program_info = next_account_info(account_info_iter)?;
+    let account_infos = account_info_iter.as_slice();
+
+    if !payer_info.is_signer {
+        msg!("Payer must be a signer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    if !system_program::check_id(system_program_info.key) {
+        msg!("Invalid system program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    if market_info.owner != program_id {
+        msg!("Market account must be owned by this program");
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    let rent = Rent::get()?;
+
+    if !has_discriminator(market_info, &MarketState::DISCRIMINATOR) {
+        migrate_market(
+            program_id,
+            payer_info,
+            market_info,
+            system_program_info,
+            account_infos,
+            &rent,
+        )?;
+    }
+
+    // Fails if the market is neither current nor was just migrated
+    let market_state = MarketState::load(program_id, market_info)?;
+    let base_mint = market_state.base_mint;
+    let quote_mint = market_state.quote_mint;
+    let bids = market_state.bids;
+    let asks = market_state.asks;
+    let market_events = market_state.market_events;
+    drop(market_state);
+
+    for account_info in account_infos {
+        // Only read for their decimals when the market is migrated
+        let key = *account_info.key;
+        if key == base_mint || key == quote_mint {
+            continue;
+        }
+
+        if account_info.owner != program_id {
+            msg!("Migrated accounts must be owned by this program");
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        // The market's own accounts are told apart by the addresses it stores
+        if key == bids || key == asks {
+            let side = if key == bids { Side::Buy } else { Side::Sell };
+            migrate_order_book(
+                payer_info,
+                account_info,
+                system_program_info,
+                market_info.key,
+                side,
+                &rent,
+            )?;
+            if has_discriminator(account_info, &OrderBook::DISCRIMINATOR) {
+                OrderBook::load_mut(program_id, account_info, market_info.key, side)?;
+            }
+        } else if key == market_events {
+            migrate_market_events(
+                payer_info,
+                account_info,
+                system_program_info,
+                market_info.key,
+                &rent,
+            )?;
+            MarketEvents::load_mut(program_id, account_info, market_info.key)?;
+        } else {
+            if !has_discriminator(account_info, &UserBalance::DISCRIMINATOR) {
+                migrate_user_balance(
+                    program_id,
+                    payer_info,
+                    market_info,
+                    account_info,
+                    system_program_info,
+                    account_infos,
+                    [bids, asks, market_events],
+                    &rent,
+                )?;
+            }
+            UserBalance::load(program_id, account_info, market_info)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn has_discriminator(account_info: &AccountInfo, discriminator: &[u8; 8]) -> bool {
+    account_info
+        .data
+        .borrow()
+        .get(..8)
+        .is_some_and(|prefix| prefix == discriminator)
+}
+
+/// The account in `account_infos` at `key`.
+fn find_account<'a, 'b>(
+    account_infos: &'b [AccountInfo<'a>],
+    key: &Pubkey,
+    name: &str,
+) -> Result<&'b AccountInfo<'a>, ProgramError> {
+    account_infos
+        .iter()
+        .find(|account_info| account_info.key == key)
+        .ok_or_else(|| {
+            msg!("The {} account {} must be passed", name, key);
+            ProgramError::NotEnoughAccountKeys
+        })
+}
+
+fn migrate_market<'a>(
+    program_id: &Pubkey,
+    payer_info: &AccountInfo<'a>,
+    market_info: &AccountInfo<'a>,
+    system_program_info: &AccountInfo<'a>,
+    account_infos: &[AccountInfo<'a>],
+    rent: &Rent,
+) -> ProgramResult {
+    let legacy = LegacyMarketState::try_from_slice(&market_info.data.borrow())?;
+
+    if !legacy.is_initialized {
+        msg!("Market account is not initialized");
+        return Err(ProgramError::UninitializedAccount);
+    }
+
+    let market_pda = Pubkey::create_program_address(
+        &[
+            b"market",
+            legacy.base_mint.as_ref(),
+            legacy.quote_mint.as_ref(),
+            &[legacy.bump],
+        ],
+        program_id,
+    )
+    .map_err(|_| ProgramError::InvalidAccountData)?;
+    if market_pda != *market_info.key {
+        msg!("Invalid market account");
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    // The first release stored neither the decimals nor the token programs
+    let base_mint_info = find_account(account_infos, &legacy.base_mint, "base mint")?;
+    let quote_mint_info = find_account(account_infos, &legacy.quote_mint, "quote mint")?;
+    let base_decimals = token::load_mint(base_mint_info)?;
+    let quote_decimals = token::load_mint(quote_mint_info)?;
+
+    // The first release stored the token account addresses but not the
+    // bumps handlers now check them with
+    let base_vault_bump =
+        stored_pda_bump(program_id, b"base_vault", &market_pda, &legacy.base_vault)?;
+    let quote_vault_bump =
+        stored_pda_bump(program_id, b"quote_vault", &market_pda, &legacy.quote_vault)?;
+    let fee_account_bump =
+        stored_pda_bump(program_id, b"fee_account", &market_pda, &legacy.fee_account)?;
+    let (_, native_unwrap_bump) =
+        Pubkey::find_program_address(&[b"native_unwrap", market_pda.as_ref()], program_id);
+
+    resize_account(
+        payer_info,
+        market_info,
+        system_program_info,
+        MarketState::LEN,
+        rent,
+    )?;
+
+    let mut market_data = market_info.data.borrow_mut();
+    let market_state: &mut MarketState = bytemuck::from_bytes_mut(&mut market_data);
+    *market_state = MarketState {
+        discriminator: MarketState::DISCRIMINATOR,
+        version: MarketState::VERSION,
+        bump: legacy.bump,
+        status: MarketStatus::Active,
+        price_reference: PriceReference::Twap,
+        base_decimals,
+        quote_decimals,
+        fee_rate_bps: legacy.fee_rate_bps,
+        max_orders_per_user: MAX_OPEN_ORDERS as u16,
+        base_vault_bump,
+        quote_vault_bump,
+        fee_account_bump,
+        native_unwrap_bump,
+        authority: legacy.authority,
+        consume_events_authority: legacy.consume_events_authority,
+        base_mint: legacy.base_mint,
+        quote_mint: legacy.quote_mint,
+        fee_account: legacy.fee_account,
+        base_vault: legacy.base_vault,
+        quote_vault: legacy.quote_vault,
+        market_events: legacy.market_events,
+        bids: legacy.bids,
+        asks: legacy.asks,
+        base_token_program: *base_mint_info.owner,
+        quote_token_program: *quote_mint_info.owner,
+        min_order_size: legacy.min_order_size,
+        tick_size: legacy.tick_size,
+        next_order_id: legacy.next_order_id,
+        last_price: legacy.last_price,
+        volume_24h: legacy.volume_24h,
+        ..MarketState::zeroed()
+    };
+    drop(market_data);
+
+    OrderbookEvent::AccountMigrated {
+        account: *market_info.key,
+        version: MarketState::VERSION,
+    }
+    .emit()?;
+
+    debug_msg!("Market migrated to version {}", MarketState::VERSION);
+    Ok(())
+}
+
+/// Bump of the `[seed, market]` PDA, checked to be the address `stored`.
+fn stored_pda_bump(
+    program_id: &Pubkey,
+    seed: &[u8],
+    market: &Pubkey,
+    stored: &Pubkey,
+) -> Result<u8, ProgramError> {
+    let (address, bump) = Pubkey::find_program_address(&[seed, market.as_ref()], program_id);
+    if address != *stored {
+        msg!(
+            "Stored account {} is not the market's PDA {}",
+            stored,
+            address
+        );
+        return Err(ProgramError::InvalidAccountData);
+    }
+    Ok(bump)
+}
+
+/// Grows a first release book towards `OrderBook::LEN` and, once it is
+/// there, converts its orders and writes the discriminator.
+fn migrate_order_book<'a>(
+    payer_info: &AccountInfo<'a>,
+    book_info: &AccountInfo<'a>,
+    system_program_info: &AccountInfo<'a>,
+    market: &Pubkey,
+    side: Side,
+    rent: &Rent,
+) -> ProgramResult {
+    if has_discriminator(book_info, &OrderBook::DISCRIMINATOR) {
+        return Ok(());
+    }
+    if !grow_legacy_account(
+        payer_info,
+        book_info,
+        system_program_info,
+        LEGACY_ORDER_BOOK_LEN,
+        OrderBook::LEN,
+        rent,
+    )? {
+        debug_msg!("Order book {} grown, not converted yet", book_info.key);
+        return Ok(());
+    }
+
+    let mut data = book_info.data.borrow_mut();
+    let tail_start = LEGACY_ORDER_BOOK_LEN - size_of::<LegacyOrderBookTail>();
+    let tail: LegacyOrderBookTail =
+        bytemuck::pod_read_unaligned(&data[tail_start..LEGACY_ORDER_BOOK_LEN]);
+    let count = tail.active_orders_count;
+
+    if tail.market != *market {
+        msg!("Order book belongs to a different market");
+        return Err(ProgramError::InvalidAccountData);
+    }
+    let tail_side = tail.side;
+    if tail_side != side {
+        msg!("Expected the {:?} book, got the {:?} book", side, tail_side);
+        return Err(ProgramError::InvalidAccountData);
+    }
+    if count > MAX_ORDERS as u64 {
+        msg!("Order book holds {} orders", count);
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    // Back to front, as every order moves to an offset at least as high as
+    // its old one and would otherwise overwrite orders not read yet
+    for i in (0..MAX_ORDERS).rev() {
+        let order = if (i as u64) < count {
+            let legacy_start = i * size_of::<LegacyOrder>();
+            let legacy: LegacyOrder = bytemuck::pod_read_unaligned(
+                &data[legacy_start..legacy_start + size_of::<LegacyOrder>()],
+            );
+            Order {
+                owner: legacy.owner,
+                market: legacy.market,
+                timestamp: legacy.timestamp,
+                order_id: legacy.order_id,
+                price: legacy.price,
+                quantity: legacy.quantity,
+                filled_quantity: legacy.filled_quantity,
+                side: legacy.side,
+                order_type: OrderType::Limit,
+                peg_offset: 0,
+                display_quantity: 0,
+                hidden_quantity: 0,
+                // Ids only ever grew, so they keep the orders' time priority
+                sequence: legacy.order_id,
+            }
+        } else {
+            Order::EMPTY
+        };
+        let start = OrderBook::DISCRIMINATOR.len() + i * size_of::<Order>();
+        data[start..start + size_of::<Order>()].copy_from_slice(bytemuck::bytes_of(&order));
+    }
+
+    let book: &mut OrderBook = bytemuck::from_bytes_mut(&mut data[..OrderBook::LEN]);
+    book.discriminator = OrderBook::DISCRIMINATOR;
+    book.market = *market;
+    book.active_orders_count = count;
+    book.side = side;
+    drop(data);
+
+    OrderbookEvent::AccountMigrated {
+        account: *book_info.key,
+        version: 0,
+    }
+    .emit()?;
+
+    debug_msg!("Order book {} migrated", book_info.key);
+    Ok(())
+}
+
+/// Rewrites a first release event queue in the current layout. The first
+/// release never removed events, so only the last `events_to_process` are
+/// still pending; of those only fills are kept, as cancels released their
+/// funds when they were queued.
+fn migrate_market_events<'a>(
+    payer_info: &AccountInfo<'a>,
+    events_info: &AccountInfo<'a>,
+    system_program_info: &AccountInfo<'a>,
+    market: &Pubkey,
+    rent: &Rent,
+) -> ProgramResult {
+    if has_discriminator(events_info, &MarketEvents::DISCRIMINATOR) {
+        return Ok(());
+    }
+    if !grow_legacy_account(
+        payer_info,
+        events_info,
+        system_program_info,
+        LEGACY_MARKET_EVENTS_LEN,
+        MarketEvents::LEN,
+        rent,
+    )? {
+        debug_msg!("Event queue {} grown, not converted yet", events_info.key);
+        return Ok(());
+    }
+
+    let mut data = events_info.data.borrow_mut();
+    let tail_start = LEGACY_MARKET_EVENTS_LEN - size_of::<LegacyMarketEventsTail>();
+    let tail: LegacyMarketEventsTail =
+        bytemuck::pod_read_unaligned(&data[tail_start..LEGACY_MARKET_EVENTS_LEN]);
+
+    if tail.market != *market {
+        msg!("Market events account belongs to a different market");
+        return Err(ProgramError::InvalidAccountData);
+    }
+    let count = tail.count.min(MAX_EVENTS as u64) as usize;
+    let pending_start = count - (tail.events_to_process.min(count as u64) as usize);
+
+    // Back to front for the same reason as the books
+    for i in (pending_start..count).rev() {
+        let legacy_start = i * size_of::<LegacyEvent>();
+        let legacy: LegacyEvent = bytemuck::pod_read_unaligned(
+            &data[legacy_start..legacy_start + size_of::<LegacyEvent>()],
+        );
+        let event = Event {
+            maker: legacy.maker,
+            taker: legacy.taker,
+            timestamp: legacy.timestamp,
+            maker_order_id: legacy.maker_order_id,
+            taker_order_id: 0,
+            quantity: legacy.quantity,
+            price: legacy.price,
+            // The first release released the buyer's quote at the fill price
+            locked_price: legacy.price,
+            event_type: legacy.event_type,
+            side: legacy.side,
+        };
+        let start = MarketEvents::DISCRIMINATOR.len() + i * size_of::<Event>();
+        data[start..start + size_of::<Event>()].copy_from_slice(bytemuck::bytes_of(&event));
+    }
+
+    let market_events: &mut MarketEvents = bytemuck::from_bytes_mut(&mut data[..MarketEvents::LEN]);
+    let mut kept = 0;
+    for i in pending_start..count {
+        let event = market_events.events[i];
+        if event.event_type == EventType::Fill {
+            market_events.events[kept] = event;
+            kept += 1;
+        }
+    }
+    market_events.events[kept..].fill(Event::EMPTY);
+    market_events.discriminator = MarketEvents::DISCRIMINATOR;
+    market_events.market = *market;
+    market_events.count = kept as u64;
+    market_events.seq_num = tail.seq_num;
+    market_events.events_to_process = kept as u64;
+    drop(data);
+
+    OrderbookEvent::AccountMigrated {
+        account: *events_info.key,
+        version: 0,
+    }
+    .emit()?;
+
+    debug_msg!("Event queue migrated with {} pending fills", kept);
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn migrate_user_balance<'a>(
+    program_id: &Pubkey,
+    payer_info: &AccountInfo<'a>,
+    market_info: &AccountInfo<'a>,
+    user_balance_info: &AccountInfo<'a>,
+    system_program_info: &AccountInfo<'a>,
+    account_infos: &[AccountInfo<'a>],
+    [bids, asks, market_events]: [Pubkey; 3],
+    rent: &Rent,
+) -> ProgramResult {
+    let legacy = LegacyUserBalance::try_from_slice(&user_balance_info.data.borrow())?;
+
+    if legacy.market != *market_info.key {
+        msg!("User balance account belongs to a different market");
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    let (user_balance_pda, bump) = Pubkey::find_program_address(
+        &[
+            b"user_balance",
+            legacy.owner.as_ref(),
+            market_info.key.as_ref(),
+        ],
+        program_id,
+    );
+    if user_balance_pda != *user_balance_info.key {
+        msg!("Invalid user balance account");
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    // The first release kept no index of a user's orders, it is rebuilt
+    // from the books
+    let bids_info = find_account(account_infos, &bids, "bids")?;
+    let asks_info = find_account(account_infos, &asks, "asks")?;
+    let events_info = find_account(account_infos, &market_events, "market events")?;
+    let bids_book = OrderBook::load_mut(program_id, bids_info, market_info.key, Side::Buy)?;
+    let asks_book = OrderBook::load_mut(program_id, asks_info, market_info.key, Side::Sell)?;
+    let market_events = MarketEvents::load_mut(program_id, events_info, market_info.key)?;
+
+    let mut open_orders = Vec::new();
+    for book in [&bids_book, &asks_book] {
+        for order in &book.orders[..book.active_orders_count as usize] {
+            if order.owner != legacy.owner {
+                continue;
+            }
+            let order_id = order.order_id;
+            // Fills still queued are settled from the open order too
+            let queued: u64 = market_events.events[..market_events.count as usize]
+                .iter()
+                .filter(|event| event.maker == legacy.owner && { event.maker_order_id } == order_id)
+                .map(|event| event.quantity)
+                .sum();
+            open_orders.push(OpenOrder {
+                order_id,
+                client_order_id: 0,
+                price: order.price,
+                quantity: order.quantity - order.filled_quantity + queued,
+                side: order.side,
+                padding: [0; 7],
+            });
+        }
+    }
+    drop(bids_book);
+    drop(asks_book);
+    drop(market_events);
+
+    if open_orders.len() > MAX_OPEN_ORDERS {
+        msg!(
+            "{} has {} resting orders, only {} are listed",
+            legacy.owner,
+            open_orders.len(),
+            MAX_OPEN_ORDERS
+        );
+        open_orders.truncate(MAX_OPEN_ORDERS);
+    }
+
+    resize_account(
+        payer_info,
+        user_balance_info,
+        system_program_info,
+        UserBalance::LEN,
+        rent,
+    )?;
+
+    let mut user_balance_data = user_balance_info.data.borrow_mut();
+    let user_balance =
+        UserBalance::init(&mut user_balance_data, legacy.owner, legacy.market, bump)?;
+    user_balance.available_base_balance = legacy.available_base_balance;
+    user_balance.available_quote_balance = legacy.available_quote_balance;
+    user_balance.locked_base_balance = legacy.locked_base_balance;
+    user_balance.locked_quote_balance = legacy.locked_quote_balance;
+    user_balance.pending_base_balance = legacy.pending_base_balance;
+    user_balance.pending_quote_balance = legacy.pending_quote_balance;
+    user_balance.open_orders[..open_orders.len()].copy_from_slice(&open_orders);
+    user_balance.open_orders_count = open_orders.len() as u8;
+    drop(user_balance_data);
+
+    OrderbookEvent::AccountMigrated {
+        account: *user_balance_info.key,
+        version: UserBalance::VERSION,
+    }
+    .emit()?;
+
+    debug_msg!("User balance migrated to version {}", UserBalance::VERSION);
+    Ok(())
+}
+
+/// Grows a first release account of `legacy_len` bytes towards `len`, as far
+/// as one instruction may. Returns whether it has reached `len`.
+fn grow_legacy_account<'a>(
+    payer_info: &AccountInfo<'a>,
+    account_info: &AccountInfo<'a>,
+    system_program_info: &AccountInfo<'a>,
+    legacy_len: usize,
+    len: usize,
+    rent: &Rent,
+) -> Result<bool, ProgramError> {
+    let data_len = account_info.data_len();
+    if !(legacy_len..=len).contains(&data_len) {
+        msg!(
+            "Account {} has size {}, expected a legacy size of {}",
+            account_info.key,
+            data_len,
+            legacy_len
+        );
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    let target_len = len.min(data_len + MAX_PERMITTED_DATA_INCREASE);
+    if target_len > data_len {
+        resize_account(
+            payer_info,
+            account_info,
+            system_program_info,
+            target_len,
+            rent,
+        )?;
+    }
+    Ok(target_len == len)
+}
+
+/// Resizes `account_info` to `len` bytes, topping up its rent from the payer.
+fn resize_account<'a>(
+    payer_info: &AccountInfo<'a>,
+    account_info: &AccountInfo<'a>,
+    system_program_info: &AccountInfo<'a>,
+    len: usize,
+    rent: &Rent,
+) -> ProgramResult {
+    let shortfall = rent
+        .minimum_balance(len)
+        .saturating_sub(account_info.lamports());
+    if shortfall > 0 {
+        invoke(
+            &system_instruction::transfer(payer_info.key, account_info.key, shortfall),
+            &[
+                payer_info.clone(),
+                account_info.clone(),
+                system_program_info.clone(),
+            ],
+        )?;
+    }
+
+    account_info.resize(len)
+}
@@ -1,15 +1,34 @@
 pub mod cancel_order;
+pub mod configure_market;
 pub mod consume_events;
-pub mod create_user_account;
 pub mod initialize_market;
+pub mod migrate;
 pub mod place_order;
+pub mod run_auction;
+pub mod set_delegate;
 pub mod settle_balance;
+pub mod trigger_orders;
+pub mod user_balance;
 
-pub use consume_events::process_consume_events;
-pub use create_user_account::{
-    process_create_acc_and_deposit_base_tokens, process_create_acc_and_deposit_quote_tokens,
+pub use cancel_order::process_cancel_order;
+pub use configure_market::{
+    process_configure_order_limit, process_configure_price_band, process_set_market_status,
 };
+pub use consume_events::process_consume_events;
 pub use initialize_market::process_initialize_market;
+pub use migrate::process_migrate;
 pub use place_order::process_place_order;
-pub use settle_balance::process_settle_balance;
-pub use cancel_order::process_cancel_order;
+pub use run_auction::process_run_auction;
+pub use set_delegate::process_set_delegate;
+pub use settle_balance::{
+    process_settle_balance, process_settle_balance_native, process_settle_for,
+};
+pub use trigger_orders::{
+    process_cancel_trigger_order, process_initialize_trigger_book, process_place_trigger_order,
+    process_trigger_orders,
+};
+pub use user_balance::{
+    process_close_user_balance, process_create_acc_and_deposit_base_tokens,
+    process_create_acc_and_deposit_quote_tokens, process_create_user_balance, process_deposit,
+    process_deposit_native, process_withdraw,
+};
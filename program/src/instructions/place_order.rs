@@ -1,25 +1,35 @@
-use borsh::{BorshDeserialize, BorshSerialize};
 use solana_program::{
     account_info::{next_account_info, AccountInfo},
     entrypoint::ProgramResult,
     msg,
-    program::invoke,
+    program::set_return_data,
     program_error::ProgramError,
     pubkey::Pubkey,
     sysvar::{clock::Clock, Sysvar},
 };
-use spl_token::instruction as token_instruction;
 
-use crate::state::{
-    Event, EventType, MarketEvents, MarketState, Order, OrderBook, Side, UserBalance,
+use crate::{
+    context::MarketContext,
+    events::OrderbookEvent,
+    matching::{execute_order, rest_order},
+    state::{
+        exceeds_deviation, MarketStatus, OpenOrder, Order, OrderType, PlaceOrderResult, Side,
+        UserBalance, DELEGATE_PLACE_ORDERS,
+    },
+    token,
 };
 
+#[allow(clippy::too_many_arguments)]
 pub fn process_place_order(
     program_id: &Pubkey,
     accounts: &[AccountInfo],
     side: Side,
     price: u64,
     quantity: u64,
+    peg_offset: Option<i64>,
+    display_quantity: Option<u64>,
+    client_order_id: u64,
+    settle_taker: bool,
 ) -> ProgramResult {
     let account_info_iter = &mut accounts.iter();
 
@@ -36,144 +46,106 @@ pub fn process_place_order(
     let token_program_info = next_account_info(account_info_iter)?;
     let clock_sysvar_info = next_account_info(account_info_iter)?;
 
-    if !spl_token::check_id(token_program_info.key) {
+    if !token::is_token_program(token_program_info.key) {
         msg!("Invalid token program");
         return Err(ProgramError::IncorrectProgramId);
     }
 
-    if !user_info.is_signer {
-        msg!("User must be a signer");
-        return Err(ProgramError::MissingRequiredSignature);
-    }
+    let mut market_state = MarketContext::load_mut(program_id, market_info)?;
+    let mut user_balance = UserBalance::load(program_id, user_balance_info, market_info)?;
 
-    if bids_info.owner != program_id {
-        msg!("Bids account must be owned by this program");
-        return Err(ProgramError::InvalidAccountData);
+    if market_state.status != MarketStatus::Active && market_state.status != MarketStatus::Auction {
+        msg!("Market is not accepting new orders");
+        return Err(ProgramError::Custom(5));
     }
 
-    if asks_info.owner != program_id {
-        msg!("Asks account must be owned by this program");
-        return Err(ProgramError::InvalidAccountData);
-    }
+    // Markets with an oracle configured take it after the clock sysvar
+    let oracle_info = if market_state.oracle != Pubkey::default() {
+        Some(next_account_info(account_info_iter)?)
+    } else {
+        None
+    };
 
-    if market_events_info.owner != program_id {
-        msg!("Market events account must be owned by this program");
-        return Err(ProgramError::InvalidAccountData);
+    // Native SOL traders deposit lamports and hold no wrapped SOL account
+    if market_state.quote_mint != spl_token::native_mint::id() {
+        token::load_token_account(
+            user_quote_token_info,
+            &market_state.quote_mint,
+            &user_balance.owner,
+        )?;
     }
-    let user_account_seeds = &[
-        b"user_balance",
-        user_info.key.as_ref(),
-        market_info.key.as_ref(),
-    ];
-    let (user_balance_pda, _user_balance_bump) =
-        Pubkey::find_program_address(user_account_seeds, program_id);
-
-    if user_balance_pda != *user_balance_info.key {
-        msg!("Invalid user account. Expected PDA: {}", user_balance_pda);
-        return Err(ProgramError::InvalidAccountData);
+    if market_state.base_mint != spl_token::native_mint::id() {
+        token::load_token_account(
+            user_base_token_info,
+            &market_state.base_mint,
+            &user_balance.owner,
+        )?;
     }
 
-    let mut market_state = MarketState::try_from_slice(&market_info.data.borrow())?;
-    let mut user_balance = UserBalance::try_from_slice(&user_balance_info.data.borrow())?;
-
-    let (market_pda, _) = Pubkey::find_program_address(
-        &[
-            b"market",
-            market_state.base_mint.as_ref(),
-            market_state.quote_mint.as_ref(),
-        ],
-        program_id,
-    );
+    // Funds never move here, but the vaults still have to be this market's
+    market_state.load_base_vault(market_base_vault_info)?;
+    market_state.load_quote_vault(market_quote_vault_info)?;
 
-    if *market_info.key != market_pda {
-        msg!("Invalid market account");
-        return Err(ProgramError::InvalidAccountData);
-    }
-    if user_balance.owner != *user_info.key {
-        msg!("User balance account does not belong to signer");
-        return Err(ProgramError::InvalidAccountData);
-    }
+    let clock = Clock::from_account_info(clock_sysvar_info)?;
+    user_balance.authorize_trader(user_info, DELEGATE_PLACE_ORDERS, clock.unix_timestamp)?;
 
-    if user_balance.market != *market_info.key {
-        msg!("User balance account does not belong to this market");
-        return Err(ProgramError::InvalidAccountData);
-    }
+    let oracle_price = match oracle_info {
+        Some(oracle_info) => Some(market_state.oracle_price(clock.unix_timestamp, oracle_info)?),
+        None => None,
+    };
 
-    msg!("Checks on user quote token acc");
-    {
-        let user_quote_token_data = user_quote_token_info.data.borrow();
-        if user_quote_token_data.len() < 32 {
-            msg!("Invalid user quote token account data");
-            return Err(ProgramError::InvalidAccountData);
+    let order_type = if peg_offset.is_some() {
+        if oracle_price.is_none() {
+            msg!("Pegged orders require a market oracle");
+            return Err(ProgramError::InvalidArgument);
         }
-        let mut mint_bytes = [0u8; 32];
-        mint_bytes.copy_from_slice(&user_quote_token_data[0..32]);
-        let user_token_mint = Pubkey::new_from_array(mint_bytes);
-        if user_token_mint != market_state.quote_mint {
-            msg!(
-                "User quote token account mint mismatch. Expected: {}, Got: {}",
-                market_state.quote_mint,
-                user_token_mint
-            );
-            return Err(ProgramError::InvalidAccountData);
+        OrderType::Pegged
+    } else if let Some(display_quantity) = display_quantity {
+        if display_quantity == 0 || display_quantity > quantity {
+            msg!("Display quantity must be between 1 and the order quantity");
+            return Err(ProgramError::InvalidInstructionData);
         }
-    }
+        OrderType::Iceberg
+    } else {
+        OrderType::Limit
+    };
 
-    msg!("Checks on user base token acc");
-    {
-        let user_base_token_data = user_base_token_info.data.borrow();
-        if user_base_token_data.len() < 32 {
-            msg!("Invalid user base token account data");
-            return Err(ProgramError::InvalidAccountData);
-        }
-        let mut mint_bytes = [0u8; 32];
-        mint_bytes.copy_from_slice(&user_base_token_data[0..32]);
-        let user_token_mint = Pubkey::new_from_array(mint_bytes);
-        if user_token_mint != market_state.base_mint {
-            msg!(
-                "User base token account mint mismatch. Expected: {}, Got: {}",
-                market_state.base_mint,
-                user_token_mint
-            );
-            return Err(ProgramError::InvalidAccountData);
+    // `price` is the hard limit for pegged orders, matching happens at the pegged price
+    let taker_order = Order {
+        order_id: market_state.next_order_id,
+        owner: user_balance.owner,
+        market: *market_info.key,
+        side,
+        price,
+        quantity,
+        filled_quantity: 0,
+        timestamp: clock.unix_timestamp,
+        order_type,
+        peg_offset: peg_offset.unwrap_or(0),
+        display_quantity: display_quantity.unwrap_or(0),
+        hidden_quantity: 0,
+        sequence: 0,
+    };
+    let taker_price = taker_order.effective_price(oracle_price);
+
+    if market_state.price_band_bps > 0 {
+        if let Some(reference_price) =
+            market_state.reference_price(clock.unix_timestamp, oracle_price)
+        {
+            if exceeds_deviation(taker_price, reference_price, market_state.price_band_bps) {
+                msg!(
+                    "Price {} outside band of {} bps around reference {}",
+                    taker_price,
+                    market_state.price_band_bps,
+                    reference_price
+                );
+                return Err(ProgramError::Custom(4));
+            }
         }
     }
 
-    let base_vault_seeds = &[b"base_vault", market_pda.as_ref()];
-    let (base_vault_pda, _base_vault_bump) =
-        Pubkey::find_program_address(base_vault_seeds, program_id);
-
-    if market_state.base_vault != base_vault_pda {
-        msg!(
-            "Invalid base vault account. Expected PDA: {}",
-            base_vault_pda
-        );
-        return Err(ProgramError::InvalidAccountData);
-    }
-
-    let quote_vault_seeds = &[b"quote_vault", market_pda.as_ref()];
-    let (quote_vault_pda, _quote_vault_bump) =
-        Pubkey::find_program_address(quote_vault_seeds, program_id);
-
-    if market_state.quote_vault != quote_vault_pda {
-        msg!(
-            "Invalid quote vault account. Expected PDA: {}",
-            quote_vault_pda
-        );
-        return Err(ProgramError::InvalidAccountData);
-    }
-
-    let clock = Clock::from_account_info(clock_sysvar_info)?;
-    let mut bids_data = bids_info.data.borrow_mut();
-    let mut asks_data = asks_info.data.borrow_mut();
-    let mut bids: &mut OrderBook = bytemuck::from_bytes_mut(&mut bids_data);
-    let mut asks: &mut OrderBook = bytemuck::from_bytes_mut(&mut asks_data);
-
-    let (taker_book, maker_book) = if side == Side::Buy {
-        (&mut bids, &mut asks)
-    } else {
-        (&mut asks, &mut bids)
-    };
+    let mut bids = market_state.load_bids(bids_info)?;
+    let mut asks = market_state.load_asks(asks_info)?;
 
     let required_base = if side == Side::Sell { quantity } else { 0 };
     let required_quote = if side == Side::Buy {
@@ -189,142 +161,138 @@ pub fn process_place_order(
         return Err(ProgramError::InsufficientFunds);
     }
 
-    if side == Side::Buy {
-        msg!(
-            "Transferring {} quote tokens to market vault",
-            required_quote
-        );
-
-        let transfer_quote_ix = token_instruction::transfer(
-            token_program_info.key,
-            user_quote_token_info.key,
-            market_quote_vault_info.key,
-            user_info.key,
-            &[],
-            required_quote,
-        )?;
-
-        invoke(
-            &transfer_quote_ix,
-            &[
-                user_quote_token_info.clone(),
-                market_quote_vault_info.clone(),
-                user_info.clone(),
-                token_program_info.clone(),
-            ],
-        )?;
-
-        msg!("Quote tokens transferred successfully");
-    } else {
-        msg!("Transferring {} base tokens to market vault", required_base);
-
-        let transfer_base_ix = token_instruction::transfer(
-            token_program_info.key,
-            user_base_token_info.key,
-            market_base_vault_info.key,
-            user_info.key,
-            &[],
-            required_base,
-        )?;
-
-        invoke(
-            &transfer_base_ix,
-            &[
-                user_base_token_info.clone(),
-                market_base_vault_info.clone(),
-                user_info.clone(),
-                token_program_info.clone(),
-            ],
-        )?;
-
-        msg!("Base tokens transferred successfully");
-    }
-
     user_balance.available_base_balance -= required_base;
     user_balance.locked_base_balance += required_base;
     user_balance.available_quote_balance -= required_quote;
     user_balance.locked_quote_balance += required_quote;
 
-    let mut maker_events_data = market_events_info.data.borrow_mut();
-    let maker_events: &mut MarketEvents = bytemuck::from_bytes_mut(&mut maker_events_data);
-    let mut remaining_quantity = quantity;
-    let mut orders_to_remove = Vec::new();
-
-    for i in 0..maker_book.orders.len() {
-        let maker_order = &mut maker_book.orders[i];
-        if remaining_quantity == 0 {
-            break;
-        }
+    let mut market_events = market_state.load_events(market_events_info)?;
+
+    OrderbookEvent::OrderPlaced {
+        market: *market_info.key,
+        owner: user_balance.owner,
+        order_id: taker_order.order_id,
+        side,
+        order_type,
+        price,
+        quantity,
+        timestamp: clock.unix_timestamp,
+    }
+    .emit()?;
 
-        let price_match = if side == Side::Buy {
-            price >= maker_order.price
+    let result = if market_state.status == MarketStatus::Auction {
+        // Collected for the auction, crossing orders are matched by `RunAuction`
+        let book = if side == Side::Buy {
+            &mut bids
         } else {
-            price <= maker_order.price
+            &mut asks
         };
-
-        if price_match {
-            let fill_quantity = std::cmp::min(
-                remaining_quantity,
-                maker_order.quantity - maker_order.filled_quantity,
-            );
-
-            if fill_quantity > 0 {
-                maker_order.filled_quantity += fill_quantity;
-                remaining_quantity -= fill_quantity;
-
-                let maker_fill_event = Event {
-                    event_type: EventType::Fill,
-                    maker: maker_order.owner,
-                    taker: *user_info.key,
-                    maker_order_id: maker_order.order_id,
-                    quantity: fill_quantity,
-                    price: maker_order.price,
-                    timestamp: clock.unix_timestamp,
-                    side,
-                };
-
-                maker_events.add_event(maker_fill_event)?;
-
-                let price = maker_order.price;
-                msg!("Filled {} quantity at {} price", fill_quantity, price);
-
-                if maker_order.filled_quantity == maker_order.quantity {
-                    orders_to_remove.push(i);
-                }
-            }
+        rest_order(
+            &mut market_state,
+            book,
+            &mut market_events,
+            taker_order,
+            oracle_price,
+        )?;
+        debug_msg!(
+            "Order queued for auction: {} quantity at {} price",
+            quantity,
+            taker_price
+        );
+        PlaceOrderResult {
+            order_id: taker_order.order_id,
+            resting_quantity: quantity,
+            ..PlaceOrderResult::default()
         }
+    } else {
+        execute_order(
+            &mut market_state,
+            &mut bids,
+            &mut asks,
+            &mut market_events,
+            taker_order,
+            oracle_price,
+            settle_taker,
+            false,
+        )?
+    };
+
+    if settle_taker && result.filled_quantity > 0 {
+        settle_taker_fills(&mut user_balance, side, price, required_quote, &result);
     }
 
-    for &index in orders_to_remove.iter().rev() {
-        maker_book.remove_order(index)?;
+    // A remainder cancelled by the circuit breaker never rests, its funds go
+    // back at once. A settled buy already got back all quote beyond what
+    // its fills and resting remainder need.
+    let cancelled_quantity = quantity - result.filled_quantity - result.resting_quantity;
+    if cancelled_quantity > 0 {
+        if side == Side::Sell {
+            user_balance.locked_base_balance -= cancelled_quantity;
+            user_balance.available_base_balance += cancelled_quantity;
+        } else if !settle_taker {
+            let cancelled_quote = (cancelled_quantity * price) / 1_000_000_000;
+            user_balance.locked_quote_balance -= cancelled_quote;
+            user_balance.available_quote_balance += cancelled_quote;
+        }
     }
 
-    if remaining_quantity > 0 {
-        let new_order = Order {
-            order_id: market_state.next_order_id,
-            owner: *user_info.key,
-            market: *market_info.key,
-            side,
-            price,
-            quantity: remaining_quantity,
-            filled_quantity: 0,
-            timestamp: clock.unix_timestamp,
-        };
-        taker_book.add_order(new_order)?;
+    // Resting orders advance next_order_id themselves, a fully filled taker's id is still reported
+    if result.resting_quantity == 0 {
         market_state.next_order_id += 1;
-
-        msg!(
-            "Added remaining order: {} quantity at {} price",
-            remaining_quantity,
-            price
-        );
     } else {
-        msg!("Order fully filled, no remaining quantity");
+        // Listed at its full quantity unless the taker fills were settled above,
+        // otherwise `ConsumeEvents` reduces it by those fills
+        user_balance.add_open_order(
+            OpenOrder {
+                order_id: taker_order.order_id,
+                client_order_id,
+                price,
+                quantity: if settle_taker {
+                    result.resting_quantity
+                } else {
+                    quantity
+                },
+                side,
+                padding: [0; 7],
+            },
+            market_state.max_orders_per_user,
+        )?;
     }
 
-    user_balance.serialize(&mut *user_balance_info.data.borrow_mut())?;
-    market_state.serialize(&mut *market_info.data.borrow_mut())?;
+    set_return_data(&borsh::to_vec(&result)?);
 
-    msg!("Order placement completed successfully");
+    debug_msg!("Order placement completed successfully");
     Ok(())
 }
+
+/// Settles the taker's side of `result` into `user_balance`: the filled side
+/// moves from locked to pending, and for a buy the quote locked beyond what
+/// the fills cost and the resting remainder needs is released.
+fn settle_taker_fills(
+    user_balance: &mut UserBalance,
+    side: Side,
+    price: u64,
+    required_quote: u64,
+    result: &PlaceOrderResult,
+) {
+    if side == Side::Buy {
+        let resting_quote = (result.resting_quantity * price) / 1_000_000_000;
+        let unlocked = required_quote - resting_quote;
+        user_balance.locked_quote_balance -= unlocked;
+        user_balance.available_quote_balance += unlocked - result.quote_quantity;
+        user_balance.pending_base_balance += result.filled_quantity;
+        debug_msg!(
+            "Taker bought: -{} quote, +{} base pending",
+            result.quote_quantity,
+            result.filled_quantity
+        );
+    } else {
+        user_balance.locked_base_balance -= result.filled_quantity;
+        user_balance.pending_quote_balance += result.quote_quantity;
+        debug_msg!(
+            "Taker sold: -{} base, +{} quote pending",
+            result.filled_quantity,
+            result.quote_quantity
+        );
+    }
+}
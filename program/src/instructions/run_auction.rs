@@ -0,0 +1,81 @@
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    entrypoint::ProgramResult,
+    msg,
+    program_error::ProgramError,
+    pubkey::Pubkey,
+    sysvar::{clock::Clock, Sysvar},
+};
+
+use crate::{
+    events::OrderbookEvent, instructions::configure_market::load_market_for_authority,
+    matching::run_auction, state::MarketStatus,
+};
+
+/// Closes the call auction: fills every crossing order at the single price
+/// that maximises volume, then opens the market for continuous trading.
+pub fn process_run_auction(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+
+    let authority_info = next_account_info(account_info_iter)?;
+    let market_info = next_account_info(account_info_iter)?;
+    let bids_info = next_account_info(account_info_iter)?;
+    let asks_info = next_account_info(account_info_iter)?;
+    let market_events_info = next_account_info(account_info_iter)?;
+    let clock_sysvar_info = next_account_info(account_info_iter)?;
+
+    let mut market_state = load_market_for_authority(program_id, authority_info, market_info)?;
+
+    let mut bids = market_state.load_bids(bids_info)?;
+    let mut asks = market_state.load_asks(asks_info)?;
+    let mut market_events = market_state.load_events(market_events_info)?;
+
+    if market_state.status != MarketStatus::Auction {
+        msg!("Market is not in auction");
+        return Err(ProgramError::Custom(5));
+    }
+
+    let oracle_info = if market_state.oracle != Pubkey::default() {
+        Some(next_account_info(account_info_iter)?)
+    } else {
+        None
+    };
+
+    let clock = Clock::from_account_info(clock_sysvar_info)?;
+    let oracle_price = match oracle_info {
+        Some(oracle_info) => Some(market_state.oracle_price(clock.unix_timestamp, oracle_info)?),
+        None => None,
+    };
+
+    match run_auction(
+        &mut market_state,
+        &mut bids,
+        &mut asks,
+        &mut market_events,
+        clock.unix_timestamp,
+        oracle_price,
+    )? {
+        Some((price, quantity)) => OrderbookEvent::AuctionCleared {
+            market: *market_info.key,
+            price,
+            quantity,
+            timestamp: clock.unix_timestamp,
+        }
+        .emit()?,
+        None => debug_msg!("Book does not cross, nothing to fill"),
+    }
+
+    market_state.status = MarketStatus::Active;
+    // The clearing price is the first reference for the breaker, not a move from an older one
+    market_state.circuit_breaker_price = 0;
+    market_state.circuit_breaker_timestamp = 0;
+
+    OrderbookEvent::MarketStatusChanged {
+        market: *market_info.key,
+        status: MarketStatus::Active,
+    }
+    .emit()?;
+
+    debug_msg!("Auction complete, market is now in continuous trading");
+    Ok(())
+}
@@ -0,0 +1,66 @@
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    entrypoint::ProgramResult,
+    msg,
+    program_error::ProgramError,
+    pubkey::Pubkey,
+};
+
+use crate::{
+    events::OrderbookEvent,
+    state::{UserBalance, DELEGATE_CANCEL_ORDERS, DELEGATE_PLACE_ORDERS},
+};
+
+/// Grants `delegate` the trading rights in `permissions` until `expiry`.
+/// Passing `Pubkey::default()` or zero permissions revokes the delegate.
+pub fn process_set_delegate(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    delegate: Pubkey,
+    expiry: i64,
+    permissions: u8,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+
+    let owner_info = next_account_info(account_info_iter)?;
+    let user_balance_info = next_account_info(account_info_iter)?;
+    let market_info = next_account_info(account_info_iter)?;
+
+    if !owner_info.is_signer {
+        msg!("Owner must be a signer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    let mut user_balance = UserBalance::load(program_id, user_balance_info, market_info)?;
+
+    if user_balance.owner != *owner_info.key {
+        msg!("Only the owner can set a delegate");
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    if permissions & !(DELEGATE_PLACE_ORDERS | DELEGATE_CANCEL_ORDERS) != 0 {
+        msg!("Unknown delegate permissions {:#04x}", permissions);
+        return Err(ProgramError::InvalidInstructionData);
+    }
+
+    if delegate == *owner_info.key {
+        msg!("Owner cannot be its own delegate");
+        return Err(ProgramError::InvalidInstructionData);
+    }
+
+    user_balance.delegate = delegate;
+    user_balance.delegate_expiry = expiry;
+    user_balance.delegate_permissions = permissions;
+
+    OrderbookEvent::DelegateSet {
+        market: *market_info.key,
+        owner: user_balance.owner,
+        delegate,
+        expiry,
+        permissions,
+    }
+    .emit()?;
+
+    debug_msg!("Delegate {} set until {}", delegate, expiry);
+    Ok(())
+}
@@ -1,15 +1,108 @@
-use borsh::{BorshDeserialize, BorshSerialize};
 use solana_program::{
     account_info::{next_account_info, AccountInfo},
     entrypoint::ProgramResult,
     msg,
-    program::invoke_signed,
+    program::{invoke, invoke_signed},
     program_error::ProgramError,
+    program_pack::Pack,
     pubkey::Pubkey,
+    rent::Rent,
+    sysvar::Sysvar,
 };
-use spl_token::instruction as token_instruction;
+use solana_system_interface::{instruction as system_instruction, program as system_program};
+use spl_token::{native_mint, state::Account};
 
-use crate::state::{MarketState, UserBalance};
+use crate::{
+    context::{MarketContext, MarketVault},
+    events::OrderbookEvent,
+    state::{MarketState, MarketToken, UserBalance},
+    token,
+};
+
+/// Pays `amount` out of a market vault, signed by the market PDA.
+fn transfer_from_vault<'a>(
+    vault: &MarketVault<'_, 'a>,
+    token_program_info: &AccountInfo<'a>,
+    mint_info: &AccountInfo<'a>,
+    user_token_info: &AccountInfo<'a>,
+    market_authority_info: &AccountInfo<'a>,
+    market_seeds: &[&[u8]],
+    amount: u64,
+) -> ProgramResult {
+    let market_token = &vault.token;
+    if *mint_info.key != market_token.mint {
+        msg!(
+            "Mint mismatch. Expected: {}, Got: {}",
+            market_token.mint,
+            mint_info.key
+        );
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    if *token_program_info.key != market_token.token_program {
+        msg!(
+            "Token program mismatch. Expected: {}, Got: {}",
+            market_token.token_program,
+            token_program_info.key
+        );
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    token::transfer_checked(
+        token_program_info,
+        vault.info,
+        mint_info,
+        user_token_info,
+        market_authority_info,
+        amount,
+        market_token.decimals,
+        &[market_seeds],
+    )
+}
+
+/// Validates the user's token account for a payout, first creating it as the
+/// user's associated token account, funded by `payer`, when it does not exist.
+#[allow(clippy::too_many_arguments)]
+fn prepare_payout_account<'a>(
+    market_token: &MarketToken,
+    payer_info: &AccountInfo<'a>,
+    user_info: &AccountInfo<'a>,
+    user_token_info: &AccountInfo<'a>,
+    mint_info: &AccountInfo<'a>,
+    token_program_info: &AccountInfo<'a>,
+    system_program_info: &AccountInfo<'a>,
+    associated_token_program_info: &AccountInfo<'a>,
+) -> ProgramResult {
+    if *mint_info.key != market_token.mint {
+        msg!(
+            "Mint mismatch. Expected: {}, Got: {}",
+            market_token.mint,
+            mint_info.key
+        );
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    if *token_program_info.key != market_token.token_program {
+        msg!(
+            "Token program mismatch. Expected: {}, Got: {}",
+            market_token.token_program,
+            token_program_info.key
+        );
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    token::create_associated_token_account_if_missing(
+        payer_info,
+        user_info,
+        user_token_info,
+        mint_info,
+        token_program_info,
+        system_program_info,
+        associated_token_program_info,
+    )?;
+    token::load_token_account(user_token_info, &market_token.mint, user_info.key)?;
+    Ok(())
+}
 
 pub fn process_settle_balance(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
     let account_info_iter = &mut accounts.iter();
@@ -23,91 +116,63 @@ pub fn process_settle_balance(program_id: &Pubkey, accounts: &[AccountInfo]) ->
     let market_base_vault_info = next_account_info(account_info_iter)?;
     let market_quote_vault_info = next_account_info(account_info_iter)?;
     let token_program_info = next_account_info(account_info_iter)?;
+    let base_mint_info = next_account_info(account_info_iter)?;
+    let quote_mint_info = next_account_info(account_info_iter)?;
+    let system_program_info = next_account_info(account_info_iter)?;
+    let associated_token_program_info = next_account_info(account_info_iter)?;
+    // Only needed when the quote mint lives in the other token program
+    let quote_token_program_info =
+        next_account_info(account_info_iter).unwrap_or(token_program_info);
 
     if !user_info.is_signer {
         msg!("User must be a signer");
         return Err(ProgramError::MissingRequiredSignature);
     }
 
-    let market_state = MarketState::try_from_slice(&market_info.data.borrow())?;
-    let mut user_balance = UserBalance::try_from_slice(&user_balance_info.data.borrow())?;
+    let market_state = MarketContext::load(program_id, market_info)?;
+    let base_vault = market_state.load_base_vault(market_base_vault_info)?;
+    let quote_vault = market_state.load_quote_vault(market_quote_vault_info)?;
+    let mut user_balance = UserBalance::load(program_id, user_balance_info, market_info)?;
 
     if user_balance.owner != *user_info.key {
         msg!("User balance account does not belong to signer");
         return Err(ProgramError::InvalidAccountData);
     }
 
-    if user_balance.market != *market_info.key {
-        msg!("User balance account does not belong to this market");
-        return Err(ProgramError::InvalidAccountData);
-    }
-
-    if *market_base_vault_info.key != market_state.base_vault {
-        msg!(
-            "Market base vault mismatch. Expected: {}, Got: {}",
-            market_state.base_vault,
-            market_base_vault_info.key
-        );
-        return Err(ProgramError::InvalidAccountData);
-    }
-
-    if *market_quote_vault_info.key != market_state.quote_vault {
-        msg!(
-            "Market quote vault mismatch. Expected: {}, Got: {}",
-            market_state.quote_vault,
-            market_quote_vault_info.key
-        );
-        return Err(ProgramError::InvalidAccountData);
-    }
-
+    let settled_base = user_balance.pending_base_balance;
+    let settled_quote = user_balance.pending_quote_balance;
     let settle_base_tokens = user_balance.pending_base_balance > 0;
     let settle_quote_tokens = user_balance.pending_quote_balance > 0;
 
     if !settle_base_tokens && !settle_quote_tokens {
-        msg!("No tokens to settle");
+        debug_msg!("No tokens to settle");
         return Ok(());
     }
 
     if settle_base_tokens {
-        let user_base_token_data = user_base_token_info.try_borrow_data()?;
-        if user_base_token_data.len() < 32 {
-            msg!("Invalid user base token account data");
-            return Err(ProgramError::InvalidAccountData);
-        }
-
-        let mut mint_bytes = [0u8; 32];
-        mint_bytes.copy_from_slice(&user_base_token_data[0..32]);
-        let user_base_token_mint = Pubkey::new_from_array(mint_bytes);
-
-        if user_base_token_mint != market_state.base_mint {
-            msg!(
-                "User base token account mint mismatch. Expected: {}, Got: {}",
-                market_state.base_mint,
-                user_base_token_mint
-            );
-            return Err(ProgramError::InvalidAccountData);
-        }
+        prepare_payout_account(
+            &base_vault.token,
+            user_info,
+            user_info,
+            user_base_token_info,
+            base_mint_info,
+            token_program_info,
+            system_program_info,
+            associated_token_program_info,
+        )?;
     }
 
     if settle_quote_tokens {
-        let user_quote_token_data = user_quote_token_info.try_borrow_data()?;
-        if user_quote_token_data.len() < 32 {
-            msg!("Invalid user quote token account data");
-            return Err(ProgramError::InvalidAccountData);
-        }
-
-        let mut mint_bytes = [0u8; 32];
-        mint_bytes.copy_from_slice(&user_quote_token_data[0..32]);
-        let user_quote_token_mint = Pubkey::new_from_array(mint_bytes);
-
-        if user_quote_token_mint != market_state.quote_mint {
-            msg!(
-                "User quote token account mint mismatch. Expected: {}, Got: {}",
-                market_state.quote_mint,
-                user_quote_token_mint
-            );
-            return Err(ProgramError::InvalidAccountData);
-        }
+        prepare_payout_account(
+            &quote_vault.token,
+            user_info,
+            user_info,
+            user_quote_token_info,
+            quote_mint_info,
+            quote_token_program_info,
+            system_program_info,
+            associated_token_program_info,
+        )?;
     }
 
     let market_seeds = &[
@@ -117,82 +182,399 @@ pub fn process_settle_balance(program_id: &Pubkey, accounts: &[AccountInfo]) ->
         &[market_state.bump],
     ];
 
-    let (expected_market_authority, _) = Pubkey::find_program_address(
-        &[
-            b"market",
-            market_state.base_mint.as_ref(),
-            market_state.quote_mint.as_ref(),
-        ],
-        program_id,
-    );
-
-    if *market_authority_info.key != expected_market_authority {
+    if market_authority_info.key != market_info.key {
         msg!("Invalid market authority");
         return Err(ProgramError::InvalidAccountData);
     }
 
     if settle_base_tokens {
-        msg!("Settling {} base tokens", user_balance.pending_base_balance);
-        let transfer_base_ix = token_instruction::transfer(
-            token_program_info.key,
-            market_base_vault_info.key,
-            user_base_token_info.key,
-            market_authority_info.key,
-            &[],
+        debug_msg!("Settling {} base tokens", user_balance.pending_base_balance);
+        transfer_from_vault(
+            &base_vault,
+            token_program_info,
+            base_mint_info,
+            user_base_token_info,
+            market_authority_info,
+            market_seeds,
             user_balance.pending_base_balance,
         )?;
 
-        invoke_signed(
-            &transfer_base_ix,
-            &[
-                market_base_vault_info.clone(),
-                user_base_token_info.clone(),
-                market_authority_info.clone(),
-                token_program_info.clone(),
-            ],
-            &[market_seeds],
-        )?;
-
         user_balance.pending_base_balance = 0;
-        msg!("Base tokens settled successfully");
+        debug_msg!("Base tokens settled successfully");
     } else {
-        msg!("No base tokens to settle");
+        debug_msg!("No base tokens to settle");
     }
 
     if settle_quote_tokens {
-        msg!(
+        debug_msg!(
             "Settling {} quote tokens",
             user_balance.pending_quote_balance
         );
 
-        let transfer_quote_ix = token_instruction::transfer(
-            token_program_info.key,
-            market_quote_vault_info.key,
-            user_quote_token_info.key,
-            market_authority_info.key,
-            &[],
+        transfer_from_vault(
+            &quote_vault,
+            quote_token_program_info,
+            quote_mint_info,
+            user_quote_token_info,
+            market_authority_info,
+            market_seeds,
             user_balance.pending_quote_balance,
         )?;
 
-        invoke_signed(
-            &transfer_quote_ix,
+        user_balance.pending_quote_balance = 0;
+        debug_msg!("Quote tokens settled successfully");
+    } else {
+        debug_msg!("No quote tokens to settle");
+    }
+
+    OrderbookEvent::Withdraw {
+        market: *market_info.key,
+        owner: user_balance.owner,
+        base_amount: settled_base,
+        quote_amount: settled_quote,
+    }
+    .emit()?;
+
+    debug_msg!("Settlement completed successfully");
+    Ok(())
+}
+
+/// Pays wrapped SOL out of `vault` to the user's wallet as lamports:
+/// the `["native_unwrap", market]` account receives the tokens and is closed
+/// to the user in the same instruction.
+#[allow(clippy::too_many_arguments)]
+fn unwrap_to_user<'a>(
+    program_id: &Pubkey,
+    vault: &MarketVault<'_, 'a>,
+    token_program_info: &AccountInfo<'a>,
+    mint_info: &AccountInfo<'a>,
+    unwrap_info: &AccountInfo<'a>,
+    user_info: &AccountInfo<'a>,
+    market_info: &AccountInfo<'a>,
+    system_program_info: &AccountInfo<'a>,
+    market_seeds: &[&[u8]],
+    unwrap_bump: u8,
+    amount: u64,
+) -> ProgramResult {
+    let unwrap_pda =
+        MarketState::market_pda(program_id, b"native_unwrap", market_info.key, unwrap_bump)?;
+    if *unwrap_info.key != unwrap_pda {
+        msg!("Invalid unwrap account. Expected PDA: {}", unwrap_pda);
+        return Err(ProgramError::InvalidAccountData);
+    }
+    let unwrap_seeds: &[&[u8]] = &[b"native_unwrap", market_info.key.as_ref(), &[unwrap_bump]];
+
+    // Allocate and assign rather than create, so lamports sent to the PDA
+    // beforehand cannot block payouts
+    let required = Rent::get()?
+        .minimum_balance(Account::LEN)
+        .saturating_sub(unwrap_info.lamports());
+    if required > 0 {
+        invoke(
+            &system_instruction::transfer(user_info.key, unwrap_info.key, required),
             &[
-                market_quote_vault_info.clone(),
-                user_quote_token_info.clone(),
-                market_authority_info.clone(),
-                token_program_info.clone(),
+                user_info.clone(),
+                unwrap_info.clone(),
+                system_program_info.clone(),
             ],
-            &[market_seeds],
         )?;
+    }
+    invoke_signed(
+        &system_instruction::allocate(unwrap_info.key, Account::LEN as u64),
+        &[unwrap_info.clone(), system_program_info.clone()],
+        &[unwrap_seeds],
+    )?;
+    invoke_signed(
+        &system_instruction::assign(unwrap_info.key, token_program_info.key),
+        &[unwrap_info.clone(), system_program_info.clone()],
+        &[unwrap_seeds],
+    )?;
 
-        user_balance.pending_quote_balance = 0;
-        msg!("Quote tokens settled successfully");
-    } else {
-        msg!("No quote tokens to settle");
+    token::initialize_account(token_program_info, unwrap_info, mint_info, market_info.key)?;
+    transfer_from_vault(
+        vault,
+        token_program_info,
+        mint_info,
+        unwrap_info,
+        market_info,
+        market_seeds,
+        amount,
+    )?;
+    token::close_account(
+        token_program_info,
+        unwrap_info,
+        user_info,
+        market_info,
+        &[market_seeds],
+    )
+}
+
+/// `SettleBalance` for markets listing the native mint: the SOL side is paid
+/// to the user's wallet, the other side to `user_token_info`.
+pub fn process_settle_balance_native(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+
+    let user_info = next_account_info(account_info_iter)?;
+    let user_balance_info = next_account_info(account_info_iter)?;
+    let market_info = next_account_info(account_info_iter)?;
+    let user_token_info = next_account_info(account_info_iter)?;
+    let base_vault_info = next_account_info(account_info_iter)?;
+    let quote_vault_info = next_account_info(account_info_iter)?;
+    let token_program_info = next_account_info(account_info_iter)?;
+    let base_mint_info = next_account_info(account_info_iter)?;
+    let quote_mint_info = next_account_info(account_info_iter)?;
+    let unwrap_info = next_account_info(account_info_iter)?;
+    let system_program_info = next_account_info(account_info_iter)?;
+    let associated_token_program_info = next_account_info(account_info_iter)?;
+    // Only needed when the quote mint lives in the other token program
+    let quote_token_program_info =
+        next_account_info(account_info_iter).unwrap_or(token_program_info);
+
+    if !user_info.is_signer {
+        msg!("User must be a signer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    if !system_program::check_id(system_program_info.key) {
+        msg!("Invalid system program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let market_state = MarketContext::load(program_id, market_info)?;
+    let base_vault = market_state.load_base_vault(base_vault_info)?;
+    let quote_vault = market_state.load_quote_vault(quote_vault_info)?;
+    let mut user_balance = UserBalance::load(program_id, user_balance_info, market_info)?;
+
+    if user_balance.owner != *user_info.key {
+        msg!("User balance account does not belong to signer");
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    if market_state.base_mint != native_mint::id() && market_state.quote_mint != native_mint::id() {
+        msg!("Market does not trade native SOL");
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    let market_seeds: &[&[u8]] = &[
+        b"market",
+        market_state.base_mint.as_ref(),
+        market_state.quote_mint.as_ref(),
+        &[market_state.bump],
+    ];
+
+    let settled_base = user_balance.pending_base_balance;
+    let settled_quote = user_balance.pending_quote_balance;
+    let sides = [
+        (
+            &base_vault,
+            base_mint_info,
+            token_program_info,
+            settled_base,
+        ),
+        (
+            &quote_vault,
+            quote_mint_info,
+            quote_token_program_info,
+            settled_quote,
+        ),
+    ];
+
+    for (vault, mint_info, side_token_program_info, amount) in sides {
+        if amount == 0 {
+            continue;
+        }
+
+        if vault.token.mint == native_mint::id() {
+            debug_msg!("Unwrapping {} lamports to the user", amount);
+            unwrap_to_user(
+                program_id,
+                vault,
+                side_token_program_info,
+                mint_info,
+                unwrap_info,
+                user_info,
+                market_info,
+                system_program_info,
+                market_seeds,
+                market_state.native_unwrap_bump,
+                amount,
+            )?;
+        } else {
+            prepare_payout_account(
+                &vault.token,
+                user_info,
+                user_info,
+                user_token_info,
+                mint_info,
+                side_token_program_info,
+                system_program_info,
+                associated_token_program_info,
+            )?;
+
+            debug_msg!("Settling {} tokens", amount);
+            transfer_from_vault(
+                vault,
+                side_token_program_info,
+                mint_info,
+                user_token_info,
+                market_info,
+                market_seeds,
+                amount,
+            )?;
+        }
+    }
+
+    user_balance.pending_base_balance = 0;
+    user_balance.pending_quote_balance = 0;
+
+    OrderbookEvent::Withdraw {
+        market: *market_info.key,
+        owner: user_balance.owner,
+        base_amount: settled_base,
+        quote_amount: settled_quote,
+    }
+    .emit()?;
+
+    Ok(())
+}
+
+/// Pays out the pending balances of any number of users to their canonical
+/// associated token accounts. Anyone may call it; the caller funds the rent
+/// of token accounts that have to be created.
+pub fn process_settle_for(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+
+    let payer_info = next_account_info(account_info_iter)?;
+    let market_info = next_account_info(account_info_iter)?;
+    let base_vault_info = next_account_info(account_info_iter)?;
+    let quote_vault_info = next_account_info(account_info_iter)?;
+    let token_program_info = next_account_info(account_info_iter)?;
+    let base_mint_info = next_account_info(account_info_iter)?;
+    let quote_mint_info = next_account_info(account_info_iter)?;
+    let system_program_info = next_account_info(account_info_iter)?;
+    let associated_token_program_info = next_account_info(account_info_iter)?;
+    // Always present here since the user accounts follow it
+    let quote_token_program_info = next_account_info(account_info_iter)?;
+
+    if !payer_info.is_signer {
+        msg!("Payer must be a signer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    let market_state = MarketContext::load(program_id, market_info)?;
+    let base_vault = market_state.load_base_vault(base_vault_info)?;
+    let quote_vault = market_state.load_quote_vault(quote_vault_info)?;
+
+    let market_seeds: &[&[u8]] = &[
+        b"market",
+        market_state.base_mint.as_ref(),
+        market_state.quote_mint.as_ref(),
+        &[market_state.bump],
+    ];
+
+    let user_accounts = account_info_iter.as_slice();
+    if user_accounts.is_empty() || !user_accounts.len().is_multiple_of(4) {
+        msg!("Expected groups of user balance, owner, base and quote token accounts");
+        return Err(ProgramError::NotEnoughAccountKeys);
     }
 
-    user_balance.serialize(&mut *user_balance_info.data.borrow_mut())?;
+    for group in user_accounts.chunks_exact(4) {
+        let user_balance_info = &group[0];
+        let owner_info = &group[1];
+        let owner_base_token_info = &group[2];
+        let owner_quote_token_info = &group[3];
+
+        let mut user_balance = UserBalance::load(program_id, user_balance_info, market_info)?;
+
+        if user_balance.owner != *owner_info.key {
+            msg!(
+                "Owner mismatch. Expected: {}, Got: {}",
+                user_balance.owner,
+                owner_info.key
+            );
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        let settled_base = user_balance.pending_base_balance;
+        let settled_quote = user_balance.pending_quote_balance;
+        if settled_base == 0 && settled_quote == 0 {
+            debug_msg!("Nothing to settle for {}", owner_info.key);
+            continue;
+        }
+
+        let sides = [
+            (
+                &base_vault,
+                base_mint_info,
+                token_program_info,
+                owner_base_token_info,
+                settled_base,
+            ),
+            (
+                &quote_vault,
+                quote_mint_info,
+                quote_token_program_info,
+                owner_quote_token_info,
+                settled_quote,
+            ),
+        ];
+
+        for (vault, mint_info, side_token_program_info, owner_token_info, amount) in sides {
+            if amount == 0 {
+                continue;
+            }
+
+            let expected = token::associated_token_address(
+                owner_info.key,
+                &vault.token.mint,
+                &vault.token.token_program,
+            );
+            if *owner_token_info.key != expected {
+                msg!(
+                    "Payouts go to the owner's associated token account. Expected: {}, Got: {}",
+                    expected,
+                    owner_token_info.key
+                );
+                return Err(ProgramError::InvalidAccountData);
+            }
+
+            prepare_payout_account(
+                &vault.token,
+                payer_info,
+                owner_info,
+                owner_token_info,
+                mint_info,
+                side_token_program_info,
+                system_program_info,
+                associated_token_program_info,
+            )?;
+
+            debug_msg!("Settling {} tokens for {}", amount, owner_info.key);
+            transfer_from_vault(
+                vault,
+                side_token_program_info,
+                mint_info,
+                owner_token_info,
+                market_info,
+                market_seeds,
+                amount,
+            )?;
+        }
+
+        user_balance.pending_base_balance = 0;
+        user_balance.pending_quote_balance = 0;
+
+        OrderbookEvent::Withdraw {
+            market: *market_info.key,
+            owner: user_balance.owner,
+            base_amount: settled_base,
+            quote_amount: settled_quote,
+        }
+        .emit()?;
+    }
 
-    msg!("Settlement completed successfully");
     Ok(())
 }
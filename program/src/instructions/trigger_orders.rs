@@ -0,0 +1,391 @@
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    entrypoint::ProgramResult,
+    msg,
+    program_error::ProgramError,
+    pubkey::Pubkey,
+    rent::Rent,
+    sysvar::{clock::Clock, Sysvar},
+};
+use std::cell::RefMut;
+
+use crate::{
+    context::MarketContext,
+    events::OrderbookEvent,
+    matching::{execute_order, queue_out},
+    state::{
+        MarketStatus, OpenOrder, Order, OrderType, Side, TriggerBook, TriggerOrder, TriggerType,
+        UserBalance, DELEGATE_CANCEL_ORDERS, DELEGATE_PLACE_ORDERS,
+    },
+};
+
+/// Upper bound on orders released by one `TriggerOrders` crank, each one runs
+/// the full matching loop.
+const MAX_TRIGGERS_PER_CRANK: usize = 8;
+
+/// Loads the balance a trigger order is placed or cancelled for, signed by
+/// its owner or a delegate holding `permission`.
+fn load_user_balance<'a>(
+    program_id: &Pubkey,
+    user_info: &AccountInfo,
+    user_balance_info: &'a AccountInfo,
+    market_info: &AccountInfo,
+    permission: u8,
+    now: i64,
+) -> Result<RefMut<'a, UserBalance>, ProgramError> {
+    let user_balance = UserBalance::load(program_id, user_balance_info, market_info)?;
+    user_balance.authorize_trader(user_info, permission, now)?;
+    Ok(user_balance)
+}
+
+pub fn process_initialize_trigger_book(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+
+    let authority_info = next_account_info(account_info_iter)?;
+    let market_info = next_account_info(account_info_iter)?;
+    let trigger_book_info = next_account_info(account_info_iter)?;
+    let rent_info = next_account_info(account_info_iter)?;
+
+    if !authority_info.is_signer {
+        msg!("Market authority must be a signer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    let mut market_state = MarketContext::load_mut(program_id, market_info)?;
+
+    if market_state.authority != *authority_info.key {
+        msg!("Invalid market authority");
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    if market_state.trigger_book != Pubkey::default() {
+        msg!(
+            "Trigger book already initialized: {}",
+            market_state.trigger_book
+        );
+        return Err(ProgramError::AccountAlreadyInitialized);
+    }
+
+    if trigger_book_info.owner != program_id {
+        msg!("Trigger book account must be owned by this program");
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    let rent = Rent::from_account_info(rent_info)?;
+    if trigger_book_info.lamports() < rent.minimum_balance(TriggerBook::LEN) {
+        msg!("Trigger book account doesn't have enough lamports to be rent exempt");
+        return Err(ProgramError::AccountNotRentExempt);
+    }
+
+    {
+        let mut raw_data = trigger_book_info.data.borrow_mut();
+        if raw_data.len() != TriggerBook::LEN {
+            msg!(
+                "Trigger book account has incorrect size. Expected: {}, Got: {}",
+                TriggerBook::LEN,
+                raw_data.len()
+            );
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        if raw_data[..8] != [0; 8] {
+            msg!("Trigger book account is already in use");
+            return Err(ProgramError::AccountAlreadyInitialized);
+        }
+
+        raw_data.fill(0);
+
+        let trigger_book: &mut TriggerBook = bytemuck::from_bytes_mut(&mut raw_data);
+        trigger_book.discriminator = TriggerBook::DISCRIMINATOR;
+        trigger_book.market = *market_info.key;
+        trigger_book.active_orders_count = 0;
+    }
+
+    market_state.trigger_book = *trigger_book_info.key;
+
+    OrderbookEvent::TriggerBookInitialized {
+        market: *market_info.key,
+        trigger_book: *trigger_book_info.key,
+    }
+    .emit()?;
+
+    debug_msg!("Trigger book: {}", trigger_book_info.key);
+    Ok(())
+}
+
+pub fn process_place_trigger_order(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    side: Side,
+    trigger_type: TriggerType,
+    trigger_price: u64,
+    price: u64,
+    quantity: u64,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+
+    let user_info = next_account_info(account_info_iter)?;
+    let user_balance_info = next_account_info(account_info_iter)?;
+    let market_info = next_account_info(account_info_iter)?;
+    let trigger_book_info = next_account_info(account_info_iter)?;
+    let clock_sysvar_info = next_account_info(account_info_iter)?;
+
+    let mut market_state = MarketContext::load_mut(program_id, market_info)?;
+    let mut trigger_book = market_state.load_trigger_book(trigger_book_info)?;
+    let clock = Clock::from_account_info(clock_sysvar_info)?;
+    let mut user_balance = load_user_balance(
+        program_id,
+        user_info,
+        user_balance_info,
+        market_info,
+        DELEGATE_PLACE_ORDERS,
+        clock.unix_timestamp,
+    )?;
+    let owner = user_balance.owner;
+
+    if market_state.status != MarketStatus::Active {
+        msg!("Market is not accepting new orders");
+        return Err(ProgramError::Custom(5));
+    }
+
+    if trigger_price == 0 || price == 0 || quantity == 0 {
+        msg!("Trigger price, price and quantity must be greater than 0");
+        return Err(ProgramError::InvalidInstructionData);
+    }
+
+    let required_base = if side == Side::Sell { quantity } else { 0 };
+    let required_quote = if side == Side::Buy {
+        (quantity * price) / 1_000_000_000
+    } else {
+        0
+    };
+
+    if user_balance.available_base_balance < required_base
+        || user_balance.available_quote_balance < required_quote
+    {
+        msg!("Insufficient funds to place trigger order");
+        return Err(ProgramError::InsufficientFunds);
+    }
+
+    // Funds stay locked while the order waits, so a triggered order can always fill
+    user_balance.available_base_balance -= required_base;
+    user_balance.locked_base_balance += required_base;
+    user_balance.available_quote_balance -= required_quote;
+    user_balance.locked_quote_balance += required_quote;
+
+    let order_id = market_state.next_order_id;
+
+    trigger_book.add_order(TriggerOrder {
+        owner,
+        timestamp: clock.unix_timestamp,
+        order_id,
+        trigger_price,
+        price,
+        quantity,
+        side,
+        trigger_type,
+    })?;
+
+    market_state.next_order_id += 1;
+
+    // Listed from placement on, a triggered order keeps its id in the book
+    user_balance.add_open_order(
+        OpenOrder {
+            order_id,
+            client_order_id: 0,
+            price,
+            quantity,
+            side,
+            padding: [0; 7],
+        },
+        market_state.max_orders_per_user,
+    )?;
+
+    OrderbookEvent::TriggerOrderPlaced {
+        market: *market_info.key,
+        owner,
+        order_id,
+        side,
+        trigger_type,
+        trigger_price,
+        price,
+        quantity,
+    }
+    .emit()?;
+
+    debug_msg!(
+        "Trigger order {} placed: {} at {} once price crosses {}",
+        order_id,
+        quantity,
+        price,
+        trigger_price
+    );
+    Ok(())
+}
+
+pub fn process_cancel_trigger_order(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    order_id: u64,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+
+    let user_info = next_account_info(account_info_iter)?;
+    let user_balance_info = next_account_info(account_info_iter)?;
+    let market_info = next_account_info(account_info_iter)?;
+    let trigger_book_info = next_account_info(account_info_iter)?;
+
+    let market_state = MarketContext::load_mut(program_id, market_info)?;
+    let mut trigger_book = market_state.load_trigger_book(trigger_book_info)?;
+    let mut user_balance = load_user_balance(
+        program_id,
+        user_info,
+        user_balance_info,
+        market_info,
+        DELEGATE_CANCEL_ORDERS,
+        Clock::get()?.unix_timestamp,
+    )?;
+    let owner = user_balance.owner;
+
+    let index = trigger_book.orders[..trigger_book.active_orders_count as usize]
+        .iter()
+        .position(|order| order.order_id == order_id && order.owner == owner)
+        .ok_or_else(|| {
+            msg!("Trigger order {} not found or not owned by user", order_id);
+            ProgramError::Custom(3)
+        })?;
+
+    let order = trigger_book.remove_order(index)?;
+
+    if order.side == Side::Buy {
+        let locked_quote = (order.quantity * order.price) / 1_000_000_000;
+        user_balance.locked_quote_balance -= locked_quote;
+        user_balance.available_quote_balance += locked_quote;
+    } else {
+        user_balance.locked_base_balance -= order.quantity;
+        user_balance.available_base_balance += order.quantity;
+    }
+    user_balance.remove_open_order(order_id);
+
+    OrderbookEvent::TriggerOrderCancelled {
+        market: *market_info.key,
+        owner,
+        order_id,
+    }
+    .emit()?;
+
+    debug_msg!("Trigger order {} cancelled", order_id);
+    Ok(())
+}
+
+pub fn process_trigger_orders(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+
+    let market_info = next_account_info(account_info_iter)?;
+    let bids_info = next_account_info(account_info_iter)?;
+    let asks_info = next_account_info(account_info_iter)?;
+    let market_events_info = next_account_info(account_info_iter)?;
+    let trigger_book_info = next_account_info(account_info_iter)?;
+    let clock_sysvar_info = next_account_info(account_info_iter)?;
+
+    let mut market_state = MarketContext::load_mut(program_id, market_info)?;
+    let mut trigger_book = market_state.load_trigger_book(trigger_book_info)?;
+
+    let mut bids = market_state.load_bids(bids_info)?;
+    let mut asks = market_state.load_asks(asks_info)?;
+    let mut market_events = market_state.load_events(market_events_info)?;
+
+    if market_state.status != MarketStatus::Active {
+        debug_msg!("Market is not active, nothing triggered");
+        return Ok(());
+    }
+
+    let oracle_info = if market_state.oracle != Pubkey::default() {
+        Some(next_account_info(account_info_iter)?)
+    } else {
+        None
+    };
+
+    let clock = Clock::from_account_info(clock_sysvar_info)?;
+    let oracle_price = match oracle_info {
+        Some(oracle_info) => Some(market_state.oracle_price(clock.unix_timestamp, oracle_info)?),
+        None => None,
+    };
+
+    let mut triggered_count = 0;
+
+    // Each release can move last_price and trigger further orders, so rescan every time
+    while triggered_count < MAX_TRIGGERS_PER_CRANK && market_state.status == MarketStatus::Active {
+        let last_price = market_state.last_price;
+        let Some(index) = trigger_book.orders[..trigger_book.active_orders_count as usize]
+            .iter()
+            .position(|order| order.is_triggered(last_price))
+        else {
+            break;
+        };
+
+        let triggered = trigger_book.remove_order(index)?;
+        let order_id = triggered.order_id;
+        debug_msg!("Triggering order {} at last price {}", order_id, last_price);
+
+        let order = Order {
+            owner: triggered.owner,
+            market: *market_info.key,
+            timestamp: clock.unix_timestamp,
+            order_id,
+            price: triggered.price,
+            quantity: triggered.quantity,
+            filled_quantity: 0,
+            side: triggered.side,
+            order_type: OrderType::Limit,
+            peg_offset: 0,
+            display_quantity: 0,
+            hidden_quantity: 0,
+            sequence: 0,
+        };
+
+        OrderbookEvent::OrderPlaced {
+            market: *market_info.key,
+            owner: order.owner,
+            order_id,
+            side: order.side,
+            order_type: order.order_type,
+            price: order.price,
+            quantity: order.quantity,
+            timestamp: order.timestamp,
+        }
+        .emit()?;
+
+        let result = execute_order(
+            &mut market_state,
+            &mut bids,
+            &mut asks,
+            &mut market_events,
+            order,
+            oracle_price,
+            false,
+            true,
+        )?;
+
+        // The crank holds no balances, a remainder cancelled by the circuit
+        // breaker or for lack of room on a full book is handed back to its
+        // owner by `ConsumeEvents`. Rejecting it would revert every other
+        // trigger in the crank.
+        let cancelled_quantity = order.quantity - result.filled_quantity - result.resting_quantity;
+        if cancelled_quantity > 0 {
+            queue_out(
+                &mut market_events,
+                &order,
+                cancelled_quantity,
+                clock.unix_timestamp,
+            )?;
+        }
+        triggered_count += 1;
+    }
+
+    debug_msg!("Triggered {} orders", triggered_count);
+    Ok(())
+}
@@ -0,0 +1,573 @@
+use crate::{
+    context::{MarketContext, MarketVault},
+    events::OrderbookEvent,
+    state::{MarketState, UserBalance},
+    token,
+};
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    entrypoint::ProgramResult,
+    msg,
+    program::{invoke, invoke_signed},
+    program_error::ProgramError,
+    pubkey::Pubkey,
+    rent::Rent,
+    sysvar::Sysvar,
+};
+use solana_system_interface::{instruction as system_instruction, program as system_program};
+use std::cell::RefMut;
+
+/// Loads the signer's own balance account; deposits and closing are never
+/// delegated.
+fn load_owned_balance<'a>(
+    program_id: &Pubkey,
+    user_info: &AccountInfo,
+    user_balance_info: &'a AccountInfo,
+    market_info: &AccountInfo,
+) -> Result<RefMut<'a, UserBalance>, ProgramError> {
+    if !user_info.is_signer {
+        msg!("User must be a signer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    if user_balance_info.owner != program_id {
+        msg!("User balance account does not exist");
+        return Err(ProgramError::UninitializedAccount);
+    }
+
+    let user_balance = UserBalance::load(program_id, user_balance_info, market_info)?;
+
+    if user_balance.owner != *user_info.key {
+        msg!("User balance account does not belong to signer");
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    Ok(user_balance)
+}
+
+/// Creates and initializes the `["user_balance", user, market]` PDA with
+/// `user` paying rent.
+fn create_user_balance<'a>(
+    program_id: &Pubkey,
+    user_info: &AccountInfo<'a>,
+    user_balance_info: &AccountInfo<'a>,
+    market_info: &AccountInfo<'a>,
+    system_program_info: &AccountInfo<'a>,
+    rent: &Rent,
+) -> ProgramResult {
+    if !system_program::check_id(system_program_info.key) {
+        msg!("Invalid system program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let (user_balance_pda, user_balance_bump) = Pubkey::find_program_address(
+        &[
+            b"user_balance",
+            user_info.key.as_ref(),
+            market_info.key.as_ref(),
+        ],
+        program_id,
+    );
+
+    if *user_balance_info.key != user_balance_pda {
+        msg!(
+            "Invalid new user account. Expected PDA: {}",
+            user_balance_pda
+        );
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    debug_msg!(
+        "Creating user balance account with {} bytes",
+        UserBalance::LEN
+    );
+    invoke_signed(
+        &system_instruction::create_account(
+            user_info.key,
+            &user_balance_pda,
+            rent.minimum_balance(UserBalance::LEN),
+            UserBalance::LEN as u64,
+            program_id,
+        ),
+        &[
+            user_info.clone(),
+            user_balance_info.clone(),
+            system_program_info.clone(),
+        ],
+        &[&[
+            b"user_balance",
+            user_info.key.as_ref(),
+            market_info.key.as_ref(),
+            &[user_balance_bump],
+        ]],
+    )?;
+
+    UserBalance::init(
+        &mut user_balance_info.data.borrow_mut(),
+        *user_info.key,
+        *market_info.key,
+        user_balance_bump,
+    )?;
+
+    OrderbookEvent::UserBalanceCreated {
+        market: *market_info.key,
+        owner: *user_info.key,
+    }
+    .emit()?;
+
+    debug_msg!("User balance account created successfully!");
+    Ok(())
+}
+
+/// Moves `amount` from the user's token account into the market vault and
+/// returns what the vault received after any Token-2022 transfer fee.
+fn transfer_to_vault<'a>(
+    vault: &MarketVault<'_, 'a>,
+    token_program_info: &AccountInfo<'a>,
+    user_info: &AccountInfo<'a>,
+    user_token_info: &AccountInfo<'a>,
+    mint_info: &AccountInfo<'a>,
+    amount: u64,
+) -> Result<u64, ProgramError> {
+    let market_token = &vault.token;
+    if *mint_info.key != market_token.mint {
+        msg!(
+            "Mint mismatch. Expected: {}, Got: {}",
+            market_token.mint,
+            mint_info.key
+        );
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    if *token_program_info.key != market_token.token_program {
+        msg!(
+            "Token program mismatch. Expected: {}, Got: {}",
+            market_token.token_program,
+            token_program_info.key
+        );
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let user_token_mint = token::token_account_mint(user_token_info)?;
+    if user_token_mint != market_token.mint {
+        msg!(
+            "User token account mint mismatch. Expected: {}, Got: {}",
+            market_token.mint,
+            user_token_mint
+        );
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    debug_msg!("Processing onramp of {} tokens", amount);
+    token::transfer_to_vault(
+        token_program_info,
+        user_token_info,
+        mint_info,
+        vault.info,
+        user_info,
+        amount,
+        market_token.decimals,
+    )
+}
+
+pub fn process_create_user_balance(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+
+    let user_info = next_account_info(account_info_iter)?;
+    let user_balance_info = next_account_info(account_info_iter)?;
+    let market_info = next_account_info(account_info_iter)?;
+    let system_program_info = next_account_info(account_info_iter)?;
+
+    if !user_info.is_signer {
+        msg!("User must be a signer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    MarketState::load(program_id, market_info)?;
+
+    if user_balance_info.lamports() > 0 {
+        msg!("User balance account already exists");
+        return Err(ProgramError::AccountAlreadyInitialized);
+    }
+
+    create_user_balance(
+        program_id,
+        user_info,
+        user_balance_info,
+        market_info,
+        system_program_info,
+        &Rent::get()?,
+    )
+}
+
+/// Funds either or both sides of an existing balance in one call.
+pub fn process_deposit(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    base_amount: u64,
+    quote_amount: u64,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+
+    let user_info = next_account_info(account_info_iter)?;
+    let user_balance_info = next_account_info(account_info_iter)?;
+    let market_info = next_account_info(account_info_iter)?;
+    let user_base_token_info = next_account_info(account_info_iter)?;
+    let user_quote_token_info = next_account_info(account_info_iter)?;
+    let base_vault_info = next_account_info(account_info_iter)?;
+    let quote_vault_info = next_account_info(account_info_iter)?;
+    let token_program_info = next_account_info(account_info_iter)?;
+    let base_mint_info = next_account_info(account_info_iter)?;
+    let quote_mint_info = next_account_info(account_info_iter)?;
+    // Only needed when the quote mint lives in the other token program
+    let quote_token_program_info =
+        next_account_info(account_info_iter).unwrap_or(token_program_info);
+
+    if base_amount == 0 && quote_amount == 0 {
+        msg!("Nothing to deposit");
+        return Err(ProgramError::InvalidInstructionData);
+    }
+
+    let market_state = MarketContext::load(program_id, market_info)?;
+    let base_vault = market_state.load_base_vault(base_vault_info)?;
+    let quote_vault = market_state.load_quote_vault(quote_vault_info)?;
+    let mut user_balance =
+        load_owned_balance(program_id, user_info, user_balance_info, market_info)?;
+
+    let mut base_received = 0;
+    if base_amount > 0 {
+        base_received = transfer_to_vault(
+            &base_vault,
+            token_program_info,
+            user_info,
+            user_base_token_info,
+            base_mint_info,
+            base_amount,
+        )?;
+        user_balance.available_base_balance += base_received;
+    }
+
+    let mut quote_received = 0;
+    if quote_amount > 0 {
+        quote_received = transfer_to_vault(
+            &quote_vault,
+            quote_token_program_info,
+            user_info,
+            user_quote_token_info,
+            quote_mint_info,
+            quote_amount,
+        )?;
+        user_balance.available_quote_balance += quote_received;
+    }
+
+    OrderbookEvent::Deposit {
+        market: *market_info.key,
+        owner: *user_info.key,
+        base_amount: base_received,
+        quote_amount: quote_received,
+    }
+    .emit()?;
+
+    debug_msg!(
+        "Deposited {} base and {} quote tokens",
+        base_received,
+        quote_received
+    );
+    Ok(())
+}
+
+/// `DepositBaseTokens` / `DepositQuoteTokens`: one side at a time, creating
+/// the balance account on first use.
+fn process_single_deposit(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    base_amount: u64,
+    quote_amount: u64,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+
+    let user_info = next_account_info(account_info_iter)?;
+    let user_balance_info = next_account_info(account_info_iter)?;
+    let market_info = next_account_info(account_info_iter)?;
+    let user_token_info = next_account_info(account_info_iter)?;
+    let vault_info = next_account_info(account_info_iter)?;
+    let token_program_info = next_account_info(account_info_iter)?;
+    let system_program_info = next_account_info(account_info_iter)?;
+    let rent_info = next_account_info(account_info_iter)?;
+    let mint_info = next_account_info(account_info_iter)?;
+
+    if !user_info.is_signer {
+        msg!("User must be a signer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    let market_state = MarketContext::load(program_id, market_info)?;
+
+    if user_balance_info.lamports() == 0 {
+        create_user_balance(
+            program_id,
+            user_info,
+            user_balance_info,
+            market_info,
+            system_program_info,
+            &Rent::from_account_info(rent_info)?,
+        )?;
+    }
+
+    if base_amount == 0 && quote_amount == 0 {
+        return Ok(());
+    }
+
+    let mut user_balance =
+        load_owned_balance(program_id, user_info, user_balance_info, market_info)?;
+
+    let vault = if base_amount > 0 {
+        market_state.load_base_vault(vault_info)?
+    } else {
+        market_state.load_quote_vault(vault_info)?
+    };
+    let received = transfer_to_vault(
+        &vault,
+        token_program_info,
+        user_info,
+        user_token_info,
+        mint_info,
+        base_amount + quote_amount,
+    )?;
+
+    let (base_received, quote_received) = if base_amount > 0 {
+        (received, 0)
+    } else {
+        (0, received)
+    };
+    user_balance.available_base_balance += base_received;
+    user_balance.available_quote_balance += quote_received;
+
+    OrderbookEvent::Deposit {
+        market: *market_info.key,
+        owner: *user_info.key,
+        base_amount: base_received,
+        quote_amount: quote_received,
+    }
+    .emit()?;
+
+    debug_msg!("Successfully deposited {} tokens to user balance", received);
+    Ok(())
+}
+
+pub fn process_create_acc_and_deposit_quote_tokens(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    quantity: u64,
+) -> ProgramResult {
+    process_single_deposit(program_id, accounts, 0, quantity)
+}
+
+pub fn process_create_acc_and_deposit_base_tokens(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    quantity: u64,
+) -> ProgramResult {
+    process_single_deposit(program_id, accounts, quantity, 0)
+}
+
+/// Wraps lamports from the signer straight into the market's wrapped SOL
+/// vault, for markets listing the native mint on either side.
+pub fn process_deposit_native(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    amount: u64,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+
+    let user_info = next_account_info(account_info_iter)?;
+    let user_balance_info = next_account_info(account_info_iter)?;
+    let market_info = next_account_info(account_info_iter)?;
+    let vault_info = next_account_info(account_info_iter)?;
+    let token_program_info = next_account_info(account_info_iter)?;
+    let system_program_info = next_account_info(account_info_iter)?;
+
+    if amount == 0 {
+        msg!("Nothing to deposit");
+        return Err(ProgramError::InvalidInstructionData);
+    }
+
+    if !system_program::check_id(system_program_info.key) {
+        msg!("Invalid system program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let market_state = MarketContext::load(program_id, market_info)?;
+    let vault = market_state.load_native_vault(vault_info)?;
+    let is_base = vault.token.mint == market_state.base_mint;
+    let mut user_balance =
+        load_owned_balance(program_id, user_info, user_balance_info, market_info)?;
+
+    if *token_program_info.key != vault.token.token_program {
+        msg!(
+            "Token program mismatch. Expected: {}, Got: {}",
+            vault.token.token_program,
+            token_program_info.key
+        );
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    debug_msg!("Wrapping {} lamports into the vault", amount);
+    invoke(
+        &system_instruction::transfer(user_info.key, vault.info.key, amount),
+        &[
+            user_info.clone(),
+            vault.info.clone(),
+            system_program_info.clone(),
+        ],
+    )?;
+    token::sync_native(token_program_info, vault.info)?;
+
+    let (base_amount, quote_amount) = if is_base { (amount, 0) } else { (0, amount) };
+    user_balance.available_base_balance += base_amount;
+    user_balance.available_quote_balance += quote_amount;
+
+    OrderbookEvent::Deposit {
+        market: *market_info.key,
+        owner: *user_info.key,
+        base_amount,
+        quote_amount,
+    }
+    .emit()?;
+
+    Ok(())
+}
+
+/// Moves available balances to pending, where `SettleBalance` pays them out.
+///
+/// Locks are released in rounded-down shares, so a few sub-unit lamports of
+/// quote can stay locked after every order is gone. Once no order is open and
+/// no queued event names the owner, whatever is still locked is such a
+/// leftover and is released to available first.
+pub fn process_withdraw(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    base_amount: u64,
+    quote_amount: u64,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+
+    let user_info = next_account_info(account_info_iter)?;
+    let user_balance_info = next_account_info(account_info_iter)?;
+    let market_info = next_account_info(account_info_iter)?;
+    let market_events_info = next_account_info(account_info_iter)?;
+
+    if base_amount == 0 && quote_amount == 0 {
+        msg!("Nothing to withdraw");
+        return Err(ProgramError::InvalidInstructionData);
+    }
+
+    let mut user_balance =
+        load_owned_balance(program_id, user_info, user_balance_info, market_info)?;
+    let market_state = MarketContext::load(program_id, market_info)?;
+
+    if user_balance.open_orders_count == 0
+        && !market_state
+            .load_events(market_events_info)?
+            .names(&user_balance.owner)
+    {
+        let locked_base = user_balance.locked_base_balance;
+        let locked_quote = user_balance.locked_quote_balance;
+        user_balance.available_base_balance += locked_base;
+        user_balance.available_quote_balance += locked_quote;
+        user_balance.locked_base_balance = 0;
+        user_balance.locked_quote_balance = 0;
+        debug_msg!(
+            "Released {} base and {} quote left locked",
+            locked_base,
+            locked_quote
+        );
+    }
+
+    if user_balance.available_base_balance < base_amount
+        || user_balance.available_quote_balance < quote_amount
+    {
+        msg!("Insufficient available balance to withdraw");
+        return Err(ProgramError::InsufficientFunds);
+    }
+
+    user_balance.available_base_balance -= base_amount;
+    user_balance.available_quote_balance -= quote_amount;
+    user_balance.pending_base_balance += base_amount;
+    user_balance.pending_quote_balance += quote_amount;
+
+    OrderbookEvent::AvailableWithdrawn {
+        market: *market_info.key,
+        owner: *user_info.key,
+        base_amount,
+        quote_amount,
+    }
+    .emit()?;
+
+    debug_msg!(
+        "Withdrew {} base and {} quote to pending",
+        base_amount,
+        quote_amount
+    );
+    Ok(())
+}
+
+/// Closes an empty balance account and refunds its rent to the owner.
+pub fn process_close_user_balance(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+
+    let user_info = next_account_info(account_info_iter)?;
+    let user_balance_info = next_account_info(account_info_iter)?;
+    let market_info = next_account_info(account_info_iter)?;
+    let market_events_info = next_account_info(account_info_iter)?;
+
+    let user_balance = load_owned_balance(program_id, user_info, user_balance_info, market_info)?;
+
+    let balances = [
+        user_balance.available_base_balance,
+        user_balance.available_quote_balance,
+        user_balance.locked_base_balance,
+        user_balance.locked_quote_balance,
+        user_balance.pending_base_balance,
+        user_balance.pending_quote_balance,
+    ];
+    if balances.iter().any(|&balance| balance > 0) {
+        msg!("User balance must be fully withdrawn before closing");
+        return Err(ProgramError::Custom(9));
+    }
+
+    if user_balance.open_orders_count > 0 {
+        msg!(
+            "User still has {} open orders",
+            user_balance.open_orders_count
+        );
+        return Err(ProgramError::Custom(9));
+    }
+
+    // ConsumeEvents stops at an event whose balance it is not given, so one
+    // left for a closed account would hold up the queue for everyone
+    let market_state = MarketContext::load(program_id, market_info)?;
+    if market_state
+        .load_events(market_events_info)?
+        .names(&user_balance.owner)
+    {
+        msg!("Queued events still name this user, consume them before closing");
+        return Err(ProgramError::Custom(9));
+    }
+    drop(user_balance);
+
+    let refund = user_balance_info.lamports();
+    **user_info.try_borrow_mut_lamports()? += refund;
+    **user_balance_info.try_borrow_mut_lamports()? = 0;
+    user_balance_info.resize(0)?;
+    user_balance_info.assign(&system_program::ID);
+
+    OrderbookEvent::UserBalanceClosed {
+        market: *market_info.key,
+        owner: *user_info.key,
+    }
+    .emit()?;
+
+    debug_msg!("Closed user balance, refunded {} lamports", refund);
+    Ok(())
+}
@@ -1,22 +1,39 @@
 use borsh::{BorshDeserialize, BorshSerialize};
 use solana_program::{
-    account_info::AccountInfo, entrypoint, entrypoint::ProgramResult, msg,
-    program_error::ProgramError, pubkey::Pubkey,
+    account_info::AccountInfo, entrypoint::ProgramResult, program_error::ProgramError,
+    pubkey::Pubkey,
 };
 
+/// `msg!` compiled in only with the `debug-logs` feature. Progress chatter
+/// goes through here, state changes are reported by `events`.
+macro_rules! debug_msg {
+    ($($arg:tt)*) => {
+        if cfg!(feature = "debug-logs") {
+            solana_program::msg!($($arg)*);
+        }
+    };
+}
+
+mod context;
 mod instructions;
+mod matching;
+mod token;
 
-mod state;
+pub mod cpi;
+pub mod events;
+pub mod instruction;
+pub mod state;
 use instructions::{
-    process_consume_events,
-    process_create_acc_and_deposit_base_tokens,
-    process_create_acc_and_deposit_quote_tokens,
-    process_initialize_market,
-    process_place_order,
-    process_settle_balance, 
-    process_cancel_order,
+    process_cancel_order, process_cancel_trigger_order, process_close_user_balance,
+    process_configure_order_limit, process_configure_price_band, process_consume_events,
+    process_create_acc_and_deposit_base_tokens, process_create_acc_and_deposit_quote_tokens,
+    process_create_user_balance, process_deposit, process_deposit_native,
+    process_initialize_market, process_initialize_trigger_book, process_migrate,
+    process_place_order, process_place_trigger_order, process_run_auction, process_set_delegate,
+    process_set_market_status, process_settle_balance, process_settle_balance_native,
+    process_settle_for, process_trigger_orders, process_withdraw,
 };
-use state::Side;
+use state::{MarketStatus, PriceReference, Side, TriggerType};
 
 #[derive(BorshSerialize, BorshDeserialize, Debug)]
 pub enum Instruction {
@@ -24,9 +41,13 @@ pub enum Instruction {
         min_order_size: u64,
         tick_size: u64,
     },
+    /// Single-sided deposit that creates the `UserBalance` on first use, with
+    /// the mint after the rent sysvar. New clients use `CreateUserBalance`
+    /// and `Deposit`
     DepositQuoteTokens {
         quantity: u64,
     },
+    /// See `DepositQuoteTokens`
     DepositBaseTokens {
         quantity: u64,
     },
@@ -35,21 +56,114 @@ pub enum Instruction {
         price: u64,
         quantity: u64,
     },
-    ConsumeEvents,
+    /// Applies at most `limit` queued events, fewer when compute runs low;
+    /// 0 leaves the count to the compute budget alone
+    ConsumeEvents {
+        limit: u16,
+    },
     SettleBalance,
     CancelOrder {
         order_id: u64,
     },
+    ConfigurePriceBand {
+        price_band_bps: u16,
+        price_reference: PriceReference,
+        oracle: Pubkey,
+        max_oracle_staleness: i64,
+        twap_window: i64,
+        circuit_breaker_bps: u16,
+        circuit_breaker_window: i64,
+    },
+    SetMarketStatus {
+        status: MarketStatus,
+    },
+    PlacePeggedOrder {
+        side: Side,
+        peg_offset: i64,
+        limit_price: u64,
+        quantity: u64,
+    },
+    InitializeTriggerBook,
+    PlaceTriggerOrder {
+        side: Side,
+        trigger_type: TriggerType,
+        trigger_price: u64,
+        price: u64,
+        quantity: u64,
+    },
+    CancelTriggerOrder {
+        order_id: u64,
+    },
+    TriggerOrders,
+    PlaceIcebergOrder {
+        side: Side,
+        price: u64,
+        quantity: u64,
+        display_quantity: u64,
+    },
+    RunAuction,
+    /// Owner only. `permissions` is a mask of `state::DELEGATE_*` bits
+    SetDelegate {
+        delegate: Pubkey,
+        expiry: i64,
+        permissions: u8,
+    },
+    /// `PlaceOrder` with a caller-chosen id stored in the owner's open orders
+    PlaceOrderWithClientId {
+        side: Side,
+        price: u64,
+        quantity: u64,
+        client_order_id: u64,
+    },
+    /// Caps `UserBalance.open_orders` per user, at most `state::MAX_OPEN_ORDERS`
+    ConfigureOrderLimit {
+        max_orders_per_user: u16,
+    },
+    CreateUserBalance,
+    Deposit {
+        base_amount: u64,
+        quote_amount: u64,
+    },
+    /// Refunds rent once all balances are zero, no orders are open and no
+    /// queued event names the owner
+    CloseUserBalance,
+    /// Wraps lamports from the signer into the vault of a native SOL market
+    DepositNative {
+        amount: u64,
+    },
+    /// `SettleBalance` paying the native SOL side to the user's wallet
+    SettleBalanceNative,
+    /// Permissionless settlement of many users to their associated token accounts
+    SettleFor,
+    /// `PlaceOrderWithClientId` settling the taker's fills into its pending
+    /// balances right away; only the makers wait for `ConsumeEvents`
+    PlaceOrderSettleTaker {
+        side: Side,
+        price: u64,
+        quantity: u64,
+        client_order_id: u64,
+    },
+    /// Rewrites a market created by the first release, and the listed books,
+    /// event queue and user balances, in the current layouts. Books take four
+    /// calls, as accounts grow at most 10KB per instruction
+    Migrate,
+    /// Moves available balances to pending for `SettleBalance` to pay out,
+    /// first releasing rounding leftovers once nothing is open or queued
+    Withdraw {
+        base_amount: u64,
+        quote_amount: u64,
+    },
 }
 
-entrypoint!(process_instruction);
+#[cfg(not(feature = "no-entrypoint"))]
+solana_program::entrypoint!(process_instruction);
 
-fn process_instruction(
+pub fn process_instruction(
     program_id: &Pubkey,
     accounts: &[AccountInfo],
     instruction_data: &[u8],
 ) -> ProgramResult {
-    msg!("Orderbook program");
+    debug_msg!("Orderbook program");
 
     let instruction = Instruction::try_from_slice(instruction_data)
         .map_err(|_| ProgramError::InvalidInstructionData)?;
@@ -59,16 +173,16 @@ fn process_instruction(
             min_order_size,
             tick_size,
         } => {
-            msg!("Instruction: Initialize Market");
+            debug_msg!("Instruction: Initialize Market");
             process_initialize_market(program_id, accounts, min_order_size, tick_size)
         }
 
         Instruction::DepositQuoteTokens { quantity } => {
-            msg!("Instruction: Deposit Quote Tokens");
+            debug_msg!("Instruction: Deposit Quote Tokens");
             process_create_acc_and_deposit_quote_tokens(program_id, accounts, quantity)
         }
         Instruction::DepositBaseTokens { quantity } => {
-            msg!("Instruction: Deposit Base Tokens");
+            debug_msg!("Instruction: Deposit Base Tokens");
             process_create_acc_and_deposit_base_tokens(program_id, accounts, quantity)
         }
         Instruction::PlaceOrder {
@@ -76,20 +190,210 @@ fn process_instruction(
             price,
             quantity,
         } => {
-            msg!("Instruction: Place Order");
-            process_place_order(program_id, accounts, side, price, quantity)
-        }
-        Instruction::ConsumeEvents => {
-            msg!("Instruction: Consume Events");
-            process_consume_events(program_id, accounts)
-        } 
-            Instruction::SettleBalance => {
-                  msg!("Instruction: Settle Balance");
-                  process_settle_balance(program_id, accounts)
-              }
-              Instruction::CancelOrder { order_id } => {
-                    msg!("Instruction: Cancel Order");
-                    process_cancel_order(program_id, accounts, order_id)
-                }
+            debug_msg!("Instruction: Place Order");
+            process_place_order(
+                program_id, accounts, side, price, quantity, None, None, 0, false,
+            )
+        }
+        Instruction::ConsumeEvents { limit } => {
+            debug_msg!("Instruction: Consume Events");
+            process_consume_events(program_id, accounts, limit)
+        }
+        Instruction::SettleBalance => {
+            debug_msg!("Instruction: Settle Balance");
+            process_settle_balance(program_id, accounts)
+        }
+        Instruction::CancelOrder { order_id } => {
+            debug_msg!("Instruction: Cancel Order");
+            process_cancel_order(program_id, accounts, order_id)
+        }
+        Instruction::ConfigurePriceBand {
+            price_band_bps,
+            price_reference,
+            oracle,
+            max_oracle_staleness,
+            twap_window,
+            circuit_breaker_bps,
+            circuit_breaker_window,
+        } => {
+            debug_msg!("Instruction: Configure Price Band");
+            process_configure_price_band(
+                program_id,
+                accounts,
+                price_band_bps,
+                price_reference,
+                oracle,
+                max_oracle_staleness,
+                twap_window,
+                circuit_breaker_bps,
+                circuit_breaker_window,
+            )
+        }
+        Instruction::SetMarketStatus { status } => {
+            debug_msg!("Instruction: Set Market Status");
+            process_set_market_status(program_id, accounts, status)
+        }
+        Instruction::PlacePeggedOrder {
+            side,
+            peg_offset,
+            limit_price,
+            quantity,
+        } => {
+            debug_msg!("Instruction: Place Pegged Order");
+            process_place_order(
+                program_id,
+                accounts,
+                side,
+                limit_price,
+                quantity,
+                Some(peg_offset),
+                None,
+                0,
+                false,
+            )
+        }
+        Instruction::InitializeTriggerBook => {
+            debug_msg!("Instruction: Initialize Trigger Book");
+            process_initialize_trigger_book(program_id, accounts)
+        }
+        Instruction::PlaceTriggerOrder {
+            side,
+            trigger_type,
+            trigger_price,
+            price,
+            quantity,
+        } => {
+            debug_msg!("Instruction: Place Trigger Order");
+            process_place_trigger_order(
+                program_id,
+                accounts,
+                side,
+                trigger_type,
+                trigger_price,
+                price,
+                quantity,
+            )
+        }
+        Instruction::CancelTriggerOrder { order_id } => {
+            debug_msg!("Instruction: Cancel Trigger Order");
+            process_cancel_trigger_order(program_id, accounts, order_id)
+        }
+        Instruction::TriggerOrders => {
+            debug_msg!("Instruction: Trigger Orders");
+            process_trigger_orders(program_id, accounts)
+        }
+        Instruction::PlaceIcebergOrder {
+            side,
+            price,
+            quantity,
+            display_quantity,
+        } => {
+            debug_msg!("Instruction: Place Iceberg Order");
+            process_place_order(
+                program_id,
+                accounts,
+                side,
+                price,
+                quantity,
+                None,
+                Some(display_quantity),
+                0,
+                false,
+            )
+        }
+        Instruction::RunAuction => {
+            debug_msg!("Instruction: Run Auction");
+            process_run_auction(program_id, accounts)
+        }
+        Instruction::SetDelegate {
+            delegate,
+            expiry,
+            permissions,
+        } => {
+            debug_msg!("Instruction: Set Delegate");
+            process_set_delegate(program_id, accounts, delegate, expiry, permissions)
+        }
+        Instruction::PlaceOrderWithClientId {
+            side,
+            price,
+            quantity,
+            client_order_id,
+        } => {
+            debug_msg!("Instruction: Place Order With Client Id");
+            process_place_order(
+                program_id,
+                accounts,
+                side,
+                price,
+                quantity,
+                None,
+                None,
+                client_order_id,
+                false,
+            )
+        }
+        Instruction::ConfigureOrderLimit {
+            max_orders_per_user,
+        } => {
+            debug_msg!("Instruction: Configure Order Limit");
+            process_configure_order_limit(program_id, accounts, max_orders_per_user)
+        }
+        Instruction::CreateUserBalance => {
+            debug_msg!("Instruction: Create User Balance");
+            process_create_user_balance(program_id, accounts)
+        }
+        Instruction::Deposit {
+            base_amount,
+            quote_amount,
+        } => {
+            debug_msg!("Instruction: Deposit");
+            process_deposit(program_id, accounts, base_amount, quote_amount)
+        }
+        Instruction::CloseUserBalance => {
+            debug_msg!("Instruction: Close User Balance");
+            process_close_user_balance(program_id, accounts)
+        }
+        Instruction::DepositNative { amount } => {
+            debug_msg!("Instruction: Deposit Native");
+            process_deposit_native(program_id, accounts, amount)
+        }
+        Instruction::SettleBalanceNative => {
+            debug_msg!("Instruction: Settle Balance Native");
+            process_settle_balance_native(program_id, accounts)
+        }
+        Instruction::SettleFor => {
+            debug_msg!("Instruction: Settle For");
+            process_settle_for(program_id, accounts)
+        }
+        Instruction::PlaceOrderSettleTaker {
+            side,
+            price,
+            quantity,
+            client_order_id,
+        } => {
+            debug_msg!("Instruction: Place Order Settle Taker");
+            process_place_order(
+                program_id,
+                accounts,
+                side,
+                price,
+                quantity,
+                None,
+                None,
+                client_order_id,
+                true,
+            )
+        }
+        Instruction::Migrate => {
+            debug_msg!("Instruction: Migrate");
+            process_migrate(program_id, accounts)
+        }
+        Instruction::Withdraw {
+            base_amount,
+            quote_amount,
+        } => {
+            debug_msg!("Instruction: Withdraw");
+            process_withdraw(program_id, accounts, base_amount, quote_amount)
+        }
     }
 }
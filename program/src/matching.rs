@@ -0,0 +1,541 @@
+use solana_program::{entrypoint::ProgramResult, msg, program_error::ProgramError, pubkey::Pubkey};
+use std::cmp::Ordering;
+
+use crate::{
+    events::OrderbookEvent,
+    state::{
+        Event, EventType, MarketEvents, MarketState, Order, OrderBook, OrderType, PlaceOrderResult,
+        Side,
+    },
+};
+
+/// Matches `taker_order` against the opposite side of the book in price-time
+/// priority and rests whatever is left on its own side.
+///
+/// Funds must already be locked in the taker's `UserBalance`; fills are only
+/// recorded as `Event`s and settled later by `ConsumeEvents`. With
+/// `settle_taker` the caller settles the taker's side from the returned
+/// result and the events are `MakerFill`s carrying only the maker's side.
+/// Resting orders take their queue `sequence` from `next_order_id`, which is
+/// advanced here.
+///
+/// If the circuit breaker trips and the remainder still crosses the best
+/// opposite price, resting it would leave the book crossed while the market
+/// is cancel-only, so it is cancelled instead. Its funds are the caller's to
+/// release: whatever is neither filled nor resting. The same goes for a
+/// remainder that finds its side full with `cancel_unrestable`, which callers
+/// that cannot afford a rejection (the trigger crank) set instead of failing
+/// on eviction.
+/// Returns what was filled and what was left resting on the book.
+#[allow(clippy::too_many_arguments)]
+pub fn execute_order(
+    market_state: &mut MarketState,
+    bids: &mut OrderBook,
+    asks: &mut OrderBook,
+    market_events: &mut MarketEvents,
+    taker_order: Order,
+    oracle_price: Option<u64>,
+    settle_taker: bool,
+    cancel_unrestable: bool,
+) -> Result<PlaceOrderResult, ProgramError> {
+    let side = taker_order.side;
+    let now = taker_order.timestamp;
+    let taker_price = taker_order.effective_price(oracle_price);
+
+    let (taker_book, maker_book) = if side == Side::Buy {
+        (&mut *bids, &mut *asks)
+    } else {
+        (&mut *asks, &mut *bids)
+    };
+
+    let mut remaining_quantity = taker_order.quantity - taker_order.filled_quantity;
+    let mut orders_to_remove = Vec::new();
+    let mut tripped = false;
+    let mut result = PlaceOrderResult {
+        order_id: taker_order.order_id,
+        ..PlaceOrderResult::default()
+    };
+
+    // Each step takes the best order still open; an iceberg refill gets a new
+    // sequence and competes again like any other order
+    while remaining_quantity > 0 {
+        let Some(i) = maker_book.best_order(oracle_price) else {
+            break;
+        };
+        let maker_order = &mut maker_book.orders[i];
+
+        let maker_price = maker_order.effective_price(oracle_price);
+
+        // Nothing worse than the best order can cross either
+        if !crosses(side, taker_price, maker_price) {
+            break;
+        }
+
+        let fill_quantity = std::cmp::min(
+            remaining_quantity,
+            maker_order.quantity - maker_order.filled_quantity,
+        );
+
+        maker_order.filled_quantity += fill_quantity;
+        remaining_quantity -= fill_quantity;
+
+        let maker_fill_event = Event {
+            event_type: if settle_taker {
+                EventType::MakerFill
+            } else {
+                EventType::Fill
+            },
+            maker: maker_order.owner,
+            taker: taker_order.owner,
+            maker_order_id: maker_order.order_id,
+            taker_order_id: taker_order.order_id,
+            quantity: fill_quantity,
+            price: maker_price,
+            locked_price: if side == Side::Buy {
+                taker_order.price
+            } else {
+                maker_order.price
+            },
+            timestamp: now,
+            side,
+        };
+
+        market_events.add_event(maker_fill_event)?;
+
+        OrderbookEvent::OrderFilled {
+            market: taker_order.market,
+            maker: maker_order.owner,
+            taker: taker_order.owner,
+            maker_order_id: maker_order.order_id,
+            taker_order_id: taker_order.order_id,
+            side,
+            price: maker_price,
+            quantity: fill_quantity,
+            timestamp: now,
+        }
+        .emit()?;
+
+        result.filled_quantity += fill_quantity;
+        result.quote_quantity += (fill_quantity * maker_price) / 1_000_000_000;
+        result.makers_hit += 1;
+
+        market_state.record_fill(maker_price, now);
+        debug_msg!("Filled {} quantity at {} price", fill_quantity, maker_price);
+
+        if maker_order.filled_quantity == maker_order.quantity {
+            if maker_order.hidden_quantity > 0 {
+                maker_order.refill(market_state.next_order_id);
+                market_state.next_order_id += 1;
+            } else {
+                orders_to_remove.push(i);
+            }
+        }
+
+        if market_state.check_circuit_breaker(maker_price, now) {
+            OrderbookEvent::MarketStatusChanged {
+                market: taker_order.market,
+                status: market_state.status,
+            }
+            .emit()?;
+            debug_msg!(
+                "Circuit breaker tripped at {}, market is now cancel-only",
+                maker_price
+            );
+            tripped = true;
+            break;
+        }
+    }
+
+    // Swap-removal is only safe from the highest index down
+    orders_to_remove.sort_unstable();
+    for &index in orders_to_remove.iter().rev() {
+        maker_book.remove_order(index)?;
+    }
+
+    let crossed = tripped
+        && maker_book
+            .best_price(oracle_price)
+            .is_some_and(|best| crosses(side, taker_price, best));
+    let unrestable = cancel_unrestable
+        && !can_rest(
+            taker_book,
+            &Order {
+                quantity: remaining_quantity,
+                ..taker_order
+            },
+            oracle_price,
+        );
+    if remaining_quantity > 0 && (crossed || unrestable) {
+        OrderbookEvent::OrderCancelled {
+            market: taker_order.market,
+            owner: taker_order.owner,
+            order_id: taker_order.order_id,
+            side,
+            price: taker_order.price,
+            quantity: remaining_quantity,
+            timestamp: now,
+        }
+        .emit()?;
+
+        debug_msg!(
+            "Cancelled remaining {} quantity that cannot rest",
+            remaining_quantity
+        );
+        remaining_quantity = 0;
+    } else if remaining_quantity > 0 {
+        rest_order(
+            market_state,
+            taker_book,
+            market_events,
+            Order {
+                quantity: remaining_quantity,
+                ..taker_order
+            },
+            oracle_price,
+        )?;
+
+        debug_msg!(
+            "Added remaining order: {} quantity at {} price",
+            remaining_quantity,
+            taker_price
+        );
+    } else {
+        debug_msg!("Order fully filled, no remaining quantity");
+    }
+
+    market_state.update_mid_price_ema(
+        bids.best_price(oracle_price),
+        asks.best_price(oracle_price),
+        now,
+    );
+
+    result.resting_quantity = remaining_quantity;
+    Ok(result)
+}
+
+/// Whether a taker at `taker_price` on `side` trades with a maker at
+/// `maker_price`.
+fn crosses(side: Side, taker_price: u64, maker_price: u64) -> bool {
+    if side == Side::Buy {
+        taker_price >= maker_price
+    } else {
+        taker_price <= maker_price
+    }
+}
+
+/// Adds `order` to `book` without matching, splitting off the hidden part of
+/// an iceberg. `order.quantity` is the full unfilled size.
+///
+/// On a full book the worst-priced resting order is evicted with an `Out`
+/// event if `order` beats its price, otherwise the order is rejected.
+pub fn rest_order(
+    market_state: &mut MarketState,
+    book: &mut OrderBook,
+    market_events: &mut MarketEvents,
+    order: Order,
+    oracle_price: Option<u64>,
+) -> Result<(), ProgramError> {
+    if book.is_full() {
+        evict_worst_order(book, market_events, &order, oracle_price)?;
+    }
+
+    let total_quantity = order.quantity;
+    let mut new_order = Order {
+        filled_quantity: 0,
+        hidden_quantity: 0,
+        sequence: market_state.next_order_id,
+        ..order
+    };
+    if new_order.order_type == OrderType::Iceberg {
+        new_order.quantity = new_order.display_quantity.min(total_quantity);
+        new_order.hidden_quantity = total_quantity - new_order.quantity;
+    }
+    book.add_order(new_order)?;
+    market_state.next_order_id += 1;
+    Ok(())
+}
+
+/// Whether `order` would be accepted by `rest_order`: `book` has a free slot
+/// or `order` beats the worst resting price.
+fn can_rest(book: &OrderBook, order: &Order, oracle_price: Option<u64>) -> bool {
+    if !book.is_full() {
+        return true;
+    }
+    book.worst_order(oracle_price).is_some_and(|index| {
+        improves(
+            book.side,
+            order.effective_price(oracle_price),
+            book.orders[index].effective_price(oracle_price),
+        )
+    })
+}
+
+/// Whether `incoming_price` is strictly better than `worst_price` for
+/// an order on `side`.
+fn improves(side: Side, incoming_price: u64, worst_price: u64) -> bool {
+    if side == Side::Buy {
+        incoming_price > worst_price
+    } else {
+        incoming_price < worst_price
+    }
+}
+
+/// Frees a slot on a full book for `incoming` by removing the order that
+/// would be matched last, provided `incoming` has a strictly better price.
+fn evict_worst_order(
+    book: &mut OrderBook,
+    market_events: &mut MarketEvents,
+    incoming: &Order,
+    oracle_price: Option<u64>,
+) -> Result<(), ProgramError> {
+    let side = book.side;
+    let index = book
+        .worst_order(oracle_price)
+        .ok_or(ProgramError::Custom(2))?;
+    let worst = book.orders[index];
+
+    let incoming_price = incoming.effective_price(oracle_price);
+    let worst_price = worst.effective_price(oracle_price);
+    if !improves(side, incoming_price, worst_price) {
+        msg!(
+            "Order book is full and price {} does not beat the worst resting price {}",
+            incoming_price,
+            worst_price
+        );
+        return Err(ProgramError::Custom(2));
+    }
+
+    let quantity = open_quantity(&worst);
+    queue_out(market_events, &worst, quantity, incoming.timestamp)?;
+    book.remove_order(index)?;
+
+    OrderbookEvent::OrderEvicted {
+        market: worst.market,
+        owner: worst.owner,
+        order_id: worst.order_id,
+        side,
+        price: worst.price,
+        quantity,
+        timestamp: incoming.timestamp,
+    }
+    .emit()?;
+
+    let worst_order_id = worst.order_id;
+    debug_msg!(
+        "Evicted order {} at {} price from the full book",
+        worst_order_id,
+        worst_price
+    );
+    Ok(())
+}
+
+/// Queues an `Out` event, through which `ConsumeEvents` hands `quantity` of
+/// `order` back to its owner and drops it from their open orders.
+pub fn queue_out(
+    market_events: &mut MarketEvents,
+    order: &Order,
+    quantity: u64,
+    timestamp: i64,
+) -> ProgramResult {
+    market_events.add_event(Event {
+        event_type: EventType::Out,
+        maker: order.owner,
+        taker: Pubkey::default(),
+        maker_order_id: order.order_id,
+        taker_order_id: 0,
+        quantity,
+        price: order.price,
+        locked_price: order.price,
+        timestamp,
+        side: order.side,
+    })
+}
+
+/// Unfilled size of a resting order, including any hidden iceberg quantity.
+fn open_quantity(order: &Order) -> u64 {
+    order.quantity - order.filled_quantity + order.hidden_quantity
+}
+
+/// Takes `quantity` out of a resting order, refilling iceberg slices as the
+/// visible part runs out. Returns true once nothing is left.
+fn take_quantity(market_state: &mut MarketState, order: &mut Order, mut quantity: u64) -> bool {
+    while quantity > 0 {
+        let visible = order.quantity - order.filled_quantity;
+        if visible == 0 {
+            order.refill(market_state.next_order_id);
+            market_state.next_order_id += 1;
+            continue;
+        }
+        let taken = visible.min(quantity);
+        order.filled_quantity += taken;
+        quantity -= taken;
+    }
+
+    if order.filled_quantity == order.quantity && order.hidden_quantity > 0 {
+        order.refill(market_state.next_order_id);
+        market_state.next_order_id += 1;
+    }
+    order.filled_quantity == order.quantity
+}
+
+/// Finds the price that maximises executed volume between `bids` and `asks`.
+///
+/// Candidates are the resting order prices. Ties on volume go to the price
+/// with the smallest leftover imbalance, then to the lowest price. Returns
+/// `None` if the book does not cross.
+pub fn clearing_price(
+    bids: &OrderBook,
+    asks: &OrderBook,
+    oracle_price: Option<u64>,
+) -> Option<(u64, u64)> {
+    // Bids ascending, asks ascending, so both curves can be walked with one pointer each
+    let mut bid_levels: Vec<(u64, u64)> = bids
+        .matching_order(oracle_price)
+        .into_iter()
+        .map(|i| {
+            let order = &bids.orders[i];
+            (order.effective_price(oracle_price), open_quantity(order))
+        })
+        .collect();
+    bid_levels.reverse();
+    let ask_levels: Vec<(u64, u64)> = asks
+        .matching_order(oracle_price)
+        .into_iter()
+        .map(|i| {
+            let order = &asks.orders[i];
+            (order.effective_price(oracle_price), open_quantity(order))
+        })
+        .collect();
+
+    let mut candidates: Vec<u64> = bid_levels
+        .iter()
+        .chain(ask_levels.iter())
+        .map(|&(price, _)| price)
+        .collect();
+    candidates.sort_unstable();
+    candidates.dedup();
+
+    let mut demand: u64 = bid_levels.iter().map(|&(_, quantity)| quantity).sum();
+    let mut supply: u64 = 0;
+    let (mut bid_cursor, mut ask_cursor) = (0, 0);
+    let mut best: Option<(u64, u64, u64)> = None;
+
+    for price in candidates {
+        // Demand at `price` is every bid willing to pay at least `price`
+        while bid_cursor < bid_levels.len() && bid_levels[bid_cursor].0 < price {
+            demand -= bid_levels[bid_cursor].1;
+            bid_cursor += 1;
+        }
+        while ask_cursor < ask_levels.len() && ask_levels[ask_cursor].0 <= price {
+            supply += ask_levels[ask_cursor].1;
+            ask_cursor += 1;
+        }
+
+        let volume = demand.min(supply);
+        if volume == 0 {
+            continue;
+        }
+        let imbalance = demand.abs_diff(supply);
+
+        let better = match best {
+            None => true,
+            Some((_, best_volume, best_imbalance)) => match volume.cmp(&best_volume) {
+                Ordering::Greater => true,
+                Ordering::Equal => imbalance < best_imbalance,
+                Ordering::Less => false,
+            },
+        };
+        if better {
+            best = Some((price, volume, imbalance));
+        }
+    }
+
+    best.map(|(price, volume, _)| (price, volume))
+}
+
+/// Uncrosses the book at a single clearing price.
+///
+/// Bids and asks are paired off in price-time priority until the clearing
+/// volume is exhausted, every pair emitting a `Fill` at the clearing price
+/// with the buyer as taker. Returns the clearing price and volume, if any.
+pub fn run_auction(
+    market_state: &mut MarketState,
+    bids: &mut OrderBook,
+    asks: &mut OrderBook,
+    market_events: &mut MarketEvents,
+    now: i64,
+    oracle_price: Option<u64>,
+) -> Result<Option<(u64, u64)>, ProgramError> {
+    let Some((price, volume)) = clearing_price(bids, asks, oracle_price) else {
+        return Ok(None);
+    };
+
+    let bid_order = bids.matching_order(oracle_price);
+    let ask_order = asks.matching_order(oracle_price);
+    let (mut bid_cursor, mut ask_cursor) = (0, 0);
+    let mut bids_to_remove = Vec::new();
+    let mut asks_to_remove = Vec::new();
+    let mut remaining_volume = volume;
+
+    while remaining_volume > 0 {
+        let (bid_index, ask_index) = (bid_order[bid_cursor], ask_order[ask_cursor]);
+        let bid = &mut bids.orders[bid_index];
+        let ask = &mut asks.orders[ask_index];
+
+        let fill_quantity = remaining_volume
+            .min(open_quantity(bid))
+            .min(open_quantity(ask));
+
+        market_events.add_event(Event {
+            event_type: EventType::Fill,
+            maker: ask.owner,
+            taker: bid.owner,
+            maker_order_id: ask.order_id,
+            taker_order_id: bid.order_id,
+            quantity: fill_quantity,
+            price,
+            locked_price: bid.price,
+            timestamp: now,
+            side: Side::Buy,
+        })?;
+        OrderbookEvent::OrderFilled {
+            market: bid.market,
+            maker: ask.owner,
+            taker: bid.owner,
+            maker_order_id: ask.order_id,
+            taker_order_id: bid.order_id,
+            side: Side::Buy,
+            price,
+            quantity: fill_quantity,
+            timestamp: now,
+        }
+        .emit()?;
+        remaining_volume -= fill_quantity;
+
+        if take_quantity(market_state, bid, fill_quantity) {
+            bids_to_remove.push(bid_index);
+            bid_cursor += 1;
+        }
+        if take_quantity(market_state, ask, fill_quantity) {
+            asks_to_remove.push(ask_index);
+            ask_cursor += 1;
+        }
+    }
+
+    for (book, mut to_remove) in [(&mut *bids, bids_to_remove), (&mut *asks, asks_to_remove)] {
+        to_remove.sort_unstable();
+        for &index in to_remove.iter().rev() {
+            book.remove_order(index)?;
+        }
+    }
+
+    market_state.record_fill(price, now);
+    market_state.update_mid_price_ema(
+        bids.best_price(oracle_price),
+        asks.best_price(oracle_price),
+        now,
+    );
+
+    debug_msg!("Auction cleared {} quantity at {} price", volume, price);
+    Ok(Some((price, volume)))
+}
@@ -1,9 +1,86 @@
 use borsh::{BorshDeserialize, BorshSerialize};
 use bytemuck::{Pod, Zeroable};
-use solana_program::{entrypoint::ProgramResult, program_error::ProgramError, pubkey::Pubkey};
+use solana_program::{
+    account_info::AccountInfo, entrypoint::ProgramResult, msg, program_error::ProgramError,
+    pubkey::Pubkey,
+};
+use std::cell::{Ref, RefMut};
 
-#[derive(BorshDeserialize, BorshSerialize, Debug)]
+/// Checks the header every zero-copy state account starts with: its length,
+/// 8-byte discriminator and layout version.
+fn check_discriminator(
+    data: &[u8],
+    len: usize,
+    discriminator: &[u8; 8],
+    name: &str,
+) -> ProgramResult {
+    if data.len() < len || data[..8] != discriminator[..] {
+        msg!("Account is not a current {} account", name);
+        return Err(ProgramError::InvalidAccountData);
+    }
+    Ok(())
+}
+
+fn check_account_header(
+    data: &[u8],
+    len: usize,
+    discriminator: &[u8; 8],
+    version: u8,
+    name: &str,
+) -> ProgramResult {
+    check_discriminator(data, len, discriminator, name)?;
+    if data[8] != version {
+        msg!("Unsupported {} account version {}", name, data[8]);
+        return Err(ProgramError::InvalidAccountData);
+    }
+    Ok(())
+}
+
+/// A `u128` kept as two little-endian words, so accounts holding one stay
+/// 8-byte aligned on every target. Encodes exactly like a `u128`.
+#[repr(C)]
+#[derive(
+    BorshDeserialize, BorshSerialize, Debug, Clone, Copy, Default, PartialEq, Zeroable, Pod,
+)]
+pub struct PodU128([u64; 2]);
+
+impl From<u128> for PodU128 {
+    fn from(value: u128) -> Self {
+        PodU128([value as u64, (value >> 64) as u64])
+    }
+}
+
+impl From<PodU128> for u128 {
+    fn from(value: PodU128) -> Self {
+        value.0[0] as u128 | (value.0[1] as u128) << 64
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Zeroable, Pod)]
 pub struct MarketState {
+    /// `MarketState::DISCRIMINATOR`
+    pub discriminator: [u8; 8],
+    /// Layout version, see `MarketState::VERSION`
+    pub version: u8,
+    pub bump: u8,
+    pub status: MarketStatus,
+    pub price_reference: PriceReference,
+    pub base_decimals: u8,
+    pub quote_decimals: u8,
+    pub fee_rate_bps: u16,
+    pub price_band_bps: u16,
+    pub circuit_breaker_bps: u16,
+    /// Most orders one user may have listed in `UserBalance.open_orders`
+    pub max_orders_per_user: u16,
+    /// Bumps of the market's token account PDAs, so handlers can check them
+    /// with `create_program_address` instead of searching for them
+    pub base_vault_bump: u8,
+    pub quote_vault_bump: u8,
+    pub fee_account_bump: u8,
+    /// Bump of the `native_unwrap` PDA, which native SOL payouts sign for
+    pub native_unwrap_bump: u8,
+    pub padding: [u8; 6],
     pub authority: Pubkey,
     pub consume_events_authority: Pubkey,
     pub base_mint: Pubkey,
@@ -14,34 +91,649 @@ pub struct MarketState {
     pub market_events: Pubkey,
     pub bids: Pubkey,
     pub asks: Pubkey,
+    pub oracle: Pubkey,
+    pub trigger_book: Pubkey,
+    /// Token program owning each mint, SPL Token or Token-2022
+    pub base_token_program: Pubkey,
+    pub quote_token_program: Pubkey,
     pub min_order_size: u64,
     pub tick_size: u64,
     pub next_order_id: u64,
     pub last_price: u64,
     pub volume_24h: u64,
-    pub fee_rate_bps: u16,
-    pub bump: u8,
-    pub is_initialized: bool,
+    pub last_price_timestamp: i64,
+    pub mid_price_ema: u64,
+    pub mid_price_ema_timestamp: i64,
+    pub max_oracle_staleness: i64,
+    pub twap_window: i64,
+    pub circuit_breaker_window: i64,
+    pub circuit_breaker_price: u64,
+    pub circuit_breaker_timestamp: i64,
+    pub cumulative_price: PodU128,
+    pub twap_checkpoint: PriceObservation,
+    /// Zeroed space for fields added by later versions
+    pub reserved: [u8; 256],
+}
+
+/// Accounts and decimals for one side of a market's token pair.
+pub struct MarketToken {
+    pub mint: Pubkey,
+    pub vault: Pubkey,
+    pub token_program: Pubkey,
+    pub decimals: u8,
+}
+
+/// Seconds over which a new mid price fully replaces the EMA.
+pub const MID_PRICE_EMA_WINDOW: i64 = 300;
+
+#[repr(u8)]
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, PartialEq)]
+#[borsh(use_discriminant = true)]
+pub enum MarketStatus {
+    Active = 0,
+    CancelOnly = 1,
+    /// Orders rest without matching until `RunAuction` uncrosses the book
+    Auction = 2,
+}
+
+unsafe impl Pod for MarketStatus {}
+unsafe impl Zeroable for MarketStatus {}
+
+#[repr(u8)]
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, PartialEq)]
+#[borsh(use_discriminant = true)]
+pub enum PriceReference {
+    Twap = 0,
+    Oracle = 1,
+}
+
+unsafe impl Pod for PriceReference {}
+unsafe impl Zeroable for PriceReference {}
+
+/// Minimal price feed layout read from `MarketState.oracle`. `price` uses the
+/// same units as order prices (quote atoms per 10^9 base atoms).
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Zeroable, Pod)]
+pub struct OraclePrice {
+    pub price: u64,
+    pub publish_time: i64,
+}
+
+impl OraclePrice {
+    pub const LEN: usize = 8 + 8; // 16 bytes
+}
+
+/// True when `price` is more than `bps` basis points away from `reference`.
+pub fn exceeds_deviation(price: u64, reference: u64, bps: u16) -> bool {
+    price.abs_diff(reference) as u128 * 10_000 > reference as u128 * bps as u128
 }
 
 impl MarketState {
-    pub const LEN: usize = 10 * 32 + 5 * 8 + 2 + 1 + 1; // 364 bytes
+    pub const LEN: usize = 8 + 6 + 4 * 2 + 4 + 6 // 32 bytes header
+        + 14 * 32 + 13 * 8 + 16 + 24 // 624 bytes
+        + 256; // 880 bytes
+
+    pub const DISCRIMINATOR: [u8; 8] = *b"market\0\0";
+    /// Version 0 is the Borsh layout from before markets were zero-copy,
+    /// `Migrate` rewrites those accounts in this one.
+    pub const VERSION: u8 = 1;
+
+    /// Market state in `data`, checked to be a market of the current version.
+    pub fn from_bytes(data: &[u8]) -> Result<&MarketState, ProgramError> {
+        check_account_header(
+            data,
+            Self::LEN,
+            &Self::DISCRIMINATOR,
+            Self::VERSION,
+            "market",
+        )?;
+        Ok(bytemuck::from_bytes(&data[..Self::LEN]))
+    }
+
+    pub fn from_bytes_mut(data: &mut [u8]) -> Result<&mut MarketState, ProgramError> {
+        check_account_header(
+            data,
+            Self::LEN,
+            &Self::DISCRIMINATOR,
+            Self::VERSION,
+            "market",
+        )?;
+        Ok(bytemuck::from_bytes_mut(&mut data[..Self::LEN]))
+    }
+
+    /// Borrows the market held by `market_info` in place.
+    pub fn load<'a>(
+        program_id: &Pubkey,
+        market_info: &'a AccountInfo,
+    ) -> Result<Ref<'a, MarketState>, ProgramError> {
+        if market_info.owner != program_id {
+            msg!("Market account must be owned by this program");
+            return Err(ProgramError::InvalidAccountData);
+        }
+        let data = market_info.try_borrow_data()?;
+        MarketState::from_bytes(&data)?;
+        Ok(Ref::map(data, |data| {
+            bytemuck::from_bytes(&data[..MarketState::LEN])
+        }))
+    }
+
+    /// Mutably borrows the market held by `market_info` in place, changes
+    /// need no write back.
+    pub fn load_mut<'a>(
+        program_id: &Pubkey,
+        market_info: &'a AccountInfo,
+    ) -> Result<RefMut<'a, MarketState>, ProgramError> {
+        if market_info.owner != program_id {
+            msg!("Market account must be owned by this program");
+            return Err(ProgramError::InvalidAccountData);
+        }
+        let mut data = market_info.try_borrow_mut_data()?;
+        MarketState::from_bytes_mut(&mut data)?;
+        Ok(RefMut::map(data, |data| {
+            bytemuck::from_bytes_mut(&mut data[..MarketState::LEN])
+        }))
+    }
+
+    /// Address of this market, derived from its mints and stored bump.
+    pub fn address(&self, program_id: &Pubkey) -> Result<Pubkey, ProgramError> {
+        Pubkey::create_program_address(
+            &[
+                b"market",
+                self.base_mint.as_ref(),
+                self.quote_mint.as_ref(),
+                &[self.bump],
+            ],
+            program_id,
+        )
+        .map_err(|_| ProgramError::InvalidAccountData)
+    }
+
+    /// Fails unless `market_info` is the account this state belongs to.
+    pub fn check_address(&self, program_id: &Pubkey, market_info: &AccountInfo) -> ProgramResult {
+        if *market_info.key != self.address(program_id)? {
+            msg!("Invalid market account");
+            return Err(ProgramError::InvalidAccountData);
+        }
+        Ok(())
+    }
+
+    /// Address of the `[seed, market]` PDA with the given stored bump.
+    pub fn market_pda(
+        program_id: &Pubkey,
+        seed: &[u8],
+        market: &Pubkey,
+        bump: u8,
+    ) -> Result<Pubkey, ProgramError> {
+        Pubkey::create_program_address(&[seed, market.as_ref(), &[bump]], program_id)
+            .map_err(|_| ProgramError::InvalidAccountData)
+    }
+
+    /// Fails unless `fee_account_info` is the market's fee account, derived
+    /// from the stored bump.
+    pub fn check_fee_account(
+        &self,
+        program_id: &Pubkey,
+        market: &Pubkey,
+        fee_account_info: &AccountInfo,
+    ) -> ProgramResult {
+        let fee_account =
+            Self::market_pda(program_id, b"fee_account", market, self.fee_account_bump)?;
+        if *fee_account_info.key != fee_account {
+            msg!(
+                "Invalid fee account. Expected: {}, Got: {}",
+                fee_account,
+                fee_account_info.key
+            );
+            return Err(ProgramError::InvalidAccountData);
+        }
+        Ok(())
+    }
+
+    pub fn base_token(&self) -> MarketToken {
+        MarketToken {
+            mint: self.base_mint,
+            vault: self.base_vault,
+            token_program: self.base_token_program,
+            decimals: self.base_decimals,
+        }
+    }
+
+    pub fn quote_token(&self) -> MarketToken {
+        MarketToken {
+            mint: self.quote_mint,
+            vault: self.quote_vault,
+            token_program: self.quote_token_program,
+            decimals: self.quote_decimals,
+        }
+    }
+
+    /// Accrues `last_price` for every second since the previous update.
+    pub fn accumulate_price(&mut self, now: i64) {
+        if self.last_price_timestamp > 0 && now > self.last_price_timestamp {
+            let elapsed = (now - self.last_price_timestamp) as u128;
+            self.cumulative_price = u128::from(self.cumulative_price)
+                .wrapping_add(self.last_price as u128 * elapsed)
+                .into();
+        }
+        self.last_price_timestamp = self.last_price_timestamp.max(now);
+    }
+
+    pub fn record_fill(&mut self, price: u64, now: i64) {
+        self.accumulate_price(now);
+        self.last_price = price;
+    }
+
+    /// Snapshot of the accumulator as of `now`, including the time `last_price`
+    /// has been standing since the last fill. Two observations give a TWAP
+    /// through [`PriceObservation::twap`].
+    pub fn observe(&self, now: i64) -> PriceObservation {
+        let mut cumulative_price = u128::from(self.cumulative_price);
+        if self.last_price_timestamp > 0 && now > self.last_price_timestamp {
+            let elapsed = (now - self.last_price_timestamp) as u128;
+            cumulative_price = cumulative_price.wrapping_add(self.last_price as u128 * elapsed);
+        }
+        PriceObservation {
+            cumulative_price: cumulative_price.into(),
+            timestamp: now.max(self.last_price_timestamp),
+        }
+    }
+
+    /// Moves the mid-price EMA towards the current book mid, weighted by the
+    /// time elapsed since the last update so same-slot updates cannot move it.
+    pub fn update_mid_price_ema(&mut self, best_bid: Option<u64>, best_ask: Option<u64>, now: i64) {
+        let (Some(bid), Some(ask)) = (best_bid, best_ask) else {
+            return;
+        };
+        let mid = ((bid as u128 + ask as u128) / 2) as i128;
+
+        if self.mid_price_ema == 0 {
+            self.mid_price_ema = mid as u64;
+        } else {
+            let elapsed = now
+                .saturating_sub(self.mid_price_ema_timestamp)
+                .clamp(0, MID_PRICE_EMA_WINDOW) as i128;
+            let ema = self.mid_price_ema as i128;
+            self.mid_price_ema =
+                (ema + (mid - ema) * elapsed / MID_PRICE_EMA_WINDOW as i128) as u64;
+        }
+        self.mid_price_ema_timestamp = now;
+    }
+    /// Reads the configured oracle account, rejecting a mismatched or stale feed.
+    pub fn oracle_price(&self, now: i64, oracle_info: &AccountInfo) -> Result<u64, ProgramError> {
+        if *oracle_info.key != self.oracle {
+            msg!(
+                "Oracle mismatch. Expected: {}, Got: {}",
+                self.oracle,
+                oracle_info.key
+            );
+            return Err(ProgramError::InvalidAccountData);
+        }
+        let data = oracle_info.data.borrow();
+        if data.len() < OraclePrice::LEN {
+            msg!("Invalid oracle account data");
+            return Err(ProgramError::InvalidAccountData);
+        }
+        let oracle: OraclePrice = bytemuck::pod_read_unaligned(&data[..OraclePrice::LEN]);
+        if now.saturating_sub(oracle.publish_time) > self.max_oracle_staleness {
+            msg!(
+                "Oracle price is stale, published at {}",
+                oracle.publish_time
+            );
+            return Err(ProgramError::Custom(6));
+        }
+        Ok(oracle.price)
+    }
+
+    /// Price that order placement is banded against. `None` means there is no
+    /// usable reference yet (no trades for TWAP) and the band is not enforced.
+    ///
+    /// For `PriceReference::Twap` the reference is the TWAP since
+    /// `twap_checkpoint`, which is rolled forward once it is older than
+    /// `twap_window`.
+    pub fn reference_price(&mut self, now: i64, oracle_price: Option<u64>) -> Option<u64> {
+        match self.price_reference {
+            PriceReference::Twap => {
+                if self.last_price_timestamp == 0 {
+                    return None;
+                }
+                let current = self.observe(now);
+                if self.twap_checkpoint.timestamp == 0 {
+                    self.twap_checkpoint = current;
+                    return None;
+                }
+                let twap = self.twap_checkpoint.twap(&current);
+                if current.timestamp - self.twap_checkpoint.timestamp >= self.twap_window {
+                    self.twap_checkpoint = current;
+                }
+                twap
+            }
+            PriceReference::Oracle => oracle_price,
+        }
+    }
+
+    /// Tracks how far fills have moved within `circuit_breaker_window` and
+    /// switches the market to cancel-only once the move exceeds
+    /// `circuit_breaker_bps`. Returns true when the breaker trips.
+    pub fn check_circuit_breaker(&mut self, price: u64, now: i64) -> bool {
+        if self.circuit_breaker_bps == 0 {
+            return false;
+        }
+        if self.circuit_breaker_price == 0
+            || now.saturating_sub(self.circuit_breaker_timestamp) > self.circuit_breaker_window
+        {
+            self.circuit_breaker_price = price;
+            self.circuit_breaker_timestamp = now;
+            return false;
+        }
+        if exceeds_deviation(price, self.circuit_breaker_price, self.circuit_breaker_bps) {
+            self.status = MarketStatus::CancelOnly;
+            return true;
+        }
+        false
+    }
 }
 
-#[derive(BorshDeserialize, BorshSerialize, Debug)]
+/// A point-in-time reading of `MarketState::cumulative_price`.
+///
+/// Consumers store one observation (or read the market account at two
+/// different times) and divide the accumulated price by the seconds between
+/// them:
+///
+/// ```ignore
+/// let start = market_state.observe(start_ts);
+/// // ... later ...
+/// let end = market_state.observe(clock.unix_timestamp);
+/// let twap = start.twap(&end).ok_or(ProgramError::InvalidArgument)?;
+/// ```
+#[repr(C)]
+#[derive(
+    BorshDeserialize, BorshSerialize, Debug, Clone, Copy, Default, PartialEq, Zeroable, Pod,
+)]
+pub struct PriceObservation {
+    pub cumulative_price: PodU128,
+    pub timestamp: i64,
+}
+
+impl PriceObservation {
+    /// Time-weighted average price between `self` and a later observation.
+    pub fn twap(&self, later: &PriceObservation) -> Option<u64> {
+        if later.timestamp <= self.timestamp {
+            return None;
+        }
+        let elapsed = (later.timestamp - self.timestamp) as u128;
+        let twap =
+            u128::from(later.cumulative_price).wrapping_sub(self.cumulative_price.into()) / elapsed;
+        u64::try_from(twap).ok()
+    }
+}
+
+/// `UserBalance.delegate_permissions` bit allowing the delegate to place orders
+pub const DELEGATE_PLACE_ORDERS: u8 = 1 << 0;
+/// `UserBalance.delegate_permissions` bit allowing the delegate to cancel orders
+pub const DELEGATE_CANCEL_ORDERS: u8 = 1 << 1;
+
+/// Capacity of `UserBalance.open_orders`, and so the most orders one user can
+/// have live on a market, trigger orders included
+pub const MAX_OPEN_ORDERS: usize = 64;
+
+/// A live order in its owner's `UserBalance`, so clients can find their
+/// order ids without scanning the books
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Zeroable, Pod)]
+pub struct OpenOrder {
+    pub order_id: u64,
+    pub client_order_id: u64,
+    pub price: u64,
+    /// Quantity not yet settled by `ConsumeEvents`, hidden iceberg quantity included
+    pub quantity: u64,
+    pub side: Side,
+    pub padding: [u8; 7],
+}
+
+impl OpenOrder {
+    pub const LEN: usize = 4 * 8 + 1 + 7; // 40 bytes
+
+    pub const EMPTY: OpenOrder = OpenOrder {
+        order_id: 0,
+        client_order_id: 0,
+        price: 0,
+        quantity: 0,
+        side: Side::Buy,
+        padding: [0; 7],
+    };
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Zeroable, Pod)]
 pub struct UserBalance {
+    /// `UserBalance::DISCRIMINATOR`
+    pub discriminator: [u8; 8],
+    /// Layout version, see `UserBalance::VERSION`
+    pub version: u8,
+    /// Bump of the `["user_balance", owner, market]` PDA
+    pub bump: u8,
+    pub delegate_permissions: u8,
+    pub open_orders_count: u8,
+    pub padding: [u8; 4],
     pub owner: Pubkey,
     pub market: Pubkey,
+    /// Hot key allowed to trade for the owner, `Pubkey::default()` if none
+    pub delegate: Pubkey,
     pub available_base_balance: u64,
     pub available_quote_balance: u64,
     pub locked_base_balance: u64,
     pub locked_quote_balance: u64,
     pub pending_base_balance: u64,
     pub pending_quote_balance: u64,
+    pub delegate_expiry: i64,
+    pub open_orders: [OpenOrder; MAX_OPEN_ORDERS],
+    /// Zeroed space for fields added by later versions
+    pub reserved: [u8; 128],
 }
 
 impl UserBalance {
-    pub const LEN: usize = 2 * 32 + 6 * 8; //112 bytes
+    pub const LEN: usize = 8 + 4 + 4 + 3 * 32 + 7 * 8 // 168 bytes
+        + OpenOrder::LEN * MAX_OPEN_ORDERS // 2,728 bytes
+        + 128; // 2,856 bytes
+
+    pub const DISCRIMINATOR: [u8; 8] = *b"balance\0";
+    /// Version 0 is the Borsh layout from before balances were zero-copy,
+    /// `Migrate` rewrites those accounts in this one.
+    pub const VERSION: u8 = 1;
+
+    /// Writes an empty balance over `data` in place.
+    pub fn init(
+        data: &mut [u8],
+        owner: Pubkey,
+        market: Pubkey,
+        bump: u8,
+    ) -> Result<&mut UserBalance, ProgramError> {
+        if data.len() < Self::LEN {
+            msg!("User balance account is too small");
+            return Err(ProgramError::AccountDataTooSmall);
+        }
+        let data = &mut data[..Self::LEN];
+        data.fill(0);
+
+        let user_balance: &mut UserBalance = bytemuck::from_bytes_mut(data);
+        user_balance.discriminator = Self::DISCRIMINATOR;
+        user_balance.version = Self::VERSION;
+        user_balance.bump = bump;
+        user_balance.owner = owner;
+        user_balance.market = market;
+        user_balance.open_orders.fill(OpenOrder::EMPTY);
+        Ok(user_balance)
+    }
+
+    /// Balance in `data`, checked to be a balance of the current version.
+    pub fn from_bytes(data: &[u8]) -> Result<&UserBalance, ProgramError> {
+        check_account_header(
+            data,
+            Self::LEN,
+            &Self::DISCRIMINATOR,
+            Self::VERSION,
+            "balance",
+        )?;
+        Ok(bytemuck::from_bytes(&data[..Self::LEN]))
+    }
+
+    pub fn from_bytes_mut(data: &mut [u8]) -> Result<&mut UserBalance, ProgramError> {
+        check_account_header(
+            data,
+            Self::LEN,
+            &Self::DISCRIMINATOR,
+            Self::VERSION,
+            "balance",
+        )?;
+        Ok(bytemuck::from_bytes_mut(&mut data[..Self::LEN]))
+    }
+
+    /// Lists a new order, `max_open_orders` is the market's per-user limit.
+    pub fn add_open_order(&mut self, open_order: OpenOrder, max_open_orders: u16) -> ProgramResult {
+        let limit = (max_open_orders as usize).min(MAX_OPEN_ORDERS);
+        if self.open_orders_count as usize >= limit {
+            msg!("Too many open orders, at most {} allowed", limit);
+            return Err(ProgramError::Custom(8));
+        }
+
+        self.open_orders[self.open_orders_count as usize] = open_order;
+        self.open_orders_count += 1;
+        Ok(())
+    }
+
+    /// Drops `order_id` from the index. Ids that are not listed are ignored.
+    pub fn remove_open_order(&mut self, order_id: u64) -> Option<OpenOrder> {
+        let count = self.open_orders_count as usize;
+        let index = self.open_orders[..count]
+            .iter()
+            .position(|open_order| open_order.order_id == order_id)?;
+        let removed = self.open_orders[index];
+
+        self.open_orders.copy_within(index + 1..count, index);
+        self.open_orders[count - 1] = OpenOrder::EMPTY;
+        self.open_orders_count -= 1;
+        Some(removed)
+    }
+
+    /// Applies a settled fill of `order_id`, dropping the order once nothing
+    /// is left of it.
+    pub fn reduce_open_order(&mut self, order_id: u64, quantity: u64) {
+        let count = self.open_orders_count as usize;
+        if let Some(open_order) = self.open_orders[..count]
+            .iter_mut()
+            .find(|open_order| open_order.order_id == order_id)
+        {
+            open_order.quantity = open_order.quantity.saturating_sub(quantity);
+            if open_order.quantity == 0 {
+                self.remove_open_order(order_id);
+            }
+        }
+    }
+
+    /// Mutably borrows a balance account in place and checks it is the
+    /// owner's PDA for this market. The PDA is derived from the stored owner
+    /// and bump, so the account can be loaded by its delegate as well.
+    pub fn load<'a>(
+        program_id: &Pubkey,
+        user_balance_info: &'a AccountInfo,
+        market_info: &AccountInfo,
+    ) -> Result<RefMut<'a, UserBalance>, ProgramError> {
+        if user_balance_info.owner != program_id {
+            msg!("User balance account must be owned by this program");
+            return Err(ProgramError::InvalidAccountData);
+        }
+        let mut data = user_balance_info.try_borrow_mut_data()?;
+        let user_balance = UserBalance::from_bytes_mut(&mut data)?;
+
+        let user_balance_pda = Pubkey::create_program_address(
+            &[
+                b"user_balance",
+                user_balance.owner.as_ref(),
+                market_info.key.as_ref(),
+                &[user_balance.bump],
+            ],
+            program_id,
+        )
+        .map_err(|_| ProgramError::InvalidAccountData)?;
+
+        if user_balance_pda != *user_balance_info.key {
+            msg!("Invalid user account. Expected PDA: {}", user_balance_pda);
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        if user_balance.market != *market_info.key {
+            msg!("User balance account does not belong to this market");
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        Ok(RefMut::map(data, |data| {
+            bytemuck::from_bytes_mut(&mut data[..UserBalance::LEN])
+        }))
+    }
+
+    /// Checks that `signer` may trade with this balance. The owner always can,
+    /// the delegate only with `permission` granted and before its expiry.
+    pub fn authorize_trader(
+        &self,
+        signer: &AccountInfo,
+        permission: u8,
+        now: i64,
+    ) -> ProgramResult {
+        if !signer.is_signer {
+            msg!("User must be a signer");
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        if *signer.key == self.owner {
+            return Ok(());
+        }
+
+        if self.delegate == Pubkey::default() || *signer.key != self.delegate {
+            msg!("User balance account does not belong to signer");
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        if now >= self.delegate_expiry {
+            msg!("Delegate expired at {}", self.delegate_expiry);
+            return Err(ProgramError::Custom(7));
+        }
+
+        if self.delegate_permissions & permission == 0 {
+            msg!("Delegate is not permitted to do this");
+            return Err(ProgramError::Custom(7));
+        }
+
+        Ok(())
+    }
+}
+
+/// Outcome of an order placement, written with `set_return_data` so CPI
+/// callers and simulations don't have to parse logs.
+#[derive(BorshDeserialize, BorshSerialize, Debug, Default, Clone, Copy, PartialEq)]
+pub struct PlaceOrderResult {
+    pub order_id: u64,
+    pub filled_quantity: u64,
+    /// Quote paid by a buy or received by a sell, at the makers' prices
+    pub quote_quantity: u64,
+    pub resting_quantity: u64,
+    /// Number of maker fills, an iceberg refill hit again counts twice
+    pub makers_hit: u32,
+}
+
+impl PlaceOrderResult {
+    pub const LEN: usize = 4 * 8 + 4; // 36 bytes
+}
+
+/// Outcome of `ConsumeEvents`, written with `set_return_data`. A crank keeps
+/// calling while `remaining` is non-zero, passing the balances it stopped at.
+#[derive(BorshDeserialize, BorshSerialize, Debug, Default, Clone, Copy, PartialEq)]
+pub struct ConsumeEventsResult {
+    pub consumed: u64,
+    pub remaining: u64,
+}
+
+impl ConsumeEventsResult {
+    pub const LEN: usize = 2 * 8; // 16 bytes
 }
 
 pub const MAX_EVENTS: usize = 512;
@@ -53,14 +745,33 @@ pub struct Event {
     pub taker: Pubkey,
     pub timestamp: i64,
     pub maker_order_id: u64,
+    /// Zero for `Out` events
+    pub taker_order_id: u64,
     pub quantity: u64,
     pub price: u64,
+    /// Limit the buyer's quote was locked at. Applying the fill releases
+    /// whatever that locked beyond `price`.
+    pub locked_price: u64,
     pub event_type: EventType,
     pub side: Side,
 }
 
 impl Event {
-    pub const LEN: usize = 32 + 32 + 8 + 8 + 8 + 8 + 1 + 1; // 98 bytes
+    pub const LEN: usize = 32 + 32 + 8 + 8 + 8 + 8 + 8 + 8 + 1 + 1; // 114 bytes
+
+    /// A free queue slot, with valid enum values where zeroes are not one.
+    pub const EMPTY: Event = Event {
+        maker: Pubkey::new_from_array([0; 32]),
+        taker: Pubkey::new_from_array([0; 32]),
+        timestamp: 0,
+        maker_order_id: 0,
+        taker_order_id: 0,
+        quantity: 0,
+        price: 0,
+        locked_price: 0,
+        event_type: EventType::Fill,
+        side: Side::Buy,
+    };
 }
 
 #[repr(u8)]
@@ -68,7 +779,11 @@ impl Event {
 #[borsh(use_discriminant = true)]
 pub enum EventType {
     Fill = 0,
+    /// An order evicted from a full book, or a triggered order's remainder the
+    /// crank could not rest; cancels release funds at once
     Out = 1,
+    /// A `Fill` whose taker side was already settled when the order was placed
+    MakerFill = 2,
 }
 
 unsafe impl Pod for EventType {}
@@ -87,8 +802,74 @@ pub struct Order {
     pub quantity: u64,
     pub filled_quantity: u64,
     pub side: Side,
+    pub order_type: OrderType,
+    pub peg_offset: i64,
+    pub display_quantity: u64,
+    pub hidden_quantity: u64,
+    pub sequence: u64,
+}
+
+impl Order {
+    /// A free book slot, with valid enum values where zeroes are not one.
+    pub const EMPTY: Order = Order {
+        owner: Pubkey::new_from_array([0; 32]),
+        market: Pubkey::new_from_array([0; 32]),
+        timestamp: 0,
+        order_id: 0,
+        price: 0,
+        quantity: 0,
+        filled_quantity: 0,
+        side: Side::Buy,
+        order_type: OrderType::Limit,
+        peg_offset: 0,
+        display_quantity: 0,
+        hidden_quantity: 0,
+        sequence: 0,
+    };
+
+    /// Price the order trades at right now. Pegged orders float at
+    /// `reference + peg_offset`, capped by `price` as their hard limit; without
+    /// a reference they fall back to the limit.
+    pub fn effective_price(&self, reference: Option<u64>) -> u64 {
+        let limit = self.price;
+        match (self.order_type, reference) {
+            (OrderType::Pegged, Some(reference)) => {
+                let pegged = (reference as i128 + self.peg_offset as i128).max(0) as u64;
+                if self.side == Side::Buy {
+                    pegged.min(limit)
+                } else {
+                    pegged.max(limit)
+                }
+            }
+            _ => limit,
+        }
+    }
+
+    /// Shows the next slice of an iceberg once the visible one is filled.
+    /// `quantity` always holds the visible slice, the rest waits in
+    /// `hidden_quantity`. The new `sequence` puts the slice at the back of the
+    /// queue for its price.
+    pub fn refill(&mut self, sequence: u64) {
+        let slice = self.display_quantity.min(self.hidden_quantity);
+        self.hidden_quantity -= slice;
+        self.quantity = slice;
+        self.filled_quantity = 0;
+        self.sequence = sequence;
+    }
 }
 
+#[repr(u8)]
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, PartialEq)]
+#[borsh(use_discriminant = true)]
+pub enum OrderType {
+    Limit = 0,
+    Pegged = 1,
+    Iceberg = 2,
+}
+
+unsafe impl Pod for OrderType {}
+unsafe impl Zeroable for OrderType {}
+
 #[repr(u8)]
 #[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, PartialEq)]
 #[borsh(use_discriminant = true)]
@@ -103,6 +884,7 @@ unsafe impl Zeroable for Side {}
 #[repr(C, packed)]
 #[derive(Debug, Clone, Copy, Zeroable, Pod)]
 pub struct OrderBook {
+    pub discriminator: [u8; 8],
     pub orders: [Order; MAX_ORDERS],
     pub market: Pubkey,
     pub active_orders_count: u64,
@@ -110,7 +892,91 @@ pub struct OrderBook {
 }
 
 impl OrderBook {
-    pub const LEN: usize = (105 * MAX_ORDERS) + 32 + 8 + 1; // 107,561 bytes (~105KB)
+    pub const LEN: usize = 8 + (138 * MAX_ORDERS) + 32 + 8 + 1; // 141,361 bytes (~138KB)
+    pub const DISCRIMINATOR: [u8; 8] = *b"orderbk\0";
+
+    /// Mutably borrows `book_info` in place, checked to be the `side` book
+    /// of `market`.
+    pub fn load_mut<'a>(
+        program_id: &Pubkey,
+        book_info: &'a AccountInfo,
+        market: &Pubkey,
+        side: Side,
+    ) -> Result<RefMut<'a, OrderBook>, ProgramError> {
+        if book_info.owner != program_id {
+            msg!("Order book account must be owned by this program");
+            return Err(ProgramError::InvalidAccountData);
+        }
+        let data = book_info.try_borrow_mut_data()?;
+        check_discriminator(&data, Self::LEN, &Self::DISCRIMINATOR, "order book")?;
+        let book = RefMut::map(data, |data| {
+            bytemuck::from_bytes_mut::<OrderBook>(&mut data[..OrderBook::LEN])
+        });
+
+        if book.market != *market {
+            msg!("Order book belongs to a different market");
+            return Err(ProgramError::InvalidAccountData);
+        }
+        let book_side = book.side;
+        if book_side != side {
+            msg!("Expected the {:?} book, got the {:?} book", side, book_side);
+            return Err(ProgramError::InvalidAccountData);
+        }
+        Ok(book)
+    }
+
+    /// Highest bid or lowest ask resting on this side of the book.
+    pub fn best_price(&self, reference: Option<u64>) -> Option<u64> {
+        let side = self.side;
+        let prices = self.orders[..self.active_orders_count as usize]
+            .iter()
+            .map(|order| order.effective_price(reference));
+        if side == Side::Buy {
+            prices.max()
+        } else {
+            prices.min()
+        }
+    }
+
+    /// Matching priority of the order at `index`, lowest first: best
+    /// effective price, then earliest in the queue.
+    fn priority(&self, index: usize, reference: Option<u64>) -> (u64, u64) {
+        let order = &self.orders[index];
+        let price = order.effective_price(reference);
+        let price_rank = if self.side == Side::Buy {
+            u64::MAX - price
+        } else {
+            price
+        };
+        (price_rank, order.sequence)
+    }
+
+    /// Indices of active orders in matching priority: best effective price
+    /// first, then earliest in the queue.
+    pub fn matching_order(&self, reference: Option<u64>) -> Vec<usize> {
+        let mut indices: Vec<usize> = (0..self.active_orders_count as usize).collect();
+        indices.sort_by_cached_key(|&i| self.priority(i, reference));
+        indices
+    }
+
+    /// Index of the order matched next, skipping orders that are already
+    /// filled in full. One pass over the book, so a taker only pays for the
+    /// makers it actually reaches.
+    pub fn best_order(&self, reference: Option<u64>) -> Option<usize> {
+        (0..self.active_orders_count as usize)
+            .filter(|&i| self.orders[i].filled_quantity < self.orders[i].quantity)
+            .min_by_key(|&i| self.priority(i, reference))
+    }
+
+    /// Index of the order matched last: worst effective price, then latest
+    /// in the queue.
+    pub fn worst_order(&self, reference: Option<u64>) -> Option<usize> {
+        (0..self.active_orders_count as usize).max_by_key(|&i| self.priority(i, reference))
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.active_orders_count >= MAX_ORDERS as u64
+    }
 
     pub fn add_order(&mut self, order: Order) -> ProgramResult {
         if self.active_orders_count >= MAX_ORDERS as u64 {
@@ -132,17 +998,7 @@ impl OrderBook {
             self.orders[index] = self.orders[last_index];
         }
 
-        // Zero out the order properly 
-        self.orders[last_index] = Order {
-            owner: Pubkey::default(),
-            market: Pubkey::default(),
-            timestamp: 0,
-            order_id: 0,
-            price: 0,
-            quantity: 0,
-            filled_quantity: 0,
-            side: Side::Buy,
-        };
+        self.orders[last_index] = Order::EMPTY;
         self.active_orders_count -= 1;
         Ok(())
     }
@@ -150,6 +1006,7 @@ impl OrderBook {
 #[repr(C)]
 #[derive(Debug, Zeroable, Pod, Clone, Copy)]
 pub struct MarketEvents {
+    pub discriminator: [u8; 8],
     pub events: [Event; MAX_EVENTS],
     pub market: Pubkey,
     pub count: u64,
@@ -158,7 +1015,32 @@ pub struct MarketEvents {
 }
 
 impl MarketEvents {
-    pub const LEN: usize = (98 * MAX_EVENTS) + 32 + 8 + 8 + 8; // 50,232 bytes (~49KB)
+    pub const LEN: usize = 8 + (114 * MAX_EVENTS) + 32 + 8 + 8 + 8; // 58,432 bytes (~57KB)
+    pub const DISCRIMINATOR: [u8; 8] = *b"events\0\0";
+
+    /// Mutably borrows `events_info` in place, checked to be the event queue
+    /// of `market`.
+    pub fn load_mut<'a>(
+        program_id: &Pubkey,
+        events_info: &'a AccountInfo,
+        market: &Pubkey,
+    ) -> Result<RefMut<'a, MarketEvents>, ProgramError> {
+        if events_info.owner != program_id {
+            msg!("Market events account must be owned by this program");
+            return Err(ProgramError::InvalidAccountData);
+        }
+        let data = events_info.try_borrow_mut_data()?;
+        check_discriminator(&data, Self::LEN, &Self::DISCRIMINATOR, "market events")?;
+        let market_events = RefMut::map(data, |data| {
+            bytemuck::from_bytes_mut::<MarketEvents>(&mut data[..MarketEvents::LEN])
+        });
+
+        if market_events.market != *market {
+            msg!("Market events account belongs to a different market");
+            return Err(ProgramError::InvalidAccountData);
+        }
+        Ok(market_events)
+    }
 
     pub fn add_event(&mut self, event: Event) -> ProgramResult {
         if self.count >= MAX_EVENTS as u64 {
@@ -171,4 +1053,140 @@ impl MarketEvents {
         self.events_to_process += 1;
         Ok(())
     }
+
+    /// Whether a queued event still names `owner` as maker or taker.
+    pub fn names(&self, owner: &Pubkey) -> bool {
+        self.events[..self.count as usize]
+            .iter()
+            .any(|event| event.maker == *owner || event.taker == *owner)
+    }
+
+    /// Drops the first `consumed` events once they are applied, moving the
+    /// rest to the front so the queue always starts at index 0.
+    pub fn pop_front(&mut self, consumed: usize) {
+        let count = self.count as usize;
+        let consumed = consumed.min(count);
+        self.events.copy_within(consumed..count, 0);
+        self.events[count - consumed..count].fill(Event::EMPTY);
+        self.count -= consumed as u64;
+        self.events_to_process = self.events_to_process.saturating_sub(consumed as u64);
+    }
+}
+
+pub const MAX_TRIGGER_ORDERS: usize = 256;
+
+#[repr(u8)]
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, PartialEq)]
+#[borsh(use_discriminant = true)]
+pub enum TriggerType {
+    StopLoss = 0,
+    TakeProfit = 1,
+}
+
+unsafe impl Pod for TriggerType {}
+unsafe impl Zeroable for TriggerType {}
+
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy, Zeroable, Pod)]
+pub struct TriggerOrder {
+    pub owner: Pubkey,
+    pub timestamp: i64,
+    pub order_id: u64,
+    pub trigger_price: u64,
+    pub price: u64,
+    pub quantity: u64,
+    pub side: Side,
+    pub trigger_type: TriggerType,
+}
+
+impl TriggerOrder {
+    /// Stops fire when the price moves against the position the order closes
+    /// (sell stops below, buy stops above); take-profits fire the other way.
+    pub fn is_triggered(&self, last_price: u64) -> bool {
+        if last_price == 0 {
+            return false;
+        }
+        let trigger_price = self.trigger_price;
+        match (self.side, self.trigger_type) {
+            (Side::Sell, TriggerType::StopLoss) | (Side::Buy, TriggerType::TakeProfit) => {
+                last_price <= trigger_price
+            }
+            (Side::Buy, TriggerType::StopLoss) | (Side::Sell, TriggerType::TakeProfit) => {
+                last_price >= trigger_price
+            }
+        }
+    }
+}
+
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy, Zeroable, Pod)]
+pub struct TriggerBook {
+    pub discriminator: [u8; 8],
+    pub orders: [TriggerOrder; MAX_TRIGGER_ORDERS],
+    pub market: Pubkey,
+    pub active_orders_count: u64,
+}
+
+impl TriggerBook {
+    pub const LEN: usize = 8 + (74 * MAX_TRIGGER_ORDERS) + 32 + 8; // 18,992 bytes (~19KB)
+    pub const DISCRIMINATOR: [u8; 8] = *b"triggers";
+
+    /// Mutably borrows `trigger_book_info` in place, checked to be the
+    /// trigger book of `market`.
+    pub fn load_mut<'a>(
+        program_id: &Pubkey,
+        trigger_book_info: &'a AccountInfo,
+        market: &Pubkey,
+    ) -> Result<RefMut<'a, TriggerBook>, ProgramError> {
+        if trigger_book_info.owner != program_id {
+            msg!("Trigger book account must be owned by this program");
+            return Err(ProgramError::InvalidAccountData);
+        }
+        let data = trigger_book_info.try_borrow_mut_data()?;
+        check_discriminator(&data, Self::LEN, &Self::DISCRIMINATOR, "trigger book")?;
+        let trigger_book = RefMut::map(data, |data| {
+            bytemuck::from_bytes_mut::<TriggerBook>(&mut data[..TriggerBook::LEN])
+        });
+
+        if trigger_book.market != *market {
+            msg!("Trigger book belongs to a different market");
+            return Err(ProgramError::InvalidAccountData);
+        }
+        Ok(trigger_book)
+    }
+
+    pub fn add_order(&mut self, order: TriggerOrder) -> ProgramResult {
+        if self.active_orders_count >= MAX_TRIGGER_ORDERS as u64 {
+            return Err(ProgramError::Custom(2));
+        }
+
+        self.orders[self.active_orders_count as usize] = order;
+        self.active_orders_count += 1;
+        Ok(())
+    }
+
+    pub fn remove_order(&mut self, index: usize) -> Result<TriggerOrder, ProgramError> {
+        if index >= self.active_orders_count as usize {
+            return Err(ProgramError::Custom(3));
+        }
+
+        let order = self.orders[index];
+        let last_index = (self.active_orders_count - 1) as usize;
+        if index != last_index {
+            self.orders[index] = self.orders[last_index];
+        }
+
+        self.orders[last_index] = TriggerOrder {
+            owner: Pubkey::default(),
+            timestamp: 0,
+            order_id: 0,
+            trigger_price: 0,
+            price: 0,
+            quantity: 0,
+            side: Side::Buy,
+            trigger_type: TriggerType::StopLoss,
+        };
+        self.active_orders_count -= 1;
+        Ok(order)
+    }
 }
@@ -0,0 +1,423 @@
+//! Token program helpers shared by every instruction that moves tokens.
+//!
+//! A market accepts mints owned by either SPL Token or Token-2022, chosen per
+//! mint. Both programs share the instruction and base account layouts used
+//! here, so instructions are packed with `spl_token` and sent to whichever
+//! program owns the mint. Token-2022 mints are only accepted with extensions
+//! the vault accounting can live with.
+
+use solana_program::{
+    account_info::AccountInfo,
+    entrypoint::ProgramResult,
+    instruction::{AccountMeta, Instruction},
+    msg,
+    program::{get_return_data, invoke, invoke_signed},
+    program_error::ProgramError,
+    program_pack::Pack,
+    pubkey,
+    pubkey::Pubkey,
+};
+use solana_system_interface::program as system_program;
+use spl_token::{
+    instruction::TokenInstruction,
+    state::{Account, AccountState, Mint},
+};
+
+pub const TOKEN_2022_PROGRAM_ID: Pubkey = pubkey!("TokenzQdBNbLqP5VEhdkAS6EPFLC1PHnBqCXEpPxuEb");
+pub const ASSOCIATED_TOKEN_PROGRAM_ID: Pubkey =
+    pubkey!("ATokenGPvbdGVxr1b2hvZbsiqW5xWH25efTNsLJA8knL");
+
+/// Token-2022 mint extensions that leave transfers to and from the vaults
+/// unchanged, or only change them by a fee that deposits measure. Anything
+/// else (non-transferable, permanent delegate, transfer hooks, default frozen
+/// accounts, pausing, confidential transfers, ...) is rejected.
+const SUPPORTED_MINT_EXTENSIONS: [u16; 10] = [
+    1,  // TransferFeeConfig
+    3,  // MintCloseAuthority
+    10, // InterestBearingConfig
+    18, // MetadataPointer
+    19, // TokenMetadata
+    20, // GroupPointer
+    21, // TokenGroup
+    22, // GroupMemberPointer
+    23, // TokenGroupMember
+    25, // ScaledUiAmount
+];
+
+/// Extensions start after the base account length and a one byte account type
+const EXTENSIONS_OFFSET: usize = Account::LEN + 1;
+const ACCOUNT_TYPE_MINT: u8 = 1;
+
+pub fn is_token_program(key: &Pubkey) -> bool {
+    spl_token::check_id(key) || *key == TOKEN_2022_PROGRAM_ID
+}
+
+pub fn check_token_program(token_program_info: &AccountInfo) -> ProgramResult {
+    if !is_token_program(token_program_info.key) {
+        msg!("Invalid token program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+    Ok(())
+}
+
+/// Validates a mint for listing and returns its decimals.
+pub fn load_mint(mint_info: &AccountInfo) -> Result<u8, ProgramError> {
+    check_token_program_owner(mint_info)?;
+
+    let data = mint_info.try_borrow_data()?;
+    if data.len() < Mint::LEN {
+        msg!("Invalid mint account data: {}", mint_info.key);
+        return Err(ProgramError::InvalidAccountData);
+    }
+    let mint = Mint::unpack_from_slice(&data[..Mint::LEN])?;
+    if !mint.is_initialized {
+        msg!("Mint is not initialized: {}", mint_info.key);
+        return Err(ProgramError::UninitializedAccount);
+    }
+
+    if data.len() > EXTENSIONS_OFFSET {
+        if data[Account::LEN] != ACCOUNT_TYPE_MINT {
+            msg!("Account {} is not a mint", mint_info.key);
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        let mut offset = EXTENSIONS_OFFSET;
+        while offset + 4 <= data.len() {
+            let extension = u16::from_le_bytes([data[offset], data[offset + 1]]);
+            let length = u16::from_le_bytes([data[offset + 2], data[offset + 3]]) as usize;
+            // Zeroed space past the last extension
+            if extension == 0 {
+                break;
+            }
+            if !SUPPORTED_MINT_EXTENSIONS.contains(&extension) {
+                msg!(
+                    "Mint {} uses unsupported Token-2022 extension {}",
+                    mint_info.key,
+                    extension
+                );
+                return Err(ProgramError::InvalidAccountData);
+            }
+            offset += 4 + length;
+        }
+    }
+
+    Ok(mint.decimals)
+}
+
+fn check_token_program_owner(account_info: &AccountInfo) -> ProgramResult {
+    if !is_token_program(account_info.owner) {
+        msg!(
+            "Account {} is not owned by a token program",
+            account_info.key
+        );
+        return Err(ProgramError::IncorrectProgramId);
+    }
+    Ok(())
+}
+
+/// Mint of a token account, checked to belong to a token program.
+pub fn token_account_mint(token_account_info: &AccountInfo) -> Result<Pubkey, ProgramError> {
+    check_token_program_owner(token_account_info)?;
+
+    //first 32 bytes of spl token account consist of mint,for ref check state on spl_token mod
+    let data = token_account_info.try_borrow_data()?;
+    if data.len() < Account::LEN {
+        msg!("Invalid token account data: {}", token_account_info.key);
+        return Err(ProgramError::InvalidAccountData);
+    }
+    Ok(Pubkey::new_from_array(data[0..32].try_into().unwrap()))
+}
+
+/// Fully unpacks a token account, checking it holds `mint` for `owner` and is
+/// neither uninitialized nor frozen.
+pub fn load_token_account(
+    token_account_info: &AccountInfo,
+    mint: &Pubkey,
+    owner: &Pubkey,
+) -> Result<Account, ProgramError> {
+    check_token_program_owner(token_account_info)?;
+
+    let data = token_account_info.try_borrow_data()?;
+    if data.len() < Account::LEN {
+        msg!("Invalid token account data: {}", token_account_info.key);
+        return Err(ProgramError::InvalidAccountData);
+    }
+    let account = Account::unpack_from_slice(&data[..Account::LEN])?;
+
+    match account.state {
+        AccountState::Initialized => {}
+        AccountState::Uninitialized => {
+            msg!(
+                "Token account {} is not initialized",
+                token_account_info.key
+            );
+            return Err(ProgramError::UninitializedAccount);
+        }
+        AccountState::Frozen => {
+            msg!("Token account {} is frozen", token_account_info.key);
+            return Err(ProgramError::InvalidAccountData);
+        }
+    }
+
+    if account.mint != *mint {
+        msg!(
+            "Token account mint mismatch. Expected: {}, Got: {}",
+            mint,
+            account.mint
+        );
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    if account.owner != *owner {
+        msg!(
+            "Token account owner mismatch. Expected: {}, Got: {}",
+            owner,
+            account.owner
+        );
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    Ok(account)
+}
+
+pub fn associated_token_address(wallet: &Pubkey, mint: &Pubkey, token_program: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(
+        &[wallet.as_ref(), token_program.as_ref(), mint.as_ref()],
+        &ASSOCIATED_TOKEN_PROGRAM_ID,
+    )
+    .0
+}
+
+/// Creates `wallet`'s associated token account for `mint` when
+/// `account_info` does not exist yet, with `payer` paying rent. Existing
+/// accounts are left for the caller to validate.
+#[allow(clippy::too_many_arguments)]
+pub fn create_associated_token_account_if_missing<'a>(
+    payer_info: &AccountInfo<'a>,
+    wallet_info: &AccountInfo<'a>,
+    account_info: &AccountInfo<'a>,
+    mint_info: &AccountInfo<'a>,
+    token_program_info: &AccountInfo<'a>,
+    system_program_info: &AccountInfo<'a>,
+    associated_token_program_info: &AccountInfo<'a>,
+) -> ProgramResult {
+    if !(account_info.data_is_empty() && system_program::check_id(account_info.owner)) {
+        return Ok(());
+    }
+
+    let expected = associated_token_address(wallet_info.key, mint_info.key, token_program_info.key);
+    if *account_info.key != expected {
+        msg!(
+            "Token account {} does not exist and is not the associated token address {}",
+            account_info.key,
+            expected
+        );
+        return Err(ProgramError::UninitializedAccount);
+    }
+
+    if *associated_token_program_info.key != ASSOCIATED_TOKEN_PROGRAM_ID {
+        msg!("Invalid associated token program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    if !system_program::check_id(system_program_info.key) {
+        msg!("Invalid system program");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    invoke(
+        &Instruction {
+            program_id: ASSOCIATED_TOKEN_PROGRAM_ID,
+            accounts: vec![
+                AccountMeta::new(*payer_info.key, true),
+                AccountMeta::new(*account_info.key, false),
+                AccountMeta::new_readonly(*wallet_info.key, false),
+                AccountMeta::new_readonly(*mint_info.key, false),
+                AccountMeta::new_readonly(system_program::ID, false),
+                AccountMeta::new_readonly(*token_program_info.key, false),
+            ],
+            // CreateIdempotent
+            data: vec![1],
+        },
+        &[
+            payer_info.clone(),
+            account_info.clone(),
+            wallet_info.clone(),
+            mint_info.clone(),
+            system_program_info.clone(),
+            token_program_info.clone(),
+            associated_token_program_info.clone(),
+        ],
+    )
+}
+
+pub fn token_account_amount(token_account_info: &AccountInfo) -> Result<u64, ProgramError> {
+    let data = token_account_info.try_borrow_data()?;
+    if data.len() < Account::LEN {
+        msg!("Invalid token account data: {}", token_account_info.key);
+        return Err(ProgramError::InvalidAccountData);
+    }
+    Ok(Account::unpack_from_slice(&data[..Account::LEN])?.amount)
+}
+
+/// Size a token account for `mint` needs, including any account extensions
+/// its mint requires.
+pub fn account_data_size<'a>(
+    token_program_info: &AccountInfo<'a>,
+    mint_info: &AccountInfo<'a>,
+) -> Result<usize, ProgramError> {
+    invoke(
+        &Instruction {
+            program_id: *token_program_info.key,
+            accounts: vec![AccountMeta::new_readonly(*mint_info.key, false)],
+            data: TokenInstruction::GetAccountDataSize.pack(),
+        },
+        &[mint_info.clone(), token_program_info.clone()],
+    )?;
+
+    match get_return_data() {
+        Some((program_id, data)) if program_id == *token_program_info.key && data.len() == 8 => {
+            Ok(u64::from_le_bytes(data.try_into().unwrap()) as usize)
+        }
+        _ => {
+            msg!("Token program returned no account size");
+            Err(ProgramError::InvalidAccountData)
+        }
+    }
+}
+
+pub fn initialize_account<'a>(
+    token_program_info: &AccountInfo<'a>,
+    account_info: &AccountInfo<'a>,
+    mint_info: &AccountInfo<'a>,
+    owner: &Pubkey,
+) -> ProgramResult {
+    invoke(
+        &Instruction {
+            program_id: *token_program_info.key,
+            accounts: vec![
+                AccountMeta::new(*account_info.key, false),
+                AccountMeta::new_readonly(*mint_info.key, false),
+            ],
+            data: TokenInstruction::InitializeAccount3 { owner: *owner }.pack(),
+        },
+        &[
+            account_info.clone(),
+            mint_info.clone(),
+            token_program_info.clone(),
+        ],
+    )
+}
+
+/// `TransferChecked` through the mint's own token program. `signer_seeds`
+/// are empty when `authority` signed the transaction.
+#[allow(clippy::too_many_arguments)]
+pub fn transfer_checked<'a>(
+    token_program_info: &AccountInfo<'a>,
+    source_info: &AccountInfo<'a>,
+    mint_info: &AccountInfo<'a>,
+    destination_info: &AccountInfo<'a>,
+    authority_info: &AccountInfo<'a>,
+    amount: u64,
+    decimals: u8,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    if *mint_info.owner != *token_program_info.key {
+        msg!(
+            "Mint {} is not owned by token program {}",
+            mint_info.key,
+            token_program_info.key
+        );
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    invoke_signed(
+        &Instruction {
+            program_id: *token_program_info.key,
+            accounts: vec![
+                AccountMeta::new(*source_info.key, false),
+                AccountMeta::new_readonly(*mint_info.key, false),
+                AccountMeta::new(*destination_info.key, false),
+                AccountMeta::new_readonly(*authority_info.key, true),
+            ],
+            data: TokenInstruction::TransferChecked { amount, decimals }.pack(),
+        },
+        &[
+            source_info.clone(),
+            mint_info.clone(),
+            destination_info.clone(),
+            authority_info.clone(),
+            token_program_info.clone(),
+        ],
+        signer_seeds,
+    )
+}
+
+/// Transfers `amount` from a user into a vault and returns what the vault
+/// actually received, which is less than `amount` for mints charging a
+/// transfer fee.
+#[allow(clippy::too_many_arguments)]
+pub fn transfer_to_vault<'a>(
+    token_program_info: &AccountInfo<'a>,
+    user_token_info: &AccountInfo<'a>,
+    mint_info: &AccountInfo<'a>,
+    vault_info: &AccountInfo<'a>,
+    user_info: &AccountInfo<'a>,
+    amount: u64,
+    decimals: u8,
+) -> Result<u64, ProgramError> {
+    let vault_before = token_account_amount(vault_info)?;
+    transfer_checked(
+        token_program_info,
+        user_token_info,
+        mint_info,
+        vault_info,
+        user_info,
+        amount,
+        decimals,
+        &[],
+    )?;
+    Ok(token_account_amount(vault_info)? - vault_before)
+}
+
+/// Updates a wrapped SOL account's token amount after lamports were moved in.
+pub fn sync_native<'a>(
+    token_program_info: &AccountInfo<'a>,
+    account_info: &AccountInfo<'a>,
+) -> ProgramResult {
+    invoke(
+        &Instruction {
+            program_id: *token_program_info.key,
+            accounts: vec![AccountMeta::new(*account_info.key, false)],
+            data: TokenInstruction::SyncNative.pack(),
+        },
+        &[account_info.clone(), token_program_info.clone()],
+    )
+}
+
+pub fn close_account<'a>(
+    token_program_info: &AccountInfo<'a>,
+    account_info: &AccountInfo<'a>,
+    destination_info: &AccountInfo<'a>,
+    authority_info: &AccountInfo<'a>,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    invoke_signed(
+        &Instruction {
+            program_id: *token_program_info.key,
+            accounts: vec![
+                AccountMeta::new(*account_info.key, false),
+                AccountMeta::new(*destination_info.key, false),
+                AccountMeta::new_readonly(*authority_info.key, true),
+            ],
+            data: TokenInstruction::CloseAccount.pack(),
+        },
+        &[
+            account_info.clone(),
+            destination_info.clone(),
+            authority_info.clone(),
+            token_program_info.clone(),
+        ],
+        signer_seeds,
+    )
+}